@@ -0,0 +1,46 @@
+//! Embeds the server in another program: serves a website directory, with a directory of downloads
+//! mounted at `/files` and a greeting computed at `/api/greet?name=...`.
+//!
+//! ```text
+//! cargo run --example serve_dir -- <website files location> [addr:port] [downloads directory]
+//! ```
+use std::env;
+use std::process;
+use std::sync::Arc;
+use simple_rust_webserver::server::{Response, WebsiteError};
+use simple_rust_webserver::Website;
+
+fn site(location: &str, downloads: Option<&str>) -> Result<Website, WebsiteError> {
+    let site = Website::new(location)?
+        .with_get_route("/api/greet", Box::new(|request| {
+            let name = request.url.split_once("?name=").map_or("world", |(_, name)| name);
+            Ok(Response::new(200)
+                .with_header("Content-Type", "text/plain; charset=utf-8")
+                .with_body(format!("Hello, {}!", name).into_bytes()))
+        }));
+    match downloads {
+        Some(downloads) => site.with_mount("/files", downloads),
+        None => Ok(site)
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let location = match args.first() {
+        Some(location) => location,
+        None => {
+            eprintln!("usage: serve_dir <website files location> [addr:port] [downloads directory]");
+            process::exit(2);
+        }
+    };
+    let address = args.get(1).map_or("127.0.0.1:8080", String::as_str);
+    let site = site(location, args.get(2).map(String::as_str)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    println!("serving {} on http://{}", location, address);
+    if let Err(e) = Arc::new(site).serve(address) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
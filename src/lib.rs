@@ -0,0 +1,5 @@
+//! A multithreaded HTTP server for static websites, which can also be embedded in a larger application:
+//! build a [`Website`] and hand it to [`Server::run`], or call [`Website::serve`].
+pub mod server;
+
+pub use crate::server::{Server, Website};
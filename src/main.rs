@@ -1,15 +1,145 @@
-pub mod server;
+mod pidfile;
 use std::env;
+use std::process;
 use std::sync::Arc;
-use crate::server::Website;
+use simple_rust_webserver::server;
+use simple_rust_webserver::server::{Cache, CacheOptions, ReloadableSite, Website};
+use crate::pidfile::PidFile;
+
+/// What the server was asked to do on the command line.
+#[derive(Debug, PartialEq)]
+enum CliArgs {
+    /// `--version` or `-V`
+    Version,
+    /// `<website files location> <addr:port>`
+    Serve { site: String, addr: String },
+    /// `--redirect-to-https [https port] <addr:port>`: only redirect plain HTTP to https
+    RedirectToHttps { https_port: u16, addr: String },
+    /// `--list-cache`: print what's in the cache and when it was cached
+    ListCache
+}
+
+const USAGE: &str = "usage: [--pid-file <path>] [--acceptors <threads>] \
+    <website files location> <addr:port> | --redirect-to-https [https port] <addr:port> | --list-cache | --version";
+
+/// Takes `option` and the value after it out of `args`, wherever they are.
+fn take_option(args: &mut Vec<String>, option: &str) -> Result<Option<String>, String> {
+    match args.iter().position(|arg| arg == option) {
+        Some(i) if i + 1 < args.len() => Ok(args.drain(i..i + 2).nth(1)),
+        Some(_) => Err(format!("{} needs a value\n{}", option, USAGE)),
+        None => Ok(None)
+    }
+}
+
+/// Parses the arguments after the program name, with `--pid-file` and `--acceptors` already taken out of them.
+fn parse_args(args: &[String]) -> Result<CliArgs, String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args[..] {
+        ["--version"] | ["-V"] => Ok(CliArgs::Version),
+        ["--list-cache"] => Ok(CliArgs::ListCache),
+        ["--redirect-to-https", addr] => Ok(CliArgs::RedirectToHttps { https_port: 443, addr: addr.to_string() }),
+        ["--redirect-to-https", port, addr] => match port.parse() {
+            Ok(https_port) => Ok(CliArgs::RedirectToHttps { https_port, addr: addr.to_string() }),
+            Err(_) => Err(format!("invalid https port {}", port))
+        },
+        ["--redirect-to-https", ..] => Err("usage: --redirect-to-https [https port] <addr:port>".to_string()),
+        [site, addr] if !site.starts_with('-') => Ok(CliArgs::Serve { site: site.to_string(), addr: addr.to_string() }),
+        _ => Err(format!("2 command line args needed: <website files location> <addr:port>, or --version\n{}", USAGE))
+    }
+}
 
 fn main() {
-    let mut args: Vec<_> = env::args().collect();
-    if args.len() != 3 {
-        panic!("2 command line args needed: <website files location> <addr:port>")
+    #[cfg(feature = "env_logger")]
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let mut args: Vec<_> = env::args().skip(1).collect();
+    // `--pid-file <path>` and `--acceptors <threads>` may come before the other arguments
+    let pid_file_path = take_option(&mut args, "--pid-file").unwrap_or_else(|usage| panic!("{}", usage));
+    let acceptors = match take_option(&mut args, "--acceptors").unwrap_or_else(|usage| panic!("{}", usage)) {
+        Some(acceptors) => acceptors.parse().unwrap_or_else(|_| panic!("invalid number of acceptors {}", acceptors)),
+        None => 1
     };
-    let addr = args.remove(2);
-    let site = args.remove(1);
-    let site = Arc::new(Website::new(site));
-    server::main(Arc::clone(&site), &addr)
+    let cli = parse_args(&args).unwrap_or_else(|usage| panic!("{}", usage));
+    if cli == CliArgs::Version {
+        println!("rust-webserver {}", env!("CARGO_PKG_VERSION"));
+        return;
+    }
+    let _pid_file = pid_file_path.map(|path| PidFile::create(&path).unwrap_or_else(|e| {
+        eprintln!("can't write pid file {}: {}", path, e);
+        process::exit(1);
+    }));
+    match cli {
+        CliArgs::Version => {}
+        CliArgs::ListCache => {
+            let cache = Cache::new_with_options(CacheOptions::default()).unwrap_or_else(|e| {
+                eprintln!("can't open the cache: {}", e);
+                process::exit(1);
+            });
+            for entry in cache.list() {
+                println!("{}\t{}", entry.url, entry.cached_at);
+            }
+        }
+        CliArgs::RedirectToHttps { https_port, addr } =>
+            bind(&addr, acceptors).run(Arc::new(server::HttpsRedirect::new(https_port))).unwrap(),
+        CliArgs::Serve { site, addr } => {
+            // rebuilt on SIGHUP, which picks up a site directory that's a symlink to a new release
+            let site = match ReloadableSite::new(Box::new(move || Website::new(&site))) {
+                Ok(site) => Arc::new(site),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+            };
+            #[cfg(unix)]
+            site.reload_on_sighup().unwrap();
+            bind(&addr, acceptors).run(site).unwrap()
+        }
+    }
+}
+
+/// Binds `addr` for a server that accepts connections on `acceptors` threads, finishes its in-flight
+/// requests and stops on SIGTERM, or exits if it can't be bound, e.g. because another server is using it.
+fn bind(addr: &str, acceptors: usize) -> server::Server {
+    let server = server::Server::bind(addr).unwrap_or_else(|e| {
+        eprintln!("Failed to bind to {}: {}", addr, e);
+        process::exit(1);
+    }).with_acceptors(acceptors);
+    #[cfg(unix)]
+    server.shutdown_handle().unwrap().shutdown_on_sigterm().unwrap();
+    server
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{CliArgs, parse_args, take_option};
+
+    fn parse(args: &[&str]) -> Result<CliArgs, String> {
+        parse_args(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn command_lines() {
+        assert_eq!(parse(&["--version"]), Ok(CliArgs::Version));
+        assert_eq!(parse(&["-V"]), Ok(CliArgs::Version));
+        assert_eq!(parse(&["--list-cache"]), Ok(CliArgs::ListCache));
+        assert_eq!(parse(&["site", "0.0.0.0:80"]),
+                   Ok(CliArgs::Serve { site: "site".to_string(), addr: "0.0.0.0:80".to_string() }));
+        assert_eq!(parse(&["--redirect-to-https", "0.0.0.0:80"]),
+                   Ok(CliArgs::RedirectToHttps { https_port: 443, addr: "0.0.0.0:80".to_string() }));
+        assert_eq!(parse(&["--redirect-to-https", "8443", "0.0.0.0:80"]),
+                   Ok(CliArgs::RedirectToHttps { https_port: 8443, addr: "0.0.0.0:80".to_string() }));
+        assert!(parse(&["--redirect-to-https", "https", "0.0.0.0:80"]).is_err());
+        assert!(parse(&[]).unwrap_err().contains("--version"));
+        assert!(parse(&["site"]).is_err());
+        assert!(parse(&["--version", "site"]).is_err());
+    }
+
+    #[test]
+    fn options() {
+        let mut args = ["--acceptors", "4", "site", "0.0.0.0:80", "--pid-file", "run.pid"].map(String::from).to_vec();
+        assert_eq!(take_option(&mut args, "--pid-file"), Ok(Some("run.pid".to_string())));
+        assert_eq!(take_option(&mut args, "--acceptors"), Ok(Some("4".to_string())));
+        assert_eq!(take_option(&mut args, "--acceptors"), Ok(None));
+        assert_eq!(args, ["site", "0.0.0.0:80"]);
+        assert!(take_option(&mut vec!["site".to_string(), "--acceptors".to_string()], "--acceptors").is_err());
+    }
 }
@@ -0,0 +1,38 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A file holding the server's process id for as long as this value lives, for scripts that manage the process.
+pub struct PidFile {
+    path: PathBuf
+}
+
+impl PidFile {
+    /// Writes the process id to `path`, replacing whatever was there.
+    pub fn create(path: impl AsRef<Path>) -> Result<PidFile, io::Error> {
+        let path = path.as_ref().to_path_buf();
+        fs::write(&path, format!("{}\n", std::process::id()))?;
+        Ok(PidFile { path })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pidfile::PidFile;
+
+    #[test]
+    fn written_and_removed() {
+        let path = std::env::temp_dir().join(format!("simple-rust-webserver-{}.pid", std::process::id()));
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap().trim(), std::process::id().to_string());
+        drop(pid_file);
+        assert!(!path.exists());
+        assert!(PidFile::create(path.join("in-a-file")).is_err());
+    }
+}
@@ -0,0 +1,138 @@
+use std::io;
+use std::time::{Duration, Instant};
+use tracing::warn;
+use crate::server::error::ServerError;
+
+/// Backoff after the first failed accept; it doubles on every further failure up to `MAX_BACKOFF`.
+const MIN_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+/// At most one accept warning is logged per this interval; the rest are only counted.
+const WARNING_INTERVAL: Duration = Duration::from_secs(5);
+
+// not in std::io::ErrorKind yet
+const EMFILE: i32 = 24;
+const ENFILE: i32 = 23;
+
+#[derive(Debug, PartialEq)]
+pub enum AcceptErrorKind {
+    /// e.g. out of file descriptors or a client that gave up during the handshake; worth retrying
+    Transient,
+    /// the listener itself is broken, so the server should stop
+    Fatal
+}
+
+pub fn classify_accept_error(e: &io::Error) -> AcceptErrorKind {
+    if matches!(e.raw_os_error(), Some(EMFILE) | Some(ENFILE)) {
+        return AcceptErrorKind::Transient;
+    }
+    match e.kind() {
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::OutOfMemory => AcceptErrorKind::Transient,
+        _ => AcceptErrorKind::Fatal
+    }
+}
+
+/// Keeps a listener that keeps failing to accept from spinning and flooding the log.
+pub struct AcceptBackoff {
+    delay: Duration,
+    last_warning: Option<Instant>,
+    suppressed: usize
+}
+
+impl AcceptBackoff {
+    pub fn new() -> AcceptBackoff {
+        AcceptBackoff {
+            delay: Duration::ZERO,
+            last_warning: None,
+            suppressed: 0
+        }
+    }
+
+    /// Pulls connections from `incoming` until one is accepted, sleeping with `sleep` after
+    /// transient errors. Returns `None` once `incoming` runs out, and an error if accepting failed fatally.
+    pub fn next_connection<T>(
+        &mut self,
+        incoming: &mut impl Iterator<Item = io::Result<T>>,
+        mut sleep: impl FnMut(Duration)
+    ) -> Result<Option<T>, ServerError> {
+        loop {
+            match incoming.next() {
+                Some(Ok(connection)) => {
+                    self.delay = Duration::ZERO;
+                    return Ok(Some(connection));
+                }
+                Some(Err(e)) if classify_accept_error(&e) == AcceptErrorKind::Transient => {
+                    self.delay = (self.delay * 2).clamp(MIN_BACKOFF, MAX_BACKOFF);
+                    self.warn(&e);
+                    sleep(self.delay);
+                }
+                Some(Err(e)) => return Err(ServerError::Io(e)),
+                None => return Ok(None)
+            }
+        }
+    }
+
+    fn warn(&mut self, e: &io::Error) {
+        let now = Instant::now();
+        match self.last_warning {
+            Some(last) if now.duration_since(last) < WARNING_INTERVAL => self.suppressed += 1,
+            _ => {
+                if self.suppressed > 0 {
+                    warn!("couldn't accept a connection, retrying in {:?}: {} ({} similar errors not shown)",
+                          self.delay, e, self.suppressed);
+                } else {
+                    warn!("couldn't accept a connection, retrying in {:?}: {}", self.delay, e);
+                }
+                self.last_warning = Some(now);
+                self.suppressed = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::time::Duration;
+    use crate::server::accept::{AcceptBackoff, AcceptErrorKind, classify_accept_error, MAX_BACKOFF, MIN_BACKOFF};
+
+    #[test]
+    fn classification() {
+        assert_eq!(classify_accept_error(&io::Error::from_raw_os_error(24)), AcceptErrorKind::Transient);
+        assert_eq!(classify_accept_error(&io::Error::from_raw_os_error(23)), AcceptErrorKind::Transient);
+        assert_eq!(classify_accept_error(&io::Error::from(io::ErrorKind::ConnectionAborted)), AcceptErrorKind::Transient);
+        assert_eq!(classify_accept_error(&io::Error::from(io::ErrorKind::Interrupted)), AcceptErrorKind::Transient);
+        assert_eq!(classify_accept_error(&io::Error::from(io::ErrorKind::InvalidInput)), AcceptErrorKind::Fatal);
+        assert_eq!(classify_accept_error(&io::Error::from(io::ErrorKind::PermissionDenied)), AcceptErrorKind::Fatal);
+    }
+
+    #[test]
+    fn backs_off_exponentially_and_resets() {
+        let mut incoming = (0..12).map(|_| Err(io::Error::from_raw_os_error(24)))
+            .chain(vec![Ok(1)])
+            .chain(vec![Err(io::Error::from(io::ErrorKind::ConnectionAborted)), Ok(2)])
+            .chain(vec![Err(io::Error::from(io::ErrorKind::InvalidInput)), Ok(3)]);
+        let mut backoff = AcceptBackoff::new();
+        let mut slept = vec![];
+
+        assert_eq!(backoff.next_connection(&mut incoming, |d| slept.push(d)).unwrap(), Some(1));
+        assert_eq!(slept.len(), 12);
+        assert_eq!(slept[0], MIN_BACKOFF);
+        assert_eq!(slept[1], MIN_BACKOFF * 2);
+        assert!(slept.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(*slept.last().unwrap(), MAX_BACKOFF);
+
+        slept.clear();
+        assert_eq!(backoff.next_connection(&mut incoming, |d| slept.push(d)).unwrap(), Some(2));
+        assert_eq!(slept, vec![MIN_BACKOFF]);
+
+        assert!(backoff.next_connection(&mut incoming, |_| {}).is_err());
+        assert_eq!(backoff.next_connection(&mut incoming, |_| {}).unwrap(), Some(3));
+        assert_eq!(backoff.next_connection(&mut incoming, |_| {}).unwrap(), None);
+        assert_eq!(Duration::ZERO, backoff.delay);
+    }
+}
@@ -0,0 +1,142 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+use chrono::{DateTime, Utc};
+use tracing::warn;
+
+/// A file or directory shown in a directory listing.
+#[derive(Debug)]
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: Option<SystemTime>
+}
+
+/// The entries of `dir`, directories first, then by name. Hidden entries are left out, and so are
+/// entries that can't be read, like broken symlinks or files without permission, after a warning.
+pub fn list(dir: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("skipping an entry of {} in its listing: {}", dir.display(), e);
+                continue;
+            }
+        };
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        // follows symlinks, so a link to a directory is listed as one
+        match fs::metadata(entry.path()) {
+            Ok(metadata) => entries.push(Entry {
+                name,
+                is_dir: metadata.is_dir(),
+                size: metadata.len(),
+                modified: metadata.modified().ok()
+            }),
+            Err(e) => warn!("skipping {} in the listing of {}: {}", name, dir.display(), e)
+        }
+    }
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    Ok(entries)
+}
+
+/// An HTML page listing `entries` for the directory at url path `url_path`, which ends in `/`.
+pub fn render(url_path: &str, entries: &[Entry]) -> String {
+    let title = escape_html(url_path);
+    let mut page = format!("<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n\
+                            <body>\n<h1>Index of {0}</h1>\n<table>\n\
+                            <tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n", title);
+    if url_path != "/" {
+        page += "<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n";
+    }
+    for entry in entries {
+        let name = if entry.is_dir { format!("{}/", entry.name) } else { entry.name.clone() };
+        let size = if entry.is_dir { "-".to_string() } else { human_size(entry.size) };
+        let modified = entry.modified
+            .map(|modified| DateTime::<Utc>::from(modified).format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        page += &format!("<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n",
+                         escape_html(&encode_href(&name)), escape_html(&name), size, modified);
+    }
+    page += "</table>\n</body>\n</html>\n";
+    page
+}
+
+/// `bytes` in the largest binary unit that keeps the number at least 1, like `1.5 KiB`.
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Percent-encodes what would otherwise end the path of a relative link or change its meaning.
+fn encode_href(name: &str) -> String {
+    let mut encoded = String::new();
+    for c in name.chars() {
+        match c {
+            '%' | '#' | '?' | ' ' | ':' => encoded += &format!("%{:02X}", c as u32),
+            _ => encoded.push(c)
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod test {
+    use crate::server::autoindex::{human_size, list, render};
+    use crate::server::scratch::ScratchDir;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(5 * 1024 * 1024), "5.0 MiB");
+        assert_eq!(human_size(u64::MAX), "16384.0 PiB");
+    }
+
+    #[test]
+    fn listing() {
+        let scratch = ScratchDir::new("autoindex");
+        let dir = scratch.path();
+        std::fs::create_dir_all(dir.join("zdir")).unwrap();
+        std::fs::create_dir_all(dir.join("adir")).unwrap();
+        std::fs::write(dir.join("b.txt"), vec![0; 2048]).unwrap();
+        std::fs::write(dir.join("a b#1.html"), "<p>").unwrap();
+        std::fs::write(dir.join(".hidden"), "").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.join("nowhere"), dir.join("broken")).unwrap();
+
+        let entries = list(dir).unwrap();
+        let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["adir", "zdir", "a b#1.html", "b.txt"]);
+        assert_eq!(entries[3].size, 2048);
+        assert!(entries.iter().all(|entry| entry.modified.is_some()));
+
+        let page = render("/files/", &entries);
+        assert!(page.contains("<title>Index of /files/</title>"));
+        assert!(page.contains("<a href=\"../\">"));
+        assert!(page.contains("<td><a href=\"adir/\">adir/</a></td><td>-</td>"));
+        assert!(page.contains("<td><a href=\"a%20b%231.html\">a b#1.html</a></td><td>3 B</td>"));
+        assert!(page.contains("<td><a href=\"b.txt\">b.txt</a></td><td>2.0 KiB</td>"));
+        assert!(page.find("zdir/").unwrap() < page.find("a b#1.html").unwrap());
+        assert!(!render("/", &entries).contains("../"));
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// The base urls one proxied prefix is spread over, and which of them are down.
+/// It's shared by all the workers forwarding requests, so its state is behind an atomic and a mutex.
+pub struct Balancer {
+    /// without a trailing `/`
+    base_urls: Vec<String>,
+    /// until when each base url is skipped after failing
+    down_until: Mutex<Vec<Option<Instant>>>,
+    /// the round-robin position
+    next: AtomicUsize
+}
+
+impl Balancer {
+    pub fn new(base_urls: Vec<String>) -> Balancer {
+        Balancer {
+            down_until: Mutex::new(vec![None; base_urls.len()]),
+            base_urls,
+            next: AtomicUsize::new(0)
+        }
+    }
+
+    pub fn base_url(&self, i: usize) -> &str {
+        &self.base_urls[i]
+    }
+
+    /// The base urls that are up, by index, in the order a request should try them: taking turns
+    /// between requests, or, if `sticky`, starting with the one `client`'s address hashes to, so a client
+    /// keeps getting the same one for as long as it's up. A lone base url is always up, since there's
+    /// nothing to fail over to.
+    pub fn candidates(&self, client: Option<IpAddr>, sticky: bool) -> Vec<usize> {
+        let count = self.base_urls.len();
+        if count == 1 {
+            return vec![0];
+        }
+        let first = match client.filter(|_| sticky) {
+            Some(client) => {
+                let mut hasher = DefaultHasher::new();
+                client.hash(&mut hasher);
+                hasher.finish() as usize % count
+            }
+            None => self.next.fetch_add(1, Ordering::Relaxed) % count
+        };
+        let now = Instant::now();
+        let down_until = self.down_until.lock().unwrap_or_else(|e| e.into_inner());
+        (0..count).map(|offset| (first + offset) % count)
+            .filter(|i| down_until[*i].is_none_or(|until| until <= now))
+            .collect()
+    }
+
+    /// Skips base url `i` for `cooldown`, after which it gets requests again.
+    pub fn mark_down(&self, i: usize, cooldown: Duration) {
+        self.down_until.lock().unwrap_or_else(|e| e.into_inner())[i] = Some(Instant::now() + cooldown);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+    use std::thread;
+    use std::time::Duration;
+    use crate::server::balancer::Balancer;
+
+    fn balancer(count: usize) -> Balancer {
+        Balancer::new((0..count).map(|i| format!("http://127.0.0.1:900{}", i)).collect())
+    }
+
+    #[test]
+    fn round_robin() {
+        let balancer = balancer(3);
+        let firsts = (0..6).map(|_| balancer.candidates(None, false)[0]).collect::<Vec<_>>();
+        assert_eq!(firsts, [0, 1, 2, 0, 1, 2]);
+        assert_eq!(balancer.candidates(None, false), [0, 1, 2]);
+        assert_eq!(balancer.candidates(None, false), [1, 2, 0]);
+    }
+
+    #[test]
+    fn down_until_cooldown() {
+        let balancer = balancer(2);
+        balancer.mark_down(1, Duration::from_millis(50));
+        assert!((0..4).all(|_| balancer.candidates(None, false) == [0]));
+        balancer.mark_down(0, Duration::from_millis(50));
+        assert!(balancer.candidates(None, false).is_empty());
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(balancer.candidates(None, false).len(), 2);
+
+        // there's no failing over from a lone upstream
+        let lone = self::balancer(1);
+        lone.mark_down(0, Duration::from_secs(60));
+        assert_eq!(lone.candidates(None, false), [0]);
+    }
+
+    #[test]
+    fn sticky_clients() {
+        let balancer = balancer(4);
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+        let first = balancer.candidates(Some(client), true)[0];
+        assert!((0..8).all(|_| balancer.candidates(Some(client), true)[0] == first));
+        // until its upstream goes down
+        balancer.mark_down(first, Duration::from_secs(60));
+        let second = balancer.candidates(Some(client), true)[0];
+        assert_ne!(second, first);
+        assert!((0..8).all(|_| balancer.candidates(Some(client), true)[0] == second));
+        // without an address, it's taking turns again
+        assert_eq!(balancer.candidates(None, true).len(), 3);
+    }
+}
@@ -1,16 +1,24 @@
 use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::DefaultHasher;
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
 use std::fs::{File, OpenOptions};
-use std::future::Future;
 use std::hash::{Hash, Hasher};
+use std::io;
 use std::io::{BufRead, BufReader, Read};
 use std::io::Write;
-use std::pin::Pin;
-use std::str::FromStr;
-use std::task::Poll;
-use chrono::format::parse;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
 use chrono::NaiveDateTime;
-use crate::server::Response;
+use tracing::{debug, warn};
+use crate::server::error::ServerError;
+use crate::server::cache_control::CacheDirectives;
+use crate::server::upstream::{UpstreamClient, UpstreamError, UpstreamResponse, UreqClient};
 /*
 
 The cache should store requests from the user.
@@ -25,6 +33,7 @@ cache/
             0/
                 key
                 data
+                hits
             1/
                 key
                 data
@@ -44,206 +53,1195 @@ cache/
 
 // the index should store the requests that have been cached.
 
-struct CacheIndex<'a> {
-    filename: &'a str,
+struct CacheIndex {
+    filename: String,
 
-    entries: HashMap<String, chrono::NaiveDateTime>
+    /// when each url was put in the cache
+    entries: HashMap<String, chrono::NaiveDateTime>,
+    /// how long urls that were put in the cache with a ttl of their own stay fresh; they go with their entries
+    ttls: HashMap<String, Duration>
 }
 
-struct Cache<'a> {
-    folder: &'a str,
-    index: CacheIndex<'a>
+/// Upstream responses cached on disk. Nothing but the builders needs `&mut self`, so a cache can be
+/// shared between threads in an `Arc`; entries are locked by hash while they're read and written.
+pub struct Cache {
+    folder: String,
+    /// shared with the thread that prunes expired entries
+    index: Arc<Mutex<CacheIndex>>,
+    /// when each url was last put in or read from the cache, for LRU eviction
+    last_used: Mutex<HashMap<String, NaiveDateTime>>,
+    /// locks for the entry directories, picked by hash, so that an entry is never read or written
+    /// while another thread writes it; shared with the thread that prunes expired entries
+    entry_locks: Arc<Vec<Mutex<()>>>,
+    /// bytes taken up by everything under `folder`
+    total_bytes: Arc<AtomicU64>,
+    /// least recently used entries are evicted to stay under this
+    max_disk_bytes: u64,
+    /// least recently used entries are evicted to keep at most this many
+    max_entries: usize,
+    /// how long entries stay fresh, if they expire at all
+    ttl: Option<Duration>,
+    /// whether a stale entry is fetched again when it's asked for, instead of served until it's pruned
+    revalidate_stale: bool,
+    /// whether responses without an explicit lifetime expire based on their `Last-Modified`
+    heuristic_freshness: bool,
+    /// whether a stale entry is served when it can't be revalidated, rather than failing
+    serve_stale_on_error: bool,
+    /// how long after going stale an entry is still served while it's revalidated in the background
+    stale_while_revalidate: Option<Duration>,
+    /// what time it is, in UTC; only tests use anything but [`now`]
+    clock: Arc<dyn Fn() -> NaiveDateTime + Send + Sync>,
+    /// urls being revalidated in the background
+    refreshing: Mutex<HashSet<String>>,
+    /// hands stale entries to the thread that revalidates them one after another, once it's started
+    revalidations: Mutex<Option<mpsc::SyncSender<(String, CachedResponse)>>>,
+    /// what background revalidations got, along with the stale entry they revalidated, to be stored
+    /// the next time the cache is used
+    refreshed: Arc<Mutex<Vec<Refreshed>>>,
+    fetcher: Fetcher
+}
+
+/// A background revalidation of a url, and what it got from the upstream.
+type Refreshed = (String, CachedResponse, Result<UpstreamResponse, ServerError>);
+
+/// Fetches what isn't cached, and can be handed to background revalidations.
+#[derive(Clone)]
+struct Fetcher {
+    client: Arc<dyn UpstreamClient>,
+    /// added to every fetch
+    headers: Vec<(String, String)>,
+    /// how often a fetch is tried before giving up on an unreachable upstream
+    attempts: u32,
+    /// how long to wait before the second attempt, doubling for every one after it
+    backoff: Duration
+}
+
+impl Fetcher {
+    /// Fetches `url` with `conditions` added to the request, retrying as configured with [`Cache::with_retries`].
+    fn fetch(&self, url: &str, conditions: &[(String, String)]) -> Result<UpstreamResponse, ServerError> {
+        let headers = self.headers.iter().chain(conditions).cloned().collect::<Vec<_>>();
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+        loop {
+            match fetch_once(self.client.as_ref(), url, &headers) {
+                Ok(response) => return Ok(response),
+                Err((e, true)) if attempt < self.attempts => {
+                    debug!("fetching {} failed, trying again in {:?}: {}", url, backoff, e);
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err((e, _)) => return Err(e)
+            }
+        }
+    }
+}
+
+/// How a [`Cache`] is set up, for [`Cache::new_with_options`]. By default, the cache is in `cache/`,
+/// laid out as described at the top of this file, and its entries never expire and aren't limited.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheOptions {
+    index_path: String,
+    data_path: String,
+    ttl_seconds: Option<u64>,
+    max_entries: Option<usize>,
+    max_disk_bytes: Option<u64>,
+    revalidate_stale: bool,
+    heuristic_freshness: bool,
+    serve_stale_on_error: bool,
+    stale_while_revalidate_seconds: Option<u64>
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        CacheOptions::new("cache/cache-meta/cache-index", "cache/data")
+    }
+}
+
+impl CacheOptions {
+    /// Options for a cache with its index in the file `index_path` and its entries under `data_path`.
+    pub fn new(index_path: &str, data_path: &str) -> CacheOptions {
+        CacheOptions {
+            index_path: index_path.to_string(),
+            data_path: data_path.to_string(),
+            ttl_seconds: None,
+            max_entries: None,
+            max_disk_bytes: None,
+            revalidate_stale: false,
+            heuristic_freshness: false,
+            serve_stale_on_error: false,
+            stale_while_revalidate_seconds: None
+        }
+    }
+
+    /// Entries become stale this many seconds after they were cached, and are pruned then,
+    /// unless they're revalidated instead.
+    pub fn with_ttl_seconds(mut self, ttl_seconds: u64) -> Self {
+        self.ttl_seconds = Some(ttl_seconds);
+        self
+    }
+
+    /// Keeps at most `max_entries` entries, evicting the least recently used ones.
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = Some(max_entries);
+        self
+    }
+
+    /// See [`Cache::with_max_disk_bytes`].
+    pub fn with_max_disk_bytes(mut self, max_disk_bytes: u64) -> Self {
+        self.max_disk_bytes = Some(max_disk_bytes);
+        self
+    }
+
+    /// Keeps entries that outlived the ttl around and revalidates them when they're asked for.
+    /// Without it, they're pruned in the background.
+    pub fn with_revalidate_stale(mut self, revalidate_stale: bool) -> Self {
+        self.revalidate_stale = revalidate_stale;
+        self
+    }
+
+    /// Lets responses that say nothing about how long they stay fresh expire anyway, a tenth of
+    /// their age after they were fetched, going by their `Last-Modified`. Without it, they only
+    /// expire with the ttl.
+    pub fn with_heuristic_freshness(mut self, heuristic_freshness: bool) -> Self {
+        self.heuristic_freshness = heuristic_freshness;
+        self
+    }
+
+    /// Serves a stale entry, with a warning, when the upstream can't be reached to revalidate it,
+    /// instead of failing.
+    pub fn with_serve_stale_on_error(mut self, serve_stale_on_error: bool) -> Self {
+        self.serve_stale_on_error = serve_stale_on_error;
+        self
+    }
+
+    /// Serves entries that went stale at most `max_staleness_seconds` ago right away, and revalidates
+    /// them in the background, so that callers don't wait for the upstream. Entries that have been stale
+    /// for longer are revalidated before they're served, as usual.
+    pub fn with_stale_while_revalidate(mut self, max_staleness_seconds: u64) -> Self {
+        self.stale_while_revalidate_seconds = Some(max_staleness_seconds);
+        self
+    }
+}
+
+/// How often stale entries are looked for when they're pruned, at most.
+const MAX_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many locks the entry directories are spread over.
+const ENTRY_LOCKS: usize = 64;
+
+/// How many background revalidations wait for the refresh thread at most. Stale entries that
+/// don't fit are served as they are, and revalidated the next time they're asked for.
+const MAX_QUEUED_REVALIDATIONS: usize = 64;
+
+/// How long connecting to an upstream may take, unless configured otherwise.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a whole fetch, from connecting to reading the body, may take, unless configured otherwise.
+pub const DEFAULT_FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A cached url as [`Cache::list`] lists it, from the index alone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheEntryMeta {
+    pub url: String,
+    pub cached_at: NaiveDateTime
+}
+
+/// What [`Cache::get_metadata`] knows about a cached entry, for debugging caching issues.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheEntryMetadata {
+    pub url: String,
+    /// the name of the directory the entry's chain is in
+    pub hash: u64,
+    /// which link of the chain holds the entry, `0` unless another url has the same hash
+    pub chain_index: usize,
+    /// the size of the cached response
+    pub size_bytes: u64,
+    pub cached_at: NaiveDateTime,
+    /// how often the entry was served from the cache by [`Cache::get`]
+    pub hit_count: u64
+}
+
+/// A cached response: the body, with what the upstream said about it when it was fetched.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedResponse {
+    pub status: u16,
+    /// the upstream's headers that are kept, see [`CACHED_HEADERS`]
+    pub headers: Vec<(String, String)>,
+    pub fetched_at: NaiveDateTime,
+    /// when the response stops being fresh and has to be fetched again, going by what the upstream said;
+    /// `None` if it didn't say
+    pub expires_at: Option<NaiveDateTime>,
+    pub body: Vec<u8>
+}
+
+/// The upstream headers stored with a cached body, so it can be replayed as it was served.
+pub const CACHED_HEADERS: [&str; 5] = ["Content-Type", "Content-Encoding", "ETag", "Last-Modified", "Cache-Control"];
+
+impl CachedResponse {
+    /// A `200` response without headers, fetched just now.
+    pub fn new(body: Vec<u8>) -> CachedResponse {
+        CachedResponse {
+            status: 200,
+            headers: vec![],
+            fetched_at: now(),
+            expires_at: None,
+            body
+        }
+    }
+
+    /// Keeps what's worth keeping of `response`, working out when it expires with [`expiry`].
+    fn from_upstream(response: UpstreamResponse, heuristic_freshness: bool) -> CachedResponse {
+        let fetched_at = now();
+        CachedResponse {
+            status: response.status,
+            expires_at: expiry(&response.headers, fetched_at, heuristic_freshness),
+            headers: response.headers.into_iter()
+                .filter(|(name, _)| CACHED_HEADERS.iter().any(|cached| cached.eq_ignore_ascii_case(name)))
+                .collect(),
+            fetched_at,
+            body: response.body
+        }
+    }
+
+    /// Whether the upstream's `Cache-Control` lets the response be stored.
+    pub fn storable(&self) -> bool {
+        self.header("Cache-Control").is_none_or(|header| CacheDirectives::parse(header).storable())
+    }
+
+    /// The headers that ask an upstream to answer `304 Not Modified` if this response is still current.
+    fn conditions(&self) -> Vec<(String, String)> {
+        let mut conditions = vec![];
+        if let Some(etag) = self.header("ETag") {
+            conditions.push(("If-None-Match".to_string(), etag.to_string()));
+        }
+        if let Some(last_modified) = self.header("Last-Modified") {
+            conditions.push(("If-Modified-Since".to_string(), last_modified.to_string()));
+        }
+        conditions
+    }
+
+    /// This response, refreshed with the `304 Not Modified` an upstream revalidated it with: its headers
+    /// replace the stored ones, and its expiry is worked out again as of now.
+    fn revalidated(self, not_modified: UpstreamResponse, heuristic_freshness: bool) -> CachedResponse {
+        let mut all_headers = not_modified.headers;
+        for (name, value) in &self.headers {
+            if !all_headers.iter().any(|(updated, _)| updated.eq_ignore_ascii_case(name)) {
+                all_headers.push((name.clone(), value.clone()));
+            }
+        }
+        let fetched_at = now();
+        CachedResponse {
+            expires_at: expiry(&all_headers, fetched_at, heuristic_freshness),
+            headers: all_headers.into_iter()
+                .filter(|(name, _)| CACHED_HEADERS.iter().any(|cached| cached.eq_ignore_ascii_case(name)))
+                .collect(),
+            fetched_at,
+            ..self
+        }
+    }
+
+    /// Whether the response is past the expiry the upstream gave it.
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now())
+    }
+
+    /// The value of the header `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The `meta` file stored next to the body: `status` and `fetched_at` lines, an `expires_at` line
+    /// if it expires, then a line per header, all `name: value`.
+    fn meta_file(&self) -> String {
+        let mut meta = format!("status: {}\nfetched_at: {}\n", self.status, self.fetched_at.format(TIME_FORMAT));
+        if let Some(expires_at) = self.expires_at {
+            meta.push_str(&format!("expires_at: {}\n", expires_at.format(TIME_FORMAT)));
+        }
+        for (name, value) in &self.headers {
+            meta.push_str(&format!("{}: {}\n", name, value));
+        }
+        meta
+    }
+
+    /// Reads a `meta` file written by [`CachedResponse::meta_file`] back, with `body`.
+    fn from_meta_file(meta: &str, body: Vec<u8>) -> Option<CachedResponse> {
+        let mut lines = meta.lines().map(|line| line.split_once(": ")).peekable();
+        let status = match lines.next()? {
+            Some(("status", status)) => status.parse().ok()?,
+            _ => return None
+        };
+        let fetched_at = match lines.next()? {
+            Some(("fetched_at", time)) => NaiveDateTime::parse_from_str(time, TIME_FORMAT).ok()?,
+            _ => return None
+        };
+        let expires_at = match lines.peek() {
+            Some(Some(("expires_at", time))) => {
+                let expires_at = NaiveDateTime::parse_from_str(time, TIME_FORMAT).ok()?;
+                lines.next();
+                Some(expires_at)
+            }
+            _ => None
+        };
+        let headers = lines
+            .map(|header| header.map(|(name, value)| (name.to_string(), value.to_string())))
+            .collect::<Option<Vec<_>>>()?;
+        Some(CachedResponse { status, headers, fetched_at, expires_at, body })
+    }
+}
+
+/// When a response fetched at `fetched_at` with `headers` stops being fresh: after the lifetime its
+/// `Cache-Control` gives it, or else at its `Expires` date, where one that can't be parsed has passed
+/// already. Without either, if `heuristic`, it stays fresh for a tenth of the time since it was last
+/// modified, as RFC 7234 section 4.2.2 suggests. Otherwise, or if the lifetime is too long to add
+/// to `fetched_at`, it doesn't expire.
+fn expiry(headers: &[(String, String)], fetched_at: NaiveDateTime, heuristic: bool) -> Option<NaiveDateTime> {
+    let header = |name: &str| headers.iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim());
+    let parse_date = |date: &str| chrono::DateTime::parse_from_rfc2822(date).ok().map(|date| date.naive_utc());
+    if let Some(lifetime) = header("Cache-Control").and_then(|header| CacheDirectives::parse(header).lifetime()) {
+        return chrono::Duration::try_seconds(lifetime.min(i64::MAX as u64) as i64)
+            .and_then(|lifetime| fetched_at.checked_add_signed(lifetime));
+    }
+    if let Some(expires) = header("Expires") {
+        return Some(parse_date(expires).unwrap_or(fetched_at));
+    }
+    let last_modified = header("Last-Modified").and_then(parse_date).filter(|_| heuristic)?;
+    Some(fetched_at + (fetched_at - last_modified).max(chrono::Duration::zero()) / 10)
 }
 
 const ENTRY_SPLITTER: &str = "%%%";
-const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f";
+
+/// Why [`Cache::export`] couldn't write out the cache.
+#[derive(Debug)]
+pub enum SerializeError {
+    Io(io::Error),
+    /// an entry in the index couldn't be read from disk
+    Cache(ServerError)
+}
+
+/// Why [`Cache::import`] couldn't restore entries.
+#[derive(Debug)]
+pub enum DeserializeError {
+    Io(io::Error),
+    /// the line with this number isn't an entry as `export` writes them
+    BadLine(usize, String),
+    /// a restored entry couldn't be stored
+    Cache(ServerError)
+}
+
+impl Display for SerializeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SerializeError::Io(e) => write!(f, "writing the export failed: {}", e),
+            SerializeError::Cache(e) => write!(f, "reading the cache failed: {}", e)
+        }
+    }
+}
+
+impl Display for DeserializeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeserializeError::Io(e) => write!(f, "reading the export failed: {}", e),
+            DeserializeError::BadLine(line, reason) => write!(f, "line {}: {}", line, reason),
+            DeserializeError::Cache(e) => write!(f, "storing an entry failed: {}", e)
+        }
+    }
+}
 
-impl CacheIndex<'_> {
+impl std::error::Error for SerializeError {}
 
-    pub fn new(filename: &str) -> Result<CacheIndex, String> {
+impl std::error::Error for DeserializeError {}
+
+impl CacheIndex {
+
+    pub fn new(filename: &str) -> Result<CacheIndex, ServerError> {
         let file = OpenOptions::new()
             .create(true).write(true) // allow creating, and thus writing
+            .truncate(false) // keep existing entries
             .read(true) // be able to read file!
             .open(filename);
         let mut entries = HashMap::new();
+        let mut ttls = HashMap::new();
         match file {
             Ok(file) => {
-                for line in BufReader::new(file).lines() {
-                    if let Ok(line) = line {
-                        if let Some((before, after)) = line.split_once(ENTRY_SPLITTER) {
-                            let after = String::from(after);
-                            let time = after.trim();
-                            if let Ok(time) = NaiveDateTime::parse_from_str(time, TIME_FORMAT) {
-                                entries.insert(
-                                    String::from(before).trim().into(),
-                                    time
-                                );
+                for line in BufReader::new(file).lines().map_while(Result::ok) {
+                    if let Some((before, after)) = line.split_once(ENTRY_SPLITTER) {
+                        // an entry's own ttl, in seconds, may follow its time
+                        let (time, ttl) = after.split_once(ENTRY_SPLITTER).unwrap_or((after, ""));
+                        if let Ok(time) = NaiveDateTime::parse_from_str(time.trim(), TIME_FORMAT) {
+                            let url = before.trim().to_string();
+                            if let Ok(ttl) = ttl.trim().parse() {
+                                ttls.insert(url.clone(), Duration::from_secs(ttl));
                             }
+                            entries.insert(url, time);
                         }
                     }
                 }
                 Ok(CacheIndex {
-                    filename,
-                    entries
+                    filename: filename.to_string(),
+                    entries,
+                    ttls
                 })
             }
             Err(e) => {
-                Err(format!("Could not create CacheIndex from filename '{}'", e))
+                Err(ServerError::CacheError(format!("Could not create CacheIndex from filename '{}'", e)))
             }
         }
     }
 
     pub fn update_file(&self) -> std::io::Result<()> {
-        let mut file = File::create(self.filename)?;
+        let mut file = File::create(&self.filename)?;
         write!(file, "{}", self.entries.iter().fold(String::new(), |str, (name, time)| {
-            str + "\n" + &*(name.to_string() + ENTRY_SPLITTER + &*time.format(TIME_FORMAT).to_string())
-        }));
-        Ok(())
+            let ttl = self.ttls.get(name).map(|ttl| format!("{}{}", ENTRY_SPLITTER, ttl.as_secs())).unwrap_or_default();
+            str + "\n" + &*(name.to_string() + ENTRY_SPLITTER + &*time.format(TIME_FORMAT).to_string()) + &ttl
+        }))
     }
 
     /// returns an error if the file does not exist
-    pub fn clear_cache(&mut self) -> std::io::Result<()> {
-        std::fs::remove_file(self.filename).map(|e| {
+    #[cfg(test)]
+    fn clear_cache(&mut self) -> std::io::Result<()> {
+        std::fs::remove_file(&self.filename).inspect(|_| {
             self.entries.clear();
-            e
+            self.ttls.clear();
         })
     }
 
-    pub fn get_entries(&self) -> &HashMap<String, chrono::NaiveDateTime> {
+    #[cfg(test)]
+    fn get_entries(&self) -> &HashMap<String, chrono::NaiveDateTime> {
         &self.entries
     }
+
+    /// When `url` outlives its own ttl, or `ttl` if it has none, if it's cached and ever does.
+    fn ttl_expiry(&self, url: &str, ttl: Duration) -> Option<NaiveDateTime> {
+        let ttl = self.ttls.get(url).copied().unwrap_or(ttl);
+        self.entries.get(url)?.checked_add_signed(chrono::Duration::from_std(ttl).ok()?)
+    }
+
+    /// Whether `url` outlived its own ttl, or `ttl` if it has none, as of `now`.
+    fn outlived(&self, url: &str, ttl: Duration, now: NaiveDateTime) -> bool {
+        self.ttl_expiry(url, ttl).is_some_and(|expiry| expiry < now)
+    }
+
+    /// Removes every entry that outlived its ttl as of `now`, `ttl` unless it was cached with one
+    /// of its own, and saves the index, returning how many entries were removed.
+    pub fn prune_expired(&mut self, ttl: Duration, now: NaiveDateTime) -> usize {
+        let expired = self.entries.keys()
+            .filter(|url| self.outlived(url, ttl, now))
+            .cloned()
+            .collect::<Vec<_>>();
+        for url in &expired {
+            self.entries.remove(url);
+            self.ttls.remove(url);
+        }
+        if !expired.is_empty() {
+            if let Err(e) = self.update_file() {
+                warn!("Couldn't save the cache index after pruning it: {}", e);
+            }
+        }
+        expired.len()
+    }
 }
 
+/// The names of the directories in `folder`. An entry that can't be read fails the whole listing,
+/// since leaving it out would make whatever is cached under it look uncached.
 fn get_sub_folders(folder: &str) -> std::io::Result<HashSet<String>> {
-    let dir = std::fs::read_dir(folder)?;
-    Ok(dir.into_iter()
-        .filter_map(|file| {
-            match file {
-                Ok(entry) => {
-                    entry.metadata().ok().and_then(|meta|
-                        if meta.is_dir() {
-                            let path = entry.path().as_path().display();
-                            Some(format!("{}", entry.file_name().to_str().unwrap()))
-                        } else {
-                            None
-                        }
-                    )
-                },
-                Err(e) => {
-                    None
-                }
+    let mut sub_folders = HashSet::new();
+    for entry in std::fs::read_dir(folder)? {
+        let entry = entry?;
+        match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => {
+                sub_folders.insert(entry.file_name().to_str().unwrap().to_string());
             }
-        }).collect())
+            Ok(_) => {}
+            // removed by another thread while listing
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(sub_folders)
+}
+
+/// total size of the files in `path` and all of its subdirectories
+fn dir_size(path: &Path) -> std::io::Result<u64> {
+    let mut total = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        total += if meta.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            meta.len()
+        };
+    }
+    Ok(total)
+}
+
+/// Checks what `client` answered for `url` before the cache stores it: error statuses become
+/// a [`ServerError::BadGateway`], and running out of time a [`ServerError::GatewayTimeout`].
+/// Also returns whether trying again may work, which it may if the upstream didn't answer at all.
+fn fetch_once(client: &dyn UpstreamClient, url: &str, headers: &[(String, String)]) -> Result<UpstreamResponse, (ServerError, bool)> {
+    let response = match client.get(url, headers) {
+        Ok(response) => response,
+        Err(UpstreamError::TimedOut(reason)) =>
+            return Err((ServerError::GatewayTimeout(format!("{} didn't answer in time: {}", url, reason)), true)),
+        Err(e) => {
+            let retryable = matches!(e, UpstreamError::Unreachable(_));
+            return Err((ServerError::BadGateway(format!("fetching {} failed: {}", url, e)), retryable));
+        }
+    };
+    if response.status >= 400 {
+        return Err((ServerError::BadGateway(format!("{} answered with {}", url, response.status)), false));
+    }
+    Ok(response)
+}
+
+fn now() -> NaiveDateTime {
+    chrono::Utc::now().naive_utc()
+}
+
+// hash!
+fn get_hash(request_url: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    request_url.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// how often the entry in `entry_dir` was served from the cache; entries that never were have no `hits` file
+fn read_hits(entry_dir: &str) -> u64 {
+    std::fs::read_to_string(format!("{}/hits", entry_dir)).ok()
+        .and_then(|hits| hits.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// which link of the chain in `folder/hash_dir` holds `url`, if any; there's no chain if nothing with
+/// the hash is cached. A link without a key, left behind by an interrupted write, holds nothing either.
+fn check_subdirs_for_url(folder: &str, url: &str, hash_dir: &str) -> io::Result<Option<usize>> {
+    let folder_path = format!("{}/{}", folder, hash_dir);
+    let chain = match get_sub_folders(folder_path.as_str()) {
+        // anything else in there, like a leftover temp dir, isn't a link of the chain
+        Ok(chain) => chain.into_iter().filter_map(|dir_name| dir_name.parse().ok()).collect::<Vec<usize>>(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e)
+    };
+    for fold_n in chain {
+        // todo: hardcoded string?
+        let key_file = format!("{}/{}/{}/key", folder, &hash_dir, fold_n);
+        // keys are compared as bytes, so a damaged one is just another key that doesn't match
+        match std::fs::read(&key_file) {
+            Ok(key) if key.trim_ascii() == url.as_bytes() => return Ok(Some(fold_n)),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => warn!("cache entry {} has no key", key_file),
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(None)
+}
+
+/// The lock of the entry directory `url` is cached in, out of `entry_locks`.
+fn lock_entry<'l>(entry_locks: &'l [Mutex<()>], url: &str) -> MutexGuard<'l, ()> {
+    entry_locks[(get_hash(url) % entry_locks.len() as u64) as usize].lock().unwrap_or_else(|e| e.into_inner())
 }
 
-impl Cache<'_> {
+/// Deletes the files cached for `url` under `folder`, returning the number of bytes freed.
+/// The entry has to be locked with [`lock_entry`].
+fn remove_entry_files(folder: &str, url: &str, total_bytes: &AtomicU64) -> Result<u64, ServerError> {
+    let hash_name = get_hash(url).to_string();
+    let freed = match check_subdirs_for_url(folder, url, &hash_name)? {
+        Some(n) => {
+            let entry_dir = format!("{}/{}/{}", folder, &hash_name, n);
+            let size = dir_size(Path::new(&entry_dir))?;
+            std::fs::remove_dir_all(&entry_dir)?;
+            total_bytes.fetch_sub(size, Ordering::SeqCst);
+            size
+        }
+        None => 0
+    };
+    // don't leave empty hash folders behind
+    let hash_dir = format!("{}/{}", folder, &hash_name);
+    if get_sub_folders(&hash_dir).map(|chain| chain.is_empty()).unwrap_or(false) {
+        std::fs::remove_dir_all(&hash_dir)?;
+    }
+    Ok(freed)
+}
+
+impl Cache {
+
+    /// A cache with the default [`CacheOptions`] for these paths.
+    pub fn new(index_filename: &str, cache_folder: &str) -> Result<Cache, ServerError> {
+        Cache::new_with_options(CacheOptions::new(index_filename, cache_folder))
+    }
 
-    pub fn new<'a>(index_filename: &'a str, cache_folder: &'a str) -> Result<Cache<'a>, String> {
-        let cache_index = CacheIndex::new(index_filename)?;
-        std::fs::create_dir_all(cache_folder)
-            .map_err(|e| e.to_string())?; // create the cache folder, or get it
-        Ok(Cache {
-            folder: cache_folder,
-            index: cache_index
+    pub fn new_with_options(options: CacheOptions) -> Result<Cache, ServerError> {
+        let cache_index = CacheIndex::new(&options.index_path)?;
+        std::fs::create_dir_all(&options.data_path)?; // create the cache folder, or get it
+        let total_bytes = dir_size(Path::new(&options.data_path))?;
+        let cache = Cache {
+            folder: options.data_path,
+            index: Arc::new(Mutex::new(cache_index)),
+            last_used: Mutex::new(HashMap::new()),
+            entry_locks: Arc::new((0..ENTRY_LOCKS).map(|_| Mutex::new(())).collect()),
+            total_bytes: Arc::new(AtomicU64::new(total_bytes)),
+            max_disk_bytes: options.max_disk_bytes.unwrap_or(u64::MAX),
+            max_entries: options.max_entries.unwrap_or(usize::MAX),
+            ttl: None,
+            revalidate_stale: options.revalidate_stale,
+            heuristic_freshness: options.heuristic_freshness,
+            serve_stale_on_error: options.serve_stale_on_error,
+            stale_while_revalidate: options.stale_while_revalidate_seconds.map(Duration::from_secs),
+            clock: Arc::new(now),
+            refreshing: Mutex::new(HashSet::new()),
+            revalidations: Mutex::new(None),
+            refreshed: Arc::new(Mutex::new(vec![])),
+            fetcher: Fetcher {
+                client: Arc::new(UreqClient::new(DEFAULT_CONNECT_TIMEOUT, DEFAULT_FETCH_TIMEOUT)),
+                headers: vec![],
+                attempts: 1,
+                backoff: Duration::ZERO
+            }
+        };
+        Ok(match options.ttl_seconds.map(Duration::from_secs) {
+            // stale entries are kept to be revalidated, rather than pruned
+            Some(ttl) if options.revalidate_stale => Cache { ttl: Some(ttl), ..cache },
+            Some(ttl) => cache.with_expiry(ttl, ttl.clamp(Duration::from_secs(1), MAX_PRUNE_INTERVAL)),
+            None => cache
         })
     }
 
+    /// Sets how long connecting to an upstream may take, and how long a whole fetch may take,
+    /// [`DEFAULT_CONNECT_TIMEOUT`] and [`DEFAULT_FETCH_TIMEOUT`] by default.
+    /// This replaces the client set with [`Cache::with_upstream_client`] with a [`UreqClient`].
+    pub fn with_timeouts(mut self, connect_timeout: Duration, timeout: Duration) -> Self {
+        self.fetcher.client = Arc::new(UreqClient::new(connect_timeout, timeout));
+        self
+    }
+
+    /// Fetches what isn't cached with `client` instead of a [`UreqClient`].
+    pub fn with_upstream_client(mut self, client: Box<dyn UpstreamClient>) -> Self {
+        self.fetcher.client = Arc::from(client);
+        self
+    }
+
+    /// Sends `name: value` along with every fetch, e.g. a `User-Agent` or an API key.
+    pub fn with_fetch_header(mut self, name: &str, value: &str) -> Self {
+        self.fetcher.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Tries fetches that couldn't connect, lost their connection or ran out of time up to `attempts` times,
+    /// waiting `backoff` before the second attempt and twice as long before every one after it.
+    /// Upstreams that answer with an error status aren't asked again. Only GETs are ever sent, which are
+    /// safe to repeat. A fetch is tried once by default.
+    pub fn with_retries(mut self, attempts: u32, backoff: Duration) -> Self {
+        self.fetcher.attempts = attempts.max(1);
+        self.fetcher.backoff = backoff;
+        self
+    }
+
+    /// Starts a background thread that removes entries older than `ttl` every `prune_interval`.
+    /// The thread stops once the cache is dropped.
+    pub fn with_expiry(mut self, ttl: Duration, prune_interval: Duration) -> Self {
+        self.ttl = Some(ttl);
+        let clock = Arc::clone(&self.clock);
+        let index = Arc::downgrade(&self.index);
+        let total_bytes = Arc::clone(&self.total_bytes);
+        let entry_locks = Arc::clone(&self.entry_locks);
+        let folder = self.folder.to_string();
+        thread::spawn(move || loop {
+            thread::sleep(prune_interval);
+            let index = match index.upgrade() {
+                Some(index) => index,
+                None => break
+            };
+            let pruned = {
+                let mut index = index.lock().unwrap_or_else(|e| e.into_inner());
+                let before = index.entries.keys().cloned().collect::<Vec<_>>();
+                index.prune_expired(ttl, clock());
+                before.into_iter().filter(|url| !index.entries.contains_key(url)).collect::<Vec<_>>()
+            };
+            for url in pruned {
+                let _entry = lock_entry(&entry_locks, &url);
+                // cached again since it was pruned
+                if index.lock().unwrap_or_else(|e| e.into_inner()).entries.contains_key(&url) {
+                    continue;
+                }
+                if let Err(e) = remove_entry_files(&folder, &url, &total_bytes) {
+                    warn!("Couldn't remove expired cache entry {}: {}", url, e);
+                }
+            }
+        });
+        self
+    }
+
+    /// Limits how much disk space the cached entries may take up.
+    pub fn with_max_disk_bytes(mut self, max_disk_bytes: u64) -> Self {
+        self.max_disk_bytes = max_disk_bytes;
+        self
+    }
+
+    pub fn disk_usage_bytes(&self) -> u64 {
+        self.total_bytes.load(Ordering::SeqCst)
+    }
+
     fn get_sub_folders(&self) -> std::io::Result<HashSet<String>> {
-        get_sub_folders(self.folder)
+        get_sub_folders(&self.folder)
     }
 
-    pub fn get(&mut self, request: &str) -> Result<String, String> {
-        let url = request;
-        if let Ok(response) = self.get_from_cache(url) {
-            println!("retrieving response from cache!");
-            Ok(response)
-        } else {
-            let response = ureq::get(url)
-                .call().map_err(|e| e.to_string())?
-                .into_string().map_err(|e| e.to_string())?;
-            self.put_in_cache(url, String::from(url), response.clone())?;
-            Ok(response)
+    fn index(&self) -> MutexGuard<'_, CacheIndex> {
+        self.index.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn now(&self) -> NaiveDateTime {
+        (self.clock)()
+    }
+
+    /// Tells the time with `clock` instead of [`now`], so that entries can expire without waiting.
+    /// The thread [`Cache::with_expiry`] starts keeps the clock the cache had then.
+    #[cfg(test)]
+    fn with_clock(mut self, clock: impl Fn() -> NaiveDateTime + Send + Sync + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    fn last_used(&self) -> MutexGuard<'_, HashMap<String, NaiveDateTime>> {
+        self.last_used.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Keeps other threads out of the entry directory of `url` until the guard is dropped.
+    fn lock_entry(&self, url: &str) -> MutexGuard<'_, ()> {
+        lock_entry(&self.entry_locks, url)
+    }
+
+    /// The body cached for `url`, fetching and caching it first if it isn't cached.
+    pub fn get(&self, request: &str) -> Result<Vec<u8>, ServerError> {
+        self.get_response(request).map(|response| response.body)
+    }
+
+    /// [`Cache::get`], with the status and headers the body was fetched with.
+    pub fn get_response(&self, url: &str) -> Result<CachedResponse, ServerError> {
+        self.store_refreshed();
+        // an entry that's fetched again keeps the ttl it was put with, even if it's evicted first
+        let ttl = self.index().ttls.get(url).copied();
+        let stale = match self.get_from_cache(url) {
+            Ok(_) if !self.revalidate_stale && self.outlived_ttl(url) => {
+                debug!("{} outlived its ttl, fetching it again", url);
+                if let Err(e) = self.evict(url).and_then(|_| Ok(self.index().update_file()?)) {
+                    warn!("Couldn't remove {} from the cache: {}", url, e);
+                }
+                None
+            }
+            // past the expiry the upstream gave it, or the ttl
+            Ok(response) if response.is_expired() || self.outlived_ttl(url) => Some(response),
+            Ok(response) => {
+                debug!("retrieving response from cache!");
+                self.last_used().insert(url.to_string(), self.now());
+                if let Err(e) = self.record_hit(url) {
+                    warn!("Couldn't count a cache hit for {}: {}", url, e);
+                }
+                return Ok(response);
+            }
+            Err(ServerError::NotFound(_)) => None,
+            Err(e) => {
+                warn!("Couldn't read {} from the cache, fetching it instead: {}", url, e);
+                None
+            }
+        };
+        if let Some(stale) = stale.as_ref().filter(|stale| self.may_serve_while_revalidating(url, stale)) {
+            debug!("serving {} stale while it's revalidated", url);
+            self.revalidate_in_background(url, stale);
+            self.last_used().insert(url.to_string(), self.now());
+            if let Err(e) = self.record_hit(url) {
+                warn!("Couldn't count a cache hit for {}: {}", url, e);
+            }
+            return Ok(stale.clone());
+        }
+        let conditions = stale.as_ref().map(CachedResponse::conditions).unwrap_or_default();
+        let fetched = self.fetcher.fetch(url, &conditions);
+        self.store_fetched(url, stale, fetched, ttl)
+    }
+
+    /// Stores what the upstream answered a fetch of `url` with, given the `stale` entry the fetch
+    /// revalidated if there was one, and returns the response to serve. A new response is cached with `ttl`.
+    fn store_fetched(&self, url: &str, stale: Option<CachedResponse>, fetched: Result<UpstreamResponse, ServerError>,
+                     ttl: Option<Duration>) -> Result<CachedResponse, ServerError> {
+        match (fetched, stale) {
+            (Ok(response), Some(stale)) if response.status == 304 => {
+                debug!("{} is still current", url);
+                let refreshed = stale.revalidated(response, self.heuristic_freshness);
+                self.refresh(url, &refreshed)?;
+                Ok(refreshed)
+            }
+            (Ok(response), _) if response.status == 304 =>
+                Err(ServerError::BadGateway(format!("{} answered 304 to an unconditional request", url))),
+            (Ok(response), _) => {
+                let response = CachedResponse::from_upstream(response, self.heuristic_freshness);
+                self.put(url, response.clone(), ttl)?;
+                Ok(response)
+            }
+            (Err(e), Some(stale)) if self.serve_stale_on_error => {
+                warn!("Couldn't revalidate {}, serving it stale: {}", url, e);
+                Ok(stale)
+            }
+            (Err(e), _) => Err(e)
+        }
+    }
+
+    /// [`Cache::get`] for text, which fails with a [`ServerError::CacheError`] if the body isn't UTF-8.
+    pub fn get_string(&self, url: &str) -> Result<String, ServerError> {
+        String::from_utf8(self.get(url)?).map_err(|_| ServerError::CacheError(format!("{} isn't UTF-8 text", url)))
+    }
+
+    /// Fetches and caches each of `urls` that isn't cached yet, or whose entry has expired,
+    /// returning how many were fetched. Urls that can't be fetched are skipped with a warning,
+    /// so one dead url doesn't keep the others out of the cache.
+    pub fn prefetch(&self, urls: &[&str]) -> Result<usize, ServerError> {
+        let mut fetched = 0;
+        for url in urls {
+            if self.is_fresh(url) {
+                continue;
+            }
+            match self.fetcher.fetch(url, &[]) {
+                Ok(response) => {
+                    self.put_in_cache(url, CachedResponse::from_upstream(response, self.heuristic_freshness))?;
+                    fetched += 1;
+                }
+                Err(e) => warn!("not prefetching {}: {}", url, e)
+            }
+        }
+        Ok(fetched)
+    }
+
+    /// Writes every entry as a line of JSON,
+    /// `{"url":"...","data":"<base64>","cached_at":"...","status":200,"headers":[["name","value"]]}`,
+    /// so the cache can be backed up as a single file and restored with [`Cache::import`].
+    pub fn export(&self, writer: &mut impl Write) -> Result<(), SerializeError> {
+        let mut entries = self.index().entries.clone().into_iter().collect::<Vec<_>>();
+        entries.sort();
+        for (url, cached_at) in entries {
+            // one entry whose files are gone shouldn't cost the whole backup
+            let response = match self.get_from_cache(&url) {
+                Ok(response) => response,
+                Err(e) => {
+                    warn!("not exporting {}: {}", url, e);
+                    continue;
+                }
+            };
+            let line = serde_json::json!({
+                "url": url,
+                "data": STANDARD.encode(response.body),
+                "cached_at": cached_at.format(TIME_FORMAT).to_string(),
+                "status": response.status,
+                "headers": response.headers,
+                "expires_at": response.expires_at.map(|expires_at| expires_at.format(TIME_FORMAT).to_string())
+            });
+            writeln!(writer, "{}", line).map_err(SerializeError::Io)?;
+        }
+        writer.flush().map_err(SerializeError::Io)
+    }
+
+    /// Stores the entries [`Cache::export`] wrote, keeping when they were cached, and returns how many
+    /// were stored; entries this cache won't store, like ones bigger than it may grow, are left out.
+    /// Entries already in the cache are replaced. Blank lines are skipped, and entries
+    /// exported without a status or headers are stored as `200`s without headers.
+    pub fn import(&self, reader: &mut impl Read) -> Result<usize, DeserializeError> {
+        let mut imported = 0;
+        for (i, line) in BufReader::new(reader).lines().enumerate() {
+            let line = line.map_err(DeserializeError::Io)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let bad_line = |reason: &str| DeserializeError::BadLine(i + 1, reason.to_string());
+            let entry: serde_json::Value = serde_json::from_str(&line).map_err(|e| bad_line(&e.to_string()))?;
+            let field = |name: &str| entry.get(name).and_then(|value| value.as_str())
+                .ok_or_else(|| bad_line(&format!("no {} string", name)));
+            let url = field("url")?;
+            let data = STANDARD.decode(field("data")?).map_err(|_| bad_line("data isn't base64 encoded"))?;
+            let cached_at = NaiveDateTime::parse_from_str(field("cached_at")?, TIME_FORMAT)
+                .map_err(|e| bad_line(&format!("bad cached_at: {}", e)))?;
+            let status = match entry.get("status") {
+                Some(status) => status.as_u64().and_then(|status| u16::try_from(status).ok())
+                    .ok_or_else(|| bad_line("bad status"))?,
+                None => 200
+            };
+            let headers = match entry.get("headers") {
+                Some(headers) => serde_json::from_value(headers.clone()).map_err(|e| bad_line(&format!("bad headers: {}", e)))?,
+                None => vec![]
+            };
+            let expires_at = match entry.get("expires_at").and_then(|value| value.as_str()) {
+                Some(time) => Some(NaiveDateTime::parse_from_str(time, TIME_FORMAT)
+                    .map_err(|e| bad_line(&format!("bad expires_at: {}", e)))?),
+                None => None
+            };
+            let response = CachedResponse { status, headers, fetched_at: cached_at, expires_at, body: data };
+            if self.put_in_cache(url, response).map_err(DeserializeError::Cache)? {
+                self.index().entries.insert(url.to_string(), cached_at);
+                imported += 1;
+            }
+        }
+        self.index().update_file().map_err(DeserializeError::Io)?;
+        Ok(imported)
+    }
+
+    /// Where `url` is cached, how big it is, when it was cached and how often it was served from the cache,
+    /// or `None` if it isn't cached.
+    pub fn get_metadata(&self, url: &str) -> Option<CacheEntryMetadata> {
+        let cached_at = *self.index().entries.get(url)?;
+        let hash = self.get_hash(url);
+        let chain_index = match self.check_subdirs_for_url(url, &hash.to_string()) {
+            Ok(chain_index) => chain_index?,
+            Err(e) => {
+                warn!("Couldn't look up {} in the cache: {}", url, e);
+                return None;
+            }
+        };
+        let entry_dir = format!("{}/{}/{}", self.folder, hash, chain_index);
+        let size_bytes = std::fs::metadata(format!("{}/data", entry_dir)).ok()?.len();
+        Some(CacheEntryMetadata {
+            url: url.to_string(),
+            hash,
+            chain_index,
+            size_bytes,
+            cached_at,
+            hit_count: read_hits(&entry_dir)
+        })
+    }
+
+    /// Every cached url and when it was cached, most recently cached first.
+    pub fn list(&self) -> Vec<CacheEntryMeta> {
+        let mut entries = self.index().entries.iter()
+            .map(|(url, cached_at)| CacheEntryMeta { url: url.clone(), cached_at: *cached_at })
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| b.cached_at.cmp(&a.cached_at).then_with(|| a.url.cmp(&b.url)));
+        entries
+    }
+
+    /// Adds one to the number of times `url` was served from the cache, which is kept next to its data.
+    fn record_hit(&self, url: &str) -> Result<(), ServerError> {
+        let _entry = self.lock_entry(url);
+        let hash_name = self.get_hash(url).to_string();
+        let chain_index = match self.check_subdirs_for_url(url, &hash_name)? {
+            Some(i) => i,
+            None => return Ok(())
+        };
+        let entry_dir = format!("{}/{}/{}", self.folder, hash_name, chain_index);
+        let hits_file = format!("{}/hits", entry_dir);
+        let old_size = std::fs::metadata(&hits_file).map_or(0, |meta| meta.len());
+        let hits = (read_hits(&entry_dir) + 1).to_string();
+        std::fs::write(&hits_file, &hits)?;
+        // the counter takes up disk space like the rest of the entry
+        self.total_bytes.fetch_add(hits.len() as u64, Ordering::SeqCst);
+        self.total_bytes.fetch_sub(old_size, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Whether `stale`, the entry of `url`, went stale recently enough to be served while it's
+    /// revalidated in the background, see [`CacheOptions::with_stale_while_revalidate`].
+    fn may_serve_while_revalidating(&self, url: &str, stale: &CachedResponse) -> bool {
+        let max_staleness = match self.stale_while_revalidate.and_then(|max| chrono::Duration::from_std(max).ok()) {
+            Some(max_staleness) => max_staleness,
+            None => return false
+        };
+        let outlived_ttl_at = self.index().ttl_expiry(url, self.ttl.unwrap_or(Duration::MAX));
+        let now = self.now();
+        let stale_since = [stale.expires_at, outlived_ttl_at].iter().flatten().copied().filter(|at| *at <= now).min();
+        stale_since.is_some_and(|stale_since| now - stale_since <= max_staleness)
+    }
+
+    /// Queues `stale`, the entry of `url`, to be revalidated on the refresh thread, unless that's
+    /// happening already or too many are queued. The thread is started the first time it's needed,
+    /// and stops with the cache. What the upstream answers is stored the next time the cache is used.
+    fn revalidate_in_background(&self, url: &str, stale: &CachedResponse) {
+        if !self.refreshing.lock().unwrap_or_else(|e| e.into_inner()).insert(url.to_string()) {
+            return;
+        }
+        let mut revalidations = self.revalidations.lock().unwrap_or_else(|e| e.into_inner());
+        let sender = revalidations.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::sync_channel::<(String, CachedResponse)>(MAX_QUEUED_REVALIDATIONS);
+            let fetcher = self.fetcher.clone();
+            let refreshed = Arc::clone(&self.refreshed);
+            thread::spawn(move || for (url, stale) in receiver {
+                let fetched = fetcher.fetch(&url, &stale.conditions());
+                refreshed.lock().unwrap_or_else(|e| e.into_inner()).push((url, stale, fetched));
+            });
+            sender
+        });
+        if sender.try_send((url.to_string(), stale.clone())).is_err() {
+            debug!("too many revalidations queued, {} stays stale for now", url);
+            self.refreshing.lock().unwrap_or_else(|e| e.into_inner()).remove(url);
         }
     }
 
-    // hash!
+    /// Stores what background revalidations got since the cache was last used.
+    fn store_refreshed(&self) {
+        let refreshed = std::mem::take(&mut *self.refreshed.lock().unwrap_or_else(|e| e.into_inner()));
+        for (url, stale, fetched) in refreshed {
+            self.refreshing.lock().unwrap_or_else(|e| e.into_inner()).remove(&url);
+            let ttl = self.index().ttls.get(&url).copied();
+            if let Err(e) = self.store_fetched(&url, Some(stale), fetched, ttl) {
+                warn!("Couldn't revalidate {} in the background: {}", url, e);
+            }
+        }
+    }
+
+    /// Whether `url` is cached and hasn't expired yet.
+    fn is_fresh(&self, url: &str) -> bool {
+        self.index().entries.contains_key(url) && !self.outlived_ttl(url)
+            && self.get_from_cache(url).is_ok_and(|response| !response.is_expired())
+    }
+
+    /// Whether `url` was cached longer ago than its own ttl, or the cache's.
+    fn outlived_ttl(&self, url: &str) -> bool {
+        self.index().outlived(url, self.ttl.unwrap_or(Duration::MAX), self.now())
+    }
+
+    /// Stores the metadata of `response`, which an upstream confirmed the cached body of `url` is still
+    /// current for, and counts the entry as cached just now.
+    fn refresh(&self, url: &str, response: &CachedResponse) -> Result<(), ServerError> {
+        let _entry = self.lock_entry(url);
+        let hash_name = self.get_hash(url).to_string();
+        let chain_index = self.check_subdirs_for_url(url, &hash_name)?
+            .ok_or_else(|| ServerError::NotFound(format!("{} isn't cached anymore", url)))?;
+        let meta_file = format!("{}/{}/{}/meta", self.folder, hash_name, chain_index);
+        let old_size = std::fs::metadata(&meta_file).map_or(0, |meta| meta.len());
+        let meta = response.meta_file();
+        std::fs::write(&meta_file, &meta)?;
+        self.total_bytes.fetch_add(meta.len() as u64, Ordering::SeqCst);
+        self.total_bytes.fetch_sub(old_size, Ordering::SeqCst);
+        self.last_used().insert(url.to_string(), self.now());
+        let mut index = self.index();
+        index.entries.insert(url.to_string(), self.now());
+        Ok(index.update_file()?)
+    }
+
     fn get_hash(&self, request_url: &str) -> u64 {
-        let mut hasher = DefaultHasher::new();
-        request_url.hash(&mut hasher);
-        hasher.finish()
+        get_hash(request_url)
     }
 
-    fn get_from_cache(&self, url: &str) -> Result<String, String> {
+    /// The response cached for `url`. Entries cached before responses had a `meta` file count as not cached.
+    fn get_from_cache(&self, url: &str) -> Result<CachedResponse, ServerError> {
+        let _entry = self.lock_entry(url);
         let url_hash = self.get_hash(url);
         let dirs = self.get_sub_folders()
-            .map_err(|e| format!("Could not obtain top-level subdirectories"))?;
+            .map_err(|e| ServerError::CacheError(format!("Could not obtain top-level subdirectories: {}", e)))?;
         let hash_name = url_hash.to_string();
         if !dirs.contains(&hash_name) {
-            Err(format!("Did not contain hash {}", url_hash))
+            Err(ServerError::NotFound(format!("Did not contain hash {}", url_hash)))
         } else {
-            let chain_index = self.check_subdirs_for_url(url, &hash_name);
+            let chain_index = self.check_subdirs_for_url(url, &hash_name)
+                .map_err(|e| ServerError::CacheError(format!("Could not look through the entries with hash {}: {}", url_hash, e)))?;
             if let Some(i) = chain_index {
+                let entry_dir = format!("{}/{}/{}", self.folder, hash_name, i);
+                let meta = match std::fs::read_to_string(format!("{}/meta", entry_dir)) {
+                    Ok(meta) => meta,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound =>
+                        return Err(ServerError::NotFound(format!("{} was cached without its metadata", url))),
+                    Err(e) => return Err(e.into())
+                };
                 let mut f = OpenOptions::new().read(true)
-                    .open(format!("{}/{}/{}/data", self.folder, hash_name, i))
-                    .map_err(|e| e.to_string())?;
-                let mut s = String::new();
-                f.read_to_string(&mut s);
-                Ok(s)
+                    .open(format!("{}/data", entry_dir))?;
+                let mut data = vec![];
+                f.read_to_end(&mut data)?;
+                CachedResponse::from_meta_file(&meta, data)
+                    .ok_or_else(|| ServerError::CacheError(format!("{}/meta isn't valid", entry_dir)))
             } else {
-                // probably remove this later?
-                panic!("Cache didn't contain {} even though it contained the hash!", url);
+                // another url with the same hash is cached, but this one isn't
+                Err(ServerError::NotFound(format!("Cache didn't contain {} even though it contained the hash!", url)))
             }
         }
     }
 
-    fn check_subdirs_for_url(&self, url: &str, hash_dir: &String) -> Option<usize> {
-        let folder_path = format!("{}/{}", self.folder, hash_dir.as_str());
-        let chain = get_sub_folders(folder_path.as_str())
-            .ok()?
-            .into_iter().map(|dir_name| usize::from_str(&dir_name).unwrap())
+    fn check_subdirs_for_url(&self, url: &str, hash_dir: &str) -> io::Result<Option<usize>> {
+        check_subdirs_for_url(&self.folder, url, hash_dir)
+    }
+
+    /// Deletes the entry for `url` from disk and the index, returning the number of bytes freed.
+    ///
+    /// Entry sizes deliberately aren't kept in the index: the size comes from the entry's files as they're
+    /// removed, which is what they actually took up even if they changed behind the cache's back, and the
+    /// index format stays as it was. Only the running total is kept, and it's recounted from disk on open.
+    fn evict(&self, url: &str) -> Result<u64, ServerError> {
+        let _entry = self.lock_entry(url);
+        let mut index = self.index();
+        index.entries.remove(url);
+        index.ttls.remove(url);
+        drop(index);
+        self.last_used().remove(url);
+        remove_entry_files(&self.folder, url, &self.total_bytes)
+    }
+
+    /// Evicts least recently used entries (never `keep`) until `incoming` more bytes and one more entry
+    /// fit under the limits.
+    fn evict_lru(&self, incoming: u64, keep: &str) -> Result<(), ServerError> {
+        let entries = self.index().entries.clone();
+        let last_used = self.last_used();
+        let mut by_age = entries.iter()
+            .filter(|(url, _)| url.as_str() != keep)
+            .map(|(url, cached_at)| (*last_used.get(url).unwrap_or(cached_at), url.clone()))
             .collect::<Vec<_>>();
-        let mut found_url = None;
-        'outer:
-        for fold_n in chain {
-            match OpenOptions::new().read(true).open(
-                // todo: hardcoded string?
-                format!("{}/{}/{}/key", self.folder, &hash_dir, fold_n)) {
-                Ok(mut f) => {
-                    let mut content = String::new();
-                    f.read_to_string(&mut content);
-                    if content.trim() == url {
-                        found_url = Some(fold_n);
-                        break 'outer;
-                    }
-                }
-                Err(_) => {
-                    // it should be able to open
-                    // but if it can't, we just skip it, I guess?
-                }
+        drop(last_used);
+        by_age.sort();
+        for (_, url) in by_age {
+            if self.disk_usage_bytes() + incoming <= self.max_disk_bytes && self.index().entries.len() < self.max_entries {
+                break;
+            }
+            debug!("evicting {} from the cache", url);
+            if self.evict(&url)? == 0 {
+                // its files were deleted behind the cache's back, so what they took up stays counted until the
+                // cache is opened again; recounting the folder here would race with other threads' puts
+                warn!("{} was in the cache index, but not in {}", url, self.folder);
             }
         }
-        found_url
+        Ok(self.index().update_file()?)
     }
 
-    fn put_in_cache(&mut self, url: &str, meta: String, data: String) -> Result<(), String> {
+    fn put_in_cache(&self, url: &str, response: CachedResponse) -> Result<bool, ServerError> {
+        // a refetched entry keeps the ttl it was put with
+        let ttl = self.index().ttls.get(url).copied();
+        self.put(url, response, ttl)
+    }
+
+    /// Caches `response` for `url`, replacing what's cached for it. With a `ttl`, the entry expires
+    /// after it instead of after the cache's ttl. Returns whether `response` was stored, which it isn't
+    /// if its `Cache-Control` forbids it or it's bigger than the whole cache may be.
+    pub fn put(&self, url: &str, response: CachedResponse, ttl: Option<Duration>) -> Result<bool, ServerError> {
+        if !response.storable() {
+            debug!("not caching {}: its Cache-Control doesn't allow it", url);
+            // what's cached was replaced upstream by something that mustn't be
+            self.evict(url)?;
+            self.index().update_file()?;
+            return Ok(false);
+        }
+        let meta = response.meta_file();
+        let size = (url.len() + meta.len() + response.body.len()) as u64;
+        if size > self.max_disk_bytes {
+            debug!("not caching {}: {} bytes is more than the whole cache may use", url, size);
+            return Ok(false);
+        }
+        // an existing entry for this url gets replaced, so it doesn't count against the limit
+        self.evict(url)?;
+        if self.disk_usage_bytes() + size > self.max_disk_bytes || self.index().entries.len() >= self.max_entries {
+            self.evict_lru(size, url)?;
+        }
+        let _entry = self.lock_entry(url);
+        // another thread may have cached it in the meantime
+        remove_entry_files(&self.folder, url, &self.total_bytes)?;
         let url_hash = self.get_hash(url);
         let hash_name = format!("{}", url_hash);
-        let hash_folders = get_sub_folders(self.folder)
-            .map_err(|e| e.to_string())?;
+        let hash_folders = get_sub_folders(&self.folder)?;
         let hash_dir = format!("{}/{}", self.folder, &hash_name);
-        let mut new_entry = false;
         if !hash_folders.contains(&hash_name) {
-            std::fs::create_dir(&hash_dir);
-            new_entry = true;
+            std::fs::create_dir(&hash_dir)?;
         }
         // find the subdirectory name with the largest value, make one larger than it
-        let chain = get_sub_folders(hash_dir.as_str())
-            .map_err(|e| e.to_string())?
-            .into_iter().map(|dir_name| usize::from_str(&dir_name).unwrap())
-            .collect::<Vec<_>>();
+        let chain = get_sub_folders(hash_dir.as_str())?
+            .into_iter().filter_map(|dir_name| dir_name.parse().ok())
+            .collect::<Vec<usize>>();
 
         // integer symbolizing part in chain (in case 2 hashes are identical)
-        let found_url = self.check_subdirs_for_url(url, &hash_name);
+        let found_url = self.check_subdirs_for_url(url, &hash_name)?;
 
         // number of chain in the directory to write to
         let n = found_url
@@ -251,9 +1249,9 @@ impl Cache<'_> {
                 chain.iter().max()
                     .map(|x| x + 1)
             )
-            .or(Some(0)).unwrap();
+            .unwrap_or(0);
         // 'create' directory in case it doesn't exist
-        std::fs::create_dir(format!("{}/{}/{}", self.folder, &hash_name, n));
+        std::fs::create_dir_all(format!("{}/{}/{}", self.folder, &hash_name, n))?;
         // write data to `data` file
         OpenOptions::new().write(true)
             .truncate(true) // clear the file before writing to it
@@ -262,11 +1260,10 @@ impl Cache<'_> {
                 // todo: hardcoded string?
                 format!("{}/{}/{}/data", self.folder, &hash_name, n)
             )
-            .map(|mut f| {
-                write!(f, "{}", data);
-            });
+            .and_then(|mut f| f.write_all(&response.body))?;
+        std::fs::write(format!("{}/{}/{}/meta", self.folder, &hash_name, n), &meta)?;
 
-        // write data to `meta` file
+        // write the url to the `key` file last, since the entry can't be found without it
         OpenOptions::new().write(true)
             .truncate(true) // clear the file before writing to it
             .create(true)
@@ -274,35 +1271,984 @@ impl Cache<'_> {
                 // todo: hardcoded string?
                 format!("{}/{}/{}/key", self.folder, &hash_name, n)
             )
-            .map(|mut f| {
-                write!(f, "{}", meta);
-            });
-        Ok(())
+            .and_then(|mut f| write!(f, "{}", url))?;
+        self.total_bytes.fetch_add(size, Ordering::SeqCst);
+        let mut index = self.index();
+        index.entries.insert(url.to_string(), self.now());
+        match ttl {
+            Some(ttl) => index.ttls.insert(url.to_string(), ttl),
+            None => index.ttls.remove(url)
+        };
+        index.update_file()?;
+        Ok(true)
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::collections::{HashMap, HashSet};
-    use crate::server::cache::{Cache, CacheIndex, get_sub_folders};
+    use std::collections::{HashMap, HashSet, VecDeque};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use crate::server::cache::{Cache, CacheEntryMeta, CachedResponse, CacheIndex, CacheOptions, DeserializeError, dir_size, expiry, get_hash, get_sub_folders, now};
+    use crate::server::encoding::Encoding;
+    use crate::server::error::ServerError;
+    use crate::server::scratch::ScratchDir;
+    use crate::server::upstream::{UpstreamClient, UpstreamError, UpstreamResponse};
+
+    /// A clock for [`Cache::with_clock`] that starts at [`now`] and is `step` seconds further along every
+    /// time it's looked at, along with how far along it is, for moving it by hand.
+    fn fake_clock(step: i64) -> (Arc<AtomicI64>, impl Fn() -> chrono::NaiveDateTime + Clone + Send + Sync + 'static) {
+        let elapsed_secs = Arc::new(AtomicI64::new(0));
+        let ticks = Arc::clone(&elapsed_secs);
+        (elapsed_secs, move || now() + chrono::Duration::seconds(ticks.fetch_add(step, Ordering::SeqCst)))
+    }
 
     #[test]
     fn test_cache_creation () {
-        let mut cache = CacheIndex::new("cache/cache-meta").unwrap();
-        cache.clear_cache();
+        let dir = ScratchDir::new("cache-creation");
+        let index_file = format!("{}/cache-meta", dir);
+        let mut cache = CacheIndex::new(&index_file).unwrap();
+        cache.clear_cache().unwrap();
         assert_eq!(cache.get_entries(), &HashMap::new());
     }
 
     #[test]
     fn naive_folder_test() {
-        println!("{:?}", get_sub_folders("cache/"));
+        let dir = ScratchDir::new("naive-folder");
+        std::fs::create_dir(format!("{}/sub", dir)).unwrap();
+        std::fs::write(format!("{}/file", dir), "not a folder").unwrap();
+        assert_eq!(get_sub_folders(&dir).unwrap(), HashSet::from(["sub".to_string()]));
+    }
+
+    /// the urls a [`FakeClient`] was asked for, with the headers they were asked for with
+    type Requests = Arc<Mutex<Vec<(String, Vec<(String, String)>)>>>;
+
+    /// Answers each url with the results queued for it, repeating the last one, after `latency`.
+    /// A gated one says which url it was asked for, and then waits to be let through before answering.
+    struct FakeClient {
+        results: Mutex<HashMap<String, VecDeque<Result<UpstreamResponse, UpstreamError>>>>,
+        latency: Duration,
+        requests: Requests,
+        gate: Option<Gate>
+    }
+
+    /// where a gated [`FakeClient`] says what it's asked for, and what lets it answer
+    type Gate = (Mutex<mpsc::Sender<String>>, Mutex<mpsc::Receiver<()>>);
+
+    impl FakeClient {
+        fn new(results: Vec<(&str, Vec<Result<UpstreamResponse, UpstreamError>>)>) -> FakeClient {
+            FakeClient {
+                results: Mutex::new(results.into_iter().map(|(url, results)| (url.to_string(), results.into())).collect()),
+                latency: Duration::ZERO,
+                requests: Arc::new(Mutex::new(vec![])),
+                gate: None
+            }
+        }
+
+        /// This client, gated: it sends the urls it's asked for on the receiver, and answers one
+        /// request for every `()` sent on the sender.
+        fn gated(mut self) -> (FakeClient, mpsc::Receiver<String>, mpsc::Sender<()>) {
+            let (asked, asked_for) = mpsc::channel();
+            let (let_through, gate) = mpsc::channel();
+            self.gate = Some((Mutex::new(asked), Mutex::new(gate)));
+            (self, asked_for, let_through)
+        }
+    }
+
+    impl UpstreamClient for FakeClient {
+        fn get(&self, url: &str, headers: &[(String, String)]) -> Result<UpstreamResponse, UpstreamError> {
+            thread::sleep(self.latency);
+            self.requests.lock().unwrap().push((url.to_string(), headers.to_vec()));
+            if let Some((asked, gate)) = &self.gate {
+                asked.lock().unwrap().send(url.to_string()).unwrap();
+                gate.lock().unwrap().recv().unwrap();
+            }
+            let mut results = self.results.lock().unwrap();
+            let queued = results.get_mut(url).ok_or_else(|| UpstreamError::Unreachable(format!("no such host in {}", url)))?;
+            if queued.len() > 1 {
+                queued.pop_front().unwrap()
+            } else {
+                queued[0].clone()
+            }
+        }
+    }
+
+    fn answer(status: u16, body: &str) -> Result<UpstreamResponse, UpstreamError> {
+        Ok(UpstreamResponse { status, headers: vec![], body: body.as_bytes().to_vec() })
     }
 
     #[test]
     fn cache_test() {
-        let mut cache = Cache::new(
-            "cache/cache-meta/cache-index",
-            "cache/data").unwrap();
-        println!("{:?}", cache.get("https://en.wikipedia.org/api/rest_v1/page/title/Earth"));
+        let dir = ScratchDir::new("cache");
+        let index_file = format!("{}/cache-index", dir);
+        let data_folder = format!("{}/data", dir);
+        let client = FakeClient::new(vec![
+            ("https://example.com/earth", vec![answer(200, "{\"title\": \"Earth\"}")]),
+            ("https://example.com/missing", vec![answer(404, "Not Found")]),
+            ("https://example.com/flaky", vec![Err(UpstreamError::Unreachable("reset".to_string())), answer(200, "back")]),
+            ("https://example.com/slow", vec![Err(UpstreamError::TimedOut("30s".to_string()))]),
+            ("https://example.com/binary", vec![answer(200, "\u{0}"), Ok(UpstreamResponse { status: 200, headers: vec![], body: vec![0xff] })])
+        ]);
+        let requests = Arc::clone(&client.requests);
+        let cache = Cache::new(&index_file, &data_folder).unwrap()
+            .with_upstream_client(Box::new(client))
+            .with_fetch_header("User-Agent", "cache-test");
+        assert_eq!(cache.get_string("https://example.com/earth").unwrap(), "{\"title\": \"Earth\"}");
+        assert_eq!(cache.get_string("https://example.com/earth").unwrap(), "{\"title\": \"Earth\"}");
+        assert_eq!(*requests.lock().unwrap(), [
+            ("https://example.com/earth".to_string(), vec![("User-Agent".to_string(), "cache-test".to_string())])
+        ]);
+        assert!(matches!(cache.get("https://example.com/missing"), Err(ServerError::BadGateway(_))));
+        assert!(cache.get_metadata("https://example.com/missing").is_none());
+        assert!(matches!(cache.get("https://example.com/slow"), Err(ServerError::GatewayTimeout(_))));
+        // without retries, a dropped connection is the end of it
+        assert!(matches!(cache.get("https://example.com/flaky"), Err(ServerError::BadGateway(_))));
+        assert_eq!(cache.get_string("https://example.com/flaky").unwrap(), "back");
+        assert_eq!(cache.get_string("https://example.com/binary").unwrap(), "\u{0}");
+    }
+
+    #[test]
+    fn cache_options() {
+        let dir = ScratchDir::new("options");
+        let (index_file, data_folder) = (format!("{}/index", dir), format!("{}/data", dir));
+        let options = CacheOptions::new(&index_file, &data_folder);
+        assert_eq!(CacheOptions::default(), CacheOptions::new("cache/cache-meta/cache-index", "cache/data"));
+
+        // the paths
+        let cache = Cache::new_with_options(options.clone()).unwrap();
+        cache.put_in_cache("http://example.com/a", CachedResponse::new(b"a".to_vec())).unwrap();
+        assert!(std::fs::read_to_string(&index_file).unwrap().contains("http://example.com/a"));
+        assert_eq!(dir_size(std::path::Path::new(&data_folder)).unwrap(), cache.disk_usage_bytes());
+        assert_eq!((cache.ttl, cache.max_entries, cache.max_disk_bytes, cache.revalidate_stale), (None, usize::MAX, u64::MAX, false));
+        drop(cache);
+
+        // the ttl: an entry is fetched again once it's outlived it
+        let (elapsed_secs, clock) = fake_clock(0);
+        let client = FakeClient::new(vec![("http://example.com/ttl", vec![answer(200, "first"), answer(200, "second")])]);
+        let requests = Arc::clone(&client.requests);
+        let ttl_dir = ScratchDir::new("options-ttl");
+        let ttl_options = CacheOptions::new(&format!("{}/index", ttl_dir), &format!("{}/data", ttl_dir)).with_ttl_seconds(60);
+        let cache = Cache::new_with_options(ttl_options).unwrap()
+            .with_upstream_client(Box::new(client))
+            .with_clock(clock);
+        assert_eq!(cache.get_string("http://example.com/ttl").unwrap(), "first");
+        elapsed_secs.store(59, Ordering::SeqCst);
+        assert_eq!(cache.get_string("http://example.com/ttl").unwrap(), "first");
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        elapsed_secs.store(61, Ordering::SeqCst);
+        assert_eq!(cache.get_string("http://example.com/ttl").unwrap(), "second");
+        assert_eq!(requests.lock().unwrap().len(), 2);
+        drop(cache);
+
+        // the entry limit
+        let cache = Cache::new_with_options(options.clone().with_max_entries(2)).unwrap();
+        for url in ["http://example.com/a", "http://example.com/b", "http://example.com/c"] {
+            cache.put_in_cache(url, CachedResponse::new(b"x".to_vec())).unwrap();
+        }
+        let mut cached = cache.index().entries.keys().cloned().collect::<Vec<_>>();
+        cached.sort();
+        assert_eq!(cached, ["http://example.com/b", "http://example.com/c"]);
+        drop(cache);
+
+        // the size limit
+        let cache = Cache::new_with_options(options.clone().with_max_disk_bytes(100)).unwrap();
+        cache.put_in_cache("http://example.com/big", CachedResponse::new(vec![b'x'; 100])).unwrap();
+        assert!(cache.get_from_cache("http://example.com/big").is_err());
+        drop(cache);
+
+        // revalidation: a stale entry is fetched again, or served stale if it can't be
+        let client = FakeClient::new(vec![
+            ("http://example.com/news", vec![answer(200, "old news"), answer(200, "news"), Err(UpstreamError::Unreachable("down".to_string()))])
+        ]);
+        let options_with_revalidation = options.clone().with_ttl_seconds(60).with_revalidate_stale(true).with_serve_stale_on_error(true);
+        let cache = Cache::new_with_options(options_with_revalidation).unwrap()
+            .with_upstream_client(Box::new(client));
+        let url = "http://example.com/news";
+        let backdate = |cache: &Cache| cache.index().entries.insert(url.to_string(), now() - chrono::Duration::hours(1));
+        assert_eq!(cache.get_string(url).unwrap(), "old news");
+        assert_eq!(cache.get_string(url).unwrap(), "old news");
+        backdate(&cache);
+        assert_eq!(cache.get_string(url).unwrap(), "news");
+        backdate(&cache);
+        assert_eq!(cache.get_string(url).unwrap(), "news");
+        drop(cache);
+
+        // without it, an entry past the ttl is fetched again
+        let url = "http://example.com/weather";
+        let client = FakeClient::new(vec![(url, vec![answer(200, "rain"), answer(200, "sun")])]);
+        let cache = Cache::new_with_options(options.with_ttl_seconds(3600)).unwrap().with_upstream_client(Box::new(client));
+        assert_eq!(cache.get_string(url).unwrap(), "rain");
+        cache.index().entries.insert(url.to_string(), now() - chrono::Duration::hours(2));
+        assert_eq!(cache.get_string(url).unwrap(), "sun");
+    }
+
+    #[test]
+    fn lru_eviction() {
+        // every look at the clock is a second later, so no two uses tie
+        let (_, ticking_clock) = fake_clock(1);
+        let dir = ScratchDir::new("lru");
+        let (index_file, data_folder) = (format!("{}/index", dir), format!("{}/data", dir));
+        let cache = Cache::new_with_options(CacheOptions::new(&index_file, &data_folder).with_max_entries(3)).unwrap()
+            .with_clock(ticking_clock);
+        let url = |name: &str| format!("http://example.com/{}", name);
+        for name in ["a", "b", "c"] {
+            cache.put(&url(name), CachedResponse::new(name.as_bytes().to_vec()), None).unwrap();
+        }
+        // a hit makes the oldest entry the most recently used one
+        assert_eq!(cache.get_string(&url("a")).unwrap(), "a");
+        // an entry whose files were deleted behind the cache's back is still evicted cleanly
+        let c_dir = format!("{}/{}", data_folder, get_hash(&url("c")));
+        let c_size = dir_size(std::path::Path::new(&c_dir)).unwrap();
+        std::fs::remove_dir_all(&c_dir).unwrap();
+        for name in ["d", "e"] {
+            cache.put(&url(name), CachedResponse::new(name.as_bytes().to_vec()), None).unwrap();
+        }
+
+        let mut cached = CacheIndex::new(&index_file).unwrap().entries.keys().cloned().collect::<Vec<_>>();
+        cached.sort();
+        assert_eq!(cached, [url("a"), url("d"), url("e")]);
+        for gone in ["b", "c"] {
+            assert!(cache.get_from_cache(&url(gone)).is_err());
+            assert!(!std::path::Path::new(&format!("{}/{}", data_folder, get_hash(&url(gone)))).exists());
+        }
+        assert_eq!(get_sub_folders(&data_folder).unwrap().len(), 3);
+        // only what eviction removed is taken off the total, so the deleted files still count
+        assert_eq!(dir_size(std::path::Path::new(&data_folder)).unwrap() + c_size, cache.disk_usage_bytes());
+    }
+
+    #[test]
+    fn ttl_expiry() {
+        let (elapsed_secs, clock) = fake_clock(0);
+        let dir = ScratchDir::new("ttl-expiry");
+        let (index_file, data_folder) = (format!("{}/index", dir), format!("{}/data", dir));
+        let (short, long) = ("http://example.com/short", "http://example.com/long");
+        let client = FakeClient::new(vec![
+            (short, vec![answer(200, "first"), Err(UpstreamError::Unreachable("down".to_string()))]),
+            (long, vec![answer(200, "refetched")])
+        ]);
+        let cache = Cache::new(&index_file, &data_folder).unwrap()
+            .with_upstream_client(Box::new(client))
+            .with_clock(clock.clone())
+            .with_expiry(Duration::from_secs(60), Duration::from_secs(3600));
+        assert_eq!(cache.get_string(short).unwrap(), "first");
+        cache.put(long, CachedResponse::new(b"kept".to_vec()), Some(Duration::from_secs(600))).unwrap();
+        assert_eq!(cache.index().ttls.get(long), Some(&Duration::from_secs(600)));
+
+        // a put ttl outlasts the cache's, and is remembered by the index file
+        elapsed_secs.store(120, Ordering::SeqCst);
+        assert_eq!(cache.get_string(long).unwrap(), "kept");
+        let reloaded = CacheIndex::new(&index_file).unwrap();
+        assert_eq!(reloaded.ttls.get(long), Some(&Duration::from_secs(600)));
+        assert!(reloaded.outlived(short, Duration::from_secs(60), clock()));
+        assert!(!reloaded.outlived(long, Duration::from_secs(60), clock()));
+
+        // the expired entry is a miss, and its files are gone even though fetching it again failed
+        assert!(cache.get_string(short).is_err());
+        assert!(!cache.index().entries.contains_key(short));
+        assert!(cache.get_from_cache(short).is_err());
+        assert!(!CacheIndex::new(&index_file).unwrap().entries.contains_key(short));
+
+        elapsed_secs.store(700, Ordering::SeqCst);
+        assert_eq!(cache.get_string(long).unwrap(), "refetched");
+        assert_eq!(cache.index().ttls.get(long), Some(&Duration::from_secs(600)));
+
+        // but it goes with the entry
+        cache.evict(long).unwrap();
+        assert!(cache.index().ttls.is_empty());
+    }
+
+    #[test]
+    fn pruning_uses_the_cache_clock() {
+        let (elapsed_secs, clock) = fake_clock(0);
+        let dir = ScratchDir::new("prune-clock");
+        let url = "http://example.com/pruned";
+        let cache = Cache::new(&format!("{}/index", dir), &format!("{}/data", dir)).unwrap()
+            .with_clock(clock)
+            .with_expiry(Duration::from_secs(60), Duration::from_millis(1));
+        cache.put(url, CachedResponse::new(b"a".to_vec()), Some(Duration::from_secs(30))).unwrap();
+        elapsed_secs.store(120, Ordering::SeqCst);
+        while cache.index().entries.contains_key(url) {
+            thread::yield_now();
+        }
+        assert!(cache.index().ttls.is_empty());
+    }
+
+    #[test]
+    fn fake_upstream_retries() {
+        let dir = ScratchDir::new("fake-retries");
+        let (index_file, data_folder) = (format!("{}/index", dir), format!("{}/data", dir));
+        let dropped = || Err(UpstreamError::Unreachable("connection reset".to_string()));
+        let mut client = FakeClient::new(vec![
+            ("http://example.com/flaky", vec![dropped(), dropped(), answer(200, "third time")]),
+            ("http://example.com/gone", vec![answer(410, "Gone")])
+        ]);
+        client.latency = Duration::from_millis(5);
+        let requests = Arc::clone(&client.requests);
+        let cache = Cache::new(&index_file, &data_folder).unwrap()
+            .with_upstream_client(Box::new(client))
+            .with_retries(3, Duration::from_millis(10));
+        let started = Instant::now();
+        assert_eq!(cache.get_string("http://example.com/flaky").unwrap(), "third time");
+        // 10ms and then 20ms of backing off
+        assert!(started.elapsed() >= Duration::from_millis(30));
+        assert!(matches!(cache.get("http://example.com/gone"), Err(ServerError::BadGateway(_))));
+        let urls = requests.lock().unwrap().iter().map(|(url, _)| url.clone()).collect::<Vec<_>>();
+        assert_eq!(urls, ["http://example.com/flaky", "http://example.com/flaky", "http://example.com/flaky", "http://example.com/gone"]);
+    }
+
+    #[test]
+    fn disk_usage_stays_under_limit() {
+        let dir = ScratchDir::new("disk-usage");
+        let index_file = format!("{}/cache-index", dir);
+        let data_folder = format!("{}/data", dir);
+        let cache = Cache::new(&index_file, &data_folder).unwrap()
+            .with_max_disk_bytes(100);
+        assert_eq!(cache.disk_usage_bytes(), 0);
+        for i in 0..10 {
+            let url = format!("http://example.com/{}", i);
+            cache.put_in_cache(&url, CachedResponse::new(b"0123456789".to_vec())).unwrap();
+            assert!(cache.disk_usage_bytes() <= 100);
+        }
+        // the most recent entry survives, the oldest ones were evicted
+        assert!(cache.get_from_cache("http://example.com/9").is_ok());
+        assert!(cache.get_from_cache("http://example.com/0").is_err());
+        // replacing an entry doesn't count it twice
+        let usage = cache.disk_usage_bytes();
+        cache.put_in_cache("http://example.com/9", CachedResponse::new(b"0123456789".to_vec())).unwrap();
+        assert_eq!(cache.disk_usage_bytes(), usage);
+        // a reopened cache finds the same usage on disk
+        drop(cache);
+        let cache = Cache::new(&index_file, &data_folder).unwrap();
+        assert_eq!(cache.disk_usage_bytes(), usage);
+    }
+
+    #[test]
+    fn eviction_by_size() {
+        let (_, ticking_clock) = fake_clock(1);
+        let dir = ScratchDir::new("eviction-by-size");
+        let (index_file, data_folder) = (format!("{}/index", dir), format!("{}/data", dir));
+        let url = |name: &str| format!("http://example.com/{}", name);
+        let cache = Cache::new(&index_file, &data_folder).unwrap().with_clock(ticking_clock.clone());
+        let mut sizes = vec![];
+        for (name, body_size) in [("small", 100), ("big", 5000), ("medium", 1000)] {
+            let before = cache.disk_usage_bytes();
+            cache.put(&url(name), CachedResponse::new(vec![b'x'; body_size]), None).unwrap();
+            sizes.push(cache.disk_usage_bytes() - before);
+            assert!(sizes.last().unwrap() > &(body_size as u64));
+        }
+        let budget = cache.disk_usage_bytes() + 100;
+        drop(cache);
+
+        // reopening counts what's on disk
+        let cache = Cache::new(&index_file, &data_folder).unwrap()
+            .with_clock(ticking_clock)
+            .with_max_disk_bytes(budget);
+        assert_eq!(cache.disk_usage_bytes(), sizes.iter().sum::<u64>());
+        assert_eq!(cache.get_string(&url("small")).unwrap().len(), 100);
+        // the least recently used entry goes, however big it is, and then there's room
+        cache.put(&url("new"), CachedResponse::new(vec![b'x'; 2000]), None).unwrap();
+        assert!(cache.get_from_cache(&url("big")).is_err());
+        for kept in ["small", "medium", "new"] {
+            assert!(cache.get_from_cache(&url(kept)).is_ok(), "{} was evicted", kept);
+        }
+        assert!(cache.disk_usage_bytes() <= budget);
+        assert_eq!(dir_size(std::path::Path::new(&data_folder)).unwrap(), cache.disk_usage_bytes());
+
+        // an entry bigger than the whole budget isn't cached, and doesn't evict anything
+        let usage = cache.disk_usage_bytes();
+        cache.put(&url("huge"), CachedResponse::new(vec![b'x'; budget as usize]), None).unwrap();
+        assert!(cache.get_from_cache(&url("huge")).is_err());
+        assert_eq!(cache.disk_usage_bytes(), usage);
+        assert_eq!(cache.index().entries.len(), 3);
+    }
+
+    /// Answers every request with `body`, after the raw header lines in `headers` (each ending in `\r\n`),
+    /// counting the requests.
+    fn start_upstream(headers: &'static str, body: Vec<u8>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&requests);
+        thread::spawn(move || for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut head = vec![];
+            let mut byte = [0];
+            while !head.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap() == 1 {
+                head.push(byte[0]);
+            }
+            counted.fetch_add(1, Ordering::SeqCst);
+            write!(stream, "HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n", headers, body.len()).unwrap();
+            stream.write_all(&body).unwrap();
+        });
+        (url, requests)
+    }
+
+    /// Closes the first `dropped` connections without answering, then answers with `status` and `body`
+    /// after `delay`, counting the connections.
+    fn start_flaky_upstream(dropped: usize, delay: Duration, status: &'static str, body: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&connections);
+        thread::spawn(move || for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            if counted.fetch_add(1, Ordering::SeqCst) < dropped {
+                continue;
+            }
+            thread::spawn(move || {
+                let mut head = vec![];
+                let mut byte = [0];
+                while !head.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap_or(0) == 1 {
+                    head.push(byte[0]);
+                }
+                thread::sleep(delay);
+                let _ = write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body);
+            });
+        });
+        (url, connections)
+    }
+
+    #[test]
+    fn fetch_timeouts() {
+        let dir = ScratchDir::new("fetch-timeouts");
+        let (index_file, data_folder) = (format!("{}/index", dir), format!("{}/data", dir));
+        let (slow, _) = start_flaky_upstream(0, Duration::from_secs(3), "200 OK", "late");
+        let cache = Cache::new(&index_file, &data_folder).unwrap()
+            .with_timeouts(Duration::from_secs(1), Duration::from_millis(200));
+        let started = Instant::now();
+        assert!(matches!(cache.get(&slow), Err(ServerError::GatewayTimeout(_))));
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        let (missing, connections) = start_flaky_upstream(0, Duration::ZERO, "404 Not Found", "gone");
+        let cache = cache.with_retries(3, Duration::from_millis(10));
+        assert!(matches!(cache.get(&missing), Err(ServerError::BadGateway(_))));
+        // an error status isn't worth asking again for
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn fetch_retries() {
+        let dir = ScratchDir::new("fetch-retries");
+        let (index_file, data_folder) = (format!("{}/index", dir), format!("{}/data", dir));
+        let (flaky, connections) = start_flaky_upstream(2, Duration::ZERO, "200 OK", "finally");
+        let cache = Cache::new(&index_file, &data_folder).unwrap()
+            .with_retries(3, Duration::from_millis(10));
+        assert_eq!(cache.get_string(&flaky).unwrap(), "finally");
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+
+        let (flaky, connections) = start_flaky_upstream(2, Duration::ZERO, "200 OK", "finally");
+        let cache = cache.with_retries(2, Duration::from_millis(10));
+        assert!(matches!(cache.get(&flaky), Err(ServerError::BadGateway(_))));
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn prefetching() {
+        let dir = ScratchDir::new("prefetch");
+        let index_file = format!("{}/cache-index", dir);
+        let data_folder = format!("{}/data", dir);
+        let (upstream, requests) = start_upstream("", b"warm".to_vec());
+        let (a, b) = (format!("{}/a", upstream), format!("{}/b", upstream));
+        let cache = Cache::new(&index_file, &data_folder).unwrap()
+            .with_expiry(Duration::from_secs(3600), Duration::from_secs(3600));
+        let unreachable = "http://127.0.0.1:1/nothing";
+        assert_eq!(cache.prefetch(&[&a, &b, unreachable]).unwrap(), 2);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        // cached entries aren't fetched again, and are served without asking upstream
+        assert_eq!(cache.prefetch(&[&a, &b]).unwrap(), 0);
+        assert_eq!(cache.get_string(&a).unwrap(), "warm");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        // expired ones are
+        cache.index().entries.insert(a.clone(), now() - chrono::Duration::hours(2));
+        assert_eq!(cache.prefetch(&[&a, &b]).unwrap(), 1);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn gzipped_upstream() {
+        let dir = ScratchDir::new("gzipped");
+        let (index_file, data_folder) = (format!("{}/index", dir), format!("{}/data", dir));
+        let (upstream, _) = start_upstream("Content-Encoding: gzip\r\n", Encoding::Gzip.encode(b"unzipped").unwrap());
+        let url = format!("{}/a", upstream);
+        let cache = Cache::new(&index_file, &data_folder).unwrap();
+        assert_eq!(cache.get_string(&url).unwrap(), "unzipped");
+        assert_eq!(cache.get_from_cache(&url).unwrap().body, b"unzipped");
+    }
+
+    #[test]
+    fn entry_metadata() {
+        let dir = ScratchDir::new("metadata");
+        let (index_file, data_folder) = (format!("{}/index", dir), format!("{}/data", dir));
+        let cache = Cache::new(&index_file, &data_folder).unwrap();
+        let url = "http://example.com/a";
+        cache.put_in_cache(url, CachedResponse::new(b"0123456789".to_vec())).unwrap();
+        let metadata = cache.get_metadata(url).unwrap();
+        assert_eq!((metadata.hash, metadata.chain_index, metadata.size_bytes, metadata.hit_count), (get_hash(url), 0, 10, 0));
+        assert_eq!(metadata.cached_at, cache.index().entries[url]);
+        cache.get(url).unwrap();
+        cache.get(url).unwrap();
+        assert_eq!(cache.get_metadata(url).unwrap().hit_count, 2);
+        // the counter counts towards the disk usage
+        assert_eq!(cache.disk_usage_bytes(), dir_size(std::path::Path::new(&data_folder)).unwrap());
+        // replacing the entry starts counting again
+        cache.put_in_cache(url, CachedResponse::new(b"new".to_vec())).unwrap();
+        assert_eq!(cache.get_metadata(url).unwrap().hit_count, 0);
+        assert_eq!(cache.get_metadata("http://example.com/b"), None);
+    }
+
+    #[test]
+    fn list_entries() {
+        let dir = ScratchDir::new("list");
+        let cache = Cache::new(&format!("{}/index", dir), &format!("{}/data", dir)).unwrap();
+        assert!(cache.list().is_empty());
+        cache.put_in_cache("http://example.com/old", CachedResponse::new(b"old".to_vec())).unwrap();
+        cache.put_in_cache("http://example.com/new", CachedResponse::new(b"new".to_vec())).unwrap();
+        let old = now() - chrono::Duration::minutes(5);
+        cache.index().entries.insert("http://example.com/old".to_string(), old);
+        let new = cache.index().entries["http://example.com/new"];
+        assert_eq!(cache.list(), [
+            CacheEntryMeta { url: "http://example.com/new".to_string(), cached_at: new },
+            CacheEntryMeta { url: "http://example.com/old".to_string(), cached_at: old }
+        ]);
+    }
+
+    #[test]
+    fn export_and_import() {
+        let dir = ScratchDir::new("export");
+        let (index_file, data_folder) = (format!("{}/index", dir), format!("{}/data", dir));
+        let cache = Cache::new(&index_file, &data_folder).unwrap();
+        cache.put_in_cache("http://example.com/a", CachedResponse::new(b"first\nline".to_vec())).unwrap();
+        cache.put_in_cache("http://example.com/b", CachedResponse::new(b"{\"json\": true}".to_vec())).unwrap();
+        let cached_at = cache.index().entries.clone();
+        let mut exported = vec![];
+        cache.export(&mut exported).unwrap();
+        let exported = String::from_utf8(exported).unwrap();
+        assert_eq!(exported.lines().count(), 2);
+        assert!(exported.starts_with("{\"cached_at\":\""));
+        assert!(exported.contains("\"url\":\"http://example.com/a\""));
+
+        let restored_dir = ScratchDir::new("import");
+        let (index_file, data_folder) = (format!("{}/index", restored_dir), format!("{}/data", restored_dir));
+        let restored = Cache::new(&index_file, &data_folder).unwrap();
+        assert_eq!(restored.import(&mut exported.as_bytes()).unwrap(), 2);
+        assert_eq!(restored.get_from_cache("http://example.com/a").unwrap().body, b"first\nline");
+        assert_eq!(restored.get_from_cache("http://example.com/b").unwrap().body, b"{\"json\": true}");
+        assert_eq!(restored.index().entries, cached_at);
+        // the restored index was saved
+        drop(restored);
+        let reopened = Cache::new(&index_file, &data_folder).unwrap();
+        assert_eq!(reopened.index().entries, cached_at);
+
+        let error = cache.import(&mut "\n{\"url\": \"http://example.com/c\"}".as_bytes()).unwrap_err();
+        assert_eq!(error.to_string(), "line 2: no data string");
+        assert!(matches!(cache.import(&mut "not json".as_bytes()), Err(DeserializeError::BadLine(1, _))));
+    }
+
+    #[test]
+    fn import_into_a_smaller_cache() {
+        let dir = ScratchDir::new("export-big");
+        let cache = Cache::new(&format!("{}/index", dir), &format!("{}/data", dir)).unwrap();
+        cache.put_in_cache("http://example.com/small", CachedResponse::new(b"small".to_vec())).unwrap();
+        cache.put_in_cache("http://example.com/big", CachedResponse::new(vec![b'x'; 1000])).unwrap();
+        let mut exported = vec![];
+        cache.export(&mut exported).unwrap();
+
+        let small_dir = ScratchDir::new("import-small");
+        let data_folder = format!("{}/data", small_dir);
+        let small = Cache::new(&format!("{}/index", small_dir), &data_folder).unwrap().with_max_disk_bytes(200);
+        // the big entry doesn't fit, so it isn't counted or listed in the index
+        assert_eq!(small.import(&mut exported.as_slice()).unwrap(), 1);
+        assert_eq!(small.index().entries.keys().collect::<Vec<_>>(), ["http://example.com/small"]);
+        let mut reexported = vec![];
+        small.export(&mut reexported).unwrap();
+        assert_eq!(String::from_utf8(reexported).unwrap().lines().count(), 1);
+
+        // an entry whose files were deleted behind the cache's back is left out of the backup
+        std::fs::remove_dir_all(format!("{}/{}", data_folder, get_hash("http://example.com/small"))).unwrap();
+        let mut reexported = vec![];
+        small.export(&mut reexported).unwrap();
+        assert!(reexported.is_empty());
+    }
+
+    #[test]
+    fn binary_entries() {
+        let dir = ScratchDir::new("binary");
+        let (index_file, data_folder) = (format!("{}/index", dir), format!("{}/data", dir));
+        let url = "http://example.com/logo.png";
+        let bytes = vec![0x89, b'P', b'N', b'G', 0, 0, 0xff, 0xfe, b'\r', b'\n', 0xc3, 0x28, 0];
+        let client = FakeClient::new(vec![(url, vec![Ok(UpstreamResponse { status: 200, headers: vec![], body: bytes.clone() })])]);
+        let cache = Cache::new(&index_file, &data_folder).unwrap().with_upstream_client(Box::new(client));
+        assert_eq!(cache.get(url).unwrap(), bytes);
+        assert!(matches!(cache.get_string(url), Err(ServerError::CacheError(_))));
+        drop(cache);
+
+        // read back from disk as it was written
+        let cache = Cache::new(&index_file, &data_folder).unwrap();
+        assert_eq!(cache.get(url).unwrap(), bytes);
+        assert_eq!(cache.get_metadata(url).unwrap().size_bytes, bytes.len() as u64);
+        let mut exported = vec![];
+        cache.export(&mut exported).unwrap();
+        let other_dir = ScratchDir::new("binary-import");
+        let restored = Cache::new(&format!("{}/index", other_dir), &format!("{}/data", other_dir)).unwrap();
+        assert_eq!(restored.import(&mut exported.as_slice()).unwrap(), 1);
+        assert_eq!(restored.get_from_cache(url).unwrap().body, bytes);
+
+        // an entry whose key isn't text doesn't get in the way of the others with the same hash
+        let url = "http://example.com/font.woff2";
+        let damaged = format!("{}/{}/0", data_folder, get_hash(url));
+        std::fs::create_dir_all(&damaged).unwrap();
+        std::fs::write(format!("{}/key", damaged), [0xff, 0xfe]).unwrap();
+        cache.put_in_cache(url, CachedResponse::new(bytes.clone())).unwrap();
+        assert_eq!(cache.get(url).unwrap(), bytes);
+    }
+
+    #[test]
+    fn response_metadata() {
+        let dir = ScratchDir::new("response-metadata");
+        let (index_file, data_folder) = (format!("{}/index", dir), format!("{}/data", dir));
+        let url = "http://example.com/style.css";
+        let headers = vec![
+            ("content-type".to_string(), "text/css".to_string()),
+            ("ETag".to_string(), "\"v1\"".to_string()),
+            ("Set-Cookie".to_string(), "session=secret".to_string()),
+            ("Cache-Control".to_string(), "max-age=60, public".to_string())
+        ];
+        let client = FakeClient::new(vec![(url, vec![Ok(UpstreamResponse { status: 203, headers, body: b"p {}".to_vec() })])]);
+        let cache = Cache::new(&index_file, &data_folder).unwrap().with_upstream_client(Box::new(client));
+        let fetched = cache.get_response(url).unwrap();
+        assert_eq!(fetched.status, 203);
+        // only the headers worth replaying are kept
+        assert_eq!(fetched.headers.len(), 3);
+        assert_eq!(fetched.header("Content-Type"), Some("text/css"));
+        assert_eq!(fetched.header("Set-Cookie"), None);
+        drop(cache);
+
+        let cache = Cache::new(&index_file, &data_folder).unwrap();
+        assert_eq!(cache.get_response(url).unwrap(), fetched);
+        assert_eq!(cache.get_response(url).unwrap().header("cache-control"), Some("max-age=60, public"));
+        let mut exported = vec![];
+        cache.export(&mut exported).unwrap();
+        let other_dir = ScratchDir::new("response-metadata-import");
+        let restored = Cache::new(&format!("{}/index", other_dir), &format!("{}/data", other_dir)).unwrap();
+        restored.import(&mut exported.as_slice()).unwrap();
+        let restored = restored.get_from_cache(url).unwrap();
+        assert_eq!((restored.status, restored.headers, restored.body), (fetched.status, fetched.headers, fetched.body));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn upstream_freshness() {
+        let dir = ScratchDir::new("upstream-freshness");
+        let (index_file, data_folder) = (format!("{}/index", dir), format!("{}/data", dir));
+        let with_headers = |headers: &[(&str, &str)], body: &str| Ok(UpstreamResponse {
+            status: 200,
+            headers: headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+            body: body.as_bytes().to_vec()
+        });
+        let (fresh, expired, dated, past, bad_date, secret, changed) = ("http://example.com/fresh", "http://example.com/expired",
+            "http://example.com/dated", "http://example.com/past", "http://example.com/bad-date", "http://example.com/secret",
+            "http://example.com/changed");
+        let client = FakeClient::new(vec![
+            (fresh, vec![with_headers(&[("Cache-Control", "public, max-age=3600")], "fresh"), answer(200, "refetched")]),
+            (expired, vec![with_headers(&[("cache-control", "max-age=0")], "expired"), answer(200, "refetched")]),
+            (dated, vec![with_headers(&[("Expires", "Fri, 01 Jan 2100 00:00:00 GMT")], "dated"), answer(200, "refetched")]),
+            (past, vec![with_headers(&[("Expires", "Thu, 01 Jan 1970 00:00:00 GMT")], "past"), answer(200, "refetched")]),
+            (bad_date, vec![with_headers(&[("Expires", "0")], "bad date"), answer(200, "refetched")]),
+            (secret, vec![with_headers(&[("Cache-Control", "private")], "secret"), answer(200, "refetched")]),
+            (changed, vec![with_headers(&[("Cache-Control", "no-cache")], "changed"),
+                           with_headers(&[("Cache-Control", "no-store")], "unstorable")])
+        ]);
+        let cache = Cache::new(&index_file, &data_folder).unwrap().with_upstream_client(Box::new(client));
+        for (url, first, second) in [(fresh, "fresh", "fresh"), (expired, "expired", "refetched"), (dated, "dated", "dated"),
+                                     (past, "past", "refetched"), (bad_date, "bad date", "refetched"), (secret, "secret", "refetched")] {
+            assert_eq!(cache.get_string(url).unwrap(), first);
+            assert_eq!(cache.get_string(url).unwrap(), second);
+        }
+        // a lifetime too long to add to the date doesn't end
+        let huge = [("Cache-Control".to_string(), "max-age=99999999999999".to_string())];
+        assert_eq!(expiry(&huge, now(), false), None);
+        let huge = [("Cache-Control".to_string(), format!("max-age={}", u64::MAX))];
+        assert_eq!(expiry(&huge, now(), false), None);
+        let expires_at = cache.get_from_cache(fresh).unwrap().expires_at.unwrap();
+        assert!(expires_at > now() + chrono::Duration::minutes(59) && expires_at <= now() + chrono::Duration::hours(1));
+        assert_eq!(cache.get_from_cache(dated).unwrap().expires_at.unwrap().to_string(), "2100-01-01 00:00:00");
+        // no-store responses aren't stored, and take the place of what was
+        assert_eq!(cache.get_string(changed).unwrap(), "changed");
+        assert!(cache.get_from_cache(changed).is_ok());
+        assert_eq!(cache.get_string(changed).unwrap(), "unstorable");
+        assert!(matches!(cache.get_from_cache(changed), Err(ServerError::NotFound(_))));
+        assert!(!cache.index().entries.contains_key(changed));
+        // the expiry is kept with the entry
+        drop(cache);
+        let cache = Cache::new(&index_file, &data_folder).unwrap();
+        assert_eq!(cache.get_from_cache(fresh).unwrap().expires_at, Some(expires_at));
+    }
+
+    #[test]
+    fn heuristic_freshness() {
+        let last_modified = (chrono::Utc::now() - chrono::Duration::days(10)).to_rfc2822();
+        let response = || Ok(UpstreamResponse {
+            status: 200,
+            headers: vec![("Last-Modified".to_string(), last_modified.clone())],
+            body: b"old".to_vec()
+        });
+        let url = "http://example.com/rarely-changes";
+        for heuristic in [false, true] {
+            let dir = ScratchDir::new(&format!("heuristic-{}", heuristic));
+            let options = CacheOptions::new(&format!("{}/index", dir), &format!("{}/data", dir)).with_heuristic_freshness(heuristic);
+            let client = FakeClient::new(vec![(url, vec![response()])]);
+            let cache = Cache::new_with_options(options).unwrap().with_upstream_client(Box::new(client));
+            let fetched = cache.get_response(url).unwrap();
+            if heuristic {
+                // a tenth of the ten days since it was modified
+                let lifetime = fetched.expires_at.unwrap() - fetched.fetched_at;
+                assert!((lifetime - chrono::Duration::days(1)).num_seconds().abs() <= 1);
+            } else {
+                assert_eq!(fetched.expires_at, None);
+            }
+        }
+    }
+
+    #[test]
+    fn conditional_revalidation() {
+        let dir = ScratchDir::new("conditional");
+        let (index_file, data_folder) = (format!("{}/index", dir), format!("{}/data", dir));
+        let validated = |status: u16, body: &str| Ok(UpstreamResponse {
+            status,
+            headers: vec![
+                ("Cache-Control".to_string(), "no-cache".to_string()),
+                ("ETag".to_string(), format!("\"{}\"", body.len())),
+                ("Last-Modified".to_string(), "Thu, 01 Jan 2015 00:00:00 GMT".to_string())
+            ],
+            body: body.as_bytes().to_vec()
+        });
+        let (same, changed, down) = ("http://example.com/same", "http://example.com/changed", "http://example.com/down");
+        let client = FakeClient::new(vec![
+            (same, vec![validated(200, "body"), Ok(UpstreamResponse {
+                status: 304,
+                headers: vec![("Cache-Control".to_string(), "max-age=3600".to_string())],
+                body: vec![]
+            })]),
+            (changed, vec![validated(200, "body"), validated(200, "new body")]),
+            (down, vec![validated(200, "body"), Err(UpstreamError::Unreachable("down".to_string()))])
+        ]);
+        let requests = Arc::clone(&client.requests);
+        let cache = Cache::new(&index_file, &data_folder).unwrap().with_upstream_client(Box::new(client));
+
+        // not modified: the body is kept, and the new lifetime makes it fresh again
+        assert_eq!(cache.get_string(same).unwrap(), "body");
+        assert_eq!(cache.get_string(same).unwrap(), "body");
+        assert_eq!(requests.lock().unwrap()[1].1, [
+            ("If-None-Match".to_string(), "\"4\"".to_string()),
+            ("If-Modified-Since".to_string(), "Thu, 01 Jan 2015 00:00:00 GMT".to_string())
+        ]);
+        let revalidated = cache.get_from_cache(same).unwrap();
+        assert!(!revalidated.is_expired());
+        assert_eq!(revalidated.header("ETag"), Some("\"4\""));
+        assert_eq!(revalidated.header("Cache-Control"), Some("max-age=3600"));
+        assert_eq!(dir_size(std::path::Path::new(&data_folder)).unwrap(), cache.disk_usage_bytes());
+        assert_eq!(cache.get_string(same).unwrap(), "body");
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        // modified: the entry is replaced
+        assert_eq!(cache.get_string(changed).unwrap(), "body");
+        assert_eq!(cache.get_string(changed).unwrap(), "new body");
+        assert_eq!(cache.get_from_cache(changed).unwrap().header("ETag"), Some("\"8\""));
+
+        // unreachable: an error, unless stale entries may be served
+        assert_eq!(cache.get_string(down).unwrap(), "body");
+        assert!(matches!(cache.get(down), Err(ServerError::BadGateway(_))));
+        let cache = Cache::new_with_options(CacheOptions::new(&index_file, &data_folder).with_serve_stale_on_error(true)).unwrap()
+            .with_upstream_client(Box::new(FakeClient::new(vec![(down, vec![Err(UpstreamError::Unreachable("down".to_string()))])])));
+        assert_eq!(cache.get_string(down).unwrap(), "body");
+    }
+
+    #[test]
+    fn stale_while_revalidate() {
+        let dir = ScratchDir::new("stale-while-revalidate");
+        let options = CacheOptions::new(&format!("{}/index", dir), &format!("{}/data", dir)).with_stale_while_revalidate(60);
+        let with_lifetime = |max_age: &str, body: &str| Ok(UpstreamResponse {
+            status: 200,
+            headers: vec![("Cache-Control".to_string(), format!("max-age={}", max_age))],
+            body: body.as_bytes().to_vec()
+        });
+        let (url, ancient) = ("http://example.com/quote", "http://example.com/ancient");
+        let (client, asked_for, let_through) = FakeClient::new(vec![
+            (url, vec![with_lifetime("0", "old"), with_lifetime("3600", "new")]),
+            (ancient, vec![with_lifetime("0", "old"), with_lifetime("3600", "new")])
+        ]).gated();
+        let requests = Arc::clone(&client.requests);
+        let fetches = |url: &str| requests.lock().unwrap().iter().filter(|(requested, _)| requested == url).count();
+        let cache = Arc::new(Cache::new_with_options(options).unwrap().with_upstream_client(Box::new(client)));
+        let_through.send(()).unwrap();
+        assert_eq!(cache.get_string(url).unwrap(), "old");
+        assert_eq!(asked_for.recv().unwrap(), url);
+
+        // everyone gets the stale body while the revalidation is held up, and only one of them has it revalidated
+        let readers = (0..8).map(|_| {
+            let cache = Arc::clone(&cache);
+            thread::spawn(move || (0..5).map(|_| cache.get_string(url).unwrap()).collect::<Vec<_>>())
+        }).collect::<Vec<_>>();
+        for reader in readers {
+            assert!(reader.join().unwrap().iter().all(|body| body == "old"));
+        }
+        assert_eq!(asked_for.recv().unwrap(), url);
+        assert!(asked_for.try_recv().is_err());
+        assert_eq!(fetches(url), 2);
+
+        // once the upstream answers, the next read after the refresh thread is done gets the new body
+        let_through.send(()).unwrap();
+        while cache.get_string(url).unwrap() == "old" {
+            thread::yield_now();
+        }
+        assert_eq!(cache.get_string(url).unwrap(), "new");
+        assert_eq!(fetches(url), 2);
+
+        // entries that have been stale for too long are revalidated before they're served
+        let_through.send(()).unwrap();
+        let_through.send(()).unwrap();
+        assert_eq!(cache.get_string(ancient).unwrap(), "old");
+        let mut stale = cache.get_from_cache(ancient).unwrap();
+        stale.expires_at = Some(now() - chrono::Duration::minutes(2));
+        cache.put_in_cache(ancient, stale).unwrap();
+        assert_eq!(cache.get_string(ancient).unwrap(), "new");
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        let dir = ScratchDir::new("shared");
+        let (index_file, data_folder) = (format!("{}/index", dir), format!("{}/data", dir));
+        let urls = (0..4).map(|i| format!("http://example.com/{}", i)).collect::<Vec<_>>();
+        let mut client = FakeClient::new(urls.iter().map(|url| (url.as_str(), vec![answer(200, url)])).collect());
+        client.latency = Duration::from_millis(20);
+        let cache = Arc::new(Cache::new(&index_file, &data_folder).unwrap().with_upstream_client(Box::new(client)));
+        let workers = (0..8).map(|i| {
+            let (cache, urls) = (Arc::clone(&cache), urls.clone());
+            thread::spawn(move || for round in 0..20 {
+                // everyone fetches the same missing url at first, and then they go their own ways
+                let url = &urls[if round == 0 { 0 } else { (i + round) % urls.len() }];
+                assert_eq!(cache.get_string(url).unwrap(), *url);
+                // and write the same entry over and over
+                cache.put_in_cache("http://example.com/contended", CachedResponse::new(vec![i as u8; 100 + i])).unwrap();
+            })
+        }).collect::<Vec<_>>();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        for url in urls.iter().map(String::as_str).chain(["http://example.com/contended"]) {
+            let chain = get_sub_folders(&format!("{}/{}", data_folder, get_hash(url))).unwrap();
+            assert_eq!(chain.len(), 1, "{} was cached more than once", url);
+        }
+        let contended = cache.get_from_cache("http://example.com/contended").unwrap().body;
+        assert!(contended.iter().all(|byte| *byte as usize + 100 == contended.len()));
+        assert_eq!(cache.list().len(), 5);
+        assert_eq!(dir_size(std::path::Path::new(&data_folder)).unwrap(), cache.disk_usage_bytes());
+    }
+
+    #[test]
+    fn legacy_entries() {
+        let dir = ScratchDir::new("legacy");
+        let (index_file, data_folder) = (format!("{}/index", dir), format!("{}/data", dir));
+        let url = "http://example.com/old-format";
+        // what older versions wrote: a key and the body, but no meta file
+        let entry_dir = format!("{}/{}/0", data_folder, get_hash(url));
+        std::fs::create_dir_all(&entry_dir).unwrap();
+        std::fs::write(format!("{}/key", entry_dir), url).unwrap();
+        std::fs::write(format!("{}/data", entry_dir), "old body").unwrap();
+        std::fs::write(&index_file, format!("{}%%%{}\n", url, now().format("%Y-%m-%d %H:%M:%S"))).unwrap();
+
+        let client = FakeClient::new(vec![(url, vec![answer(200, "new body")])]);
+        let cache = Cache::new(&index_file, &data_folder).unwrap().with_upstream_client(Box::new(client));
+        assert!(matches!(cache.get_from_cache(url), Err(ServerError::NotFound(_))));
+        // so it's fetched again, and replaced with an entry in the new format
+        assert_eq!(cache.get_string(url).unwrap(), "new body");
+        assert_eq!(cache.get_from_cache(url).unwrap().body, b"new body");
+        assert!(std::path::Path::new(&format!("{}/meta", entry_dir)).exists());
+    }
+
+    #[test]
+    fn unreadable_entries() {
+        let dir = ScratchDir::new("unreadable");
+        let (index_file, data_folder) = (format!("{}/index", dir), format!("{}/data", dir));
+        let cache = Cache::new(&index_file, &data_folder).unwrap();
+        let url = "http://example.com/a";
+        cache.put_in_cache(url, CachedResponse::new(b"a".to_vec())).unwrap();
+        let entry_dir = format!("{}/{}/0", data_folder, get_hash(url));
+
+        // a key that can't be read isn't a miss
+        std::fs::remove_file(format!("{}/key", entry_dir)).unwrap();
+        std::fs::create_dir(format!("{}/key", entry_dir)).unwrap();
+        assert!(matches!(cache.get_from_cache(url), Err(ServerError::CacheError(_))));
+        assert_eq!(cache.get_metadata(url), None);
+        std::fs::remove_dir(format!("{}/key", entry_dir)).unwrap();
+        // a missing one, though, leaves the entry unreachable, so that's a miss
+        assert!(matches!(cache.get_from_cache(url), Err(ServerError::NotFound(_))));
+
+        // but a chain that can't be read isn't, unless permissions don't apply to whoever runs the test
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            cache.put_in_cache(url, CachedResponse::new(b"a".to_vec())).unwrap();
+            let hash_dir = format!("{}/{}", data_folder, get_hash(url));
+            std::fs::set_permissions(&hash_dir, std::fs::Permissions::from_mode(0o000)).unwrap();
+            let readable = std::fs::read_dir(&hash_dir).is_ok();
+            let result = cache.get_from_cache(url);
+            std::fs::set_permissions(&hash_dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+            if !readable {
+                assert!(matches!(result, Err(ServerError::CacheError(_))));
+            }
+        }
+    }
+
+    #[test]
+    fn stray_folders_in_a_chain() {
+        let dir = ScratchDir::new("stray-folders");
+        let (index_file, data_folder) = (format!("{}/index", dir), format!("{}/data", dir));
+        let cache = Cache::new_with_options(CacheOptions::new(&index_file, &data_folder).with_max_entries(1)).unwrap();
+        let url = "http://example.com/a";
+        cache.put_in_cache(url, CachedResponse::new(b"a".to_vec())).unwrap();
+        std::fs::create_dir(format!("{}/{}/lost+found", data_folder, get_hash(url))).unwrap();
+        // folders that aren't links of the chain are passed over, not tripped on
+        assert_eq!(cache.get_from_cache(url).unwrap().body, b"a");
+        cache.put_in_cache(url, CachedResponse::new(b"b".to_vec())).unwrap();
+        assert_eq!(cache.get_from_cache(url).unwrap().body, b"b");
+        // and evicting the entry to make room works too
+        cache.put_in_cache("http://example.com/b", CachedResponse::new(b"b".to_vec())).unwrap();
+        assert_eq!(cache.index().entries.keys().collect::<Vec<_>>(), ["http://example.com/b"]);
+    }
+
+    #[test]
+    fn prune_expired_entries() {
+        let dir = ScratchDir::new("prune-index");
+        let mut index = CacheIndex::new(&format!("{}/cache-index", dir)).unwrap();
+        let long_ago = now() - chrono::Duration::hours(2);
+        index.entries.insert("http://example.com/old".to_string(), long_ago);
+        index.entries.insert("http://example.com/older".to_string(), long_ago - chrono::Duration::hours(1));
+        index.entries.insert("http://example.com/new".to_string(), now());
+        assert_eq!(index.prune_expired(Duration::from_secs(3600), now()), 2);
+        assert_eq!(index.prune_expired(Duration::from_secs(3600), now()), 0);
+        assert!(index.get_entries().contains_key("http://example.com/new"));
+        // the pruned index was saved
+        let reloaded = CacheIndex::new(&format!("{}/cache-index", dir)).unwrap();
+        assert_eq!(reloaded.get_entries().keys().collect::<Vec<_>>(), vec!["http://example.com/new"]);
+    }
+
+    #[test]
+    fn background_pruning_removes_files() {
+        let dir = ScratchDir::new("prune-background");
+        let index_file = format!("{}/cache-index", dir);
+        let data_folder = format!("{}/data", dir);
+        let cache = Cache::new(&index_file, &data_folder).unwrap()
+            .with_expiry(Duration::from_secs(3600), Duration::from_millis(20));
+        cache.put_in_cache("http://example.com/old", CachedResponse::new(b"old".to_vec())).unwrap();
+        cache.put_in_cache("http://example.com/new", CachedResponse::new(b"new".to_vec())).unwrap();
+        cache.index().entries.insert("http://example.com/old".to_string(), now() - chrono::Duration::hours(2));
+        thread::sleep(Duration::from_millis(200));
+        assert!(cache.get_from_cache("http://example.com/old").is_err());
+        assert_eq!(cache.get_from_cache("http://example.com/new").unwrap().body, b"new");
+        assert_eq!(cache.disk_usage_bytes(), dir_size(std::path::Path::new(&data_folder)).unwrap());
+    }
+}
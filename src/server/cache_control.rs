@@ -0,0 +1,96 @@
+/// What an upstream's `Cache-Control` header says about caching its response, as far as a shared cache
+/// cares (RFC 7234 section 5.2.2).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CacheDirectives {
+    /// seconds the response stays fresh
+    pub max_age: Option<u64>,
+    /// like `max_age`, but only for shared caches, which it takes precedence for
+    pub s_maxage: Option<u64>,
+    pub no_store: bool,
+    /// the response may be stored, but has to be fetched again before it's reused
+    pub no_cache: bool,
+    /// the response is for a single user, so a shared cache doesn't store it
+    pub private: bool
+}
+
+impl CacheDirectives {
+    /// Parses a `Cache-Control` value. Directive names are case-insensitive and unknown ones are ignored.
+    /// A lifetime that isn't a number of seconds, or that is given more than once, is invalid, which
+    /// RFC 7234 section 4.2.1 says to treat as already stale. `private` and `no-cache` with a list of
+    /// header names only concern those headers, which the cache doesn't keep anyway, so they're ignored.
+    pub fn parse(header: &str) -> CacheDirectives {
+        let mut directives = CacheDirectives::default();
+        for directive in header.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (directive, None)
+            };
+            match name.to_ascii_lowercase().as_str() {
+                "max-age" => set_lifetime(&mut directives.max_age, value),
+                "s-maxage" => set_lifetime(&mut directives.s_maxage, value),
+                "no-store" => directives.no_store = true,
+                "no-cache" if value.is_none() => directives.no_cache = true,
+                "private" if value.is_none() => directives.private = true,
+                _ => {}
+            }
+        }
+        directives
+    }
+
+    /// Whether a shared cache may store the response at all.
+    pub fn storable(&self) -> bool {
+        !self.no_store && !self.private
+    }
+
+    /// How many seconds the response stays fresh, if the header says.
+    pub fn lifetime(&self) -> Option<u64> {
+        if self.no_cache {
+            return Some(0);
+        }
+        self.s_maxage.or(self.max_age)
+    }
+}
+
+/// Sets a lifetime directive to `value`, or to `0` if it's invalid or was set already.
+fn set_lifetime(lifetime: &mut Option<u64>, value: Option<&str>) {
+    *lifetime = match (*lifetime, value.and_then(|seconds| seconds.parse().ok())) {
+        (None, Some(seconds)) => Some(seconds),
+        _ => Some(0)
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use crate::server::cache_control::CacheDirectives;
+
+    #[test]
+    fn directives() {
+        let directives = CacheDirectives::parse("public, max-age=3600");
+        assert_eq!(directives, CacheDirectives { max_age: Some(3600), ..CacheDirectives::default() });
+        assert_eq!(directives.lifetime(), Some(3600));
+        assert!(directives.storable());
+        // shared caches go by s-maxage
+        assert_eq!(CacheDirectives::parse("max-age=60, s-maxage=600").lifetime(), Some(600));
+        assert_eq!(CacheDirectives::parse("Max-Age=\"60\"").lifetime(), Some(60));
+        assert_eq!(CacheDirectives::parse("no-cache, max-age=60").lifetime(), Some(0));
+        assert!(!CacheDirectives::parse("no-store").storable());
+        assert!(!CacheDirectives::parse("PRIVATE, max-age=60").storable());
+        assert_eq!(CacheDirectives::parse(""), CacheDirectives::default());
+        assert_eq!(CacheDirectives::parse("public").lifetime(), None);
+    }
+
+    #[test]
+    fn weird_directives() {
+        // lifetimes that make no sense are stale
+        assert_eq!(CacheDirectives::parse("max-age=").lifetime(), Some(0));
+        assert_eq!(CacheDirectives::parse("max-age").lifetime(), Some(0));
+        assert_eq!(CacheDirectives::parse("max-age=-1").lifetime(), Some(0));
+        assert_eq!(CacheDirectives::parse("max-age=soon").lifetime(), Some(0));
+        assert_eq!(CacheDirectives::parse("max-age=60, max-age=60").lifetime(), Some(0));
+        assert_eq!(CacheDirectives::parse("max-age=60, max-age=3600").lifetime(), Some(0));
+        // the rest is ignored
+        assert_eq!(CacheDirectives::parse(" , ,max-age=60,, x-custom=\"a, b\"").lifetime(), Some(60));
+        assert_eq!(CacheDirectives::parse("private=\"Set-Cookie\", no-cache=\"Set-Cookie\""), CacheDirectives::default());
+        assert!(CacheDirectives::parse("no-store=whatever").no_store);
+    }
+}
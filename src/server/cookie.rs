@@ -0,0 +1,134 @@
+use crate::server::error::ServerError;
+
+/// Which cross-site requests a browser sends a cookie with.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SameSite {
+    /// only requests from the cookie's own site
+    Strict,
+    /// requests from its own site, and top-level navigations to it from others
+    #[default]
+    Lax,
+    /// all requests; browsers only accept this for `Secure` cookies
+    None
+}
+
+impl SameSite {
+    fn token(self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None"
+        }
+    }
+}
+
+/// The attributes a cookie is set with. By default, it's a session cookie for the whole site.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CookieOptions {
+    /// seconds until the cookie expires; without it, the cookie lasts until the browser is closed
+    pub max_age: Option<u64>,
+    /// whether scripts on the page are kept from reading the cookie
+    pub http_only: bool,
+    /// whether the cookie is only sent over https
+    pub secure: bool,
+    pub same_site: SameSite,
+    /// the url path the cookie is sent for, along with everything below it
+    pub path: String,
+    /// the host the cookie is sent to, along with its subdomains; without it, only the host that set it
+    pub domain: Option<String>
+}
+
+impl Default for CookieOptions {
+    fn default() -> CookieOptions {
+        CookieOptions {
+            max_age: None,
+            http_only: false,
+            secure: false,
+            same_site: SameSite::default(),
+            path: "/".to_string(),
+            domain: None
+        }
+    }
+}
+
+/// The value of a `Set-Cookie` header that sets `name` to `value` (RFC 6265 section 4.1).
+/// `name` has to be a token and `value` cookie-octets, optionally in double quotes; the path and
+/// domain mustn't have a `;` or control characters. Anything else could add attributes or headers.
+pub fn set_cookie(name: &str, value: &str, options: &CookieOptions) -> Result<String, ServerError> {
+    let invalid = |what: &str, text: &str| Err(ServerError::Cookie(format!("{} {:?}", what, text)));
+    if name.is_empty() || !name.bytes().all(is_tchar) {
+        return invalid("name", name);
+    }
+    let unquoted = value.strip_prefix('"').and_then(|value| value.strip_suffix('"')).unwrap_or(value);
+    if !unquoted.bytes().all(is_cookie_octet) {
+        return invalid("value", value);
+    }
+    let is_attribute_value = |text: &str| text.bytes().all(|b| !b.is_ascii_control() && b != b';');
+    if !is_attribute_value(&options.path) {
+        return invalid("path", &options.path);
+    }
+    let mut header = format!("{}={}; Path={}", name, value, options.path);
+    if let Some(domain) = &options.domain {
+        if domain.is_empty() || !is_attribute_value(domain) {
+            return invalid("domain", domain);
+        }
+        header += &format!("; Domain={}", domain);
+    }
+    if let Some(max_age) = options.max_age {
+        header += &format!("; Max-Age={}", max_age);
+    }
+    if options.http_only {
+        header += "; HttpOnly";
+    }
+    if options.secure {
+        header += "; Secure";
+    }
+    Ok(header + "; SameSite=" + options.same_site.token())
+}
+
+/// A character of a token, as in RFC 7230 section 3.2.6.
+fn is_tchar(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+/// US-ASCII without controls, whitespace, double quotes, commas, semicolons and backslashes (RFC 6265 section 4.1.1).
+fn is_cookie_octet(byte: u8) -> bool {
+    matches!(byte, 0x21 | 0x23..=0x2B | 0x2D..=0x3A | 0x3C..=0x5B | 0x5D..=0x7E)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::server::cookie::{CookieOptions, SameSite, set_cookie};
+
+    #[test]
+    fn set_cookie_headers() {
+        assert_eq!(set_cookie("theme", "dark", &CookieOptions::default()).unwrap(), "theme=dark; Path=/; SameSite=Lax");
+        let options = CookieOptions {
+            max_age: Some(3600),
+            http_only: true,
+            secure: true,
+            same_site: SameSite::None,
+            path: "/admin".to_string(),
+            domain: Some("example.com".to_string())
+        };
+        assert_eq!(set_cookie("session", "\"abc123\"", &options).unwrap(),
+                   "session=\"abc123\"; Path=/admin; Domain=example.com; Max-Age=3600; HttpOnly; Secure; SameSite=None");
+    }
+
+    #[test]
+    fn invalid_cookies() {
+        let defaults = CookieOptions::default();
+        for (name, value) in [("", "a"), ("a b", "a"), ("a=b", "a"), ("a;", "a"), ("a\r\nX-Evil", "a"),
+                              ("a", "b; Domain=evil.com"), ("a", "b c"), ("a", "b,c"), ("a", "\"b"), ("a", "b\\"),
+                              ("a", "b\r\nX: y"), ("a", "é")] {
+            assert_eq!(set_cookie(name, value, &defaults).unwrap_err().status(), 500, "{:?}={:?}", name, value);
+        }
+        let path = CookieOptions { path: "/; HttpOnly=false".to_string(), ..CookieOptions::default() };
+        assert!(set_cookie("a", "b", &path).is_err());
+        let path = CookieOptions { path: "/\r\nX: y".to_string(), ..CookieOptions::default() };
+        assert!(set_cookie("a", "b", &path).is_err());
+        let domain = CookieOptions { domain: Some("evil.com; Secure".to_string()), ..CookieOptions::default() };
+        assert!(set_cookie("a", "b", &domain).is_err());
+        assert!(set_cookie("a", "", &defaults).is_ok());
+    }
+}
@@ -0,0 +1,38 @@
+/// A `Content-Disposition` value that has browsers download the response as `filename` (RFC 6266).
+/// Names that can't go in a quoted string as they are, because they aren't ASCII or have quotes
+/// or backslashes in them, are also given percent-encoded in `filename*` (RFC 8187), which browsers
+/// prefer; the plain `filename` then has those characters replaced for the ones that don't.
+pub fn attachment(filename: &str) -> String {
+    let plain = filename.chars().all(|c| c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\');
+    if plain {
+        return format!("attachment; filename=\"{}\"", filename);
+    }
+    let fallback = filename.chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' { c } else { '_' })
+        .collect::<String>();
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, percent_encode(filename))
+}
+
+/// `text` as an RFC 8187 value, with every byte that isn't an `attr-char` percent-encoded.
+fn percent_encode(text: &str) -> String {
+    text.bytes().map(|byte| match byte {
+        b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' =>
+            (byte as char).to_string(),
+        _ => format!("%{:02X}", byte)
+    }).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::server::disposition::attachment;
+
+    #[test]
+    fn attachments() {
+        assert_eq!(attachment("report.pdf"), "attachment; filename=\"report.pdf\"");
+        assert_eq!(attachment("annual report.pdf"), "attachment; filename=\"annual report.pdf\"");
+        assert_eq!(attachment("say \"hi\".txt"),
+                   "attachment; filename=\"say _hi_.txt\"; filename*=UTF-8''say%20%22hi%22.txt");
+        assert_eq!(attachment("naïve.txt"), "attachment; filename=\"na_ve.txt\"; filename*=UTF-8''na%C3%AFve.txt");
+        assert_eq!(attachment("a\\b"), "attachment; filename=\"a_b\"; filename*=UTF-8''a%5Cb");
+    }
+}
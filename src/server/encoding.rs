@@ -0,0 +1,116 @@
+use std::io;
+use std::io::Write;
+
+/// The content codings the server can compress responses with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip
+}
+
+/// Which encoding wins when a client accepts several of them.
+pub const DEFAULT_ENCODINGS: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+impl Encoding {
+    /// The name of the encoding in `Accept-Encoding` and `Content-Encoding` headers.
+    pub fn token(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip"
+        }
+    }
+
+    pub fn encode(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut encoder = brotli::CompressorWriter::new(vec![], 4096, 5, 22);
+                encoder.write_all(data)?;
+                Ok(encoder.into_inner())
+            }
+            Encoding::Zstd => zstd::encode_all(data, 0),
+            Encoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Parses an `Accept-Encoding` or `Accept` header into `(coding, quality)` pairs.
+/// Codings without a valid `q` parameter get a quality of 1.
+pub fn parse_qualities(header: &str) -> Vec<(String, f32)> {
+    header.split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let coding = params.next()?.trim().to_ascii_lowercase();
+            if coding.is_empty() {
+                return None;
+            }
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((coding, quality))
+        })
+        .collect()
+}
+
+/// Picks the first encoding in `preference` that the client accepts.
+/// An encoding is acceptable if it's listed (or covered by `*`) with a quality above 0,
+/// so `br;q=0` rules brotli out even if `*` would allow it.
+pub fn negotiate(accept_encoding: &str, preference: &[Encoding]) -> Option<Encoding> {
+    let accepted = parse_qualities(accept_encoding);
+    let quality = |token: &str| accepted.iter()
+        .find(|(coding, _)| coding == token)
+        .or_else(|| accepted.iter().find(|(coding, _)| coding == "*"))
+        .map(|(_, q)| *q)
+        .unwrap_or(0.0);
+    preference.iter().copied().find(|encoding| quality(encoding.token()) > 0.0)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use crate::server::encoding::{DEFAULT_ENCODINGS, Encoding, negotiate};
+
+    #[test]
+    fn server_preference_wins() {
+        assert_eq!(negotiate("gzip, br;q=0.9", &DEFAULT_ENCODINGS), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip, zstd", &DEFAULT_ENCODINGS), Some(Encoding::Zstd));
+        assert_eq!(negotiate("gzip, br", &[Encoding::Gzip, Encoding::Brotli]), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn q_zero_excludes() {
+        assert_eq!(negotiate("gzip, br;q=0", &DEFAULT_ENCODINGS), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*, br;q=0", &DEFAULT_ENCODINGS), Some(Encoding::Zstd));
+        assert_eq!(negotiate("gzip;q=0.000", &DEFAULT_ENCODINGS), None);
+    }
+
+    #[test]
+    fn nothing_acceptable() {
+        assert_eq!(negotiate("", &DEFAULT_ENCODINGS), None);
+        assert_eq!(negotiate("identity, deflate", &DEFAULT_ENCODINGS), None);
+        assert_eq!(negotiate("gzip", &[]), None);
+    }
+
+    #[test]
+    fn round_trips() {
+        let data = b"hello hello hello hello hello".to_vec();
+
+        let mut decoded = vec![];
+        flate2::read::GzDecoder::new(&Encoding::Gzip.encode(&data).unwrap()[..])
+            .read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, data);
+
+        assert_eq!(zstd::decode_all(&Encoding::Zstd.encode(&data).unwrap()[..]).unwrap(), data);
+
+        let mut decoded = vec![];
+        brotli::Decompressor::new(&Encoding::Brotli.encode(&data).unwrap()[..], 4096)
+            .read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, data);
+    }
+}
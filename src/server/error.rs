@@ -0,0 +1,249 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::PathBuf;
+
+/// Everything that can go wrong while serving a request.
+/// The `Display` output is meant for logs; clients only get [`ServerError::client_message`].
+#[derive(Debug)]
+pub enum ServerError {
+    /// the requested resource doesn't exist
+    NotFound(String),
+    /// the requested resource exists, but may not be served
+    Forbidden(String),
+    /// the request itself is malformed; the reason is safe to show the client
+    BadRequest(String),
+    /// a standard method the server doesn't serve, along with the methods it does serve
+    MethodNotAllowed(String, Vec<&'static str>),
+    /// a method the server has never heard of, or some other part of HTTP it doesn't speak
+    NotImplemented(String),
+    /// the request body is in a format the server can't read, e.g. an unknown `Content-Encoding`
+    UnsupportedMediaType(String),
+    /// an `If-Match` or `If-Unmodified-Since` condition doesn't hold for the resource
+    PreconditionFailed(String),
+    /// the request body is larger than the server accepts
+    PayloadTooLarge(String),
+    /// none of the requested byte ranges lies within the body, which is this long
+    RangeNotSatisfiable(u64),
+    /// an `Expect` header the server can't or won't meet
+    ExpectationFailed(String),
+    /// an HTTP version other than 1.0 and 1.1
+    VersionNotSupported(String),
+    Io(io::Error),
+    CacheError(String),
+    /// a page couldn't be rendered, e.g. because of a missing or cyclic include
+    Template(String),
+    /// the rewrite rules kept rewriting a url
+    Rewrite(String),
+    /// a handler tried to set a cookie that can't be put in a `Set-Cookie` header as it is
+    Cookie(String),
+    /// answering the request panicked, with this message
+    Panic(String),
+    /// the upstream a request was forwarded to couldn't be reached or didn't answer properly
+    BadGateway(String),
+    /// the upstream a request was forwarded to didn't answer before the request's deadline
+    GatewayTimeout(String)
+}
+
+/// The status a response should have when serving a file failed with `e`.
+pub fn status_for_io_error(e: &io::Error) -> u16 {
+    match e.kind() {
+        io::ErrorKind::NotFound => 404,
+        io::ErrorKind::PermissionDenied => 403,
+        _ => 500
+    }
+}
+
+/// The message a panic was raised with, as far as it can be told from its payload.
+pub fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(message), _) => message.to_string(),
+        (_, Some(message)) => message.clone(),
+        _ => "unknown panic".to_string()
+    }
+}
+
+/// Whether `e` is an I/O error from running out of time to read or write.
+pub fn is_timeout(e: Option<&(dyn std::error::Error + 'static)>) -> bool {
+    e.and_then(|e| e.downcast_ref::<io::Error>())
+        .is_some_and(|e| matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock))
+}
+
+impl ServerError {
+    /// The HTTP status code a response for this error should have.
+    pub fn status(&self) -> u16 {
+        match self {
+            ServerError::NotFound(_) => 404,
+            ServerError::Forbidden(_) => 403,
+            ServerError::BadRequest(_) => 400,
+            ServerError::MethodNotAllowed(..) => 405,
+            ServerError::NotImplemented(_) => 501,
+            ServerError::UnsupportedMediaType(_) => 415,
+            ServerError::PreconditionFailed(_) => 412,
+            ServerError::PayloadTooLarge(_) => 413,
+            ServerError::RangeNotSatisfiable(_) => 416,
+            ServerError::ExpectationFailed(_) => 417,
+            ServerError::VersionNotSupported(_) => 505,
+            ServerError::Io(e) => status_for_io_error(e),
+            ServerError::CacheError(_) | ServerError::Template(_) | ServerError::Rewrite(_) | ServerError::Cookie(_)
+            | ServerError::Panic(_) => 500,
+            ServerError::BadGateway(_) => 502,
+            ServerError::GatewayTimeout(_) => 504
+        }
+    }
+
+    /// A description of the error that doesn't leak any internals.
+    pub fn client_message(&self) -> &str {
+        match self {
+            ServerError::NotFound(_) => "Not Found",
+            ServerError::Forbidden(_) => "Forbidden",
+            ServerError::Io(e) => match status_for_io_error(e) {
+                404 => "Not Found",
+                403 => "Forbidden",
+                _ => "Internal Server Error"
+            },
+            ServerError::BadRequest(reason) => reason,
+            ServerError::MethodNotAllowed(..) => "Method Not Allowed",
+            ServerError::NotImplemented(_) => "Not Implemented",
+            ServerError::UnsupportedMediaType(_) => "Unsupported Media Type",
+            ServerError::PreconditionFailed(_) => "Precondition Failed",
+            ServerError::PayloadTooLarge(_) => "Payload Too Large",
+            ServerError::RangeNotSatisfiable(_) => "Range Not Satisfiable",
+            ServerError::ExpectationFailed(_) => "Expectation Failed",
+            ServerError::VersionNotSupported(_) => "HTTP Version Not Supported",
+            ServerError::CacheError(_) | ServerError::Template(_) | ServerError::Rewrite(_) | ServerError::Cookie(_)
+            | ServerError::Panic(_) => "Internal Server Error",
+            ServerError::BadGateway(_) => "Bad Gateway",
+            ServerError::GatewayTimeout(_) => "Gateway Timeout"
+        }
+    }
+}
+
+impl Display for ServerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerError::NotFound(what) => write!(f, "not found: {}", what),
+            ServerError::Forbidden(what) => write!(f, "forbidden: {}", what),
+            ServerError::BadRequest(reason) => write!(f, "bad request: {}", reason),
+            ServerError::MethodNotAllowed(method, allowed) =>
+                write!(f, "method {} not allowed, only {}", method, allowed.join(", ")),
+            ServerError::NotImplemented(what) => write!(f, "not implemented: {}", what),
+            ServerError::UnsupportedMediaType(what) => write!(f, "unsupported media type: {}", what),
+            ServerError::PreconditionFailed(what) => write!(f, "precondition failed: {}", what),
+            ServerError::PayloadTooLarge(what) => write!(f, "payload too large: {}", what),
+            ServerError::RangeNotSatisfiable(length) => write!(f, "range not satisfiable for {} bytes", length),
+            ServerError::ExpectationFailed(expectation) => write!(f, "can't meet expectation {}", expectation),
+            ServerError::VersionNotSupported(version) => write!(f, "unsupported HTTP version {}", version),
+            ServerError::Io(e) => write!(f, "I/O error: {}", e),
+            ServerError::CacheError(reason) => write!(f, "cache error: {}", reason),
+            ServerError::Template(reason) => write!(f, "template error: {}", reason),
+            ServerError::Rewrite(reason) => write!(f, "rewrite error: {}", reason),
+            ServerError::Cookie(reason) => write!(f, "invalid cookie: {}", reason),
+            ServerError::Panic(message) => write!(f, "panicked: {}", message),
+            ServerError::BadGateway(reason) => write!(f, "bad gateway: {}", reason),
+            ServerError::GatewayTimeout(reason) => write!(f, "gateway timeout: {}", reason)
+        }
+    }
+}
+
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ServerError::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(e: io::Error) -> Self {
+        ServerError::Io(e)
+    }
+}
+
+/// Why a directory can't be served as a website, see [`crate::server::Website::new`].
+#[derive(Debug)]
+pub enum WebsiteError {
+    /// the path doesn't exist or can't be resolved
+    Missing(PathBuf, io::Error),
+    NotADirectory(PathBuf),
+    /// the `layout/` directory pages are served from is missing or unreadable
+    NoLayout(PathBuf, io::Error),
+    /// a configuration file couldn't be read or makes no sense
+    BadConfig(PathBuf, String),
+    /// the path is somewhere no website should be served from, for the reason given
+    UnsafeLocation(PathBuf, &'static str)
+}
+
+impl Display for WebsiteError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebsiteError::Missing(path, e) => write!(f, "can't find website directory {}: {}", path.display(), e),
+            WebsiteError::NotADirectory(path) => write!(f, "website location {} isn't a directory", path.display()),
+            WebsiteError::NoLayout(path, e) => write!(f, "can't read layout directory {}: {}", path.display(), e),
+            WebsiteError::BadConfig(path, reason) => write!(f, "bad configuration in {}: {}", path.display(), reason),
+            WebsiteError::UnsafeLocation(path, reason) =>
+                write!(f, "refusing to serve {} as a website, since {}", path.display(), reason)
+        }
+    }
+}
+
+impl std::error::Error for WebsiteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WebsiteError::Missing(_, e) | WebsiteError::NoLayout(_, e) => Some(e),
+            WebsiteError::NotADirectory(_) | WebsiteError::BadConfig(..) | WebsiteError::UnsafeLocation(..) => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use crate::server::error::{ServerError, status_for_io_error};
+
+    #[test]
+    fn status_codes() {
+        assert_eq!(ServerError::NotFound("/a.html".to_string()).status(), 404);
+        assert_eq!(ServerError::Forbidden("/a.html".to_string()).status(), 403);
+        assert_eq!(ServerError::BadRequest("no".to_string()).status(), 400);
+        assert_eq!(ServerError::MethodNotAllowed("DELETE".to_string(), vec!["GET"]).status(), 405);
+        assert_eq!(ServerError::NotImplemented("FROBNICATE".to_string()).status(), 501);
+        assert_eq!(ServerError::UnsupportedMediaType("br".to_string()).status(), 415);
+        assert_eq!(ServerError::PreconditionFailed("If-Match".to_string()).status(), 412);
+        assert_eq!(ServerError::PayloadTooLarge("1 GB".to_string()).status(), 413);
+        assert_eq!(ServerError::RangeNotSatisfiable(10).status(), 416);
+        assert_eq!(ServerError::ExpectationFailed("magic".to_string()).status(), 417);
+        assert_eq!(ServerError::VersionNotSupported("HTTP/2.0".to_string()).status(), 505);
+        assert_eq!(ServerError::Io(io::Error::other("disk on fire")).status(), 500);
+        assert_eq!(ServerError::CacheError("index is gone".to_string()).status(), 500);
+        assert_eq!(ServerError::Template("include cycle".to_string()).status(), 500);
+        assert_eq!(ServerError::Rewrite("loop".to_string()).status(), 500);
+        assert_eq!(ServerError::Cookie("a;b".to_string()).status(), 500);
+        assert_eq!(ServerError::Panic("index out of bounds".to_string()).status(), 500);
+        assert_eq!(ServerError::BadGateway("connection refused".to_string()).status(), 502);
+        assert_eq!(ServerError::GatewayTimeout("took 30s".to_string()).status(), 504);
+    }
+
+    #[test]
+    fn io_error_statuses() {
+        assert_eq!(status_for_io_error(&io::Error::from(io::ErrorKind::NotFound)), 404);
+        assert_eq!(status_for_io_error(&io::Error::from(io::ErrorKind::PermissionDenied)), 403);
+        assert_eq!(status_for_io_error(&io::Error::from(io::ErrorKind::InvalidData)), 500);
+        assert_eq!(status_for_io_error(&io::Error::from(io::ErrorKind::UnexpectedEof)), 500);
+        assert_eq!(status_for_io_error(&io::Error::other("disk on fire")), 500);
+        let e = ServerError::Io(io::Error::new(io::ErrorKind::PermissionDenied, "/secret/path"));
+        assert_eq!((e.status(), e.client_message()), (403, "Forbidden"));
+        let e = ServerError::from(io::Error::from(io::ErrorKind::NotFound));
+        assert_eq!((e.status(), e.client_message()), (404, "Not Found"));
+    }
+
+    #[test]
+    fn client_message_hides_details() {
+        let e = ServerError::Io(io::Error::other("/secret/path is on fire"));
+        assert!(e.to_string().contains("/secret/path"));
+        assert!(!e.client_message().contains("/secret/path"));
+        let e = ServerError::NotFound("/srv/site/layout/a.html".to_string());
+        assert!(!e.client_message().contains("/srv/site"));
+        assert_eq!(ServerError::BadRequest("Malformatted request.".to_string()).client_message(), "Malformatted request.");
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs::Metadata;
+use std::hash::{Hash, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::server::encoding::Encoding;
+use crate::server::error::ServerError;
+use crate::server::request::Request;
+
+/// A strong entity tag for a response body. The same content sent with a different
+/// `Content-Encoding` is a different representation, so it gets a different tag.
+pub fn for_body(body: &[u8], encoding: Option<Encoding>) -> String {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    match encoding {
+        Some(encoding) => format!("\"{:016x}-{}\"", hasher.finish(), encoding.token()),
+        None => format!("\"{:016x}\"", hasher.finish())
+    }
+}
+
+/// An entity tag for a file sent as is, from its size and modification time, so that the file
+/// doesn't have to be read to tell whether it changed.
+pub fn for_file(metadata: &Metadata) -> String {
+    let modified = metadata.modified().ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since_epoch| since_epoch.as_nanos());
+    format!("\"{:x}-{:x}\"", metadata.len(), modified)
+}
+
+/// [`for_file`] for a text file that may be sent compressed, which like [`for_body`] tags each encoding
+/// differently.
+pub fn for_encoded_file(metadata: &Metadata, encoding: Option<Encoding>) -> String {
+    let etag = for_file(metadata);
+    match encoding {
+        Some(encoding) => format!("{}-{}\"", etag.trim_end_matches('"'), encoding.token()),
+        None => etag
+    }
+}
+
+/// Whether an `If-None-Match` header lists `etag` (or is `*`), meaning the client's copy is current.
+/// Uses the weak comparison, as RFC 7232 says to for `If-None-Match`.
+pub fn matches_any(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match.split(',').any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+/// Whether an `If-Match` header lists `etag` (or is `*`), meaning the client's idea of the resource
+/// is current. Uses the strong comparison, as RFC 7232 says to for `If-Match`, so weak tags never match.
+pub fn matches_strongly(if_match: &str, etag: &str) -> bool {
+    if_match.split(',').map(str::trim).any(|tag| tag == "*" || (!tag.starts_with("W/") && tag == etag))
+}
+
+/// Evaluates `If-Match`, or else `If-Unmodified-Since`, for a resource that currently has any of `etags`
+/// (none if it doesn't exist) and was last modified at `modified`, as RFC 7232 section 6 orders them.
+/// Dates that can't be parsed are ignored, like the RFC says.
+pub fn check_preconditions(request: &Request, etags: &[String], modified: Option<SystemTime>) -> Result<(), ServerError> {
+    if let Some(if_match) = request.header("If-Match") {
+        if !etags.iter().any(|etag| matches_strongly(if_match, etag)) {
+            return Err(ServerError::PreconditionFailed(format!("{} doesn't match If-Match: {}", request.url, if_match)));
+        }
+        return Ok(());
+    }
+    let since = request.header("If-Unmodified-Since")
+        .and_then(|date| chrono::DateTime::parse_from_rfc2822(date).ok());
+    let modified = modified
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|since_epoch| since_epoch.as_secs() as i64);
+    match (since, modified) {
+        (Some(since), Some(modified)) if modified > since.timestamp() =>
+            Err(ServerError::PreconditionFailed(format!("{} was modified since {}", request.url, since.to_rfc2822()))),
+        _ => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::server::encoding::Encoding;
+    use crate::server::etag::{for_body, for_encoded_file, for_file, matches_any, matches_strongly};
+    use crate::server::scratch::ScratchDir;
+
+    #[test]
+    fn tags_per_representation() {
+        assert_eq!(for_body(b"hello", None), for_body(b"hello", None));
+        assert_ne!(for_body(b"hello", None), for_body(b"hello!", None));
+        assert_ne!(for_body(b"hello", None), for_body(b"hello", Some(Encoding::Gzip)));
+        assert!(for_body(b"hello", Some(Encoding::Gzip)).ends_with("-gzip\""));
+
+        let scratch = ScratchDir::new("etag");
+        let file = scratch.path().join("hello.txt");
+        std::fs::write(&file, "hello").unwrap();
+        let etag = for_file(&std::fs::metadata(&file).unwrap());
+        assert!(etag.starts_with("\"5-") && etag.ends_with('"'));
+        assert_eq!(etag, for_file(&std::fs::metadata(&file).unwrap()));
+        assert_eq!(for_encoded_file(&std::fs::metadata(&file).unwrap(), None), etag);
+        assert_eq!(for_encoded_file(&std::fs::metadata(&file).unwrap(), Some(Encoding::Gzip)),
+                   format!("{}-gzip\"", etag.trim_end_matches('"')));
+    }
+
+    #[test]
+    fn if_none_match() {
+        let etag = for_body(b"hello", None);
+        assert!(matches_any(&etag, &etag));
+        assert!(matches_any(&format!("\"other\", W/{}", etag), &etag));
+        assert!(matches_any("*", &etag));
+        assert!(!matches_any("\"other\"", &etag));
+        assert!(!matches_any("", &etag));
+    }
+
+    #[test]
+    fn if_match() {
+        let etag = for_body(b"hello", None);
+        assert!(matches_strongly(&etag, &etag));
+        assert!(matches_strongly(&format!("\"other\", {}", etag), &etag));
+        assert!(matches_strongly("*", &etag));
+        assert!(!matches_strongly(&format!("W/{}", etag), &etag));
+        assert!(!matches_strongly("\"other\"", &etag));
+    }
+}
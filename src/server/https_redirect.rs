@@ -0,0 +1,145 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+use crate::server::error::ServerError;
+use crate::server::request::{find_head_end, Request};
+use crate::server::response::Response;
+use crate::server::sites::Handler;
+use crate::server::MAX_HEAD_SIZE;
+
+/// How long a client may take to send its request head before the connection is dropped.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Answers every request with a `301` to the same url on https, for a plain HTTP listener
+/// next to the one that serves the site over TLS. Nothing is read from the file system.
+pub struct HttpsRedirect {
+    /// the port https is served on
+    https_port: u16,
+    /// the host redirected to when a request has no `Host` header
+    fallback_host: Option<String>
+}
+
+impl HttpsRedirect {
+    pub fn new(https_port: u16) -> HttpsRedirect {
+        HttpsRedirect {
+            https_port,
+            fallback_host: None
+        }
+    }
+
+    /// Redirects requests without a `Host` header (like HTTP/1.0 ones) to `host`, instead of rejecting them.
+    pub fn with_fallback_host(mut self, host: &str) -> HttpsRedirect {
+        self.fallback_host = Some(host.to_string());
+        self
+    }
+
+    /// Where `request` is redirected to: its host, on the https port, with the path and query it had.
+    fn location(&self, request: &Request) -> Result<String, ServerError> {
+        let host = request.header("Host").map(str::trim).filter(|host| !host.is_empty())
+            .or(self.fallback_host.as_deref())
+            .ok_or_else(|| ServerError::BadRequest("Missing Host header.".to_string()))?;
+        // the port in the Host header is the plain HTTP one
+        let host = match host.rsplit_once(':') {
+            Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+            _ => host
+        };
+        let path = if request.url.starts_with('/') { request.url.as_str() } else { "/" };
+        Ok(match self.https_port {
+            443 => format!("https://{}{}", host, path),
+            port => format!("https://{}:{}{}", host, port, path)
+        })
+    }
+
+    fn read_request(stream: &mut TcpStream) -> std::io::Result<Option<Result<Request, ServerError>>> {
+        let mut head = vec![];
+        let mut buffer = [0; 1024];
+        let end = loop {
+            if let Some(end) = find_head_end(&head) {
+                break end;
+            }
+            if head.len() >= MAX_HEAD_SIZE {
+                return Ok(Some(Err(ServerError::BadRequest("Malformatted request.".to_string()))));
+            }
+            match stream.read(&mut buffer)? {
+                0 => return Ok(None),
+                read => head.extend_from_slice(&buffer[..read])
+            }
+        };
+        Ok(Some(Request::parse(&String::from_utf8_lossy(&head[..end]), false)))
+    }
+
+    fn serve_connection(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let request = match HttpsRedirect::read_request(&mut stream)? {
+            Some(request) => request,
+            None => return Ok(())
+        };
+        let started = Instant::now();
+        let (method, url) = request.as_ref()
+            .map_or(("-", "-"), |request| (request.method.as_str(), request.url.as_str()));
+        let mut response = request.as_ref().map_err(|e| ServerError::BadRequest(e.client_message().to_string()))
+            .and_then(|request| self.location(request))
+            .map(|location| Response::new(301).with_header("Location", &location))
+            .unwrap_or_else(Response::from)
+            .with_header("Connection", "close");
+        response.write_to(&mut stream)?;
+        stream.flush()?;
+        info!("{} {} -> {} in {:?}", method, url, response.status, started.elapsed());
+        Ok(())
+    }
+}
+
+impl Handler for HttpsRedirect {
+    fn handle_connection(&self, stream: TcpStream) {
+        if let Err(e) = self.serve_connection(stream) {
+            match e.kind() {
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => debug!("client sent no request: {}", e),
+                _ => warn!("Connection failed: {}", e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use crate::server::https_redirect::HttpsRedirect;
+    use crate::server::request::Request;
+    use crate::server::sites::Handler;
+
+    fn location(redirect: &HttpsRedirect, head: &str) -> Result<String, u16> {
+        redirect.location(&Request::parse(head, true).unwrap()).map_err(|e| e.status())
+    }
+
+    #[test]
+    fn locations() {
+        let redirect = HttpsRedirect::new(443);
+        assert_eq!(location(&redirect, "GET /a/b%20c?x=1&y HTTP/1.1\r\nHost: example.com\r\n\r\n"),
+                   Ok("https://example.com/a/b%20c?x=1&y".to_string()));
+        assert_eq!(location(&redirect, "GET / HTTP/1.1\r\nHost: example.com:80\r\n\r\n"),
+                   Ok("https://example.com/".to_string()));
+        assert_eq!(location(&redirect, "GET / HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n"), Ok("https://[::1]/".to_string()));
+        assert_eq!(location(&redirect, "GET /?q HTTP/1.0\r\n\r\n"), Err(400));
+
+        let redirect = HttpsRedirect::new(8443).with_fallback_host("example.org");
+        assert_eq!(location(&redirect, "GET /docs/ HTTP/1.1\r\nHost: example.com:8080\r\n\r\n"),
+                   Ok("https://example.com:8443/docs/".to_string()));
+        assert_eq!(location(&redirect, "GET /?q HTTP/1.0\r\n\r\n"), Ok("https://example.org:8443/?q".to_string()));
+    }
+
+    #[test]
+    fn redirects_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(b"POST /form?a=1 HTTP/1.1\r\nHost: example.com\r\nContent-Length: 3\r\n\r\nabc").unwrap();
+        let (server_side, _) = listener.accept().unwrap();
+        HttpsRedirect::new(443).handle_connection(server_side);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+        assert!(response.contains("\r\nLocation: https://example.com/form?a=1\r\n"));
+        assert!(response.contains("\r\nConnection: close\r\n"));
+    }
+}
@@ -0,0 +1,76 @@
+use std::path::{Path, PathBuf};
+use crate::server::encoding::parse_qualities;
+use crate::server::resources::relative_path;
+
+/// The formats an extensionless image url may be served in, as file extension and media type.
+/// When the client likes several of them equally, the earlier one wins.
+pub const IMAGE_FORMATS: [(&str, &str); 6] = [
+    ("avif", "image/avif"),
+    ("webp", "image/webp"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif")
+];
+
+/// How much a client whose `Accept` header parsed into `accepted` wants `media_type`.
+/// The most specific range that covers it counts, so `image/png;q=0, image/*` rules out png only.
+fn quality(accepted: &[(String, f32)], media_type: &str) -> f32 {
+    let type_range = media_type.split('/').next().map(|kind| format!("{}/*", kind)).unwrap_or_default();
+    [media_type, type_range.as_str(), "*/*"].iter()
+        .find_map(|range| accepted.iter().find(|(accepted, _)| accepted == range))
+        .map_or(0.0, |(_, q)| *q)
+}
+
+/// For a url without an extension, like `/logo`, finds the `logo.<ext>` under `root` in the format the
+/// `accept` header prefers, along with its media type. Formats the client accepts equally are tried in
+/// [`IMAGE_FORMATS`] order, and ones without a file are skipped.
+pub fn negotiate_content_type(url: &str, accept: &str, root: &Path) -> Option<(PathBuf, &'static str)> {
+    let path = url.split('?').next().unwrap_or_default();
+    let relative = relative_path(path).ok()?;
+    if path.ends_with('/') || relative.extension().is_some() || relative.file_name().is_none() {
+        return None;
+    }
+    let accepted = parse_qualities(accept);
+    let mut candidates: Vec<(f32, &str, &'static str)> = IMAGE_FORMATS.iter()
+        .map(|(extension, media_type)| (quality(&accepted, media_type), *extension, *media_type))
+        .filter(|(q, _, _)| *q > 0.0)
+        .collect();
+    // stable, so ties keep the server's order
+    candidates.sort_by(|(a, _, _), (b, _, _)| b.total_cmp(a));
+    candidates.into_iter()
+        .map(|(_, extension, media_type)| (root.join(relative.with_extension(extension)), media_type))
+        .find(|(file, _)| file.is_file())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use crate::server::media::negotiate_content_type;
+    use crate::server::scratch::ScratchDir;
+
+    fn negotiate(root: &Path, url: &str, accept: &str) -> Option<(String, &'static str)> {
+        negotiate_content_type(url, accept, root)
+            .map(|(file, media_type)| (file.strip_prefix(root).unwrap().display().to_string(), media_type))
+    }
+
+    #[test]
+    fn preferred_format_that_exists() {
+        let scratch = ScratchDir::new("media");
+        let root = scratch.path();
+        std::fs::create_dir_all(root.join("img")).unwrap();
+        for file in ["logo.webp", "logo.png", "img/photo.png", "img/photo.jpg"] {
+            std::fs::write(root.join(file), "").unwrap();
+        }
+        let browser = "image/avif,image/webp,image/apng,image/*,*/*;q=0.8";
+        assert_eq!(negotiate(root, "/logo", browser), Some(("logo.webp".to_string(), "image/webp")));
+        assert_eq!(negotiate(root, "/logo?v=2", "image/png, image/webp;q=0.5"), Some(("logo.png".to_string(), "image/png")));
+        assert_eq!(negotiate(root, "/logo", "image/*, image/webp;q=0"), Some(("logo.png".to_string(), "image/png")));
+        assert_eq!(negotiate(root, "/img/photo", browser), Some(("img/photo.png".to_string(), "image/png")));
+        assert_eq!(negotiate(root, "/img/photo", "image/jpeg"), Some(("img/photo.jpg".to_string(), "image/jpeg")));
+        assert_eq!(negotiate(root, "/logo", "text/html"), None);
+        assert_eq!(negotiate(root, "/logo.png", browser), None);
+        assert_eq!(negotiate(root, "/missing", browser), None);
+        assert_eq!(negotiate(root, "/", browser), None);
+    }
+}
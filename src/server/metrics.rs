@@ -0,0 +1,75 @@
+use std::time::Duration;
+use prometheus::{DEFAULT_BUCKETS, Encoder, Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+/// Upper bounds of the response size buckets, in bytes: 100 B up to 100 MB.
+pub const DEFAULT_SIZE_BUCKETS: [f64; 7] = [1e2, 1e3, 1e4, 1e5, 1e6, 1e7, 1e8];
+
+/// Request and connection metrics, exposed in the Prometheus text format at `/_prometheus`.
+pub struct PrometheusCollector {
+    registry: Registry,
+    requests_total: IntCounterVec,
+    request_duration: Histogram,
+    response_size: Histogram,
+    active_connections: IntGauge
+}
+
+/// Counts a connection as active until dropped.
+pub struct ActiveConnection<'a>(&'a IntGauge);
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl PrometheusCollector {
+    /// A collector with Prometheus' default duration buckets and [`DEFAULT_SIZE_BUCKETS`].
+    pub fn new() -> Result<PrometheusCollector, prometheus::Error> {
+        PrometheusCollector::with_buckets(DEFAULT_BUCKETS.to_vec(), DEFAULT_SIZE_BUCKETS.to_vec())
+    }
+
+    /// A collector whose histograms count durations (in seconds) and response sizes (in bytes)
+    /// into buckets with these upper bounds, which must be increasing.
+    pub fn with_buckets(duration_buckets: Vec<f64>, size_buckets: Vec<f64>) -> Result<PrometheusCollector, prometheus::Error> {
+        let registry = Registry::new();
+        let requests_total = IntCounterVec::new(
+            Opts::new("http_requests_total", "Requests answered, by method and status"),
+            &["method", "status"])?;
+        let request_duration = Histogram::with_opts(HistogramOpts::new(
+            "http_request_duration_seconds", "Time from reading a request to having written the response")
+            .buckets(duration_buckets))?;
+        let response_size = Histogram::with_opts(HistogramOpts::new(
+            "http_response_size_bytes", "Bytes written for a response, head included")
+            .buckets(size_buckets))?;
+        let active_connections = IntGauge::new("http_active_connections", "Connections currently open")?;
+        registry.register(Box::new(requests_total.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        registry.register(Box::new(response_size.clone()))?;
+        registry.register(Box::new(active_connections.clone()))?;
+        Ok(PrometheusCollector {
+            registry,
+            requests_total,
+            request_duration,
+            response_size,
+            active_connections
+        })
+    }
+
+    pub fn connection_opened(&self) -> ActiveConnection<'_> {
+        self.active_connections.inc();
+        ActiveConnection(&self.active_connections)
+    }
+
+    pub fn request_answered(&self, method: &str, status: u16, duration: Duration, bytes: u64) {
+        self.requests_total.with_label_values(&[method, &status.to_string()]).inc();
+        self.request_duration.observe(duration.as_secs_f64());
+        self.response_size.observe(bytes as f64);
+    }
+
+    /// Everything collected so far, in the Prometheus text format.
+    pub fn render(&self) -> Result<Vec<u8>, prometheus::Error> {
+        let mut rendered = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut rendered)?;
+        Ok(rendered)
+    }
+}
@@ -1,29 +1,327 @@
-use std::net::{TcpListener, TcpStream};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
 use std::io::{Read, Write};
 use std::fs;
-use std::sync::Arc;
+use std::panic::AssertUnwindSafe;
+use std::path::{Component, Path, PathBuf};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, info_span, warn};
 use crate::server::threadpool::ThreadPool;
+use crate::server::accept::AcceptBackoff;
+use crate::server::request::find_head_end;
+use crate::server::redirects::Redirect;
+use crate::server::rewrites::{RewriteRule, Rewritten};
+use crate::server::response::CountingWriter;
+use crate::server::sse::StreamCount;
+pub use crate::server::cache::{Cache, CacheEntryMeta, CacheOptions, CachedResponse};
+pub use crate::server::encoding::{DEFAULT_ENCODINGS, Encoding};
+pub use crate::server::error::{ServerError, WebsiteError};
+pub use crate::server::multipart::Part;
+pub use crate::server::request::Request;
+pub use crate::server::cookie::{CookieOptions, SameSite};
+pub use crate::server::response::{FlushStrategy, Response};
+pub use crate::server::resources::{Mount, ResourceLayout, TrailingSlash};
+pub use crate::server::sites::{Handler, ReloadableSite, SiteBuilder, SiteRule, SiteSelector};
+pub use crate::server::https_redirect::HttpsRedirect;
+pub use crate::server::proxy::ProxyHandler;
+pub use crate::server::websocket::WebSocketHandler;
+pub use crate::server::sse::{EventStream, EventStreamHandler, SseEvent};
+#[cfg(feature = "prometheus")]
+pub use crate::server::metrics::PrometheusCollector;
 
 mod threadpool;
+mod accept;
 mod cache;
+mod cache_control;
+mod encoding;
+mod media;
+mod autoindex;
+mod balancer;
+mod range;
+mod cookie;
+mod disposition;
+mod multipart;
+mod etag;
+mod error;
+mod request;
+mod response;
+mod template;
+mod resources;
+mod redirects;
+mod rewrites;
+mod sites;
+mod https_redirect;
+mod proxy;
+mod upstream;
+mod websocket;
+mod sse;
+#[cfg(test)]
+mod scratch;
+#[cfg(feature = "prometheus")]
+mod metrics;
 
-pub fn main(site: Arc<Website>, address: &str) {
-    println!("starting server...");
-    let listener = TcpListener::bind(address).unwrap();
-    let threadpool = ThreadPool::new(4);
-    for stream in listener.incoming() {
-        let n_site = Arc::clone(&site);
-        match stream {
-            Ok(stream) => threadpool.execute(move || n_site.handle_connection(stream)),
-            Err(e) => {
-                println!("An error occurred when connecting to the client! Luckily, they'll probably try to connect again. {}", e);
+/// Kept for backwards compatibility; fails if the address can't be bound.
+#[deprecated(note = "use `Website::serve` or `Server::run` instead")]
+#[allow(dead_code)]
+pub fn main(handler: Arc<impl Handler>, address: &str) -> Result<(), ServerError> {
+    Server::bind(address)?.run(handler)
+}
+
+/// How long in-flight requests get to finish once shutdown is signaled, unless configured otherwise.
+pub const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+/// A bound listener that a [`Website`] can be served on.
+pub struct Server {
+    listener: TcpListener,
+    shutdown: Arc<AtomicBool>,
+    shutdown_grace: Duration,
+    /// how many threads accept connections, see [`Server::with_acceptors`]
+    acceptors: usize
+}
+
+/// Stops a running [`Server`] from another thread.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    flag: Arc<AtomicBool>,
+    addr: SocketAddr
+}
+
+impl Server {
+    pub fn bind(address: &str) -> Result<Server, ServerError> {
+        Ok(Server {
+            listener: TcpListener::bind(address)?,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            shutdown_grace: DEFAULT_SHUTDOWN_GRACE,
+            acceptors: 1
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, ServerError> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Sets how long to wait for in-flight requests after shutdown is signaled.
+    /// Workers still busy after that are abandoned so the server always stops.
+    pub fn with_shutdown_grace(mut self, grace: Duration) -> Server {
+        self.shutdown_grace = grace;
+        self
+    }
+
+    /// Accepts connections on `acceptors` threads sharing the listener, instead of only the one calling
+    /// [`Server::run`], for connection rates a single thread can't keep up with. At least one is used.
+    pub fn with_acceptors(mut self, acceptors: usize) -> Server {
+        self.acceptors = acceptors.max(1);
+        self
+    }
+
+    /// Runs the accept loop, handing every connection to `handler`, and blocks the current thread
+    /// until the server is shut down.
+    pub fn run<H: Handler>(self, handler: Arc<H>) -> Result<(), ServerError> {
+        info!("starting server on {} with {} acceptor(s)...", self.local_addr()?, self.acceptors);
+        let threadpool = ThreadPool::new(4);
+        let listeners = (1..self.acceptors).map(|_| self.listener.try_clone()).collect::<Result<Vec<_>, _>>()?;
+        let server = &self;
+        let result = std::thread::scope(|scope| {
+            let others = listeners.iter().map(|listener| {
+                let (threadpool, handler) = (&threadpool, Arc::clone(&handler));
+                scope.spawn(move || server.accept(listener, threadpool, handler))
+            }).collect::<Vec<_>>();
+            let result = server.accept(&server.listener, &threadpool, Arc::clone(&handler));
+            others.into_iter()
+                .map(|acceptor| acceptor.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+                .fold(result, Result::and)
+        });
+        info!("shutting down server...");
+        let unfinished = threadpool.shutdown(self.shutdown_grace);
+        if !unfinished.is_empty() {
+            warn!("gave up waiting on {} job(s): {}", unfinished.len(), unfinished.join(", "));
+        }
+        result
+    }
+
+    /// One acceptor's loop, which hands connections from `listener` to `threadpool` until the server is
+    /// shut down or `listener` fails. With several acceptors, each one that stops wakes up another,
+    /// so they all stop.
+    fn accept<H: Handler>(&self, listener: &TcpListener, threadpool: &ThreadPool, handler: Arc<H>) -> Result<(), ServerError> {
+        let mut incoming = listener.incoming();
+        let mut backoff = AcceptBackoff::new();
+        let result = loop {
+            let stream = match backoff.next_connection(&mut incoming, std::thread::sleep) {
+                Ok(Some(stream)) => stream,
+                Ok(None) => break Ok(()),
+                Err(e) => {
+                    error!("can't accept connections anymore, stopping the server: {}", e);
+                    break Err(e);
+                }
+            };
+            if self.shutdown.load(Ordering::SeqCst) {
+                break Ok(());
+            }
+            let handler = Arc::clone(&handler);
+            match stream.peer_addr() {
+                Ok(peer) => threadpool.execute_labeled(
+                    format!("connection from {}", peer),
+                    move || handler.handle_connection(stream)),
+                Err(_) => threadpool.execute(move || handler.handle_connection(stream))
             }
+        };
+        if self.acceptors > 1 {
+            if let Ok(handle) = self.shutdown_handle() {
+                handle.shutdown();
+            }
+        }
+        result
+    }
+
+    pub fn shutdown_handle(&self) -> Result<ShutdownHandle, ServerError> {
+        let mut addr = self.local_addr()?;
+        // a listener on the wildcard address can't be connected to directly
+        if addr.ip().is_unspecified() {
+            addr.set_ip(if addr.is_ipv4() { Ipv4Addr::LOCALHOST.into() } else { Ipv6Addr::LOCALHOST.into() });
         }
+        Ok(ShutdownHandle {
+            flag: Arc::clone(&self.shutdown),
+            addr
+        })
+    }
+}
+
+impl ShutdownHandle {
+    /// Stops accepting connections; the serving thread then waits out the grace period and returns.
+    pub fn shutdown(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+        // wake the accept loop up so it notices the flag
+        let _ = TcpStream::connect(self.addr);
+    }
+
+    /// Shuts the server down like [`ShutdownHandle::shutdown`] when the process receives a SIGTERM,
+    /// so a deployment can replace it without cutting off the requests it's still answering.
+    #[cfg(unix)]
+    pub fn shutdown_on_sigterm(self) -> Result<(), ServerError> {
+        let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGTERM])?;
+        std::thread::spawn(move || {
+            if signals.forever().next().is_some() {
+                info!("received SIGTERM, finishing in-flight requests");
+                self.shutdown();
+            }
+        });
+        Ok(())
     }
 }
 
+/// The methods `handle_request` serves.
+const ALLOWED_METHODS: [&str; 2] = ["GET", "PUT"];
+/// Methods from RFC 7231 that the server knows of, but doesn't serve.
+/// PATCH (RFC 5789) is only served for the paths given to [`Website::with_patch_route`].
+const KNOWN_METHODS: [&str; 6] = ["HEAD", "POST", "DELETE", "CONNECT", "OPTIONS", "TRACE"];
+
+/// Served for `/favicon.ico` when the website has none of its own, see [`Website::with_default_favicon`].
+const DEFAULT_FAVICON: &[u8] = include_bytes!("favicon.ico");
+
+/// How long to wait for more data from a client whose request was rejected, before closing the connection.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+/// How much of a rejected request is read, at most, before closing the connection anyway.
+const MAX_DRAINED: u64 = 1024 * 1024;
+
+/// How many event streams a website keeps open at once, unless configured otherwise.
+pub const DEFAULT_MAX_EVENT_STREAMS: usize = 32;
+/// How long writing an event to a client may take before the client is considered gone.
+const EVENT_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Requests whose head (request line and headers) doesn't fit in this many bytes are rejected.
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+/// How many locks uploads are spread over, by the path they're stored at.
+const UPLOAD_LOCKS: usize = 64;
+
 pub struct Website {
-    loc: String
+    /// canonical path of the website directory
+    loc: PathBuf,
+    /// how long a connection may idle between requests; without one, every connection serves a single request
+    keep_alive: Option<Duration>,
+    /// when responses are flushed to the connection, see [`Website::with_flush_strategy`]
+    flush_strategy: FlushStrategy,
+    /// how long answering a request may take, see [`Website::with_request_timeout`]
+    request_timeout: Option<Duration>,
+    /// where in `loc` files are looked up
+    resources: ResourceLayout,
+    /// url prefixes served from other directories, which take precedence over `resources`
+    mounts: Vec<Mount>,
+    /// paths that are answered with a redirect instead of a file
+    redirects: HashMap<String, Redirect>,
+    /// paths that are answered with another file than the one they'd resolve to, without a redirect
+    aliases: HashMap<String, PathBuf>,
+    /// regex rules urls are rewritten with before their files are looked up, in order
+    rewrites: Vec<RewriteRule>,
+    /// file names tried in order for urls that name a directory
+    index_files: Vec<String>,
+    /// whether directories without any of the index files are answered with a listing of their files
+    autoindex: bool,
+    /// whether urls whose trailing `/` doesn't fit the file they name get redirected
+    trailing_slash: TrailingSlash,
+    /// reject requests that end lines with a bare `\n` instead of `\r\n`
+    strict_line_endings: bool,
+    /// encodings text responses may be compressed with, most preferred first
+    encodings: Vec<Encoding>,
+    /// the `admin_token` header value that allows uploads with PUT; uploads are off without one
+    admin_token: Option<String>,
+    /// locks for the paths uploads are stored at, picked by hash, so that checking an upload's
+    /// preconditions and storing it happen without another upload of the same file in between
+    upload_locks: Vec<Mutex<()>>,
+    /// the largest request body read; larger ones are refused with a 413
+    max_body_size: Option<usize>,
+    /// limits that replace `max_body_size` for urls with a prefix, and maybe only for one method
+    route_body_sizes: Vec<(Option<String>, String, usize)>,
+    /// whether `/favicon.ico` falls back to `DEFAULT_FAVICON`
+    default_favicon: bool,
+    /// whether files may be served through symlinks that lead out of `loc`
+    follow_symlinks: bool,
+    /// `Cache-Control` values for files, by lowercase extension
+    cache_control: HashMap<String, String>,
+    /// lowercase extensions of files that are always sent as downloads
+    download_extensions: HashSet<String>,
+    /// whether `.html` pages go through `template::render` before being sent
+    templating: bool,
+    /// whether error responses describe what went wrong in detail, file paths and all
+    verbose_errors: bool,
+    /// clients allowed to use admin endpoints like `/_prometheus`
+    admin_ip_allowlist: Vec<IpAddr>,
+    /// takes over connections that upgrade to WebSocket; upgrade requests are served like any other without one
+    websocket: Option<Arc<WebSocketHandler>>,
+    /// url paths whose GET requests are answered by code instead of files
+    get_routes: HashMap<String, RouteHandler>,
+    /// url paths that take PATCH requests, which are answered by code; there are no files to patch
+    patch_routes: HashMap<String, RouteHandler>,
+    /// url paths answered with an event stream, which the handler pushes events to
+    event_streams: HashMap<String, Arc<EventStreamHandler>>,
+    /// the number of event streams open at once; requests for more are answered with a 503
+    max_event_streams: usize,
+    open_event_streams: StreamCount,
+    /// the scheme and host requests for other hosts get redirected to
+    canonical_host: Option<(String, String)>,
+    #[cfg(feature = "prometheus")]
+    metrics: Option<Arc<PrometheusCollector>>,
+    #[cfg(feature = "tera")]
+    template_routes: Vec<TemplateRoute>
+}
+
+/// Answers requests for a url path, see [`Website::with_get_route`] and [`Website::with_patch_route`].
+pub type RouteHandler = Box<dyn Fn(&Request) -> Result<Response, ServerError> + Send + Sync>;
+
+/// Builds the variables a tera template is rendered with.
+#[cfg(feature = "tera")]
+pub type ContextFn = Box<dyn Fn(&Request) -> tera::Context + Send + Sync>;
+
+/// A url path served by rendering `<loc>/templates/<template_name>.html.tera`.
+#[cfg(feature = "tera")]
+struct TemplateRoute {
+    path: String,
+    template_name: String,
+    context_fn: ContextFn
 }
 
 enum SendMethod {
@@ -31,44 +329,341 @@ enum SendMethod {
     PlainText
 }
 
-enum Response {
-    Binary(Vec<u8>),
-    PlainText(String)
-}
-
 impl Website {
-    pub fn new(website_location: String) -> Website {
-        Website {
-            loc: website_location
-        }
-    }
-    fn get_resource(&self, url: String) -> Result<(SendMethod, String), String> {
-        let path: Vec<&str> = url.split("/").into_iter().filter(|s| !s.is_empty()).collect();
-        // println!("{:?}", path);
-        if path.len() > 0 {
-            let mut last_file = path.last().unwrap();
-            let args: Vec<_> = last_file.split("?").collect();
-            if args.len() > 1 {
-                last_file = args.get(0).unwrap();
-                let args: Vec<_> = args.last().unwrap().split("&").collect();
-                // do something with args
-            }
-            if last_file.ends_with(".js") {
-                Ok((SendMethod::PlainText, format!("{}/scripts/{}", self.loc, last_file)))
-            } else if vec![".html", ".css"].iter().any(|s| last_file.ends_with(s)) {
-                Ok((SendMethod::PlainText, format!("{}/layout/{}", self.loc, last_file)))
-            } else if vec![".jpg", ".ico", ".png"].iter().any(|s| last_file.ends_with(s)) {
-                Ok((SendMethod::Binary, format!("{}/layout/{}", self.loc, last_file)))
-            } else {
-                Err(format!("Don't know how to look for resource at {}", url))
-            }
+    /// Checks that `website_location` is a directory with a readable `layout/` directory in it,
+    /// and that it passes [`Website::validate`]. The site is served with [`ResourceLayout::Split`].
+    pub fn new(website_location: impl AsRef<Path>) -> Result<Website, WebsiteError> {
+        Website::new_with_layout(website_location, ResourceLayout::default())
+    }
+
+    /// Like [`Website::new`], but files are looked up according to `resources`,
+    /// whose directories all have to be readable.
+    pub fn new_with_layout(website_location: impl AsRef<Path>, resources: ResourceLayout) -> Result<Website, WebsiteError> {
+        let given = website_location.as_ref();
+        let loc = fs::canonicalize(given).map_err(|e| WebsiteError::Missing(given.to_path_buf(), e))?;
+        if !loc.is_dir() {
+            return Err(WebsiteError::NotADirectory(loc));
+        }
+        let website = Website {
+            loc,
+            keep_alive: None,
+            flush_strategy: FlushStrategy::default(),
+            request_timeout: None,
+            resources,
+            mounts: vec![],
+            redirects: HashMap::new(),
+            aliases: HashMap::new(),
+            rewrites: vec![],
+            index_files: vec!["index.html".to_string()],
+            autoindex: false,
+            trailing_slash: TrailingSlash::None,
+            strict_line_endings: false,
+            encodings: DEFAULT_ENCODINGS.to_vec(),
+            admin_token: None,
+            upload_locks: (0..UPLOAD_LOCKS).map(|_| Mutex::new(())).collect(),
+            max_body_size: None,
+            route_body_sizes: vec![],
+            default_favicon: true,
+            follow_symlinks: false,
+            cache_control: HashMap::new(),
+            download_extensions: HashSet::new(),
+            templating: false,
+            verbose_errors: false,
+            admin_ip_allowlist: vec![],
+            websocket: None,
+            get_routes: HashMap::new(),
+            patch_routes: HashMap::new(),
+            event_streams: HashMap::new(),
+            max_event_streams: DEFAULT_MAX_EVENT_STREAMS,
+            open_event_streams: StreamCount::default(),
+            canonical_host: None,
+            #[cfg(feature = "prometheus")]
+            metrics: None,
+            #[cfg(feature = "tera")]
+            template_routes: vec![]
+        };
+        website.validate()?;
+        Ok(website)
+    }
+
+    /// Refuses website locations that are almost certainly a mistake, since everything under them
+    /// could end up being served: the file system root and home directories. The directories the
+    /// resource layout looks files up in have to be readable, too.
+    pub fn validate(&self) -> Result<(), WebsiteError> {
+        if self.loc.parent().is_none() {
+            return Err(WebsiteError::UnsafeLocation(self.loc.clone(), "it's the file system root"));
+        }
+        let home = std::env::var_os("HOME").and_then(|home| fs::canonicalize(home).ok());
+        if home.as_deref() == Some(&self.loc) || self.loc == Path::new("/root")
+            || self.loc.parent() == Some(Path::new("/home")) {
+            return Err(WebsiteError::UnsafeLocation(self.loc.clone(), "it's a home directory"));
+        }
+        for dir in self.resources.directories() {
+            fs::read_dir(self.loc.join(dir)).map_err(|e| WebsiteError::NoLayout(self.loc.join(dir), e))?;
+        }
+        Ok(())
+    }
+
+    /// By default, lines ending in a bare `\n` are accepted as well as `\r\n` (RFC 7230, section 3.5).
+    /// Strict mode rejects such requests with a 400 instead.
+    pub fn with_strict_line_endings(mut self, strict: bool) -> Website {
+        self.strict_line_endings = strict;
+        self
+    }
+
+    /// Sets which encodings text responses may be compressed with. When a client accepts several,
+    /// the one listed first here is used. An empty list turns compression off.
+    pub fn with_encodings(mut self, encodings: Vec<Encoding>) -> Website {
+        self.encodings = encodings;
+        self
+    }
+
+    /// Allows PUT requests carrying this token in their `admin_token` header to upload files
+    /// anywhere under the website location.
+    pub fn with_admin_token(mut self, token: &str) -> Website {
+        self.admin_token = Some(token.to_string());
+        self
+    }
+
+    /// Serves urls starting with `prefix` from `directory`, with the prefix stripped.
+    /// When several mounts match a url, the one with the longest prefix wins.
+    pub fn with_mount(mut self, prefix: &str, directory: impl AsRef<Path>) -> Result<Website, WebsiteError> {
+        let given = directory.as_ref();
+        let dir = fs::canonicalize(given).map_err(|e| WebsiteError::Missing(given.to_path_buf(), e))?;
+        if !dir.is_dir() {
+            return Err(WebsiteError::NotADirectory(dir));
+        }
+        self.mounts.push(Mount::new(prefix, dir));
+        Ok(self)
+    }
+
+    /// Adds the mounts listed in `file`, one `prefix -> directory` per line.
+    /// Relative directories are relative to the file's own directory.
+    pub fn with_mounts_file(self, file: impl AsRef<Path>) -> Result<Website, WebsiteError> {
+        let file = file.as_ref();
+        let mounts = read_config(file, resources::parse_mounts)?;
+        let base = file.parent().unwrap_or_else(|| Path::new(""));
+        mounts.into_iter().try_fold(self, |site, (prefix, dir)| site.with_mount(&prefix, base.join(dir)))
+    }
+
+    /// Redirects the paths listed in `file`, one `from -> to [status]` per line, e.g. `/blog -> /posts 308`.
+    /// A path may only be listed once; later files replace redirects from earlier ones.
+    pub fn with_redirects_file(mut self, file: impl AsRef<Path>) -> Result<Website, WebsiteError> {
+        self.redirects.extend(read_config(file.as_ref(), redirects::parse_redirects)?);
+        Ok(self)
+    }
+
+    /// Adds the rewrite rules listed in `file`, one `pattern -> replacement [status]` per line,
+    /// e.g. `^/img/(\d+)/(.*)$ -> /images/$2?size=$1`. Rules with a status redirect to the rewritten url;
+    /// the others change which file is served. Patterns are regexes and are checked here.
+    pub fn with_rewrites_file(mut self, file: impl AsRef<Path>) -> Result<Website, WebsiteError> {
+        self.rewrites.extend(read_config(file.as_ref(), rewrites::parse_rewrites)?);
+        Ok(self)
+    }
+
+    /// Lets clients from these addresses use admin endpoints; nobody may by default.
+    pub fn with_admin_ip_allowlist(mut self, allowed: Vec<IpAddr>) -> Website {
+        self.admin_ip_allowlist = allowed;
+        self
+    }
+
+    /// Records requests and connections in `metrics`, and serves them at `/_prometheus`
+    /// to clients in the admin IP allowlist. A collector may be shared between sites.
+    #[cfg(feature = "prometheus")]
+    pub fn with_metrics(mut self, metrics: Arc<PrometheusCollector>) -> Website {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Puts the full error, as logged, into the body of error responses instead of a generic message.
+    /// Meant for development, since it shows clients internals like file system paths.
+    pub fn with_verbose_errors(mut self, verbose: bool) -> Website {
+        self.verbose_errors = verbose;
+        self
+    }
+
+    /// Keeps connections open for further requests, including pipelined ones, until they've been idle
+    /// for `timeout` or the client asks to close them.
+    pub fn with_keep_alive(mut self, timeout: Duration) -> Website {
+        self.keep_alive = Some(timeout);
+        self
+    }
+
+    /// Flushes responses as `strategy` says. By default, each response is flushed once it's complete;
+    /// flushing [`FlushStrategy::PerChunk`] gets the start of large files to clients sooner.
+    pub fn with_flush_strategy(mut self, strategy: FlushStrategy) -> Website {
+        self.flush_strategy = strategy;
+        self
+    }
+
+    /// Gives up on requests that take longer than `timeout` to answer, counted from when their head was read.
+    /// Proxied requests are answered with a `504 Gateway Timeout` once the upstream has had the time
+    /// that's left. Other handlers can't be interrupted, so when they're done too late, the connection
+    /// is closed without an answer.
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Website {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Refuses requests with a body larger than `limit` bytes before reading it. Since the rest
+    /// of the body can't be told apart from a next request, such connections are closed.
+    pub fn with_max_body_size(mut self, limit: usize) -> Website {
+        self.max_body_size = Some(limit);
+        self
+    }
+
+    /// Sets the body size limit for urls starting with `prefix`, overriding the one set with
+    /// [`Website::with_max_body_size`]; with a `method`, only for requests with that method.
+    /// The longest matching prefix wins, and a limit for the request's method beats one for any method.
+    pub fn with_max_body_size_for(mut self, method: Option<&str>, prefix: &str, limit: usize) -> Website {
+        self.route_body_sizes.push((method.map(str::to_string), prefix.to_string(), limit));
+        self
+    }
+
+    /// Browsers ask every site for `/favicon.ico`, so a built-in one is served when the website
+    /// doesn't have the file. Turning that off makes those requests 404 instead.
+    pub fn with_default_favicon(mut self, default_favicon: bool) -> Website {
+        self.default_favicon = default_favicon;
+        self
+    }
+
+    /// By default, a file whose path goes through a symlink leading out of the website location
+    /// is refused with a 403, so a link can't expose the rest of the file system. Following them
+    /// serves whatever they point to; mounted directories never serve files outside of them.
+    pub fn with_follow_symlinks(mut self, follow: bool) -> Website {
+        self.follow_symlinks = follow;
+        self
+    }
+
+    /// Sends `value` as the `Cache-Control` header of files with any of these extensions, given
+    /// without the dot and matched regardless of case, e.g. `public, max-age=31536000, immutable`
+    /// for `js` and `css`, and `no-cache` for `html`. Other files get no `Cache-Control`.
+    pub fn with_cache_control(mut self, extensions: &[&str], value: &str) -> Website {
+        for extension in extensions {
+            self.cache_control.insert(extension.to_ascii_lowercase(), value.to_string());
+        }
+        self
+    }
+
+    /// Has browsers download files with any of these extensions, given without the dot and matched
+    /// regardless of case, instead of showing them. Any file can be asked for as a download
+    /// with `?download=1`.
+    pub fn with_download_extensions(mut self, extensions: &[&str]) -> Website {
+        self.download_extensions.extend(extensions.iter().map(|extension| extension.to_ascii_lowercase()));
+        self
+    }
+
+    /// The files a url naming a directory, like `/` or `/docs/`, is served from: the first of them
+    /// that exists in the directory. Without any of them there, the request is a 404.
+    pub fn with_index_files(mut self, index_files: Vec<String>) -> Website {
+        self.index_files = index_files;
+        self
+    }
+
+    /// Lists the files of directories that have none of the index files, instead of answering with a 404.
+    pub fn with_autoindex(mut self, autoindex: bool) -> Website {
+        self.autoindex = autoindex;
+        self
+    }
+
+    /// Sets what happens to `/docs` when `docs` is a directory, and to `/page.html/` when `page.html` is a file.
+    pub fn with_trailing_slash(mut self, policy: TrailingSlash) -> Website {
+        self.trailing_slash = policy;
+        self
+    }
+
+    /// Permanently redirects requests whose `Host` header names any other host to the same path
+    /// and query on `host`, e.g. `www.example.com` to `example.com`. `scheme` goes into the `Location`,
+    /// since the request doesn't say which one the client used. Requests without a `Host` are served as usual.
+    pub fn with_canonical_host(mut self, scheme: &str, host: &str) -> Website {
+        self.canonical_host = Some((scheme.to_string(), host.to_string()));
+        self
+    }
+
+    /// Answers GET requests for `path`, without a query, with whatever `handler` returns instead of a file.
+    /// Redirects and rewrites apply first, so `path` is matched against the rewritten url.
+    pub fn with_get_route(mut self, path: &str, handler: RouteHandler) -> Website {
+        self.get_routes.insert(resources::normalize_url_path(path), handler);
+        self
+    }
+
+    /// Answers PATCH requests for `path`, without a query, with whatever `handler` returns. The body is
+    /// read and limited like any other, see [`Website::with_max_body_size_for`]. PATCH requests for
+    /// other paths are refused with a 405, and so are GET requests for `path`, unless it has a GET route too.
+    pub fn with_patch_route(mut self, path: &str, handler: RouteHandler) -> Website {
+        self.patch_routes.insert(resources::normalize_url_path(path), handler);
+        self
+    }
+
+    /// Answers GET requests for `path` with a `text/event-stream` response that stays open,
+    /// handing the connection to `handler` to push events over. Handlers that keep streams
+    /// around, e.g. to broadcast to them from another thread, should return quickly,
+    /// since every connection still being handled ties up a worker thread.
+    pub fn with_event_stream(mut self, path: &str, handler: EventStreamHandler) -> Website {
+        self.event_streams.insert(resources::normalize_url_path(path), Arc::new(handler));
+        self
+    }
+
+    /// Caps the number of event streams open at once, [`DEFAULT_MAX_EVENT_STREAMS`] by default.
+    pub fn with_max_event_streams(mut self, max: usize) -> Website {
+        self.max_event_streams = max;
+        self
+    }
+
+    /// Answers WebSocket upgrade requests with the opening handshake, then hands the connection,
+    /// and the request that asked for the upgrade, to `handler` on the worker thread it was served on.
+    pub fn with_websocket(mut self, handler: WebSocketHandler) -> Website {
+        self.websocket = Some(Arc::new(handler));
+        self
+    }
+
+    /// Renders `{{year}}`, `{{date}}` and `{{include:file.html}}` in `.html` pages,
+    /// with includes looked up next to the pages in `layout/`.
+    pub fn with_templating(mut self, templating: bool) -> Website {
+        self.templating = templating;
+        self
+    }
+
+    /// Serves GET requests for `path` by rendering `<loc>/templates/<template_name>.html.tera`
+    /// with the context `context_fn` builds for the request. The template is read on every request,
+    /// like any other file, so edits show up without a restart.
+    #[cfg(feature = "tera")]
+    pub fn add_template_route(&mut self, path: &str, template_name: &str, context_fn: ContextFn) {
+        self.template_routes.push(TemplateRoute {
+            path: path.to_string(),
+            template_name: template_name.to_string(),
+            context_fn
+        });
+    }
+
+    /// Serves GET requests for `url_path` with the file at `file_path`, relative to the website directory,
+    /// as if it had been asked for directly. Unlike a redirect, the client never sees the file's own url.
+    /// Redirects and rewrites apply first, so `url_path` is matched against the rewritten url; both are
+    /// normalized, so `//home` and `/./home` are aliased along with `/home`. A `file_path` that's
+    /// absolute or has `..` in it could name a file outside the website, so that alias is left out.
+    pub fn with_alias(mut self, url_path: &str, file_path: &str) -> Website {
+        if Path::new(file_path).components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+            self.aliases.insert(resources::normalize_url_path(url_path), self.loc.join(file_path));
         } else {
-            Ok((SendMethod::PlainText, format!("{}/layout/index.html", self.loc)))
+            warn!("leaving out the alias {} -> {}, since the file isn't inside the website", url_path, file_path);
         }
+        self
+    }
+
+    /// Binds `address` and serves this website on it, blocking the current thread.
+    pub fn serve(self: Arc<Self>, address: &str) -> Result<(), ServerError> {
+        let server = Server::bind(address)?;
+        Website::serve_on(self, server)
     }
+
+    /// Runs the accept loop for `site` on an already bound `server`, blocking the current thread.
+    pub fn serve_on(site: Arc<Website>, server: Server) -> Result<(), ServerError> {
+        server.run(site)
+    }
+
     /**
     HTTP Format:
-    ```
+    ```text
     data: [GET|SET|POST] URL HTTP/[HTTP Version]\r\n
     Header-Key: Header-Value\r\n
     ...
@@ -76,89 +671,2496 @@ impl Website {
     \r\n [notice double CRLF]
     [content with content length in bytes]
     ```
+
+    The connection is read and kept alive according to this site's settings, while each request
+    is answered by `answer`, given the site `select` picks for it, usually with [`Website::handle_request`].
      */
-    fn handle_connection(&self, mut stream: TcpStream) {
-        let mut buffer = [0; 1024];
-        stream.read(&mut buffer).unwrap();
-        println!("data: {}", String::from_utf8_lossy(&buffer[..]));
-        let data_as_string: String = String::from_utf8_lossy(&buffer[..]).into();
-        let response = match data_as_string.split("\r\n").next() {
-            Some(line) => {
-                let args = line.split(" ").collect::<Vec<_>>();
-                if args.len() < 3 {
-                    create_bad_request_error("Badly formatted HTTP request.".to_string())
-                } else {
-                    let message_type = args[0];
-                    let url = args[1];
-                    let http_version = args[2];
-                    if http_version == "HTTP/6.9" {
-                        Response::PlainText(format!("HTTP/6.9 420 nice 👌\r\n\r\n"))
-                    } else {
-                        match message_type {
-                            "GET" => self.handle_get(url),
-                            "PUT" => {
-                                create_bad_request_error("server doesn't expect a put request".to_string())
-                            },
-                            _ => {
-                                create_bad_request_error("what are you even trying to do".to_string())
-                            }
+    fn handle_connection_with<'s>(&'s self, stream: TcpStream, select: &dyn Fn(&Request) -> &'s Website,
+                                  answer: &dyn Fn(&Website, Request) -> Result<Response, ServerError>) {
+        if let Err(e) = self.serve_connection(stream, select, answer) {
+            if is_disconnect(&e) {
+                debug!("client went away mid-response: {}", e);
+            } else {
+                warn!("Connection failed: {}", e);
+            }
+        }
+    }
+
+    fn serve_connection<'s>(&'s self, mut stream: TcpStream, select: &dyn Fn(&Request) -> &'s Website,
+                            answer: &dyn Fn(&Website, Request) -> Result<Response, ServerError>) -> std::io::Result<()> {
+        stream.set_read_timeout(self.keep_alive)?;
+        #[cfg(feature = "prometheus")]
+        let _active = self.metrics.as_ref().map(|metrics| metrics.connection_opened());
+        // bytes read past the end of the current request, i.e. the start of pipelined ones
+        let mut pending = vec![];
+        loop {
+            let request = match self.read_request(&mut stream, &mut pending)? {
+                Some(request) => request,
+                None => return Ok(())
+            };
+            let started = Instant::now();
+            // a rejected request's body may still be on its way
+            let rejected = request.is_err();
+            let span = match &request {
+                Ok(request) => info_span!("request", method = %request.method, url = %request.url),
+                Err(_) => info_span!("request")
+            };
+            let _entered = span.enter();
+            let mut deadline = None;
+            let (site, method, url, keep_alive, response) = match request {
+                Ok(mut request) => {
+                    let site = select(&request);
+                    deadline = site.request_timeout.map(|timeout| started + timeout);
+                    request.deadline = deadline;
+                    // only a request that passes the usual checks gets the connection handed over;
+                    // any other is answered below, with whatever those checks answer it with
+                    let admitted = || matches!(site.check_request(&request), Ok(None));
+                    if let Some(handler) = site.websocket.as_ref().filter(|_| websocket::is_upgrade(&request) && admitted()) {
+                        return site.upgrade_to_websocket(stream, request, handler, started);
+                    }
+                    if let Some(handler) = site.event_stream_for(&request).filter(|_| admitted()) {
+                        return site.open_event_stream(stream, request, handler, started);
+                    }
+                    let (method, url) = (request.method.clone(), request.url.clone());
+                    let keep_alive = self.keeps_alive(&request);
+                    // a panic is answered like any other error, and the worker goes on to the next connection
+                    match std::panic::catch_unwind(AssertUnwindSafe(|| answer(site, request))) {
+                        Ok(response) => (site, method, url, keep_alive, response),
+                        // whatever the handler was in the middle of, the connection isn't trusted anymore
+                        Err(panic) => {
+                            let message = error::panic_message(panic.as_ref());
+                            error!("{} {} panicked: {}", method, url, message);
+                            (site, method, url, None, Err(ServerError::Panic(message)))
                         }
                     }
                 }
-            },
-            None => create_bad_request_error("Malformatted request.".to_string())
+                // after a bad request, there's no telling where the next one would start
+                Err(e) => (self, "-".to_string(), "-".to_string(), None, Err(e))
+            };
+            if deadline.is_some_and(|deadline| Instant::now() > deadline)
+                && !matches!(response, Err(ServerError::GatewayTimeout(_))) {
+                warn!("{} {} took {:?}, longer than the request timeout; closing the connection", method, url, started.elapsed());
+                return Ok(());
+            }
+            let response = response.unwrap_or_else(|e| {
+                info!("Couldn't handle request: {}", e);
+                create_error_response(&e, site.verbose_errors)
+            });
+            let mut response = response.with_header("Connection", keep_alive.unwrap_or("close"));
+            let mut writer = CountingWriter::new(&mut stream);
+            response.send(&mut writer, site.flush_strategy)?;
+            info!("{} {} -> {} ({} bytes) in {:?}", method, url, response.status, writer.bytes_written(), started.elapsed());
+            #[cfg(feature = "prometheus")]
+            if let Some(metrics) = &site.metrics {
+                metrics.request_answered(&method, response.status, started.elapsed(), writer.bytes_written());
+            }
+            if keep_alive.is_none() {
+                if rejected {
+                    drain_before_close(&mut stream);
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    /// Completes the WebSocket handshake `request` starts and gives the connection to `handler`,
+    /// or answers with an error and closes the connection if the handshake isn't valid.
+    fn upgrade_to_websocket(&self, mut stream: TcpStream, request: Request, handler: &WebSocketHandler, started: Instant) -> std::io::Result<()> {
+        let mut response = websocket::handshake(&request).unwrap_or_else(|e| {
+            info!("Couldn't handle request: {}", e);
+            create_error_response(&e, self.verbose_errors).with_header("Connection", "close")
+        });
+        let mut writer = CountingWriter::new(&mut stream);
+        // all of it right away, since the handler takes the connection over next
+        response.send(&mut writer, FlushStrategy::OnCompletion)?;
+        info!("{} {} -> {} ({} bytes) in {:?}", request.method, request.url, response.status, writer.bytes_written(), started.elapsed());
+        #[cfg(feature = "prometheus")]
+        if let Some(metrics) = &self.metrics {
+            metrics.request_answered(&request.method, response.status, started.elapsed(), writer.bytes_written());
+        }
+        if response.status == 101 {
+            // the handler decides how long to wait for frames
+            stream.set_read_timeout(None)?;
+            handler(stream, request);
+        }
+        Ok(())
+    }
+
+    /// The handler of the event stream `request` asks for, if it asks for one.
+    fn event_stream_for(&self, request: &Request) -> Option<&EventStreamHandler> {
+        if request.method != "GET" {
+            return None;
+        }
+        let path = resources::normalize_url_path(request.url.split('?').next().unwrap_or_default());
+        self.event_streams.get(&path).map(|handler| handler.as_ref())
+    }
+
+    /// Answers `request` with the head of an event stream and gives the connection to `handler`,
+    /// or with a 503 if as many streams as allowed are open already.
+    fn open_event_stream(&self, mut stream: TcpStream, request: Request, handler: &EventStreamHandler, started: Instant) -> std::io::Result<()> {
+        let slot = self.open_event_streams.reserve(self.max_event_streams);
+        let mut response = match slot {
+            Some(_) => Response::new(200)
+                .with_header("Content-Type", "text/event-stream")
+                .with_header("Cache-Control", "no-cache")
+                .open_ended(),
+            None => Response::new(503)
+                .with_header("Retry-After", "10")
+                .with_body(b"Too many open event streams.".to_vec())
+        }.with_header("Connection", "close");
+        let mut writer = CountingWriter::new(&mut stream);
+        // all of it right away, since the handler takes the connection over next
+        response.send(&mut writer, FlushStrategy::OnCompletion)?;
+        info!("{} {} -> {} ({} bytes) in {:?}", request.method, request.url, response.status, writer.bytes_written(), started.elapsed());
+        #[cfg(feature = "prometheus")]
+        if let Some(metrics) = &self.metrics {
+            metrics.request_answered(&request.method, response.status, started.elapsed(), writer.bytes_written());
+        }
+        if let Some(slot) = slot {
+            // nothing more is read, and a client that stops reading shows up as a failed write
+            stream.set_read_timeout(None)?;
+            stream.set_write_timeout(Some(EVENT_WRITE_TIMEOUT))?;
+            handler(&request, EventStream::new(stream, slot));
+        }
+        Ok(())
+    }
+
+    /// Whether the connection stays open after answering `request`, and if so, the `Connection` header
+    /// that says so. HTTP/1.1 connections are persistent unless the client asks to close them,
+    /// HTTP/1.0 ones only if the client asks for it.
+    fn keeps_alive(&self, request: &Request) -> Option<&'static str> {
+        self.keep_alive?;
+        let connection = request.header("Connection").unwrap_or_default().to_ascii_lowercase();
+        let has_option = |option: &str| connection.split(',').any(|o| o.trim() == option);
+        match request.http_version.as_str() {
+            "HTTP/1.1" if !has_option("close") => Some("keep-alive"),
+            "HTTP/1.0" if has_option("keep-alive") => Some("keep-alive"),
+            _ => None
+        }
+    }
+
+    /// Reads a request and its body, starting with whatever was read too far last time, which `pending`
+    /// holds before and after. An I/O error means the connection is unusable, while a `ServerError`
+    /// is something to answer the client with. `None` means the client closed the connection
+    /// (or let it idle) before sending a whole head, so there's no one to answer.
+    fn read_request(&self, stream: &mut TcpStream, pending: &mut Vec<u8>) -> std::io::Result<Option<Result<Request, ServerError>>> {
+        let mut buffer = [0; 1024];
+        // keep reading until the blank line that ends the headers shows up
+        let head_end = loop {
+            if let Some(end) = find_head_end(pending) {
+                break Some(end);
+            }
+            if pending.len() >= MAX_HEAD_SIZE {
+                break None;
+            }
+            let read = match stream.read(&mut buffer) {
+                Ok(read) => read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                // the keep-alive timeout ran out between requests
+                Err(e) if pending.is_empty()
+                    && matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => return Ok(None),
+                Err(e) => return Err(e)
+            };
+            if read == 0 {
+                if !pending.is_empty() {
+                    debug!("client hung up after a truncated request: {}", String::from_utf8_lossy(pending));
+                }
+                return Ok(None);
+            }
+            pending.extend_from_slice(&buffer[..read]);
+        };
+        debug!("data: {}", String::from_utf8_lossy(pending));
+        let end = match head_end {
+            Some(end) => end,
+            None => return Ok(Some(Err(ServerError::BadRequest("Malformatted request.".to_string()))))
         };
-        match response {
-            Response::PlainText(string) => {
-                stream.write(string.as_bytes()).unwrap();
+        let parsed = Request::parse(&String::from_utf8_lossy(&pending[..end]), self.strict_line_endings)
+            .and_then(|request| request.content_length().map(|length| (request, length)));
+        let (mut request, length) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => return Ok(Some(Err(e)))
+        };
+        // part of the body, and maybe more requests, may have come in with the head
+        let mut body = pending.split_off(end);
+        if let Err(e) = self.check_before_body(&request, length) {
+            return Ok(Some(Err(e)));
+        }
+        if body.len() < length && self.expects_continue(&request) {
+            // the client is waiting to hear that the body is welcome before sending it
+            stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        }
+        if body.len() < length {
+            Read::by_ref(stream).take((length - body.len()) as u64).read_to_end(&mut body)?;
+        }
+        // the client stopped sending before the end of the body it announced
+        if body.len() < length {
+            return Ok(Some(Err(ServerError::BadRequest("Content-Length mismatch.".to_string()))));
+        }
+        *pending = body.split_off(length.min(body.len()));
+        request.body = body;
+        request.local_addr = stream.local_addr().ok();
+        request.peer_addr = stream.peer_addr().ok();
+        let limit = self.body_size_limit(&request);
+        Ok(Some(request.decode_body(limit).map(|_| request)))
+    }
+
+    /// Whether `request` has a body that is acceptable as far as can be told from its head,
+    /// so that reading it isn't a waste.
+    fn check_before_body(&self, request: &Request, length: usize) -> Result<(), ServerError> {
+        if let Some(limit) = self.body_size_limit(request).filter(|limit| length > *limit) {
+            return Err(ServerError::PayloadTooLarge(format!("{} byte body, at most {} allowed", length, limit)));
+        }
+        match request.header("Expect").map(str::trim) {
+            None => Ok(()),
+            // the final answer is sent instead of `100 Continue` when it's known already
+            Some(expectation) if expectation.eq_ignore_ascii_case("100-continue") => match request.method.as_str() {
+                "PUT" => self.check_admin_token(request),
+                _ => Ok(())
             },
-            Response::Binary(data) => {
-                stream.write(data.as_slice()).unwrap();
-            }
-        };
-        stream.flush().unwrap();
-    }
-
-    fn handle_get(&self, url: &str) -> Response {
-        match self.get_resource(url.to_string()) {
-            Ok((send_method, resource_path)) => match send_method {
-                SendMethod::PlainText =>
-                    match fs::read_to_string(resource_path.clone()) {
-                        Ok(resource_file) => Response::PlainText(format!(
-                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
-                            resource_file.len(),
-                            resource_file
-                        )),
-                        Err(err) => create_bad_request_error(
-                            format!("Cannot open file: {}", err.to_string())
-                        )
-                    },
-                SendMethod::Binary =>
-                    match fs::read(resource_path.clone()) {
-                        Ok(binary_data) => {
-                            let header = format!(
-                                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
-                                binary_data.len());
-                            let mut data = Vec::with_capacity(header.len() + binary_data.len());
-                            for c in header.as_bytes() {
-                                data.push(*c);
-                            }
-                            for b in binary_data {
-                                data.push(b);
-                            }
-                            Response::Binary(data)
-                        },
-                        Err(err) => create_bad_request_error(
-                            format!("Cannot open file: {}", err.to_string())
-                        )
+            Some(expectation) => Err(ServerError::ExpectationFailed(expectation.to_string()))
+        }
+    }
+
+    /// The largest body `request` may have, if there's a limit.
+    fn body_size_limit(&self, request: &Request) -> Option<usize> {
+        let path = request.url.split('?').next().unwrap_or_default();
+        self.route_body_sizes.iter()
+            .filter(|(method, prefix, _)| path.starts_with(prefix.as_str())
+                && method.as_ref().is_none_or(|method| *method == request.method))
+            .max_by_key(|(method, prefix, _)| (prefix.len(), method.is_some()))
+            .map_or(self.max_body_size, |(_, _, limit)| Some(*limit))
+    }
+
+    /// Whether `request` waits for a `100 Continue` before sending its body.
+    /// HTTP/1.0 clients don't know about it (RFC 7231, section 5.1.1).
+    fn expects_continue(&self, request: &Request) -> bool {
+        request.http_version != "HTTP/1.0"
+            && request.header("Expect").is_some_and(|expectation| expectation.trim().eq_ignore_ascii_case("100-continue"))
+    }
+
+    /// The checks every request goes through before it's dispatched, websocket upgrades and event streams included.
+    /// Gives the response to answer with instead when there is one.
+    fn check_request(&self, request: &Request) -> Result<Option<Response>, ServerError> {
+        // the one version that gets an answer without being supported
+        if request.http_version == "HTTP/6.9" {
+            let mut response = Response::new(420);
+            response.reason = "nice 👌".to_string();
+            response.http_version = request.http_version.clone();
+            return Ok(Some(response));
+        }
+        if !is_supported_version(&request.http_version) {
+            return Err(ServerError::VersionNotSupported(request.http_version.clone()));
+        }
+        Ok(self.redirect_to_canonical_host(request))
+    }
+
+    fn handle_request(&self, request: Request) -> Result<Response, ServerError> {
+        if let Some(response) = self.check_request(&request)? {
+            return Ok(response);
+        }
+        #[cfg(feature = "prometheus")]
+        if let (Some(metrics), "/_prometheus") = (&self.metrics, request.url.split('?').next().unwrap_or_default()) {
+            self.check_admin(&request)?;
+            let body = metrics.render().map_err(|e| ServerError::Io(std::io::Error::other(e)))?;
+            return Ok(Response::new(200)
+                .with_header("Content-Type", "text/plain; version=0.0.4")
+                .with_body(body));
+        }
+        let response = match request.method.as_str() {
+            "GET" => self.handle_get(&request),
+            "PUT" => self.handle_put(&request),
+            "PATCH" => self.handle_patch(&request),
+            method if KNOWN_METHODS.contains(&method) =>
+                Err(ServerError::MethodNotAllowed(method.to_string(), ALLOWED_METHODS.to_vec())),
+            method => Err(ServerError::NotImplemented(format!("method {}", method)))
+        }?;
+        Ok(match request.vary() {
+            Some(vary) => response.with_header("Vary", &vary),
+            None => response
+        })
+    }
+
+    /// Finds the file for `url`, in the mounts first.
+    fn resolve(&self, url: &str) -> Result<(SendMethod, PathBuf), ServerError> {
+        self.resolve_with(url, &self.index_files)
+    }
+
+    fn resolve_with(&self, url: &str, index_files: &[String]) -> Result<(SendMethod, PathBuf), ServerError> {
+        match resources::resolve_mounted(&self.mounts, url, index_files)? {
+            Some(mounted) => Ok(mounted),
+            None => self.resources.resolve(&self.loc, url, index_files)
+        }
+    }
+
+    /// A listing of the directory `url` names, if it names one that has none of the index files.
+    fn directory_listing(&self, url: &str) -> Result<Option<Response>, ServerError> {
+        let path = url.split('?').next().unwrap_or_default();
+        if !path.ends_with('/') || self.resolve(url).is_ok_and(|(_, index)| index.is_file()) {
+            return Ok(None);
+        }
+        // the directory itself is what an empty index file name leads to
+        let dir = match self.resolve_with(url, &[String::new()]) {
+            Ok((_, dir)) if dir.is_dir() => dir,
+            _ => return Ok(None)
+        };
+        if !self.follow_symlinks && dir.starts_with(&self.loc) {
+            resources::check_no_symlink_escape(&dir, &self.loc)?;
+        }
+        let entries = autoindex::list(&dir).map_err(|e| file_error(&dir, e))?;
+        Ok(Some(Response::new(200)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(autoindex::render(path, &entries).into_bytes())))
+    }
+
+    /// A `301` to the form of `url` the trailing slash policy wants, if it isn't in that form already.
+    /// `resolved` is where `url` itself led.
+    fn trailing_slash_redirect(&self, url: &str, resolved: &Path) -> Result<Option<Response>, ServerError> {
+        let (path, query) = url.split_at(url.find('?').unwrap_or(url.len()));
+        let location = match self.trailing_slash {
+            _ if path.trim_end_matches('/').is_empty() => None,
+            TrailingSlash::RedirectToSlash if !path.ends_with('/') && resolved.is_dir() =>
+                Some(format!("{}/{}", path, query)),
+            TrailingSlash::RedirectToNoSlash if path.ends_with('/') => {
+                let trimmed = path.trim_end_matches('/');
+                match self.resolve(trimmed) {
+                    Ok((_, file)) if file.is_file() => Some(format!("{}{}", trimmed, query)),
+                    _ => None
+                }
+            }
+            _ => None
+        };
+        Ok(location.map(|location| Response::new(301).with_header("Location", &location)))
+    }
+
+    /// For an extensionless url like `/logo`, the image file in the format the client's `Accept`
+    /// header prefers, looked for in each of the layout's directories. Mounts aren't looked in,
+    /// so images under a mount have to be asked for with their extension.
+    fn negotiate_image(&self, request: &Request, url: &str) -> Option<(PathBuf, &'static str)> {
+        let path = url.split('?').next().unwrap_or_default();
+        if Path::new(path).extension().is_some() || path.ends_with('/') {
+            return None;
+        }
+        // no Accept header means anything goes
+        let accept = request.varying_header("Accept").unwrap_or("*/*");
+        self.resources.directories().into_iter()
+            .find_map(|dir| media::negotiate_content_type(url, accept, &self.loc.join(dir)))
+    }
+
+    /// A `301` to the canonical host, if there is one and `request` was for some other host.
+    /// The path and query are copied as they were sent, percent-encoding and all.
+    fn redirect_to_canonical_host(&self, request: &Request) -> Option<Response> {
+        let (scheme, canonical) = self.canonical_host.as_ref()?;
+        let host = request.header("Host")?.trim();
+        if host.eq_ignore_ascii_case(canonical) || !request.url.starts_with('/') {
+            return None;
+        }
+        Some(Response::new(301)
+            .with_header("Location", &format!("{}://{}{}", scheme, canonical, request.url)))
+    }
+
+    fn handle_get(&self, request: &Request) -> Result<Response, ServerError> {
+        // clients shouldn't send a fragment, but one that does means the same url without it;
+        // the path is split off before it's decoded, so an encoded `?` or `#` is part of it
+        let url = request.url.split('#').next().unwrap_or_default();
+        let (path, query) = url.split_at(url.find('?').unwrap_or(url.len()));
+        let path = resources::normalize_url_path(path);
+        if let Some(redirect) = self.redirects.get(&redirects::key(&path)) {
+            return Ok(redirect.response(query));
+        }
+        let url = match rewrites::apply(&self.rewrites, &path, query)? {
+            Rewritten::Url(url) => url,
+            Rewritten::Redirect(response) => return Ok(response)
+        };
+        // rewritten urls aren't normalized yet
+        let rewritten_path = resources::normalize_url_path(url.split('?').next().unwrap_or_default());
+        if let Some(handler) = self.get_routes.get(&rewritten_path) {
+            return handler(request);
+        }
+        // a path that only takes PATCH has no file behind it to GET
+        if self.patch_routes.contains_key(&rewritten_path) {
+            return Err(ServerError::MethodNotAllowed(request.method.clone(), vec!["PATCH"]));
+        }
+        #[cfg(feature = "tera")]
+        if let Some(response) = self.render_template_route(request, &url)? {
+            return Ok(response);
+        }
+        let alias = self.aliases.get(&rewritten_path);
+        let (send_method, resource_path, negotiated) = match alias {
+            Some(file) => (resources::send_method_for(file), file.clone(), None),
+            None => {
+                if self.default_favicon && url.split('?').next() == Some("/favicon.ico")
+                    && !self.resolve(&url).is_ok_and(|(_, path)| path.is_file()) {
+                    return Ok(Response::new(200)
+                        .with_header("Content-Type", "image/x-icon")
+                        .with_body(DEFAULT_FAVICON.to_vec()));
+                }
+                if self.autoindex {
+                    if let Some(response) = self.directory_listing(&url)? {
+                        return Ok(response);
+                    }
+                }
+                let negotiated = self.negotiate_image(request, &url);
+                let (send_method, resource_path) = match &negotiated {
+                    // images vary by format, not by language, so a negotiated one isn't localized
+                    Some((file, _)) => (SendMethod::Binary, file.clone()),
+                    None => {
+                        let (send_method, resource_path) = self.resolve(&url)?;
+                        (send_method, localize(request, resource_path))
+                    }
+                };
+                (send_method, resource_path, negotiated)
+            }
+        };
+        if !self.follow_symlinks && resource_path.starts_with(&self.loc) {
+            resources::check_no_symlink_escape(&resource_path, &self.loc)?;
+        }
+        if let Some(response) = self.trailing_slash_redirect(&url, &resource_path)? {
+            return Ok(response);
+        }
+        let mut response = self.serve_file(request, send_method, &resource_path, negotiated)?;
+        let extension = resource_path.extension().map(|ext| ext.to_string_lossy().to_ascii_lowercase());
+        if let Some(value) = extension.as_ref().and_then(|ext| self.cache_control.get(ext)) {
+            response = response.with_header("Cache-Control", value);
+        }
+        let asked_to_download = url.split_once('?')
+            .is_some_and(|(_, query)| query.split('&').any(|param| param == "download=1"));
+        let download = asked_to_download || extension.is_some_and(|ext| self.download_extensions.contains(&ext));
+        if download && response.status != 304 {
+            let filename = resource_path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+            response = response.with_header("Content-Disposition", &disposition::attachment(&filename));
+        }
+        Ok(response)
+    }
+
+    /// Answers a GET for the file at `resource_path`, or for part of it if the request has a `Range`.
+    /// `negotiated` is the image picked by content negotiation along with its media type.
+    fn serve_file(&self, request: &Request, send_method: SendMethod, resource_path: &Path,
+                  negotiated: Option<(PathBuf, &'static str)>) -> Result<Response, ServerError> {
+        let open_error = |err| file_error(resource_path, err);
+        match send_method {
+            SendMethod::PlainText => {
+                let encoding = if self.encodings.is_empty() {
+                    None
+                } else {
+                    request.varying_header("Accept-Encoding")
+                        .and_then(|accepted| encoding::negotiate(accepted, &self.encodings))
+                };
+                let mut file = fs::File::open(resource_path).map_err(open_error)?;
+                let metadata = file.metadata().map_err(open_error)?;
+                // a templated page also changes with the files it includes, so only the rendered page can be tagged;
+                // other text files are tagged from their metadata, so revalidating them doesn't read them
+                let (etag, rendered) = if self.templating && resource_path.extension().is_some_and(|ext| ext == "html") {
+                    let mut resource_file = String::new();
+                    file.read_to_string(&mut resource_file).map_err(open_error)?;
+                    let name = resource_path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+                    let layout_dir = resource_path.parent().unwrap_or(resource_path);
+                    let rendered = template::render(&resource_file, name, layout_dir)?;
+                    (etag::for_body(rendered.as_bytes(), encoding), Some(rendered))
+                } else {
+                    (etag::for_encoded_file(&metadata, encoding), None)
+                };
+                etag::check_preconditions(request, std::slice::from_ref(&etag), metadata.modified().ok())?;
+                if request.header("If-None-Match").is_some_and(|tags| etag::matches_any(tags, &etag)) {
+                    return Ok(Response::not_modified(&etag));
+                }
+                let resource_file = match rendered {
+                    Some(rendered) => rendered,
+                    None => {
+                        let mut resource_file = String::new();
+                        file.read_to_string(&mut resource_file).map_err(open_error)?;
+                        resource_file
                     }
+                };
+                let response = Response::new(200).with_header("ETag", &etag);
+                match encoding {
+                    Some(encoding) => Ok(response
+                        .with_header("Content-Encoding", encoding.token())
+                        .with_body(encoding.encode(resource_file.as_bytes())?)),
+                    None => Ok(response.with_body(resource_file.into_bytes()))
+                }
             },
-            Err(error_message) => create_bad_request_error(
-                format!("Cannot handle GET Request. {}", error_message))
+            SendMethod::Binary => {
+                // binary files can be huge, so they're copied to the client as they're read
+                let file = fs::File::open(resource_path).map_err(open_error)?;
+                let metadata = file.metadata().map_err(open_error)?;
+                let etag = etag::for_file(&metadata);
+                etag::check_preconditions(request, std::slice::from_ref(&etag), metadata.modified().ok())?;
+                if request.header("If-None-Match").is_some_and(|tags| etag::matches_any(tags, &etag)) {
+                    return Ok(Response::not_modified(&etag));
+                }
+                let ranges = match request.header("Range") {
+                    Some(range) => range::parse_ranges(range, metadata.len())?,
+                    None => None
+                };
+                let response = match ranges.as_deref() {
+                    Some(&[single]) => range::build_range_response(file, metadata.len(), single).map_err(open_error)?,
+                    // each part has its own headers, in a body of its own type
+                    Some(ranges) => return Ok(range::build_multipart_range_response(file, metadata.len(), ranges)
+                        .with_header("ETag", &etag)),
+                    None => Response::new(200).with_streamed_body(file, metadata.len())
+                };
+                let response = response.with_header("ETag", &etag).with_header("Accept-Ranges", "bytes");
+                Ok(match negotiated {
+                    Some((_, media_type)) => response.with_header("Content-Type", media_type),
+                    None => response
+                })
+            }
         }
     }
 }
 
-fn create_bad_request_error(description: String) -> Response {
-    Response::PlainText(format!("HTTP/1.1 400 {}\r\n\r\n", description))
-}
\ No newline at end of file
+#[cfg(feature = "tera")]
+impl Website {
+    /// Renders the template route matching the request's path, if there is one.
+    fn render_template_route(&self, request: &Request, url: &str) -> Result<Option<Response>, ServerError> {
+        let path = url.split('?').next().unwrap_or_default();
+        let route = match self.template_routes.iter().find(|route| route.path == path) {
+            Some(route) => route,
+            None => return Ok(None)
+        };
+        let template_path = self.loc.join("templates").join(format!("{}.html.tera", route.template_name));
+        let source = fs::read_to_string(&template_path).map_err(|e| file_error(&template_path, e))?;
+        let rendered = tera::Tera::one_off(&source, &(route.context_fn)(request), true)
+            .map_err(|e| ServerError::Template(format!("rendering {} failed: {:?}", template_path.display(), e)))?;
+        Ok(Some(Response::new(200)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(rendered.into_bytes())))
+    }
+}
+
+/// Turns a failure to read `path` into an error with the matching status, naming the path for the log.
+fn file_error(path: &Path, err: std::io::Error) -> ServerError {
+    match error::status_for_io_error(&err) {
+        404 => ServerError::NotFound(path.display().to_string()),
+        403 => ServerError::Forbidden(path.display().to_string()),
+        _ => ServerError::Io(err)
+    }
+}
+
+/// `path`, or if there's no such file, its variant in the first of the client's preferred languages
+/// that has one, like `index.fr.html` for `index.html`.
+fn localize(request: &Request, path: PathBuf) -> PathBuf {
+    if path.is_file() {
+        return path;
+    }
+    let (stem, extension) = match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(extension)) => (stem.to_string_lossy(), format!(".{}", extension.to_string_lossy())),
+        (Some(stem), None) => (stem.to_string_lossy(), String::new()),
+        _ => return path
+    };
+    request.preferred_languages().iter()
+        .map(|language| path.with_file_name(format!("{}.{}{}", stem, language, extension)))
+        .find(|localized| localized.is_file())
+        .unwrap_or(path)
+}
+
+/// whether `e` just means the client closed the connection on us
+fn is_disconnect(e: &std::io::Error) -> bool {
+    matches!(e.kind(),
+        std::io::ErrorKind::BrokenPipe
+        | std::io::ErrorKind::ConnectionReset
+        | std::io::ErrorKind::ConnectionAborted
+        | std::io::ErrorKind::UnexpectedEof)
+}
+
+impl Website {
+    /// Maps the path of `url` onto the website location, refusing paths that would leave it.
+    fn resolve_within_root(&self, url: &str) -> Result<PathBuf, ServerError> {
+        Ok(self.loc.join(resources::relative_path(url)?))
+    }
+
+    /// Only lets clients in the admin IP allowlist through.
+    #[cfg_attr(not(feature = "prometheus"), allow(dead_code))]
+    fn check_admin(&self, request: &Request) -> Result<(), ServerError> {
+        match request.peer_addr {
+            Some(peer) if self.admin_ip_allowlist.contains(&peer.ip()) => Ok(()),
+            _ => Err(ServerError::Forbidden(format!("{} from {:?}, which isn't an admin", request.url, request.peer_addr)))
+        }
+    }
+
+    /// Refuses to write `path`, a result of `resolve_within_root`, if a symlink on the way there or
+    /// `path` itself leads out of the website location. Only the part of it that exists can be a
+    /// symlink, so this is checked before creating any directories.
+    fn check_writable_within_root(&self, path: &Path) -> Result<(), ServerError> {
+        let existing = path.ancestors()
+            .find(|ancestor| fs::symlink_metadata(ancestor).is_ok())
+            .unwrap_or(&self.loc);
+        resources::check_no_symlink_escape(existing, &self.loc)?;
+        // writing through a dangling symlink would create whatever it points to
+        if existing == path && fs::symlink_metadata(path)?.file_type().is_symlink() && fs::metadata(path).is_err() {
+            return Err(ServerError::Forbidden(format!("{} is a dangling symlink", path.display())));
+        }
+        Ok(())
+    }
+
+    fn check_admin_token(&self, request: &Request) -> Result<(), ServerError> {
+        match (&self.admin_token, request.header("admin_token")) {
+            (Some(expected), Some(given)) if tokens_match(expected, given) => Ok(()),
+            _ => Err(ServerError::Forbidden(format!("{} {} without a valid admin_token", request.method, request.url)))
+        }
+    }
+
+    fn handle_patch(&self, request: &Request) -> Result<Response, ServerError> {
+        let path = resources::normalize_url_path(request.url.split('?').next().unwrap_or_default());
+        match self.patch_routes.get(&path) {
+            Some(handler) => handler(request),
+            // static files can be replaced with PUT, but not patched
+            None => Err(ServerError::MethodNotAllowed(request.method.clone(), ALLOWED_METHODS.to_vec()))
+        }
+    }
+
+    fn handle_put(&self, request: &Request) -> Result<Response, ServerError> {
+        self.check_admin_token(request)?;
+        let path = self.resolve_within_root(&request.url)?;
+        if path == self.loc {
+            return Err(ServerError::BadRequest("PUT needs a file name.".to_string()));
+        }
+        self.check_writable_within_root(&path)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let _upload = self.upload_locks[(hash_path(&path) % UPLOAD_LOCKS as u64) as usize].lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let existed = path.exists();
+        if request.header("If-Match").is_some() || request.header("If-Unmodified-Since").is_some() {
+            // the client may have the file's tag from either way GET sends files
+            let (etags, modified) = match fs::metadata(&path) {
+                Ok(metadata) if metadata.is_file() =>
+                    (vec![etag::for_file(&metadata), etag::for_body(&fs::read(&path)?, None)], metadata.modified().ok()),
+                _ => (vec![], None)
+            };
+            etag::check_preconditions(request, &etags, modified)?;
+        }
+        // written next to the file and renamed over it, so that GETs never see half an upload
+        let file_name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        let upload = path.with_file_name(format!(".{}.upload", file_name));
+        if let Err(e) = fs::write(&upload, &request.body).and_then(|_| fs::rename(&upload, &path)) {
+            let _ = fs::remove_file(&upload);
+            return Err(e.into());
+        }
+        info!("stored {} bytes at {}", request.body.len(), path.display());
+        Ok(if existed {
+            Response::new(204)
+        } else {
+            Response::new(201).with_header("Location", &request.url)
+        })
+    }
+}
+
+/// Reads the configuration file `file` and parses it with `parse`, blaming `file` for whatever goes wrong.
+pub(crate) fn read_config<T>(file: &Path, parse: impl FnOnce(&str) -> Result<T, String>) -> Result<T, WebsiteError> {
+    let text = fs::read_to_string(file).map_err(|e| WebsiteError::BadConfig(file.to_path_buf(), e.to_string()))?;
+    parse(&text).map_err(|e| WebsiteError::BadConfig(file.to_path_buf(), e))
+}
+
+/// The hash that picks the upload lock for `path`.
+fn hash_path(path: &Path) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Whether `given` is the `expected` secret, taking as long to tell for any `given` of the same length,
+/// so that how far a guess got can't be timed.
+fn tokens_match(expected: &str, given: &str) -> bool {
+    let (expected, given) = (expected.as_bytes(), given.as_bytes());
+    expected.len() == given.len() && expected.iter().zip(given).fold(0, |differs, (a, b)| differs | (a ^ b)) == 0
+}
+
+/// Whether requests in `version` can be answered; only HTTP/1.0 and HTTP/1.1 are spoken here.
+fn is_supported_version(version: &str) -> bool {
+    matches!(version, "HTTP/1.0" | "HTTP/1.1")
+}
+
+/// Reads and drops what the client still sends for a moment before the connection is closed.
+/// Closing a socket with unread data resets the connection, which can destroy the response
+/// before the client has read it.
+fn drain_before_close(stream: &mut TcpStream) {
+    let _ = stream.shutdown(std::net::Shutdown::Write);
+    let _ = stream.set_read_timeout(Some(DRAIN_TIMEOUT));
+    let _ = std::io::copy(&mut Read::by_ref(stream).take(MAX_DRAINED), &mut std::io::sink());
+}
+
+fn create_error_response(error: &ServerError, verbose: bool) -> Response {
+    let body = if verbose {
+        error.to_string()
+    } else {
+        error.client_message().to_string()
+    };
+    let response = Response::new(error.status())
+        .with_header("Content-Type", "text/plain; charset=utf-8")
+        .with_body(body.into_bytes());
+    match error {
+        ServerError::MethodNotAllowed(_, allowed) => response.with_header("Allow", &allowed.join(", ")),
+        ServerError::RangeNotSatisfiable(length) => response.with_header("Content-Range", &format!("bytes */{}", length)),
+        _ => response
+    }
+}
+
+/// The response a client gets for `error` when errors aren't verbose, so with a generic message.
+impl From<ServerError> for Response {
+    fn from(error: ServerError) -> Response {
+        create_error_response(&error, false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use crate::server::{Encoding, EventStream, Handler, ProxyHandler, ReloadableSite, ResourceLayout, Response, RouteHandler, Server, ServerError, SiteRule, SiteSelector, SseEvent, TrailingSlash, Website, WebsiteError};
+    use crate::server::etag;
+    use crate::server::request::find_head_end;
+    use crate::server::scratch::ScratchDir;
+
+    /// creates a site folder with a `layout/index.html`, which is removed again once the returned dir is dropped
+    fn fixture_site(name: &str) -> ScratchDir {
+        let dir = ScratchDir::new(&format!("site-{}", name));
+        std::fs::create_dir_all(dir.path().join("layout")).unwrap();
+        std::fs::write(dir.path().join("layout/index.html"), "<h1>hello</h1>").unwrap();
+        dir
+    }
+
+    fn start(site: Website) -> SocketAddr {
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || Website::serve_on(Arc::new(site), server));
+        addr
+    }
+
+    fn request(addr: SocketAddr, raw: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(raw.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn get_routes() {
+        let dir = fixture_site("get-routes");
+        let addr = start(Website::new(&dir).unwrap()
+            .with_get_route("/api/greet", Box::new(|request| Ok(Response::new(200)
+                .with_body(format!("hello from {}", request.url).into_bytes())))));
+        assert!(request(addr, "GET /api/greet?x=1 HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nhello from /api/greet?x=1"));
+        assert!(request(addr, "GET / HTTP/1.1\r\n\r\n").ends_with("<h1>hello</h1>"));
+        assert!(request(addr, "GET /api/greet/more HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        // routes are registered by their normalized path, like the requests they're matched against
+        let addr = start(Website::new(&dir).unwrap()
+            .with_get_route("api//greet", Box::new(|_| Ok(Response::new(200).with_body(b"hi".to_vec())))));
+        for url in ["/api/greet", "//api/./greet"] {
+            assert!(request(addr, &format!("GET {} HTTP/1.1\r\n\r\n", url)).ends_with("\r\n\r\nhi"), "{}", url);
+        }
+    }
+
+    #[test]
+    fn aliases() {
+        let site = fixture_site("aliases");
+        std::fs::write(site.path().join("layout/style.css"), "h1 {}").unwrap();
+        std::fs::write(site.path().join("secret.txt"), "secret").unwrap();
+        let website = Website::new(&site).unwrap()
+            .with_alias("/home", "layout/index.html")
+            .with_alias("/theme.css", "layout/style.css")
+            .with_alias("/gone", "layout/missing.html")
+            .with_alias("/a/b", "./layout/index.html")
+            .with_alias("/my page", "layout/index.html")
+            .with_alias("/passwd", "/etc/passwd")
+            .with_alias("/up", "layout/../../secret.txt");
+        let addr = start(website);
+        let response = request(addr, "GET /home HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n<h1>hello</h1>"));
+        assert!(request(addr, "GET /home?from=nav HTTP/1.1\r\n\r\n").ends_with("<h1>hello</h1>"));
+        assert!(request(addr, "GET /theme.css HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nh1 {}"));
+        assert!(request(addr, "GET /gone HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        // the original url still works
+        assert!(request(addr, "GET / HTTP/1.1\r\n\r\n").ends_with("<h1>hello</h1>"));
+        // other spellings of an aliased path get the alias too
+        for url in ["//home", "/./home", "/x/../home", "/%68ome", "/a%2Fb", "/a//b", "/my%20page"] {
+            assert!(request(addr, &format!("GET {} HTTP/1.1\r\n\r\n", url)).ends_with("\r\n\r\n<h1>hello</h1>"), "{}", url);
+        }
+        // and aliases for files outside the website are left out
+        assert!(request(addr, "GET /passwd HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(request(addr, "GET /up HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn encoded_separators_in_file_names() {
+        let site = fixture_site("encoded-separators");
+        let layout = site.path().join("layout");
+        std::fs::write(layout.join("what?.html"), "<h1>question</h1>").unwrap();
+        std::fs::write(layout.join("c#.html"), "<h1>sharp</h1>").unwrap();
+        std::fs::write(layout.join("my page.html"), "<h1>spaced</h1>").unwrap();
+        let addr = start(Website::new(&site).unwrap().with_encodings(vec![]));
+        assert!(request(addr, "GET /what%3F.html HTTP/1.1\r\n\r\n").ends_with("\r\n\r\n<h1>question</h1>"));
+        assert!(request(addr, "GET /what%3F.html?x=1 HTTP/1.1\r\n\r\n").ends_with("\r\n\r\n<h1>question</h1>"));
+        assert!(request(addr, "GET /c%23.html#top HTTP/1.1\r\n\r\n").ends_with("\r\n\r\n<h1>sharp</h1>"));
+        assert!(request(addr, "GET /my%20page.html HTTP/1.1\r\n\r\n").ends_with("\r\n\r\n<h1>spaced</h1>"));
+        assert!(request(addr, "GET /what HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn localized_files() {
+        let site = fixture_site("localized");
+        let layout = site.path().join("layout");
+        std::fs::remove_file(layout.join("index.html")).unwrap();
+        std::fs::write(layout.join("index.fr.html"), "<h1>bonjour</h1>").unwrap();
+        std::fs::write(layout.join("index.en.html"), "<h1>hello</h1>").unwrap();
+        std::fs::write(layout.join("about.html"), "<h1>about</h1>").unwrap();
+        std::fs::write(layout.join("about.fr.html"), "<h1>à propos</h1>").unwrap();
+        let addr = start(Website::new(&site).unwrap());
+        let response = request(addr, "GET / HTTP/1.1\r\nAccept-Language: fr;q=0.9, en;q=0.8\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\nVary: Accept-Language, Accept-Encoding\r\n"));
+        assert!(response.ends_with("\r\n\r\n<h1>bonjour</h1>"));
+        assert!(request(addr, "GET /index.html HTTP/1.1\r\nAccept-Language: de, en;q=0.5\r\n\r\n").ends_with("<h1>hello</h1>"));
+        // the file itself wins when there is one
+        assert!(request(addr, "GET /about.html HTTP/1.1\r\nAccept-Language: fr\r\n\r\n").ends_with("<h1>about</h1>"));
+        assert!(request(addr, "GET / HTTP/1.1\r\nAccept-Language: de\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(request(addr, "GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn panicking_handlers() {
+        let dir = fixture_site("panics");
+        let site = Website::new(&dir).unwrap()
+            .with_keep_alive(Duration::from_secs(5))
+            .with_get_route("/boom", Box::new(|_| panic!("handler bug")));
+        let addr = start(site);
+        // more panics than there are workers
+        for _ in 0..8 {
+            let response = request(addr, "GET /boom HTTP/1.1\r\n\r\n");
+            assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+            assert!(response.contains("\r\nConnection: close\r\n"));
+            assert!(response.ends_with("\r\n\r\nInternal Server Error"));
+        }
+        assert!(request(addr, "GET / HTTP/1.1\r\nConnection: close\r\n\r\n").ends_with("<h1>hello</h1>"));
+    }
+
+    #[test]
+    fn downloads() {
+        let site = fixture_site("downloads");
+        let layout = site.path().join("layout");
+        std::fs::write(layout.join("report.CSV"), "a,b").unwrap();
+        std::fs::write(layout.join("résumé.css"), "h1 {}").unwrap();
+        let addr = start(Website::new(&site).unwrap().with_mount("/files", &layout).unwrap().with_download_extensions(&["csv"]));
+        let response = request(addr, "GET /index.html?download=1 HTTP/1.1\r\n\r\n");
+        assert!(response.contains("\r\nContent-Disposition: attachment; filename=\"index.html\"\r\n"));
+        assert!(response.ends_with("<h1>hello</h1>"));
+        assert!(!request(addr, "GET /index.html HTTP/1.1\r\n\r\n").contains("Content-Disposition"));
+        assert!(!request(addr, "GET /index.html?download=0 HTTP/1.1\r\n\r\n").contains("Content-Disposition"));
+        assert!(request(addr, "GET /files/report.CSV HTTP/1.1\r\n\r\n")
+            .contains("\r\nContent-Disposition: attachment; filename=\"report.CSV\"\r\n"));
+        assert!(request(addr, "GET /r%C3%A9sum%C3%A9.css?x=1&download=1 HTTP/1.1\r\n\r\n")
+            .contains("\r\nContent-Disposition: attachment; filename=\"r_sum_.css\"; filename*=UTF-8''r%C3%A9sum%C3%A9.css\r\n"));
+    }
+
+    #[test]
+    fn multipart_uploads() {
+        let dir = fixture_site("multipart");
+        let addr = start(Website::new(&dir).unwrap()
+            .with_patch_route("/upload", Box::new(|request| {
+                let parts = request.multipart_parts()?;
+                let summary = parts.iter()
+                    .map(|part| format!("{}={:?} ({} bytes)", part.name, part.filename, part.body.len()))
+                    .collect::<Vec<_>>();
+                Ok(Response::new(200).with_body(summary.join("\n").into_bytes()))
+            })));
+        let body = "--boundary\r\nContent-Disposition: form-data; name=\"caption\"\r\n\r\nsunset\r\n\
+            --boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\nContent-Type: text/plain\r\n\r\n\
+            hello\r\n--boundary--\r\n";
+        let upload = |content_type: &str| request(addr, &format!(
+            "PATCH /upload HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}", content_type, body.len(), body));
+        let response = upload("multipart/form-data; boundary=boundary");
+        assert!(response.ends_with("\r\n\r\ncaption=None (6 bytes)\nfile=Some(\"a.txt\") (5 bytes)"), "{}", response);
+        assert!(upload("multipart/form-data; boundary=other").starts_with("HTTP/1.1 400 "));
+        assert!(upload("text/plain").starts_with("HTTP/1.1 415 "));
+    }
+
+    #[test]
+    fn patch_routes() {
+        let dir = fixture_site("patch-routes");
+        let addr = start(Website::new(&dir).unwrap()
+            .with_max_body_size_for(Some("PATCH"), "/api/", 64)
+            .with_patch_route("/api/settings", Box::new(|request| Ok(Response::new(200)
+                .with_header("Content-Type", request.header("Content-Type").unwrap_or_default())
+                .with_body(request.body.clone())))));
+        let patch = |url: &str, body: &str| request(addr, &format!(
+            "PATCH {} HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", url, body.len(), body));
+        let response = patch("/api/settings", "{\"theme\": \"dark\"}");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\nContent-Type: application/json\r\n"));
+        assert!(response.ends_with("\r\n\r\n{\"theme\": \"dark\"}"));
+        assert!(patch("/api/settings", &"x".repeat(65)).starts_with("HTTP/1.1 413 "));
+        // files are only ever replaced whole
+        let response = patch("/index.html", "{}");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(response.contains("\r\nAllow: GET, PUT\r\n"));
+        // and a path that only takes PATCH can't be read
+        for url in ["/api/settings", "/api/settings?x=1", "/api//settings"] {
+            let response = request(addr, &format!("GET {} HTTP/1.1\r\n\r\n", url));
+            assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"), "{}", url);
+            assert!(response.contains("\r\nAllow: PATCH\r\n"), "{}", url);
+        }
+
+        // GET goes by the rewritten path, the same as GET routes do
+        let rewrites = format!("{}/rewrites", dir);
+        std::fs::write(&rewrites, "^/settings$ -> /api/settings\n^/api/legacy$ -> /index.html\n").unwrap();
+        let patch_only = || -> RouteHandler { Box::new(|_| Ok(Response::new(204))) };
+        let addr = start(Website::new(&dir).unwrap().with_rewrites_file(&rewrites).unwrap()
+            .with_patch_route("/api/settings", patch_only())
+            .with_patch_route("/api/legacy", patch_only()));
+        assert!(request(addr, "GET /settings HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(request(addr, "GET /api/legacy HTTP/1.1\r\n\r\n").ends_with("\r\n\r\n<h1>hello</h1>"));
+    }
+
+    #[test]
+    fn error_responses() {
+        let header = |response: &Response, name: &str| response.headers.iter()
+            .find(|(key, _)| key == name).map(|(_, value)| value.clone());
+        let response = Response::from(ServerError::NotFound("/srv/site/layout/a.html".to_string()));
+        assert_eq!((response.status, response.body.as_slice()), (404, &b"Not Found"[..]));
+        assert_eq!(header(&response, "Content-Type").as_deref(), Some("text/plain; charset=utf-8"));
+        let response = Response::from(ServerError::MethodNotAllowed("DELETE".to_string(), vec!["GET", "PUT"]));
+        assert_eq!((response.status, header(&response, "Allow").as_deref()), (405, Some("GET, PUT")));
+        let response = Response::from(ServerError::RangeNotSatisfiable(10));
+        assert_eq!((response.status, header(&response, "Content-Range").as_deref()), (416, Some("bytes */10")));
+        let response = Response::from(ServerError::BadGateway("127.0.0.1:9000 refused".to_string()));
+        assert_eq!((response.status, response.reason.as_str(), response.body.as_slice()), (502, "Bad Gateway", &b"Bad Gateway"[..]));
+        assert_eq!(Response::from(ServerError::GatewayTimeout("took 30s".to_string())).status, 504);
+        assert_eq!(Response::from(ServerError::Io(std::io::Error::from(std::io::ErrorKind::PermissionDenied))).status, 403);
+    }
+
+    #[test]
+    fn split_request_line() {
+        let dir = fixture_site("split-request-line");
+        let addr = start(Website::new(&dir).unwrap());
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_nodelay(true).unwrap();
+        // a slow client's request line arrives in two reads, and only the whole of it names the page
+        stream.write_all(b"GET /index.ht").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        stream.write_all(b"ml HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("<h1>hello</h1>"));
+    }
+
+    #[test]
+    fn serve_on_bound_server() {
+        let dir = fixture_site("serve-on");
+        let addr = start(Website::new(&dir).unwrap());
+        let response = request(addr, "GET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("<h1>hello</h1>"));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn address_in_use() {
+        let taken = Server::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap().to_string();
+        assert!(matches!(Server::bind(&addr), Err(ServerError::Io(e)) if e.kind() == std::io::ErrorKind::AddrInUse));
+        let dir = fixture_site("address-in-use");
+        let site = Arc::new(Website::new(&dir).unwrap());
+        assert!(matches!(super::main(site, &addr), Err(ServerError::Io(_))));
+        assert!(matches!(Server::bind("not an address"), Err(ServerError::Io(_))));
+    }
+
+    #[test]
+    fn shutdown_returns_from_serve() {
+        let server = Server::bind("127.0.0.1:0").unwrap()
+            .with_shutdown_grace(Duration::from_millis(100));
+        let handle = server.shutdown_handle().unwrap();
+        let dir = fixture_site("shutdown");
+        let site = Arc::new(Website::new(&dir).unwrap());
+        let serving = thread::spawn(move || Website::serve_on(site, server));
+        handle.shutdown();
+        assert!(serving.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn several_acceptors() {
+        let server = Server::bind("127.0.0.1:0").unwrap()
+            .with_acceptors(4)
+            .with_shutdown_grace(Duration::from_millis(100));
+        let (addr, handle) = (server.local_addr().unwrap(), server.shutdown_handle().unwrap());
+        let dir = fixture_site("acceptors");
+        let site = Arc::new(Website::new(&dir).unwrap());
+        let serving = thread::spawn(move || Website::serve_on(site, server));
+        let clients = (0..16).map(|_| thread::spawn(move || request(addr, "GET / HTTP/1.1\r\n\r\n")))
+            .collect::<Vec<_>>();
+        assert!(clients.into_iter().all(|client| client.join().unwrap().ends_with("<h1>hello</h1>")));
+        // waking up one acceptor stops them all
+        handle.shutdown();
+        assert!(serving.join().unwrap().is_ok());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn sigterm_drains_connections() {
+        struct Slow(Mutex<mpsc::Sender<&'static str>>);
+        impl Handler for Slow {
+            fn handle_connection(&self, _: TcpStream) {
+                self.0.lock().unwrap().send("started").unwrap();
+                thread::sleep(Duration::from_millis(300));
+                self.0.lock().unwrap().send("finished").unwrap();
+            }
+        }
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        server.shutdown_handle().unwrap().shutdown_on_sigterm().unwrap();
+        let (sender, events) = mpsc::channel();
+        let serving = thread::spawn(move || server.run(Arc::new(Slow(Mutex::new(sender)))));
+        let _connection = TcpStream::connect(addr).unwrap();
+        assert_eq!(events.recv().unwrap(), "started");
+        signal_hook::low_level::raise(signal_hook::consts::SIGTERM).unwrap();
+        assert!(serving.join().unwrap().is_ok());
+        assert_eq!(events.try_recv(), Ok("finished"));
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn sighup_reloads_site() {
+        let old = fixture_site("reload-old");
+        let new = fixture_site("reload-new");
+        std::fs::write(format!("{}/layout/index.html", new), "<h1>new</h1>").unwrap();
+        let current = std::env::temp_dir().join(format!("simple-rust-webserver-reload-{}", std::process::id()));
+        let _ = std::fs::remove_file(&current);
+        std::os::unix::fs::symlink(&old, &current).unwrap();
+        let root = current.clone();
+        let site = Arc::new(ReloadableSite::new(Box::new(move || Ok(Website::new(&root)?.with_encodings(vec![])))).unwrap());
+        site.reload_on_sighup().unwrap();
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let serving = Arc::clone(&site);
+        thread::spawn(move || server.run(serving));
+        assert!(request(addr, "GET / HTTP/1.1\r\n\r\n").ends_with("<h1>hello</h1>"));
+
+        std::fs::remove_file(&current).unwrap();
+        std::os::unix::fs::symlink(&new, &current).unwrap();
+        signal_hook::low_level::raise(signal_hook::consts::SIGHUP).unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !request(addr, "GET / HTTP/1.1\r\n\r\n").ends_with("<h1>new</h1>") {
+            assert!(std::time::Instant::now() < deadline, "site wasn't reloaded");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // a site that can't be built doesn't replace the one being served
+        std::fs::remove_file(&current).unwrap();
+        assert!(site.reload().is_err());
+        assert!(request(addr, "GET / HTTP/1.1\r\n\r\n").ends_with("<h1>new</h1>"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn sighup_rereads_config_files() {
+        let site = fixture_site("reload-config");
+        let redirects = site.path().join("redirects");
+        std::fs::write(&redirects, "/old -> /a").unwrap();
+        let (root, file) = (site.path().to_path_buf(), redirects.clone());
+        let site = Arc::new(ReloadableSite::new(Box::new(move || Website::new(&root)?
+            .with_keep_alive(Duration::from_secs(5))
+            .with_redirects_file(&file))).unwrap());
+        site.reload_on_sighup().unwrap();
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let serving = Arc::clone(&site);
+        thread::spawn(move || server.run(serving));
+        let mut kept_alive = TcpStream::connect(addr).unwrap();
+        let mut redirect_on_kept_alive = || {
+            kept_alive.write_all(b"GET /old HTTP/1.1\r\n\r\n").unwrap();
+            let mut response = vec![];
+            let mut buf = [0; 1024];
+            while !response.ends_with(b"\r\n\r\n") {
+                let n = kept_alive.read(&mut buf).unwrap();
+                assert!(n > 0, "connection closed");
+                response.extend_from_slice(&buf[..n]);
+            }
+            String::from_utf8(response).unwrap()
+        };
+        assert!(redirect_on_kept_alive().contains("\r\nLocation: /a\r\n"));
+
+        std::fs::write(&redirects, "/old -> /b").unwrap();
+        signal_hook::low_level::raise(signal_hook::consts::SIGHUP).unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !request(addr, "GET /old HTTP/1.1\r\nConnection: close\r\n\r\n").contains("\r\nLocation: /b\r\n") {
+            assert!(std::time::Instant::now() < deadline, "config wasn't reloaded");
+            thread::sleep(Duration::from_millis(10));
+        }
+        // a connection accepted before the reload keeps being served by the old config
+        assert!(redirect_on_kept_alive().contains("\r\nLocation: /a\r\n"));
+    }
+
+    #[test]
+    fn event_streams() {
+        let (sender, streams) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let dir = fixture_site("event-streams");
+        let site = Website::new(&dir).unwrap()
+            .with_max_event_streams(1)
+            .with_event_stream("/events", Box::new(move |_, stream| sender.lock().unwrap().send(stream).unwrap()));
+        let addr = start(site);
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /events?since=0 HTTP/1.1\r\n\r\n").unwrap();
+        let mut stream: EventStream = streams.recv().unwrap();
+        // the worker is free again, and the stream is pushed to from another thread
+        assert!(request(addr, "GET /events HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(request(addr, "GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200 OK\r\n"));
+        let pushing = thread::spawn(move || {
+            for i in 1..=3 {
+                stream.send(&SseEvent::new(&format!("tick\n{}", i)).with_id(&i.to_string())).unwrap();
+            }
+            stream
+        });
+        let mut stream = pushing.join().unwrap();
+        let expected = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n\
+                        id: 1\ndata: tick\ndata: 1\n\nid: 2\ndata: tick\ndata: 2\n\nid: 3\ndata: tick\ndata: 3\n\n";
+        let mut received = vec![0; expected.len()];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(String::from_utf8(received).unwrap(), expected);
+
+        drop(client);
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while stream.send(&SseEvent::new("anyone there?")).is_ok() {
+            assert!(std::time::Instant::now() < deadline, "disconnect went unnoticed");
+            thread::sleep(Duration::from_millis(10));
+        }
+        drop(stream);
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET /events HTTP/1.1\r\n\r\n").unwrap();
+        let stream = streams.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // other spellings of the path open the same stream
+        drop((client, stream));
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET //./events?since=3 HTTP/1.1\r\n\r\n").unwrap();
+        assert!(streams.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+
+    #[test]
+    fn event_streams_are_checked_first() {
+        let dir = fixture_site("event-streams-checked");
+        let site = Website::new(&dir).unwrap()
+            .with_canonical_host("https", "example.com")
+            .with_event_stream("/events", Box::new(|_, _| panic!("the stream shouldn't have been opened")));
+        let addr = start(site);
+        let response = request(addr, "GET /events HTTP/1.1\r\nHost: www.example.com\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+        assert!(response.contains("\r\nLocation: https://example.com/events\r\n"));
+        assert!(request(addr, "GET /events HTTP/2.0\r\n\r\n").starts_with("HTTP/1.1 505 "));
+    }
+
+    #[test]
+    fn bare_lf_requests() {
+        let dir = fixture_site("bare-lf");
+        let addr = start(Website::new(&dir).unwrap());
+        assert!(request(addr, "GET / HTTP/1.1\nHost: localhost\n\n").starts_with("HTTP/1.1 200 OK"));
+        let strict_dir = fixture_site("bare-lf-strict");
+        let strict = start(Website::new(&strict_dir).unwrap().with_strict_line_endings(true));
+        assert!(request(strict, "GET / HTTP/1.1\r\nHost: localhost\n\r\n").starts_with("HTTP/1.1 400"));
+        assert!(request(strict, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[test]
+    fn error_statuses() {
+        let dir = fixture_site("error-statuses");
+        let addr = start(Website::new(&dir).unwrap());
+        assert!(request(addr, "GET /missing.html HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(request(addr, "GET /mystery HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = request(addr, "GET\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(response.ends_with("\r\n\r\nBadly formatted HTTP request."));
+    }
+
+    #[test]
+    fn error_verbosity() {
+        let site = fixture_site("verbosity");
+        let terse = request(start(Website::new(&site).unwrap()), "GET /missing.html HTTP/1.1\r\n\r\n");
+        let verbose = request(start(Website::new(&site).unwrap().with_verbose_errors(true)), "GET /missing.html HTTP/1.1\r\n\r\n");
+        assert!(terse.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(verbose.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(terse.ends_with("\r\n\r\nNot Found"));
+        assert!(!terse.contains(&*site));
+        assert!(verbose.ends_with("layout/missing.html"));
+        assert!(verbose.contains(&*site));
+    }
+
+    #[test]
+    fn version_statuses() {
+        let dir = fixture_site("versions");
+        let addr = start(Website::new(&dir).unwrap());
+        assert!(request(addr, "GET / HTTP/2.0\r\n\r\n").starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"));
+        assert!(request(addr, "GET / HTTP/1.2\r\n\r\n").starts_with("HTTP/1.1 505 "));
+        assert!(request(addr, "GET / HTTP/1.0\r\n\r\n").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(request(addr, "GET / HTTP/6.9\r\n\r\n").starts_with("HTTP/6.9 420 "));
+    }
+
+    #[test]
+    fn default_favicon() {
+        let site = fixture_site("favicon");
+        let mut stream = TcpStream::connect(start(Website::new(&site).unwrap())).unwrap();
+        stream.write_all(b"GET /favicon.ico HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(String::from_utf8_lossy(&response).contains("\r\nContent-Type: image/x-icon\r\n"));
+        assert!(response.ends_with(super::DEFAULT_FAVICON));
+
+        let disabled = start(Website::new(&site).unwrap().with_default_favicon(false));
+        assert!(request(disabled, "GET /favicon.ico HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        std::fs::write(format!("{}/layout/favicon.ico", site), "own").unwrap();
+        let response = request(start(Website::new(&site).unwrap()), "GET /favicon.ico HTTP/1.1\r\n\r\n");
+        assert!(response.ends_with("\r\n\r\nown"));
+    }
+
+    #[test]
+    fn cache_control_by_extension() {
+        let site = fixture_site("cache-control");
+        std::fs::write(format!("{}/layout/style.css", site), "h1 {}").unwrap();
+        std::fs::write(format!("{}/layout/logo.png", site), "png").unwrap();
+        std::fs::write(format!("{}/layout/photo.jpg", site), "jpg").unwrap();
+        let addr = start(Website::new(&site).unwrap()
+            .with_cache_control(&["js", "css", "PNG"], "public, max-age=31536000, immutable")
+            .with_cache_control(&["html"], "no-cache"));
+        let immutable = "\r\nCache-Control: public, max-age=31536000, immutable\r\n";
+        assert!(request(addr, "GET /style.css HTTP/1.1\r\n\r\n").contains(immutable));
+        assert!(request(addr, "GET /logo.png HTTP/1.1\r\n\r\n").contains(immutable));
+        assert!(request(addr, "GET / HTTP/1.1\r\n\r\n").contains("\r\nCache-Control: no-cache\r\n"));
+        assert!(!request(addr, "GET /photo.jpg HTTP/1.1\r\n\r\n").contains("Cache-Control"));
+        assert!(!request(addr, "GET /missing.css HTTP/1.1\r\n\r\n").contains("Cache-Control"));
+        // revalidations keep the header
+        let response = request(addr, "GET /style.css HTTP/1.1\r\nIf-None-Match: *\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+        assert!(response.contains(immutable));
+
+        let default = start(Website::new(&site).unwrap());
+        assert!(!request(default, "GET /style.css HTTP/1.1\r\n\r\n").contains("Cache-Control"));
+    }
+
+    #[test]
+    fn method_statuses() {
+        let dir = fixture_site("method-statuses");
+        let addr = start(Website::new(&dir).unwrap());
+        assert!(request(addr, "GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200 OK\r\n"));
+        // PUT is served, just not without an admin token
+        assert!(request(addr, "PUT /a.html HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        let response = request(addr, "DELETE / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(response.contains("\r\nAllow: GET, PUT\r\n"));
+        assert!(request(addr, "FROBNICATE / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 501 Not Implemented\r\n"));
+        let response = request(addr, "G{T / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(response.ends_with("\r\n\r\nInvalid method."));
+        assert!(request(addr, "garbage\r\n\r\n").starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn not_modified() {
+        let dir = fixture_site("not-modified");
+        let addr = start(Website::new(&dir).unwrap());
+        let response = request(addr, "GET / HTTP/1.1\r\n\r\n");
+        let etag = response.lines().find_map(|line| line.strip_prefix("ETag: ")).unwrap().to_string();
+        let response = request(addr, &format!("GET / HTTP/1.1\r\nIf-None-Match: {}\r\n\r\n", etag));
+        assert!(response.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+        assert!(response.contains(&format!("\r\nETag: {}\r\n", etag)));
+        assert!(!response.contains("Content-Length"));
+        assert!(response.ends_with("\r\n\r\n"));
+        let response = request(addr, "GET / HTTP/1.1\r\nIf-None-Match: \"stale\"\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n<h1>hello</h1>"));
+    }
+
+    #[test]
+    fn not_modified_without_reading() {
+        let site = fixture_site("not-modified-unread");
+        // reading this file as text fails, so only a response that never read it can succeed
+        let path = format!("{}/layout/broken.css", site);
+        std::fs::write(&path, [0xff, 0xfe, 0xfd]).unwrap();
+        let addr = start(Website::new(&site).unwrap());
+        let response = request(addr, "GET /broken.css HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 500 "));
+        let metadata = std::fs::metadata(&path).unwrap();
+        for (accept_encoding, etag) in [("", etag::for_encoded_file(&metadata, None)),
+                                        ("Accept-Encoding: gzip\r\n", etag::for_encoded_file(&metadata, Some(Encoding::Gzip)))] {
+            let response = request(addr, &format!("GET /broken.css HTTP/1.1\r\n{}If-None-Match: {}\r\n\r\n", accept_encoding, etag));
+            assert!(response.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+            assert!(response.contains(&format!("\r\nETag: {}\r\n", etag)));
+        }
+    }
+
+    #[test]
+    fn precondition_failed() {
+        let dir = fixture_site("preconditions");
+        let addr = start(Website::new(&dir).unwrap().with_encodings(vec![]).with_admin_token("letmein"));
+        let response = request(addr, "GET /index.html HTTP/1.1\r\n\r\n");
+        let etag = response.lines().find_map(|line| line.strip_prefix("ETag: ")).unwrap().to_string();
+        let get = |headers: &str| request(addr, &format!("GET /index.html HTTP/1.1\r\n{}\r\n\r\n", headers));
+        assert!(get(&format!("If-Match: {}", etag)).starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get("If-Match: *").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get("If-Match: \"stale\"").starts_with("HTTP/1.1 412 Precondition Failed\r\n"));
+        assert!(get(&format!("If-Match: W/{}", etag)).starts_with("HTTP/1.1 412 Precondition Failed\r\n"));
+        assert!(get("If-Unmodified-Since: Sun, 06 Nov 1994 08:49:37 GMT").starts_with("HTTP/1.1 412 Precondition Failed\r\n"));
+        assert!(get("If-Unmodified-Since: Fri, 01 Jan 2100 00:00:00 GMT").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get("If-Unmodified-Since: someday").starts_with("HTTP/1.1 200 OK\r\n"));
+        // If-Match wins over If-Unmodified-Since
+        assert!(get(&format!("If-Match: {}\r\nIf-Unmodified-Since: Sun, 06 Nov 1994 08:49:37 GMT", etag))
+            .starts_with("HTTP/1.1 200 OK\r\n"));
+
+        // an upload only replaces the version the client has seen
+        let put_if = |if_match: &str| request(addr, &format!(
+            "PUT /layout/index.html HTTP/1.1\r\nadmin_token: letmein\r\nIf-Match: {}\r\nContent-Length: 3\r\n\r\nnew", if_match));
+        assert!(put_if("\"stale\"").starts_with("HTTP/1.1 412 Precondition Failed\r\n"));
+        assert!(get("").ends_with("<h1>hello</h1>"));
+        assert!(put_if(&etag).starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(put_if(&etag).starts_with("HTTP/1.1 412 Precondition Failed\r\n"));
+        assert!(request(addr, "PUT /layout/missing.html HTTP/1.1\r\nadmin_token: letmein\r\nIf-Match: *\r\nContent-Length: 0\r\n\r\n")
+            .starts_with("HTTP/1.1 412 Precondition Failed\r\n"));
+
+        // of uploads racing to replace the same version, only one does
+        let response = get("");
+        let etag = response.lines().find_map(|line| line.strip_prefix("ETag: ")).unwrap().to_string();
+        let uploads: Vec<_> = (0..8).map(|i| {
+            let etag = etag.clone();
+            thread::spawn(move || {
+                let body = "x".repeat(10 + i);
+                request(addr, &format!("PUT /layout/index.html HTTP/1.1\r\nadmin_token: letmein\r\nIf-Match: {}\r\n\
+                                        Content-Length: {}\r\n\r\n{}", etag, body.len(), body))
+            })
+        }).collect();
+        let responses: Vec<_> = uploads.into_iter().map(|upload| upload.join().unwrap()).collect();
+        assert_eq!(responses.iter().filter(|response| response.starts_with("HTTP/1.1 204 ")).count(), 1, "{:?}", responses);
+        assert_eq!(responses.iter().filter(|response| response.starts_with("HTTP/1.1 412 ")).count(), 7, "{:?}", responses);
+    }
+
+    #[test]
+    fn compressed_responses() {
+        let dir = fixture_site("compressed");
+        let addr = start(Website::new(&dir).unwrap());
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n").unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
+        let body_start = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8_lossy(&response[..body_start]);
+        assert!(head.contains("Content-Encoding: gzip\r\n"));
+        assert!(head.contains("Vary: Accept-Encoding\r\n"));
+        let mut body = String::new();
+        flate2::read::GzDecoder::new(&response[body_start..]).read_to_string(&mut body).unwrap();
+        assert_eq!(body, "<h1>hello</h1>");
+
+        let plain_dir = fixture_site("uncompressed");
+        let plain = start(Website::new(&plain_dir).unwrap().with_encodings(vec![]));
+        let response = request(plain, "GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+        assert!(!response.contains("Content-Encoding"));
+        assert!(!response.contains("Vary"));
+        assert!(response.ends_with("<h1>hello</h1>"));
+    }
+
+    #[test]
+    fn vary_only_when_negotiated() {
+        let site = fixture_site("vary");
+        std::fs::write(format!("{}/layout/logo.png", site), "not really a png").unwrap();
+        let addr = start(Website::new(&site).unwrap());
+        // images are never compressed, so Accept-Encoding doesn't matter for them
+        let response = request(addr, "GET /logo.png HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(!response.contains("Vary"));
+        // text could have been compressed, so even the identity response varies
+        let response = request(addr, "GET / HTTP/1.1\r\n\r\n");
+        assert!(!response.contains("Content-Encoding"));
+        assert!(response.contains("Vary: Accept-Encoding\r\n"));
+    }
+
+    #[test]
+    fn client_hanging_up_mid_download() {
+        let site = fixture_site("hang-up");
+        std::fs::write(format!("{}/layout/big.png", site), vec![b'x'; 16 * 1024 * 1024]).unwrap();
+        let addr = start(Website::new(&site).unwrap());
+        // more aborted downloads than there are workers, so every worker sees at least one
+        for _ in 0..8 {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(b"GET /big.png HTTP/1.1\r\n\r\n").unwrap();
+            let mut head = [0; 64];
+            stream.read_exact(&mut head).unwrap();
+            assert!(head.starts_with(b"HTTP/1.1 200 OK"));
+        }
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("<h1>hello</h1>"));
+    }
+
+    #[test]
+    fn templated_pages() {
+        let site = fixture_site("templating");
+        std::fs::write(format!("{}/layout/page.html", site), "{{include:nav.html}}<p>{{year}}</p>").unwrap();
+        std::fs::write(format!("{}/layout/nav.html", site), "<nav>home</nav>").unwrap();
+        let addr = start(Website::new(&site).unwrap().with_templating(true).with_encodings(vec![]));
+        let year = chrono::Datelike::year(&chrono::Local::now());
+        assert!(request(addr, "GET /page.html HTTP/1.1\r\n\r\n")
+            .ends_with(&format!("\r\n\r\n<nav>home</nav><p>{}</p>", year)));
+
+        let addr = start(Website::new(&site).unwrap().with_encodings(vec![]));
+        assert!(request(addr, "GET /page.html HTTP/1.1\r\n\r\n")
+            .ends_with("\r\n\r\n{{include:nav.html}}<p>{{year}}</p>"));
+    }
+
+    #[cfg(feature = "tera")]
+    #[test]
+    fn tera_template_routes() {
+        let site = fixture_site("tera");
+        std::fs::create_dir_all(format!("{}/templates", site)).unwrap();
+        std::fs::write(format!("{}/templates/greet.html.tera", site), "<h1>{{ greeting }}</h1>").unwrap();
+        let mut website = Website::new(&site).unwrap();
+        website.add_template_route("/greet", "greet", Box::new(|_| {
+            let mut context = tera::Context::new();
+            context.insert("greeting", "Hello, World!");
+            context
+        }));
+        website.add_template_route("/missing", "missing", Box::new(|_| tera::Context::new()));
+        let addr = start(website);
+        assert!(request(addr, "GET /greet?x=1 HTTP/1.1\r\n\r\n").ends_with("\r\n\r\n<h1>Hello, World!</h1>"));
+        assert!(request(addr, "GET /missing HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    fn put(addr: SocketAddr, url: &str, token: &str, body: &str) -> String {
+        request(addr, &format!(
+            "PUT {} HTTP/1.1\r\nadmin_token: {}\r\nContent-Length: {}\r\n\r\n{}",
+            url, token, body.len(), body))
+    }
+
+    #[test]
+    fn put_uploads() {
+        let site = fixture_site("put");
+        let addr = start(Website::new(&site).unwrap().with_admin_token("letmein"));
+        let response = put(addr, "/layout/new.html", "letmein", "<p>new</p>");
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"));
+        assert!(response.contains("Location: /layout/new.html\r\n"));
+        assert_eq!(std::fs::read_to_string(format!("{}/layout/new.html", site)).unwrap(), "<p>new</p>");
+        assert!(request(addr, "GET /new.html HTTP/1.1\r\n\r\n").ends_with("<p>new</p>"));
+
+        let response = put(addr, "/layout/new.html", "letmein", "<p>newer</p>");
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(!response.contains("Content-Length"));
+        assert_eq!(std::fs::read_to_string(format!("{}/layout/new.html", site)).unwrap(), "<p>newer</p>");
+
+        // new directories are fine as long as they're inside the site
+        assert!(put(addr, "/uploads/a/b.txt", "letmein", "b").starts_with("HTTP/1.1 201 Created\r\n"));
+        assert_eq!(std::fs::read_to_string(format!("{}/uploads/a/b.txt", site)).unwrap(), "b");
+    }
+
+    #[test]
+    fn put_rejections() {
+        let site = fixture_site("put-rejections");
+        let addr = start(Website::new(&site).unwrap().with_admin_token("letmein"));
+        for guess in ["guess", "letmei", "letmein!", "letmeim"] {
+            assert!(put(addr, "/layout/a.html", guess, "a").starts_with("HTTP/1.1 403 Forbidden\r\n"), "{}", guess);
+        }
+        assert!(request(addr, "PUT /layout/a.html HTTP/1.1\r\nContent-Length: 1\r\n\r\na")
+            .starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(put(addr, "/../escaped.txt", "letmein", "a").starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(put(addr, "/layout/../../escaped.txt", "letmein", "a").starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(!std::path::Path::new(&format!("{}/../escaped.txt", site)).exists());
+        assert!(!std::path::Path::new(&format!("{}/layout/a.html", site)).exists());
+
+        // uploads are off without a token
+        let dir = fixture_site("put-disabled");
+        let addr = start(Website::new(&dir).unwrap());
+        assert!(put(addr, "/layout/a.html", "", "a").starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn put_through_symlinks() {
+        let site = fixture_site("put-symlinks");
+        let outside = fixture_site("put-symlinks-outside");
+        std::fs::write(format!("{}/secret.txt", outside), "secret").unwrap();
+        std::os::unix::fs::symlink(format!("{}/secret.txt", outside), format!("{}/layout/secret.txt", site)).unwrap();
+        std::os::unix::fs::symlink(format!("{}/nothing.txt", outside), format!("{}/layout/dangling.txt", site)).unwrap();
+        std::os::unix::fs::symlink(&outside, format!("{}/out", site)).unwrap();
+        std::os::unix::fs::symlink(format!("{}/layout", site), format!("{}/inside", site)).unwrap();
+        let addr = start(Website::new(&site).unwrap().with_admin_token("letmein"));
+
+        assert!(put(addr, "/layout/secret.txt", "letmein", "pwned").starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert_eq!(std::fs::read_to_string(format!("{}/secret.txt", outside)).unwrap(), "secret");
+        assert!(put(addr, "/layout/dangling.txt", "letmein", "pwned").starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(!std::path::Path::new(&format!("{}/nothing.txt", outside)).exists());
+        assert!(put(addr, "/out/new.txt", "letmein", "pwned").starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(put(addr, "/out/deep/new.txt", "letmein", "pwned").starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(!std::path::Path::new(&format!("{}/new.txt", outside)).exists());
+        assert!(!std::path::Path::new(&format!("{}/deep", outside)).exists());
+        // symlinks that stay inside the site are fine
+        assert!(put(addr, "/inside/linked.txt", "letmein", "ok").starts_with("HTTP/1.1 201 Created\r\n"));
+        assert_eq!(std::fs::read_to_string(format!("{}/layout/linked.txt", site)).unwrap(), "ok");
+    }
+
+    #[test]
+    fn expect_continue() {
+        let site = fixture_site("expect-continue");
+        let addr = start(Website::new(&site).unwrap().with_admin_token("letmein").with_max_body_size(16));
+        let put = |headers: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(format!("PUT /layout/a.txt HTTP/1.1\r\n{}\r\n", headers).as_bytes()).unwrap();
+            // the body is only sent once the server asks for it
+            let mut interim = [0; 25];
+            stream.read_exact(&mut interim).unwrap();
+            if &interim == b"HTTP/1.1 100 Continue\r\n\r\n" {
+                stream.write_all(b"0123456789").unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).unwrap();
+                format!("100 then {}", response)
+            } else {
+                let mut response = String::from_utf8_lossy(&interim).into_owned();
+                stream.read_to_string(&mut response).unwrap();
+                response
+            }
+        };
+        let response = put("admin_token: letmein\r\nExpect: 100-continue\r\nContent-Length: 10\r\n");
+        assert!(response.starts_with("100 then HTTP/1.1 201 Created\r\n"), "{}", response);
+        assert_eq!(std::fs::read_to_string(format!("{}/layout/a.txt", site)).unwrap(), "0123456789");
+
+        let response = put("admin_token: letmein\r\nExpect: 100-continue\r\nContent-Length: 1000\r\n");
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"), "{}", response);
+        assert!(response.contains("\r\nConnection: close\r\n"));
+        let response = put("admin_token: guess\r\nExpect: 100-continue\r\nContent-Length: 10\r\n");
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"), "{}", response);
+        let response = put("admin_token: letmein\r\nExpect: magic\r\nContent-Length: 10\r\n");
+        assert!(response.starts_with("HTTP/1.1 417 Expectation Failed\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn body_size_limits() {
+        let site = fixture_site("body-size");
+        let website = Website::new(&site).unwrap()
+            .with_admin_token("letmein")
+            .with_max_body_size(1000)
+            .with_max_body_size_for(None, "/uploads/", 10)
+            .with_max_body_size_for(Some("PUT"), "/uploads/", 100_000)
+            .with_max_body_size_for(Some("PUT"), "/uploads/small/", 100);
+        let addr = start(website);
+        let send = |url: &str, size: usize| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(format!("PUT {} HTTP/1.1\r\nadmin_token: letmein\r\nContent-Length: {}\r\n\r\n", url, size).as_bytes()).unwrap();
+            stream.write_all(&vec![b'x'; size]).unwrap();
+            // the response arrives intact and the connection ends without a reset
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = send("/layout/big.txt", 50_000);
+        assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"), "{}", response);
+        assert!(response.contains("\r\nConnection: close\r\n"));
+        assert!(send("/layout/small.txt", 1000).starts_with("HTTP/1.1 201 Created\r\n"));
+        assert!(send("/uploads/big.txt", 50_000).starts_with("HTTP/1.1 201 Created\r\n"));
+        assert!(send("/uploads/small/big.txt", 1000).starts_with("HTTP/1.1 413 "));
+        assert!(!std::path::Path::new(&format!("{}/layout/big.txt", site)).exists());
+    }
+
+    #[test]
+    fn truncated_bodies() {
+        let site = fixture_site("truncated-body");
+        let addr = start(Website::new(&site).unwrap().with_admin_token("letmein").with_keep_alive(Duration::from_secs(5)));
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"PUT /layout/a.txt HTTP/1.1\r\nadmin_token: letmein\r\nContent-Length: 20\r\n\r\n0123456789").unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(response.ends_with("\r\n\r\nContent-Length mismatch."));
+        assert!(response.contains("\r\nConnection: close\r\n"));
+        assert!(!std::path::Path::new(&format!("{}/layout/a.txt", site)).exists());
+    }
+
+    #[test]
+    fn pipelined_requests() {
+        let site = fixture_site("pipelining");
+        std::fs::write(format!("{}/layout/second.html", site), "<h1>second</h1>").unwrap();
+        let addr = start(Website::new(&site).unwrap().with_encodings(vec![]).with_keep_alive(Duration::from_secs(5)));
+        let response = request(addr, "GET / HTTP/1.1\r\n\r\n\
+            PUT /body HTTP/1.1\r\nContent-Length: 4\r\n\r\nGET \
+            GET /second.html HTTP/1.1\r\nConnection: close\r\n\r\n");
+        let first = response.find("<h1>hello</h1>").unwrap();
+        let forbidden = response.find("HTTP/1.1 403 Forbidden\r\n").unwrap();
+        let second = response.find("<h1>second</h1>").unwrap();
+        assert!(first < forbidden && forbidden < second, "{}", response);
+        assert_eq!(response.matches("Connection: keep-alive\r\n").count(), 2);
+        assert!(response.ends_with("Connection: close\r\nContent-Length: 15\r\n\r\n<h1>second</h1>"));
+    }
+
+    #[test]
+    fn keep_alive_times_out() {
+        let dir = fixture_site("keep-alive");
+        let addr = start(Website::new(&dir).unwrap()
+            .with_encodings(vec![])
+            .with_keep_alive(Duration::from_millis(200)));
+        let started = std::time::Instant::now();
+        let response = request(addr, "GET / HTTP/1.1\r\n\r\nGET / HTTP/1.0\r\n\r\n");
+        assert_eq!(response.matches("<h1>hello</h1>").count(), 2);
+        assert!(response.ends_with("Connection: close\r\nContent-Length: 14\r\n\r\n<h1>hello</h1>"));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let started = std::time::Instant::now();
+        let response = request(addr, "GET / HTTP/1.1\r\n\r\n");
+        assert!(response.ends_with("Connection: keep-alive\r\nContent-Length: 14\r\n\r\n<h1>hello</h1>"));
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn hang_up_without_request() {
+        let dir = fixture_site("hang-up");
+        let addr = start(Website::new(&dir).unwrap());
+        for sent in ["", "GET / HT", "GET / HTTP/1.1\r\nHost: exa"] {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(sent.as_bytes()).unwrap();
+            stream.shutdown(std::net::Shutdown::Write).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert_eq!(response, "", "after sending {:?}", sent);
+        }
+        // the workers are all still there
+        assert!(request(addr, "GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn websocket_upgrade() {
+        let dir = fixture_site("websocket");
+        let site = Website::new(&dir).unwrap()
+            .with_websocket(Box::new(|mut stream, request| {
+                // echo whatever comes in, after the url that was upgraded
+                stream.write_all(request.url.as_bytes()).unwrap();
+                let mut buffer = [0; 4];
+                stream.read_exact(&mut buffer).unwrap();
+                stream.write_all(&buffer).unwrap();
+            }));
+        let addr = start(site);
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /live HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains("\r\nUpgrade: websocket\r\n"));
+        assert!(head.contains("\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        stream.write_all(b"ping").unwrap();
+        let mut rest = String::new();
+        stream.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "/liveping");
+
+        let response = request(addr, "GET /live HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 "));
+        // without a handler, an upgrade request is just a GET
+        let plain_dir = fixture_site("no-websocket");
+        let plain = start(Website::new(&plain_dir).unwrap());
+        let response = request(plain, "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn canonical_host_redirects() {
+        let dir = fixture_site("canonical-host");
+        let addr = start(Website::new(&dir).unwrap().with_canonical_host("https", "example.com"));
+        let response = request(addr, "GET /a%20b/c.html?q=x%26y&z HTTP/1.1\r\nHost: www.example.com\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+        assert!(response.contains("\r\nLocation: https://example.com/a%20b/c.html?q=x%26y&z\r\n"));
+        let response = request(addr, "GET / HTTP/1.1\r\nHost: example.com:8080\r\n\r\n");
+        assert!(response.contains("\r\nLocation: https://example.com/\r\n"));
+        assert!(request(addr, "GET / HTTP/1.1\r\nHost: EXAMPLE.com\r\n\r\n").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(request(addr, "GET / HTTP/1.0\r\n\r\n").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn directory_listings() {
+        let site = fixture_site("autoindex");
+        std::fs::create_dir_all(format!("{}/public/files/sub", site)).unwrap();
+        std::fs::create_dir_all(format!("{}/public/docs", site)).unwrap();
+        std::fs::write(format!("{}/public/files/report.pdf", site), vec![0; 3000]).unwrap();
+        std::fs::write(format!("{}/public/docs/index.html", site), "<h1>docs</h1>").unwrap();
+        let website = || Website::new_with_layout(&site, ResourceLayout::DocumentRoot("public".into())).unwrap()
+            .with_encodings(vec![]);
+        let addr = start(website().with_autoindex(true));
+        let response = request(addr, "GET /files/ HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(response.contains("<h1>Index of /files/</h1>"));
+        assert!(response.find("sub/").unwrap() < response.find("report.pdf").unwrap());
+        assert!(response.contains("<td>2.9 KiB</td>"));
+        // directories with an index file are served it
+        assert!(request(addr, "GET /docs/ HTTP/1.1\r\n\r\n").ends_with("\r\n\r\n<h1>docs</h1>"));
+        assert!(request(addr, "GET /missing/ HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let off = start(website());
+        assert!(request(off, "GET /files/ HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn plain_document_root() {
+        let site = fixture_site("document-root");
+        std::fs::create_dir_all(format!("{}/public/js", site)).unwrap();
+        std::fs::write(format!("{}/public/index.html", site), "<h1>public</h1>").unwrap();
+        std::fs::write(format!("{}/public/style.css", site), "h1 {}").unwrap();
+        std::fs::write(format!("{}/public/js/app.js", site), "go()").unwrap();
+        std::fs::write(format!("{}/public/data.bin", site), [0xff, 0x00]).unwrap();
+        let website = Website::new_with_layout(&site, ResourceLayout::DocumentRoot("public".into())).unwrap();
+        let addr = start(website.with_encodings(vec![]));
+        assert!(request(addr, "GET / HTTP/1.1\r\n\r\n").ends_with("\r\n\r\n<h1>public</h1>"));
+        assert!(request(addr, "GET /style.css HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nh1 {}"));
+        assert!(request(addr, "GET /js/app.js HTTP/1.1\r\n\r\n").ends_with("\r\n\r\ngo()"));
+        assert!(request(addr, "GET /app.js HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        // `..` can't climb above the document root
+        assert!(request(addr, "GET /../layout/index.html HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(request(addr, "GET /js/../../style.css HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nh1 {}"));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /data.bin HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
+        assert!(response.ends_with(b"\r\n\r\n\xff\x00"));
+
+        assert!(matches!(
+            Website::new_with_layout(&site, ResourceLayout::DocumentRoot("private".into())),
+            Err(WebsiteError::NoLayout(..))));
+    }
+
+    #[test]
+    fn trailing_slash_redirects() {
+        let site = fixture_site("trailing-slash");
+        std::fs::create_dir_all(format!("{}/public/docs", site)).unwrap();
+        std::fs::write(format!("{}/public/index.html", site), "root").unwrap();
+        std::fs::write(format!("{}/public/docs/index.html", site), "docs").unwrap();
+        std::fs::write(format!("{}/public/page.html", site), "page").unwrap();
+        let website = |policy| Website::new_with_layout(&site, ResourceLayout::DocumentRoot("public".into())).unwrap()
+            .with_encodings(vec![])
+            .with_trailing_slash(policy);
+
+        let to_slash = start(website(TrailingSlash::RedirectToSlash));
+        let response = request(to_slash, "GET /docs?a=1&b=%2F HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+        assert!(response.contains("\r\nLocation: /docs/?a=1&b=%2F\r\n"));
+        assert!(request(to_slash, "GET /docs/ HTTP/1.1\r\n\r\n").ends_with("\r\n\r\ndocs"));
+        assert!(request(to_slash, "GET /page.html HTTP/1.1\r\n\r\n").ends_with("\r\n\r\npage"));
+        assert!(request(to_slash, "GET /?a=1 HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nroot"));
+
+        let to_no_slash = start(website(TrailingSlash::RedirectToNoSlash));
+        let response = request(to_no_slash, "GET /page.html/?q=x HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+        assert!(response.contains("\r\nLocation: /page.html?q=x\r\n"));
+        assert!(request(to_no_slash, "GET /docs/ HTTP/1.1\r\n\r\n").ends_with("\r\n\r\ndocs"));
+        assert!(request(to_no_slash, "GET / HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nroot"));
+
+        let response = request(start(website(TrailingSlash::None)), "GET /page.html/ HTTP/1.1\r\n\r\n");
+        assert!(!response.starts_with("HTTP/1.1 301"));
+    }
+
+    #[test]
+    fn index_files() {
+        let site = fixture_site("index-files");
+        std::fs::create_dir_all(format!("{}/public/docs", site)).unwrap();
+        std::fs::write(format!("{}/public/index.htm", site), "htm").unwrap();
+        std::fs::write(format!("{}/public/docs/default.html", site), "default").unwrap();
+        std::fs::write(format!("{}/public/docs/index.htm", site), "docs htm").unwrap();
+        let website = Website::new_with_layout(&site, ResourceLayout::DocumentRoot("public".into())).unwrap()
+            .with_encodings(vec![])
+            .with_index_files(vec!["index.htm".to_string()]);
+        let addr = start(website);
+        assert!(request(addr, "GET / HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nhtm"));
+        assert!(request(addr, "GET /docs/ HTTP/1.1\r\n\r\n").ends_with("\r\n\r\ndocs htm"));
+
+        let website = Website::new_with_layout(&site, ResourceLayout::DocumentRoot("public".into())).unwrap()
+            .with_encodings(vec![])
+            .with_index_files(vec!["default.html".to_string(), "index.htm".to_string()]);
+        let addr = start(website);
+        assert!(request(addr, "GET / HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nhtm"));
+        assert!(request(addr, "GET /docs/ HTTP/1.1\r\n\r\n").ends_with("\r\n\r\ndefault"));
+
+        // the default is index.html, which isn't there
+        let website = Website::new_with_layout(&site, ResourceLayout::DocumentRoot("public".into())).unwrap();
+        assert!(request(start(website), "GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn redirects_file() {
+        let site = fixture_site("redirects");
+        let file = format!("{}/redirects", site);
+        std::fs::write(&file, "\
+            /old.html -> /index.html\n\
+            /found -> /elsewhere?from=found 302\n\
+            /temporary -> https://example.com/t 307\n\
+            /permanent -> /p%20q 308\n").unwrap();
+        let addr = start(Website::new(&site).unwrap().with_redirects_file(&file).unwrap());
+        let get = |url: &str| request(addr, &format!("GET {} HTTP/1.1\r\n\r\n", url));
+        let response = get("/old.html?a=1&b=2");
+        assert!(response.starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+        assert!(response.contains("\r\nLocation: /index.html?a=1&b=2\r\n"));
+        let response = get("/found?a=1");
+        assert!(response.starts_with("HTTP/1.1 302 Found\r\n"));
+        assert!(response.contains("\r\nLocation: /elsewhere?from=found\r\n"));
+        let response = get("/temporary?a=1");
+        assert!(response.starts_with("HTTP/1.1 307 Temporary Redirect\r\n"));
+        assert!(response.contains("\r\nLocation: https://example.com/t\r\n"));
+        let response = get("/permanent");
+        assert!(response.starts_with("HTTP/1.1 308 Permanent Redirect\r\n"));
+        assert!(response.contains("\r\nLocation: /p%20q\r\n"));
+        // other spellings of a redirected path are redirected too
+        for url in ["/old.html/", "//old.html", "/%6Fld.html", "/x/../old.html?a=1"] {
+            let response = get(url);
+            assert!(response.starts_with("HTTP/1.1 301 Moved Permanently\r\n"), "{}", url);
+            assert!(response.contains("\r\nLocation: /index.html"), "{}", url);
+        }
+        assert!(get("/index.html").starts_with("HTTP/1.1 200 OK\r\n"));
+
+        std::fs::write(&file, "/a -> /b\n/a -> /c\n").unwrap();
+        match Website::new(&site).unwrap().with_redirects_file(&file) {
+            Err(WebsiteError::BadConfig(_, reason)) => assert_eq!(reason, "line 2: /a is already redirected on line 1"),
+            _ => panic!("duplicate redirects should be rejected")
+        }
+    }
+
+    #[test]
+    fn rewrites_file() {
+        let site = fixture_site("rewrites");
+        std::fs::write(format!("{}/layout/cat.png", site), "meow").unwrap();
+        let file = format!("{}/rewrites", site);
+        std::fs::write(&file, "\
+            ^/img/(\\d+)/(.*)$ -> /$2?size=$1\n\
+            ^/old/(.*)$ -> /new/$1 301\n\
+            ^/ping$ -> /pong\n\
+            ^/pong$ -> /ping\n").unwrap();
+        let addr = start(Website::new(&site).unwrap().with_rewrites_file(&file).unwrap());
+        let get = |url: &str| request(addr, &format!("GET {} HTTP/1.1\r\n\r\n", url));
+        assert!(get("/img/200/cat.png").ends_with("\r\n\r\nmeow"));
+        let response = get("/old/a/b.html?x=1");
+        assert!(response.starts_with("HTTP/1.1 301 Moved Permanently\r\n"));
+        assert!(response.contains("\r\nLocation: /new/a/b.html?x=1\r\n"));
+        // an encoded line break stays encoded in the redirect instead of splitting the response
+        let response = get("/old/%0D%0ASet-Cookie:%20x=1");
+        assert!(response.contains("\r\nLocation: /new/%0D%0ASet-Cookie:%20x=1\r\n"));
+        assert!(!response.contains("\r\nSet-Cookie"));
+        assert!(get("/ping").starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+
+        std::fs::write(&file, "^/(unclosed -> /x\n").unwrap();
+        assert!(matches!(Website::new(&site).unwrap().with_rewrites_file(&file), Err(WebsiteError::BadConfig(..))));
+    }
+
+    #[test]
+    fn image_negotiation() {
+        let site = fixture_site("image-negotiation");
+        std::fs::write(format!("{}/layout/logo.webp", site), "webp").unwrap();
+        std::fs::write(format!("{}/layout/logo.png", site), "png").unwrap();
+        let addr = start(Website::new(&site).unwrap());
+        let response = request(addr, "GET /logo HTTP/1.1\r\nAccept: image/avif, image/webp, image/*\r\n\r\n");
+        assert!(response.contains("\r\nContent-Type: image/webp\r\n"));
+        assert!(response.contains("\r\nVary: Accept\r\n"));
+        assert!(response.ends_with("\r\n\r\nwebp"));
+        let response = request(addr, "GET /logo HTTP/1.1\r\nAccept: image/png, image/webp;q=0.1\r\n\r\n");
+        assert!(response.contains("\r\nContent-Type: image/png\r\n"));
+        assert!(response.ends_with("\r\n\r\npng"));
+        assert!(request(addr, "GET /logo HTTP/1.1\r\nAccept: image/gif\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        // urls with an extension are served as they are
+        let response = request(addr, "GET /logo.png HTTP/1.1\r\nAccept: image/webp\r\n\r\n");
+        assert!(response.ends_with("\r\n\r\npng"));
+        assert!(!response.contains("Vary"));
+    }
+
+    #[test]
+    fn byte_ranges() {
+        let site = fixture_site("byte-ranges");
+        std::fs::write(format!("{}/layout/data.png", site), "0123456789abcdefghij").unwrap();
+        let addr = start(Website::new(&site).unwrap());
+        let get = |range: &str| request(addr, &format!("GET /data.png HTTP/1.1\r\nRange: {}\r\n\r\n", range));
+        let response = get("bytes=5-9");
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(response.contains("\r\nContent-Range: bytes 5-9/20\r\n"));
+        assert!(response.contains("\r\nContent-Length: 5\r\n"));
+        assert!(response.ends_with("\r\n\r\n56789"));
+
+        let response = get("bytes=0-1, 18-");
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        let boundary = response.split("boundary=").nth(1).unwrap().split("\r\n").next().unwrap();
+        assert!(response.ends_with(&format!("\r\n\r\n\
+            \r\n--{b}\r\nContent-Range: bytes 0-1/20\r\n\r\n01\
+            \r\n--{b}\r\nContent-Range: bytes 18-19/20\r\n\r\nij\
+            \r\n--{b}--\r\n", b = boundary)));
+
+        let response = get("bytes=0-1, 20-30");
+        assert!(response.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"));
+        assert!(response.contains("\r\nContent-Range: bytes */20\r\n"));
+        let response = get("lines=1-2");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\nAccept-Ranges: bytes\r\n"));
+    }
+
+    #[test]
+    fn mount_points() {
+        let site = fixture_site("mounts");
+        let assets = fixture_site("mounts-assets");
+        for (path, contents) in [
+            ("public/index.html", "root"),
+            ("public/static/app.css", "shadowed"),
+            ("public/docs/page.html", "shadowed too")
+        ] {
+            std::fs::create_dir_all(site.path().join(path).parent().unwrap()).unwrap();
+            std::fs::write(site.path().join(path), contents).unwrap();
+        }
+        std::fs::create_dir_all(format!("{}/api", assets)).unwrap();
+        std::fs::write(format!("{}/app.css", assets), "mounted css").unwrap();
+        std::fs::write(format!("{}/layout/page.html", assets), "mounted docs").unwrap();
+        std::fs::write(format!("{}/api/index.html", assets), "api docs").unwrap();
+        std::fs::write(format!("{}/mounts", site), format!(
+            "/static/ -> {}\n/docs -> {}/layout\n/docs/api/ -> {}/api\n", assets, assets, assets)).unwrap();
+        let website = Website::new_with_layout(&site, ResourceLayout::DocumentRoot("public".into())).unwrap()
+            .with_mounts_file(format!("{}/mounts", site)).unwrap()
+            .with_encodings(vec![]);
+        let addr = start(website);
+        let get = |url: &str| request(addr, &format!("GET {} HTTP/1.1\r\n\r\n", url));
+        assert!(get("/").ends_with("\r\n\r\nroot"));
+        assert!(get("/static/app.css").ends_with("\r\n\r\nmounted css"));
+        assert!(get("/docs/page.html").ends_with("\r\n\r\nmounted docs"));
+        assert!(get("/docs/api/").ends_with("\r\n\r\napi docs"));
+        assert!(get("/docs/api/../page.html").ends_with("\r\n\r\nmounted docs"));
+        assert!(get("/docs/api/../../../layout/index.html").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(get("/staticx/app.css").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&site, format!("{}/escape", assets)).unwrap();
+            assert!(get("/static/escape/public/index.html").starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        }
+
+        assert!(matches!(Website::new(&site).unwrap().with_mount("/x/", format!("{}/nowhere", site)),
+            Err(WebsiteError::Missing(..))));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn symlinks_out_of_the_root() {
+        let site = fixture_site("symlinks");
+        let outside = fixture_site("symlinks-outside");
+        std::fs::write(format!("{}/secret.html", outside), "secret").unwrap();
+        std::os::unix::fs::symlink(format!("{}/secret.html", outside), format!("{}/layout/secret.html", site)).unwrap();
+        std::os::unix::fs::symlink(format!("{}/layout/index.html", site), format!("{}/layout/home.html", site)).unwrap();
+        let get = |addr, url: &str| request(addr, &format!("GET {} HTTP/1.1\r\n\r\n", url));
+
+        let addr = start(Website::new(&site).unwrap().with_encodings(vec![]));
+        let response = get(addr, "/secret.html");
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(!response.contains("secret"));
+        assert!(get(addr, "/home.html").ends_with("\r\n\r\n<h1>hello</h1>"));
+
+        let following = start(Website::new(&site).unwrap().with_encodings(vec![]).with_follow_symlinks(true));
+        assert!(get(following, "/secret.html").ends_with("\r\n\r\nsecret"));
+    }
+
+    /// Answers every request with what it received, headers and all, along with
+    /// hop-by-hop headers of its own and a header only it sends.
+    fn start_upstream() -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut received = vec![];
+            let mut buffer = [0; 1024];
+            let head_end = loop {
+                let n = stream.read(&mut buffer).unwrap();
+                received.extend_from_slice(&buffer[..n]);
+                if let Some(end) = find_head_end(&received) {
+                    break end;
+                }
+            };
+            let head = String::from_utf8_lossy(&received[..head_end]).to_string();
+            let length: usize = head.lines()
+                .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length: ").map(|l| l.parse().unwrap()))
+                .unwrap_or(0);
+            while received.len() < head_end + length {
+                let n = stream.read(&mut buffer).unwrap();
+                received.extend_from_slice(&buffer[..n]);
+            }
+            let status = if head.starts_with("GET /v1/missing") { "404 Nope" } else { "200 OK" };
+            let _ = stream.write_all(format!(
+                "HTTP/1.1 {}\r\nConnection: keep-alive, X-Private\r\nKeep-Alive: timeout=5\r\nX-Private: yes\r\n\
+                 X-Upstream: yes\r\nContent-Length: {}\r\n\r\n", status, received.len()).as_bytes());
+            let _ = stream.write_all(&received);
+        });
+        addr
+    }
+
+    #[test]
+    fn reverse_proxy() {
+        let upstream = start_upstream();
+        let dir = fixture_site("proxy");
+        let site = Arc::new(Website::new(&dir).unwrap().with_encodings(vec![]));
+        let proxy = ProxyHandler::new(site).with_upstream("/api", &format!("http://{}/v1", upstream));
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run(Arc::new(proxy)));
+
+        let response = request(addr, "POST /api/items?sort=new HTTP/1.1\r\nHost: example.com\r\nX-Request-Id: 7\r\n\
+                                      Keep-Alive: timeout=100\r\nContent-Length: 5\r\n\r\nhello");
+        let (head, echoed) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        let relayed = head.to_ascii_lowercase();
+        assert!(relayed.contains("\r\nx-upstream: yes\r\n"));
+        assert!(!relayed.contains("keep-alive: timeout") && !relayed.contains("x-private"));
+        // the connection to the client is the website's to manage
+        assert!(head.contains("\r\nConnection: close\r\n"));
+        assert!(echoed.starts_with("POST /v1/items?sort=new HTTP/1.1\r\n"));
+        assert!(echoed.to_ascii_lowercase().contains(&format!("\r\nhost: {}\r\n", upstream)));
+        assert!(echoed.to_ascii_lowercase().contains("\r\nx-request-id: 7\r\n"));
+        assert!(echoed.to_ascii_lowercase().contains("\r\nx-forwarded-for: 127.0.0.1\r\n"));
+        assert!(!echoed.to_ascii_lowercase().contains("keep-alive: timeout=100"));
+        assert!(echoed.ends_with("\r\n\r\nhello"));
+        // error statuses are relayed as they are
+        assert!(request(addr, "GET /api/missing HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Nope\r\n"));
+        // everything else is the website's
+        assert!(request(addr, "GET / HTTP/1.1\r\n\r\n").ends_with("\r\n\r\n<h1>hello</h1>"));
+
+        // a body longer than allowed isn't relayed
+        let capped_dir = fixture_site("proxy-capped");
+        let site = Arc::new(Website::new(&capped_dir).unwrap().with_encodings(vec![]));
+        let proxy = ProxyHandler::new(site).with_upstream("/api", &format!("http://{}/v1", upstream)).with_max_upstream_body(10);
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let capped = server.local_addr().unwrap();
+        thread::spawn(move || server.run(Arc::new(proxy)));
+        assert!(request(capped, "GET /api/items HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+
+        let gone = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let down_dir = fixture_site("proxy-down");
+        let proxy = ProxyHandler::new(Arc::new(Website::new(&down_dir).unwrap()))
+            .with_upstream("/api", &format!("http://{}", gone));
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run(Arc::new(proxy)));
+        let response = request(addr, "GET /api/items HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+        assert!(response.ends_with("\r\n\r\nBad Gateway"));
+    }
+
+    /// An upstream answering every request with `name`, or with a 503 while `failing` is set.
+    fn start_named_upstream(name: &'static str, failing: Arc<AtomicBool>) -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut received = vec![];
+            let mut buffer = [0; 1024];
+            while find_head_end(&received).is_none() {
+                let n = stream.read(&mut buffer).unwrap();
+                received.extend_from_slice(&buffer[..n]);
+            }
+            let status = if failing.load(Ordering::SeqCst) { "503 Service Unavailable" } else { "200 OK" };
+            let _ = write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n{}", status, name.len(), name);
+        });
+        addr
+    }
+
+    #[test]
+    fn load_balancing() {
+        let b_failing = Arc::new(AtomicBool::new(false));
+        let a = start_named_upstream("a", Arc::new(AtomicBool::new(false)));
+        let b = start_named_upstream("b", Arc::clone(&b_failing));
+        let gone = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let start_proxy = |proxy: ProxyHandler| {
+            let server = Server::bind("127.0.0.1:0").unwrap();
+            let addr = server.local_addr().unwrap();
+            thread::spawn(move || server.run(Arc::new(proxy)));
+            addr
+        };
+        let dir = fixture_site("load-balancing");
+        let new_proxy = || ProxyHandler::new(Arc::new(Website::new(&dir).unwrap().with_encodings(vec![])));
+        let answers = |addr, count| (0..count)
+            .map(|_| request(addr, "GET /api/ HTTP/1.1\r\n\r\n").rsplit("\r\n\r\n").next().unwrap().to_string())
+            .collect::<Vec<_>>();
+
+        // the upstreams take turns
+        let addr = start_proxy(new_proxy()
+            .with_balanced_upstream("/api", &[&format!("http://{}", a), &format!("http://{}", b)])
+            .with_upstream_cooldown(Duration::from_millis(500)));
+        assert_eq!(answers(addr, 4), ["a", "b", "a", "b"]);
+        // a failing one gets one more request, and is skipped after that
+        b_failing.store(true, Ordering::SeqCst);
+        assert_eq!(answers(addr, 4), ["a", "b", "a", "a"]);
+        // until the cooldown is over
+        b_failing.store(false, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(600));
+        let recovered = answers(addr, 4);
+        assert_eq!(recovered.iter().filter(|answer| *answer == "b").count(), 2, "{:?}", recovered);
+
+        // requests that can't reach an upstream go to the next one
+        let addr = start_proxy(new_proxy()
+            .with_balanced_upstream("/api", &[&format!("http://{}", gone), &format!("http://{}", a)]));
+        assert_eq!(answers(addr, 4), ["a", "a", "a", "a"]);
+
+        // with all of them down, there's nothing to answer with
+        let addr = start_proxy(new_proxy()
+            .with_balanced_upstream("/api", &[&format!("http://{}", gone), &format!("http://{}", gone)]));
+        assert!(request(addr, "GET /api/ HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+        assert!(request(addr, "GET /api/ HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+
+        // a sticky client keeps its upstream
+        let addr = start_proxy(new_proxy()
+            .with_balanced_upstream("/api", &[&format!("http://{}", a), &format!("http://{}", b)])
+            .with_sticky_upstreams(true));
+        let sticky = answers(addr, 4);
+        assert!(sticky.iter().all(|answer| *answer == sticky[0]), "{:?}", sticky);
+    }
+
+    #[test]
+    fn slow_upstream() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream = listener.local_addr().unwrap();
+        thread::spawn(move || for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_secs(3));
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nlate");
+            });
+        });
+        let start_proxy = |site: Website, timeout| {
+            let proxy = ProxyHandler::new(Arc::new(site))
+                .with_upstream("/api", &format!("http://{}", upstream))
+                .with_timeout(timeout);
+            let server = Server::bind("127.0.0.1:0").unwrap();
+            let addr = server.local_addr().unwrap();
+            thread::spawn(move || server.run(Arc::new(proxy)));
+            addr
+        };
+
+        // the request timeout is up before the upstream timeout
+        let dir = fixture_site("slow-upstream");
+        let site = Website::new(&dir).unwrap().with_request_timeout(Duration::from_millis(300));
+        let addr = start_proxy(site, Duration::from_secs(30));
+        let started = Instant::now();
+        let response = request(addr, "GET /api/a HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nGateway Timeout"));
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // and the other way around
+        let timeout_dir = fixture_site("slow-upstream-timeout");
+        let addr = start_proxy(Website::new(&timeout_dir).unwrap(), Duration::from_millis(300));
+        let started = Instant::now();
+        assert!(request(addr, "GET /api/a HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 504 Gateway Timeout\r\n"));
+        assert!(started.elapsed() < Duration::from_secs(2));
+        // the website's own files are quick enough
+        assert!(request(addr, "GET / HTTP/1.1\r\n\r\n").ends_with("<h1>hello</h1>"));
+    }
+
+    #[test]
+    fn gzipped_upstream() {
+        // an upstream that gzips its answers whatever the client accepts
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream = listener.local_addr().unwrap();
+        thread::spawn(move || for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut received = vec![];
+            let mut buffer = [0; 1024];
+            while find_head_end(&received).is_none() {
+                let n = stream.read(&mut buffer).unwrap();
+                received.extend_from_slice(&buffer[..n]);
+            }
+            let body = Encoding::Gzip.encode(b"plain text from upstream").unwrap();
+            let _ = stream.write_all(format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Encoding: gzip\r\nETag: \"v1\"\r\n\
+                 Content-Length: {}\r\n\r\n", body.len()).as_bytes());
+            let _ = stream.write_all(&body);
+        });
+        let dir = fixture_site("gzipped-upstream");
+        let site = Arc::new(Website::new(&dir).unwrap().with_encodings(vec![Encoding::Gzip]));
+        let proxy = ProxyHandler::new(site).with_upstream("/api", &format!("http://{}", upstream));
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run(Arc::new(proxy)));
+
+        let response = request(addr, "GET /api/a HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!response.to_ascii_lowercase().contains("content-encoding"));
+        assert!(response.contains("Content-Length: 24\r\n"));
+        assert!(response.ends_with("\r\n\r\nplain text from upstream"));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /api/a HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n").unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
+        let body_start = find_head_end(&response).unwrap();
+        let head = String::from_utf8_lossy(&response[..body_start]).to_ascii_lowercase();
+        assert!(head.contains("content-encoding: gzip\r\n"));
+        assert!(head.contains("etag: w/\"v1\"\r\n"));
+        let mut body = String::new();
+        flate2::read::GzDecoder::new(&response[body_start..]).read_to_string(&mut body).unwrap();
+        assert_eq!(body, "plain text from upstream");
+    }
+
+    #[test]
+    fn forwarded_headers() {
+        let upstream = start_upstream();
+        let start_proxy = |proxy: ProxyHandler| {
+            let server = Server::bind("127.0.0.1:0").unwrap();
+            let addr = server.local_addr().unwrap();
+            thread::spawn(move || server.run(Arc::new(proxy.with_upstream("/api", &format!("http://{}", upstream)))));
+            addr
+        };
+        let forwarded = |addr, headers: &str| {
+            let response = request(addr, &format!("GET /api/a HTTP/1.1\r\nHost: example.com\r\n{}\r\n", headers));
+            let echoed = response.split_once("\r\n\r\n").unwrap().1.to_ascii_lowercase();
+            echoed.lines()
+                .filter(|line| line.starts_with("x-forwarded-") || line.starts_with("host:"))
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+        let spoofed = "X-Forwarded-For: 203.0.113.7\r\nX-Forwarded-Proto: https\r\nX-Forwarded-Host: www.example.com\r\n";
+
+        // a direct client's claims are dropped
+        let direct_dir = fixture_site("forwarded");
+        let direct = start_proxy(ProxyHandler::new(Arc::new(Website::new(&direct_dir).unwrap())));
+        let mut expected = vec![
+            format!("host: {}", upstream),
+            "x-forwarded-for: 127.0.0.1".to_string(),
+            "x-forwarded-proto: http".to_string(),
+            "x-forwarded-host: example.com".to_string()
+        ];
+        expected.sort();
+        let mut headers = forwarded(direct, spoofed);
+        headers.sort();
+        assert_eq!(headers, expected);
+
+        // a trusted proxy's are passed on
+        let chained_dir = fixture_site("forwarded-chained");
+        let chained = start_proxy(ProxyHandler::new(Arc::new(Website::new(&chained_dir).unwrap()))
+            .with_trusted_proxies(vec!["127.0.0.1".parse().unwrap()])
+            .with_preserved_host(true));
+        let mut expected = vec![
+            "host: example.com".to_string(),
+            "x-forwarded-for: 203.0.113.7, 127.0.0.1".to_string(),
+            "x-forwarded-proto: https".to_string(),
+            "x-forwarded-host: www.example.com".to_string()
+        ];
+        expected.sort();
+        let mut headers = forwarded(chained, spoofed);
+        headers.sort();
+        assert_eq!(headers, expected);
+        let mut headers = forwarded(chained, "");
+        headers.sort();
+        assert_eq!(headers, ["host: example.com", "x-forwarded-for: 127.0.0.1", "x-forwarded-host: example.com", "x-forwarded-proto: http"]);
+    }
+
+    #[test]
+    fn proxy_routes_with_static_files() {
+        let upstream = start_upstream();
+        let site = fixture_site("proxy-routes");
+        std::fs::create_dir_all(format!("{}/public/api", site)).unwrap();
+        std::fs::write(format!("{}/public/index.html", site), "static index").unwrap();
+        std::fs::write(format!("{}/public/api/data.json", site), "static data").unwrap();
+        std::fs::write(format!("{}/public/apifoo.html", site), "static apifoo").unwrap();
+        std::fs::write(format!("{}/upstreams", site), format!(
+            "/api/ -> http://{0}\n/keep -> http://{0}/v1 keep-prefix\n", upstream)).unwrap();
+        let website = Website::new_with_layout(&site, ResourceLayout::DocumentRoot("public".into())).unwrap()
+            .with_encodings(vec![]);
+        let proxy = ProxyHandler::new(Arc::new(website)).with_upstreams_file(format!("{}/upstreams", site)).unwrap();
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run(Arc::new(proxy)));
+        let get = |url: &str| request(addr, &format!("GET {} HTTP/1.1\r\n\r\n", url));
+        let forwarded = |url: &str| get(url).split_once("\r\n\r\n").unwrap().1.lines().next().unwrap_or_default().to_string();
+
+        // the proxy wins over the file of the same name
+        assert_eq!(forwarded("/api/data.json"), "GET /data.json HTTP/1.1");
+        assert_eq!(forwarded("/api"), "GET / HTTP/1.1");
+        assert_eq!(forwarded("/api/"), "GET / HTTP/1.1");
+        assert_eq!(forwarded("/api/?q=1"), "GET /?q=1 HTTP/1.1");
+        assert_eq!(forwarded("/keep/a"), "GET /v1/keep/a HTTP/1.1");
+        assert!(get("/apifoo.html").ends_with("\r\n\r\nstatic apifoo"));
+        assert!(get("/apifoo").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(get("/").ends_with("\r\n\r\nstatic index"));
+
+        std::fs::write(format!("{}/upstreams", site), "/api -> localhost:9000\n").unwrap();
+        let website = Website::new_with_layout(&site, ResourceLayout::DocumentRoot("public".into())).unwrap();
+        assert!(matches!(ProxyHandler::new(Arc::new(website)).with_upstreams_file(format!("{}/upstreams", site)),
+            Err(WebsiteError::BadConfig(..))));
+    }
+
+    #[test]
+    fn sites_by_port_and_host() {
+        let main_site = fixture_site("sites-main");
+        let blog = fixture_site("sites-blog");
+        std::fs::write(format!("{}/layout/index.html", blog), "<h1>blog</h1>").unwrap();
+        let main_site = Arc::new(Website::new(&main_site).unwrap().with_encodings(vec![]));
+        let blog = Arc::new(Website::new(&blog).unwrap().with_encodings(vec![]));
+        let main_server = Server::bind("127.0.0.1:0").unwrap();
+        let blog_server = Server::bind("127.0.0.1:0").unwrap();
+        let main_addr = main_server.local_addr().unwrap();
+        let blog_addr = blog_server.local_addr().unwrap();
+        let selector = Arc::new(SiteSelector::new(Arc::clone(&main_site))
+            .with_site(SiteRule::Port(blog_addr.port()), Arc::clone(&blog))
+            .with_site(SiteRule::Host("blog.example.com".to_string()), Arc::clone(&blog))
+            .with_site(SiteRule::Host("[::1]".to_string()), blog));
+        let run_selector = Arc::clone(&selector);
+        thread::spawn(move || main_server.run(run_selector));
+        thread::spawn(move || blog_server.run(selector));
+
+        assert!(request(main_addr, "GET / HTTP/1.1\r\n\r\n").ends_with("\r\n\r\n<h1>hello</h1>"));
+        assert!(request(blog_addr, "GET / HTTP/1.1\r\n\r\n").ends_with("\r\n\r\n<h1>blog</h1>"));
+        assert!(request(main_addr, "GET / HTTP/1.1\r\nHost: Blog.Example.com:8080\r\n\r\n")
+            .ends_with("\r\n\r\n<h1>blog</h1>"));
+        // the colons in an IPv6 address aren't the port's
+        for host in ["[::1]:8080", "[::1]"] {
+            assert!(request(main_addr, &format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", host))
+                .ends_with("\r\n\r\n<h1>blog</h1>"), "{}", host);
+        }
+        for host in ["[::2]:8080", "[::1:8080", "blog.example.com.evil:80"] {
+            assert!(request(main_addr, &format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", host))
+                .ends_with("\r\n\r\n<h1>hello</h1>"), "{}", host);
+        }
+    }
+
+    #[test]
+    fn sites_by_path_prefix() {
+        let main_dir = fixture_site("sites-prefix-main");
+        let admin_dir = fixture_site("sites-prefix-admin");
+        // both sites answer the same paths, so the body tells which one was picked
+        let site = |dir: &ScratchDir, name: &'static str| {
+            let answer = move || -> RouteHandler { Box::new(move |_| Ok(Response::new(200).with_body(name.as_bytes().to_vec()))) };
+            Arc::new(Website::new(dir).unwrap().with_get_route("/admin", answer()).with_get_route("/adminx", answer()))
+        };
+        let selector = SiteSelector::new(site(&main_dir, "main"))
+            .with_site(SiteRule::PathPrefix("/admin".to_string()), site(&admin_dir, "admin"));
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run(Arc::new(selector)));
+
+        for url in ["/admin", "/admin?x=1", "//admin", "/./admin", "/x/../admin", "/%61dmin"] {
+            assert!(request(addr, &format!("GET {} HTTP/1.1\r\n\r\n", url)).ends_with("\r\n\r\nadmin"), "{}", url);
+        }
+        assert!(request(addr, "GET /adminx HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nmain"));
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn prometheus_metrics() {
+        use crate::server::PrometheusCollector;
+        let metrics = Arc::new(PrometheusCollector::new().unwrap());
+        let dir = fixture_site("prometheus");
+        let addr = start(Website::new(&dir).unwrap()
+            .with_metrics(Arc::clone(&metrics))
+            .with_admin_ip_allowlist(vec!["127.0.0.1".parse().unwrap()]));
+        request(addr, "GET / HTTP/1.1\r\n\r\n");
+        request(addr, "GET / HTTP/1.1\r\n\r\n");
+        let exposition = request(addr, "GET /_prometheus HTTP/1.1\r\n\r\n");
+        assert!(exposition.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(exposition.contains("\nhttp_requests_total{method=\"GET\",status=\"200\"} 2\n"), "{}", exposition);
+        assert!(exposition.contains("\nhttp_request_duration_seconds_count 2\n"));
+        assert!(exposition.contains("\nhttp_response_size_bytes_bucket{le=\"100\"} 0\n"), "{}", exposition);
+        assert!(exposition.contains("\nhttp_response_size_bytes_bucket{le=\"1000\"} 2\n"));
+        assert!(exposition.contains("\nhttp_response_size_bytes_count 2\n"));
+        assert!(exposition.contains("\nhttp_active_connections 1\n"));
+
+        let metrics = Arc::new(PrometheusCollector::with_buckets(vec![0.5], vec![50.0, 5000.0]).unwrap());
+        let buckets_dir = fixture_site("prometheus-buckets");
+        let addr = start(Website::new(&buckets_dir).unwrap()
+            .with_metrics(Arc::clone(&metrics))
+            .with_admin_ip_allowlist(vec!["127.0.0.1".parse().unwrap()]));
+        request(addr, "GET / HTTP/1.1\r\n\r\n");
+        let exposition = request(addr, "GET /_prometheus HTTP/1.1\r\n\r\n");
+        assert!(exposition.contains("\nhttp_request_duration_seconds_bucket{le=\"0.5\"} 1\n"));
+        assert!(exposition.contains("\nhttp_response_size_bytes_bucket{le=\"5000\"} 1\n"));
+        assert!(PrometheusCollector::with_buckets(vec![1.0, 0.5], vec![]).is_err());
+
+        let forbidden_dir = fixture_site("prometheus-forbidden");
+        let addr = start(Website::new(&forbidden_dir).unwrap().with_metrics(metrics));
+        assert!(request(addr, "GET /_prometheus HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    }
+
+    #[test]
+    fn website_location_is_checked() {
+        let site = fixture_site("location");
+        assert!(matches!(Website::new(format!("{}/nowhere", site)), Err(WebsiteError::Missing(..))));
+        assert!(matches!(Website::new(format!("{}/layout/index.html", site)), Err(WebsiteError::NotADirectory(_))));
+        assert!(matches!(Website::new(format!("{}/layout", site)), Err(WebsiteError::NoLayout(..))));
+        let website = Website::new(format!("{}/layout/..", site)).unwrap();
+        assert_eq!(website.loc, std::fs::canonicalize(&site).unwrap());
+        assert!(website.validate().is_ok());
+
+        let root = Website::new_with_layout("/", ResourceLayout::DocumentRoot(".".into()));
+        assert!(matches!(root, Err(WebsiteError::UnsafeLocation(_, "it's the file system root"))));
+        if let Some(home) = std::env::var_os("HOME").filter(|home| std::path::Path::new(home).is_dir()) {
+            let home = Website::new_with_layout(home, ResourceLayout::DocumentRoot(".".into()));
+            assert!(matches!(home, Err(WebsiteError::UnsafeLocation(_, "it's a home directory"))));
+        }
+    }
+
+    /// Collects the messages of every log record, from all tests running at the time.
+    struct CapturingLogger(Mutex<Vec<String>>);
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            let mut logged = self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            logged.push(format!("{} {}", record.level(), record.args()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn logs_through_the_log_facade() {
+        static LOGGER: CapturingLogger = CapturingLogger(Mutex::new(vec![]));
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+        let dir = fixture_site("logging");
+        let addr = start(Website::new(&dir).unwrap());
+        request(addr, "GET /logged.html HTTP/1.1\r\n\r\n");
+        let logged = LOGGER.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone();
+        assert!(logged.iter().any(|line| line.starts_with("INFO starting server on ")));
+        assert!(logged.iter().any(|line| line.starts_with("INFO GET /logged.html -> 404 (114 bytes) in ")), "{:?}", logged);
+    }
+
+    /// Records every span created while it's the default subscriber as `name field=value...`.
+    #[derive(Clone, Default)]
+    struct SpanRecorder(Arc<Mutex<Vec<String>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for SpanRecorder {
+        fn on_new_span(&self, attrs: &tracing::span::Attributes<'_>, _: &tracing::span::Id, _: tracing_subscriber::layer::Context<'_, S>) {
+            struct Fields(String);
+            impl tracing::field::Visit for Fields {
+                fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                    self.0 += &format!(" {}={:?}", field.name(), value);
+                }
+            }
+            let mut fields = Fields(attrs.metadata().name().to_string());
+            attrs.record(&mut fields);
+            self.0.lock().unwrap().push(fields.0);
+        }
+    }
+
+    #[test]
+    fn request_spans() {
+        use tracing_subscriber::layer::SubscriberExt;
+        let recorder = SpanRecorder::default();
+        let subscriber = tracing_subscriber::registry().with(recorder.clone());
+        let dir = fixture_site("spans");
+        let site = Website::new(&dir).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let (server_side, _) = listener.accept().unwrap();
+        tracing::subscriber::with_default(subscriber, || site.handle_connection(server_side));
+        assert_eq!(*recorder.0.lock().unwrap(), vec!["request method=GET url=/"]);
+    }
+}
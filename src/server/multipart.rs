@@ -0,0 +1,185 @@
+use crate::server::error::ServerError;
+
+/// One part of a `multipart/form-data` body (RFC 7578): a form field, or an uploaded file
+/// if it has a file name.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Part {
+    /// the name of the form field
+    pub name: String,
+    /// the name of the uploaded file, as the client has it
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>
+}
+
+/// The boundary a `multipart/form-data` body with this `Content-Type` is split on, or `None` if it
+/// isn't `multipart/form-data` at all.
+pub fn boundary(content_type: &str) -> Option<Result<String, ServerError>> {
+    let (media_type, params) = content_type.split_once(';').unwrap_or((content_type, ""));
+    if !media_type.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    let boundary = parameters(params).into_iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("boundary"))
+        .map(|(_, boundary)| boundary)
+        .filter(|boundary| !boundary.is_empty() && boundary.len() <= 70);
+    Some(boundary.ok_or_else(|| ServerError::BadRequest("multipart/form-data without a valid boundary.".to_string())))
+}
+
+/// Splits `body` into its parts. Anything before the first boundary and after the last one is ignored.
+pub fn parse(body: &[u8], boundary: &str) -> Result<Vec<Part>, ServerError> {
+    let malformed = |reason: &str| ServerError::BadRequest(format!("Malformed multipart body: {}.", reason));
+    let delimiter = format!("\r\n--{}", boundary).into_bytes();
+    // the first boundary may start the body, without the line break before it
+    let mut at = if body.starts_with(&delimiter[2..]) {
+        delimiter.len() - 2
+    } else {
+        find(body, &delimiter, 0).ok_or_else(|| malformed("no boundary"))? + delimiter.len()
+    };
+    let mut parts = vec![];
+    loop {
+        if body[at..].starts_with(b"--") {
+            return Ok(parts);
+        }
+        // whitespace may follow a boundary before its line break
+        while body.get(at).is_some_and(|b| *b == b' ' || *b == b'\t') {
+            at += 1;
+        }
+        if !body[at..].starts_with(b"\r\n") {
+            return Err(malformed("no line break after a boundary"));
+        }
+        at += 2;
+        let (head_end, content_start) = if body[at..].starts_with(b"\r\n") {
+            (at, at + 2)
+        } else {
+            let end = find(body, b"\r\n\r\n", at).ok_or_else(|| malformed("a part's headers don't end"))?;
+            (end, end + 4)
+        };
+        let head = std::str::from_utf8(&body[at..head_end]).map_err(|_| malformed("a part's headers aren't UTF-8"))?;
+        let content_end = find(body, &delimiter, content_start).ok_or_else(|| malformed("no closing boundary"))?;
+        parts.push(part(head, body[content_start..content_end].to_vec()).ok_or_else(|| malformed("a part isn't a named form field"))?);
+        at = content_end + delimiter.len();
+    }
+}
+
+/// The part with the headers `head` and `body`, if its `Content-Disposition` names a form field.
+fn part(head: &str, body: Vec<u8>) -> Option<Part> {
+    let header = |wanted: &str| head.split("\r\n")
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case(wanted))
+        .map(|(_, value)| value.trim());
+    let (disposition, params) = header("Content-Disposition")?.split_once(';')?;
+    if !disposition.trim().eq_ignore_ascii_case("form-data") {
+        return None;
+    }
+    let params = parameters(params);
+    let param = |wanted: &str| params.iter().find(|(name, _)| name.eq_ignore_ascii_case(wanted)).map(|(_, value)| value.clone());
+    Some(Part {
+        name: param("name")?,
+        filename: param("filename"),
+        content_type: header("Content-Type").map(str::to_string),
+        body
+    })
+}
+
+/// The `name=value` parameters of a header, like `; name="field"; filename="a.txt"`, with quoted
+/// values unquoted. Semicolons in quoted values don't end them.
+fn parameters(text: &str) -> Vec<(String, String)> {
+    let mut params = vec![];
+    let mut chars = text.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| *c == ';' || c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            return params;
+        }
+        let mut name = String::new();
+        let mut has_value = false;
+        for c in chars.by_ref() {
+            match c {
+                '=' => {
+                    has_value = true;
+                    break;
+                }
+                ';' => break,
+                c => name.push(c)
+            }
+        }
+        if !has_value {
+            continue;
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c)
+                }
+            }
+        }
+        value.extend(chars.by_ref().take_while(|c| *c != ';'));
+        params.push((name.trim().to_string(), value.trim().to_string()));
+    }
+}
+
+/// Where `needle` first shows up in `haystack` at or after `from`.
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack.get(from..)?.windows(needle.len()).position(|window| window == needle).map(|i| i + from)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::server::error::ServerError;
+    use crate::server::multipart::{boundary, parse, Part};
+
+    #[test]
+    fn form_with_a_file() {
+        let body = b"--XyZ\r\n\
+            Content-Disposition: form-data; name=\"title\"\r\n\
+            \r\n\
+            Holiday\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"photo\"; filename=\"beach; 1.jpg\"\r\n\
+            Content-Type: image/jpeg\r\n\
+            \r\n\
+            \xff\xd8\r\n--not the boundary\r\n\
+            --XyZ--\r\n";
+        assert_eq!(parse(body, "XyZ").unwrap(), [
+            Part { name: "title".to_string(), filename: None, content_type: None, body: b"Holiday".to_vec() },
+            Part {
+                name: "photo".to_string(),
+                filename: Some("beach; 1.jpg".to_string()),
+                content_type: Some("image/jpeg".to_string()),
+                body: b"\xff\xd8\r\n--not the boundary".to_vec()
+            }
+        ]);
+    }
+
+    #[test]
+    fn framing() {
+        // a preamble, an epilogue, padding after a boundary, and a part without headers' values
+        let body = b"ignored\r\n--b  \r\nContent-Disposition: form-data; name=empty\r\n\r\n\r\n--b--ignored too";
+        assert_eq!(parse(body, "b").unwrap(), [Part { name: "empty".to_string(), filename: None, content_type: None, body: vec![] }]);
+        assert_eq!(parse(b"--b--", "b").unwrap(), []);
+        let bad = |body: &[u8]| matches!(parse(body, "b"), Err(ServerError::BadRequest(_)));
+        assert!(bad(b"no boundary at all"));
+        assert!(bad(b"--b\r\nContent-Disposition: form-data; name=a\r\n\r\nnever closed"));
+        assert!(bad(b"--b\r\nContent-Disposition: attachment; name=a\r\n\r\nx\r\n--b--"));
+        assert!(bad(b"--b\r\nContent-Disposition: form-data\r\n\r\nx\r\n--b--"));
+        assert!(bad(b"--bx\r\n"));
+        assert!(bad(b"--b\r\n\r\nno headers\r\n--b--"));
+    }
+
+    #[test]
+    fn boundaries() {
+        assert_eq!(boundary("multipart/form-data; boundary=abc").unwrap().unwrap(), "abc");
+        assert_eq!(boundary("Multipart/Form-Data; charset=utf-8; BOUNDARY=\"a b;c\"").unwrap().unwrap(), "a b;c");
+        assert_eq!(boundary("multipart/form-data; strange; boundary=abc").unwrap().unwrap(), "abc");
+        assert!(boundary("multipart/form-data").unwrap().is_err());
+        assert!(boundary("multipart/form-data; boundary=").unwrap().is_err());
+        assert!(boundary("application/x-www-form-urlencoded").is_none());
+    }
+}
@@ -0,0 +1,438 @@
+use std::io::Read;
+use std::net::{IpAddr, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::warn;
+use crate::server::balancer::Balancer;
+use crate::server::encoding;
+use crate::server::error::{is_timeout, ServerError, WebsiteError};
+use crate::server::request::Request;
+use crate::server::resources;
+use crate::server::response::Response;
+use crate::server::sites::Handler;
+use crate::server::{read_config, Website};
+
+/// How long the upstream gets to connect and to answer, unless configured otherwise.
+pub const DEFAULT_UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a failed upstream of a balanced prefix is skipped, unless configured otherwise.
+pub const DEFAULT_UPSTREAM_COOLDOWN: Duration = Duration::from_secs(10);
+
+/// The largest body an upstream may answer with, unless configured otherwise.
+pub const DEFAULT_MAX_UPSTREAM_BODY: u64 = 64 * 1024 * 1024;
+
+/// Headers that only concern a single connection, which a proxy mustn't pass on (RFC 7230, section 6.1).
+/// `Content-Length` is set again for the body as it's sent on.
+const HOP_BY_HOP: [&str; 9] = ["Connection", "Keep-Alive", "Transfer-Encoding", "TE", "Trailer", "Upgrade",
+    "Proxy-Authorization", "Proxy-Authenticate", "Content-Length"];
+
+/// Headers telling the upstream about the original request, which are only taken from trusted proxies.
+const FORWARDED: [&str; 3] = ["X-Forwarded-For", "X-Forwarded-Proto", "X-Forwarded-Host"];
+
+/// Forwards requests whose url path starts with a configured prefix to an upstream server,
+/// and serves all others with a website, which also reads the connections. An upstream's prefix
+/// wins over the website's files and mounts, even if there's a file of the same name.
+pub struct ProxyHandler {
+    site: Arc<Website>,
+    /// longest prefix first
+    upstreams: Vec<Upstream>,
+    /// proxies in front of this one, whose `X-Forwarded-*` headers are passed on instead of dropped
+    trusted_proxies: Vec<IpAddr>,
+    /// whether the upstream gets the client's `Host` instead of its own
+    preserve_host: bool,
+    /// how long the upstream gets, unless the request's deadline is sooner
+    timeout: Duration,
+    /// how long an upstream that failed is skipped
+    cooldown: Duration,
+    /// whether an upstream answering with a 5xx counts as failed, like one that can't be connected to
+    server_errors_fail: bool,
+    /// whether each client sticks to one upstream of a prefix, chosen by its address
+    sticky: bool,
+    /// bodies are read whole to be compressed, so longer ones are refused
+    max_body: u64,
+    agent: ureq::Agent
+}
+
+struct Upstream {
+    /// without a trailing `/`, so `/api` for `/api/`
+    prefix: String,
+    /// the base urls requests are spread over
+    balancer: Balancer,
+    /// whether the prefix is left out of the path the upstream gets
+    strip_prefix: bool
+}
+
+/// How forwarding a request to one of an upstream's base urls went.
+enum Forwarded {
+    Answered(Response),
+    /// the base url couldn't be connected to, so the request can go to another one
+    Unreachable(ureq::Error)
+}
+
+impl ProxyHandler {
+    pub fn new(site: Arc<Website>) -> ProxyHandler {
+        ProxyHandler {
+            site,
+            upstreams: vec![],
+            trusted_proxies: vec![],
+            preserve_host: false,
+            timeout: DEFAULT_UPSTREAM_TIMEOUT,
+            cooldown: DEFAULT_UPSTREAM_COOLDOWN,
+            server_errors_fail: true,
+            sticky: false,
+            max_body: DEFAULT_MAX_UPSTREAM_BODY,
+            agent: agent(DEFAULT_UPSTREAM_TIMEOUT)
+        }
+    }
+
+    /// Forwards requests under `prefix` to `base_url`, so with `/api` and `http://127.0.0.1:9000/v1`,
+    /// `/api/users?page=2` goes to `http://127.0.0.1:9000/v1/users?page=2`. `/api` and `/api/`
+    /// are under the prefix, `/apifoo` isn't. The longest matching prefix wins.
+    pub fn with_upstream(self, prefix: &str, base_url: &str) -> ProxyHandler {
+        self.add_upstream(prefix, &[base_url], true)
+    }
+
+    /// Like [`ProxyHandler::with_upstream`], but the upstream gets the whole path,
+    /// so `/api/users` goes to `http://127.0.0.1:9000/v1/api/users`.
+    pub fn with_upstream_keeping_prefix(self, prefix: &str, base_url: &str) -> ProxyHandler {
+        self.add_upstream(prefix, &[base_url], false)
+    }
+
+    /// Like [`ProxyHandler::with_upstream`], but requests under `prefix` are spread over `base_urls`,
+    /// which take turns. One that can't be connected to, or that answers with a 5xx, is skipped
+    /// for the cooldown, and a request that couldn't connect is tried on the next one.
+    /// When all of them are down, the client gets a 502.
+    pub fn with_balanced_upstream(self, prefix: &str, base_urls: &[&str]) -> ProxyHandler {
+        self.add_upstream(prefix, base_urls, true)
+    }
+
+    /// Adds the upstreams listed in `file`, one `prefix -> base url... [keep-prefix]` per line.
+    pub fn with_upstreams_file(self, file: impl AsRef<Path>) -> Result<ProxyHandler, WebsiteError> {
+        let upstreams = read_config(file.as_ref(), parse_upstreams)?;
+        Ok(upstreams.into_iter().fold(self, |proxy, (prefix, base_urls, strip_prefix)| {
+            proxy.add_upstream(&prefix, &base_urls.iter().map(String::as_str).collect::<Vec<_>>(), strip_prefix)
+        }))
+    }
+
+    fn add_upstream(mut self, prefix: &str, base_urls: &[&str], strip_prefix: bool) -> ProxyHandler {
+        self.upstreams.push(Upstream {
+            prefix: format!("/{}", prefix.trim_matches('/')).trim_end_matches('/').to_string(),
+            balancer: Balancer::new(base_urls.iter().map(|base_url| base_url.trim_end_matches('/').to_string()).collect()),
+            strip_prefix
+        });
+        self.upstreams.sort_by_key(|upstream| std::cmp::Reverse(upstream.prefix.len()));
+        self
+    }
+
+    /// Requests from these addresses come from proxies in front of this one, so the client address
+    /// is appended to their `X-Forwarded-For`, and their `X-Forwarded-Proto` and `X-Forwarded-Host`
+    /// are kept. Those headers from anyone else could be made up, so they're replaced.
+    pub fn with_trusted_proxies(mut self, trusted: Vec<IpAddr>) -> ProxyHandler {
+        self.trusted_proxies = trusted;
+        self
+    }
+
+    /// Sends the upstream the `Host` the client sent, instead of the upstream's own host,
+    /// for upstreams that serve several sites.
+    pub fn with_preserved_host(mut self, preserve: bool) -> ProxyHandler {
+        self.preserve_host = preserve;
+        self
+    }
+
+    /// How long connecting to the upstream and reading its response may take before the client gets a 504.
+    /// A request timeout set with [`Website::with_request_timeout`] cuts this short.
+    pub fn with_timeout(mut self, timeout: Duration) -> ProxyHandler {
+        self.timeout = timeout;
+        self.agent = agent(timeout);
+        self
+    }
+
+    /// How long an upstream of a balanced prefix is skipped after failing.
+    pub fn with_upstream_cooldown(mut self, cooldown: Duration) -> ProxyHandler {
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// Whether an upstream of a balanced prefix that answers with a 5xx is skipped for the cooldown,
+    /// which it is by default. The 5xx still goes to the client.
+    pub fn with_server_errors_as_failures(mut self, fail: bool) -> ProxyHandler {
+        self.server_errors_fail = fail;
+        self
+    }
+
+    /// Sends each client to the same upstream of a balanced prefix, picked by hashing its address,
+    /// instead of taking turns. A client whose upstream is down goes to the next one that's up.
+    pub fn with_sticky_upstreams(mut self, sticky: bool) -> ProxyHandler {
+        self.sticky = sticky;
+        self
+    }
+
+    /// The longest body an upstream may answer with; the client gets a 502 for a longer one.
+    pub fn with_max_upstream_body(mut self, bytes: u64) -> ProxyHandler {
+        self.max_body = bytes;
+        self
+    }
+
+    /// Forwards `request` to the first of `upstream`'s base urls that's up and can be connected to,
+    /// with `path` appended, marking the ones that fail as down.
+    fn balance(&self, upstream: &Upstream, path: &str, request: &Request) -> Result<Response, ServerError> {
+        let client = request.peer_addr.map(|peer| peer.ip());
+        let mut unreachable = None;
+        for i in upstream.balancer.candidates(client, self.sticky) {
+            let base_url = upstream.balancer.base_url(i);
+            match self.forward(&format!("{}{}", base_url, path), request)? {
+                Forwarded::Answered(response) => {
+                    if response.status >= 500 && self.server_errors_fail {
+                        warn!("upstream {} answered with {}, skipping it for {:?}", base_url, response.status, self.cooldown);
+                        upstream.balancer.mark_down(i, self.cooldown);
+                    }
+                    return Ok(response);
+                }
+                Forwarded::Unreachable(e) => {
+                    warn!("upstream {} is unreachable, skipping it for {:?}: {}", base_url, self.cooldown, e);
+                    upstream.balancer.mark_down(i, self.cooldown);
+                    unreachable = Some(format!("{} {}{}: {}", request.method, base_url, path, e));
+                }
+            }
+        }
+        Err(ServerError::BadGateway(unreachable.unwrap_or_else(|| format!("every upstream for {} is down", upstream.prefix))))
+    }
+
+    fn forward(&self, upstream_url: &str, request: &Request) -> Result<Forwarded, ServerError> {
+        let timed_out = || ServerError::GatewayTimeout(format!("{} {} didn't answer in time", request.method, upstream_url));
+        let timeout = match request.deadline {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()).min(self.timeout),
+            None => self.timeout
+        };
+        if timeout.is_zero() {
+            return Err(timed_out());
+        }
+        let mut upstream_request = self.agent.request(&request.method, upstream_url).timeout(timeout);
+        for (name, value) in &request.headers {
+            // the body has been decoded already, and the upstream's host is in the url
+            if !is_hop_by_hop(name, request.header("Connection"))
+                && !name.eq_ignore_ascii_case("Host") && !name.eq_ignore_ascii_case("Content-Encoding")
+                && !FORWARDED.iter().any(|forwarded| forwarded.eq_ignore_ascii_case(name)) {
+                upstream_request = upstream_request.set(name, value);
+            }
+        }
+        for (name, value) in self.forwarded_headers(request) {
+            upstream_request = upstream_request.set(name, &value);
+        }
+        if let Some(host) = request.header("Host").filter(|_| self.preserve_host) {
+            upstream_request = upstream_request.set("Host", host);
+        }
+        let upstream_response = match upstream_request.send_bytes(&request.body) {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+            Err(e) if is_timeout(std::error::Error::source(&e)) => return Err(timed_out()),
+            Err(e) if matches!(e.kind(), ureq::ErrorKind::ConnectionFailed | ureq::ErrorKind::Dns) =>
+                return Ok(Forwarded::Unreachable(e)),
+            Err(e) => return Err(ServerError::BadGateway(format!("{} {}: {}", request.method, upstream_url, e)))
+        };
+        let mut response = Response::new(upstream_response.status());
+        response.reason = upstream_response.status_text().to_string();
+        let connection = upstream_response.header("Connection").map(str::to_string);
+        for name in upstream_response.headers_names() {
+            if !is_hop_by_hop(&name, connection.as_deref()) {
+                for value in upstream_response.all(&name) {
+                    response = response.with_header(&name, value);
+                }
+            }
+        }
+        let is_text = upstream_response.header("Content-Type").is_some_and(is_text);
+        let mut body = vec![];
+        upstream_response.into_reader().take(self.max_body.saturating_add(1)).read_to_end(&mut body).map_err(|e| match is_timeout(Some(&e)) {
+            true => timed_out(),
+            false => ServerError::BadGateway(format!("reading the body from {} failed: {}", upstream_url, e))
+        })?;
+        if body.len() as u64 > self.max_body {
+            return Err(ServerError::BadGateway(format!("{} answered with more than {} bytes", upstream_url, self.max_body)));
+        }
+        // a gzipped body has been decompressed already, and has to be compressed again for clients that take it
+        let compressible = is_text && !self.site.encodings.is_empty()
+            && !response.headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("Content-Encoding"));
+        if compressible {
+            response = response.with_header("Vary", "Accept-Encoding");
+        }
+        let encoding = request.header("Accept-Encoding")
+            .and_then(|accepted| encoding::negotiate(accepted, &self.site.encodings))
+            .filter(|_| compressible && !body.is_empty());
+        Ok(Forwarded::Answered(match encoding {
+            Some(encoding) => {
+                // the upstream's tag is for the uncompressed body, which this only matches weakly
+                for (name, value) in response.headers.iter_mut() {
+                    if name.eq_ignore_ascii_case("ETag") && !value.starts_with("W/") {
+                        *value = format!("W/{}", value);
+                    }
+                }
+                let body = encoding.encode(&body)
+                    .map_err(|e| ServerError::BadGateway(format!("compressing the body from {} failed: {}", upstream_url, e)))?;
+                response.with_header("Content-Encoding", encoding.token()).with_body(body)
+            }
+            None => response.with_body(body)
+        }))
+    }
+
+    /// The `X-Forwarded-*` headers the upstream gets for `request`.
+    fn forwarded_headers(&self, request: &Request) -> Vec<(&'static str, String)> {
+        let trusted = request.peer_addr.is_some_and(|peer| self.trusted_proxies.contains(&peer.ip()));
+        let earlier = |name| request.header(name).filter(|_| trusted);
+        let mut headers = vec![];
+        let client = request.peer_addr.map(|peer| peer.ip().to_string());
+        match (earlier("X-Forwarded-For"), client) {
+            (Some(earlier), Some(client)) => headers.push(("X-Forwarded-For", format!("{}, {}", earlier, client))),
+            (Some(earlier), None) => headers.push(("X-Forwarded-For", earlier.to_string())),
+            (None, Some(client)) => headers.push(("X-Forwarded-For", client)),
+            (None, None) => {}
+        }
+        // connections are only ever plain HTTP here
+        headers.push(("X-Forwarded-Proto", earlier("X-Forwarded-Proto").unwrap_or("http").to_string()));
+        if let Some(host) = earlier("X-Forwarded-Host").or_else(|| request.header("Host")) {
+            headers.push(("X-Forwarded-Host", host.to_string()));
+        }
+        headers
+    }
+}
+
+impl Handler for ProxyHandler {
+    fn handle_connection(&self, stream: TcpStream) {
+        self.site.handle_connection_with(stream, &|_| &self.site, &|site, request| match route(&self.upstreams, &request.url) {
+            Some((upstream, path)) => self.balance(upstream, &path, &request),
+            None => site.handle_request(request)
+        })
+    }
+}
+
+/// The first of `upstreams` whose prefix `url` starts with, if any, along with the path and query
+/// to append to its base url. Dot segments are resolved first, so a path can't climb out of
+/// the prefix it's forwarded under.
+fn route<'u>(upstreams: &'u [Upstream], url: &str) -> Option<(&'u Upstream, String)> {
+    let (path, query) = url.split_at(url.find('?').unwrap_or(url.len()));
+    let path = resources::normalize_url_path(path);
+    let path = path.as_str();
+    upstreams.iter().find_map(|upstream| {
+        let rest = path.strip_prefix(upstream.prefix.as_str()).filter(|rest| rest.is_empty() || rest.starts_with('/'))?;
+        let forwarded_path = if upstream.strip_prefix { rest } else { path };
+        Some((upstream, format!("{}{}", forwarded_path, query)))
+    })
+}
+
+/// Parses upstreams from lines like `/api -> http://127.0.0.1:9000`, `/api -> http://127.0.0.1:9000 keep-prefix`
+/// or `/api -> http://127.0.0.1:9000 http://127.0.0.1:9001` for a balanced prefix,
+/// as `(prefix, base urls, strip prefix)`. Blank lines and lines starting with `#` are skipped.
+pub fn parse_upstreams(text: &str) -> Result<Vec<(String, Vec<String>, bool)>, String> {
+    let mut upstreams = vec![];
+    for (i, line) in text.lines().enumerate().map(|(i, line)| (i + 1, line.trim())) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let expected = || format!("line {}: expected `prefix -> base url... [keep-prefix]`", i);
+        let (prefix, rest) = line.split_once("->").ok_or_else(expected)?;
+        let mut base_urls = rest.split_whitespace().map(str::to_string).collect::<Vec<_>>();
+        let strip_prefix = base_urls.last().is_none_or(|last| last != "keep-prefix");
+        if !strip_prefix {
+            base_urls.pop();
+        }
+        let prefix = prefix.trim().to_string();
+        if !prefix.starts_with('/') || base_urls.is_empty() {
+            return Err(expected());
+        }
+        if let Some(base_url) = base_urls.iter().find(|url| !url.starts_with("http://") && !url.starts_with("https://")) {
+            return Err(format!("line {}: {} isn't an http or https url", i, base_url));
+        }
+        upstreams.push((prefix, base_urls, strip_prefix));
+    }
+    Ok(upstreams)
+}
+
+fn agent(timeout: Duration) -> ureq::Agent {
+    // redirects are the client's to follow
+    ureq::AgentBuilder::new()
+        .timeout(timeout)
+        .redirects(0)
+        .build()
+}
+
+/// Whether a body of `content_type` is text worth compressing, as opposed to images and archives,
+/// which are compressed already.
+fn is_text(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    media_type.starts_with("text/") || media_type.ends_with("+json") || media_type.ends_with("+xml")
+        || ["application/json", "application/javascript", "application/xml", "image/svg+xml"].contains(&media_type.as_str())
+}
+
+/// Whether the header `name` is hop-by-hop, either always or because `connection` lists it.
+fn is_hop_by_hop(name: &str, connection: Option<&str>) -> bool {
+    HOP_BY_HOP.iter().any(|header| header.eq_ignore_ascii_case(name))
+        || connection.is_some_and(|listed| listed.split(',').any(|option| option.trim().eq_ignore_ascii_case(name)))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::server::balancer::Balancer;
+    use crate::server::proxy::{is_hop_by_hop, is_text, parse_upstreams, route, Upstream};
+
+    #[test]
+    fn upstream_urls() {
+        let upstream = |prefix: &str, base_url: &str, strip_prefix| Upstream {
+            prefix: prefix.to_string(),
+            balancer: Balancer::new(vec![base_url.to_string()]),
+            strip_prefix
+        };
+        let upstream_url = |upstreams: &[Upstream], url: &str| route(upstreams, url)
+            .map(|(upstream, path)| format!("{}{}", upstream.balancer.base_url(0), path));
+        let upstreams = vec![
+            upstream("/api/admin", "http://127.0.0.1:9001", true),
+            upstream("/api", "http://127.0.0.1:9000/v1", true),
+            upstream("/raw", "http://127.0.0.1:9002", false)
+        ];
+        assert_eq!(upstream_url(&upstreams, "/api/users?page=2").as_deref(), Some("http://127.0.0.1:9000/v1/users?page=2"));
+        assert_eq!(upstream_url(&upstreams, "/api").as_deref(), Some("http://127.0.0.1:9000/v1"));
+        assert_eq!(upstream_url(&upstreams, "/api/").as_deref(), Some("http://127.0.0.1:9000/v1/"));
+        assert_eq!(upstream_url(&upstreams, "/api/admin/stats").as_deref(), Some("http://127.0.0.1:9001/stats"));
+        assert_eq!(upstream_url(&upstreams, "/raw/a?b").as_deref(), Some("http://127.0.0.1:9002/raw/a?b"));
+        assert_eq!(upstream_url(&upstreams, "/api/a/../b").as_deref(), Some("http://127.0.0.1:9000/v1/b"));
+        assert_eq!(upstream_url(&upstreams, "/api/admin/../stats").as_deref(), Some("http://127.0.0.1:9000/v1/stats"));
+        // nothing climbs out of a prefix
+        assert_eq!(upstream_url(&upstreams, "/api/../../admin"), None);
+        assert_eq!(upstream_url(&upstreams, "/api/%2e%2e/%2E%2E/admin?x"), None);
+        assert_eq!(upstream_url(&upstreams, "/raw/..//raw/../../etc/passwd"), None);
+        assert_eq!(upstream_url(&upstreams, "/apis"), None);
+        assert_eq!(upstream_url(&upstreams, "/"), None);
+    }
+
+    #[test]
+    fn upstreams_file() {
+        let upstreams = parse_upstreams("# backends\n/api -> http://127.0.0.1:9000\n\n/raw/ -> https://example.com/v1 keep-prefix\n").unwrap();
+        assert_eq!(upstreams, vec![
+            ("/api".to_string(), vec!["http://127.0.0.1:9000".to_string()], true),
+            ("/raw/".to_string(), vec!["https://example.com/v1".to_string()], false)
+        ]);
+        assert_eq!(parse_upstreams("/api -> http://127.0.0.1:9000 http://127.0.0.1:9001").unwrap(), vec![
+            ("/api".to_string(), vec!["http://127.0.0.1:9000".to_string(), "http://127.0.0.1:9001".to_string()], true)
+        ]);
+        assert!(parse_upstreams("/api -> keep-prefix").unwrap_err().starts_with("line 1: expected"));
+        assert!(parse_upstreams("/api http://127.0.0.1:9000").unwrap_err().starts_with("line 1:"));
+        assert!(parse_upstreams("\napi -> http://127.0.0.1:9000").unwrap_err().starts_with("line 2:"));
+        assert!(parse_upstreams("/api -> 127.0.0.1:9000").unwrap_err().contains("isn't an http or https url"));
+        assert!(parse_upstreams("/api -> http://127.0.0.1:9000 strip").is_err());
+    }
+
+    #[test]
+    fn hop_by_hop_headers() {
+        assert!(is_hop_by_hop("connection", None));
+        assert!(is_hop_by_hop("Transfer-Encoding", None));
+        assert!(is_hop_by_hop("X-Secret", Some("close, x-secret")));
+        assert!(!is_hop_by_hop("X-Request-Id", Some("close")));
+    }
+
+    #[test]
+    fn text_content_types() {
+        assert!(is_text("text/html; charset=utf-8"));
+        assert!(is_text("Application/JSON"));
+        assert!(is_text("application/problem+json"));
+        assert!(!is_text("image/png"));
+        assert!(!is_text("application/gzip"));
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::server::error::ServerError;
+use crate::server::response::Response;
+
+/// The most ranges, once overlapping and adjacent ones are merged, that are answered with a
+/// multipart body. Past this, the whole body is sent instead of hundreds of tiny parts.
+const MAX_RANGES: usize = 16;
+
+/// Parses a `Range` header for a body of `length` bytes into inclusive `(first, last)` byte positions.
+/// `None` means the header should be ignored and the whole body sent, as for units other than bytes
+/// or syntax errors (RFC 7233, section 3.1). A range that starts past the end makes it unsatisfiable.
+/// Ranges are sorted and the ones that overlap or touch are merged (section 4.1), and more than
+/// [`MAX_RANGES`] of them are ignored as well.
+pub fn parse_ranges(header: &str, length: u64) -> Result<Option<Vec<(u64, u64)>>, ServerError> {
+    let specs = match header.trim().strip_prefix("bytes=") {
+        Some(specs) => specs,
+        None => return Ok(None)
+    };
+    let mut ranges = vec![];
+    for spec in specs.split(',').map(str::trim).filter(|spec| !spec.is_empty()) {
+        let (first, last) = match spec.split_once('-') {
+            Some(bounds) => bounds,
+            None => return Ok(None)
+        };
+        let range = match (first.trim(), last.trim()) {
+            // the last `last` bytes
+            ("", suffix) => match suffix.parse::<u64>() {
+                Ok(0) => None,
+                Ok(suffix) => Some((length.saturating_sub(suffix), length.saturating_sub(1))),
+                Err(_) => return Ok(None)
+            },
+            (first, last) => {
+                let first = match first.parse::<u64>() {
+                    Ok(first) => first,
+                    Err(_) => return Ok(None)
+                };
+                let last = match last {
+                    "" => u64::MAX,
+                    last => match last.parse::<u64>() {
+                        Ok(last) if last >= first => last,
+                        _ => return Ok(None)
+                    }
+                };
+                Some((first, last.min(length.saturating_sub(1))))
+            }
+        };
+        match range {
+            Some((first, last)) if first < length => ranges.push((first, last)),
+            _ => return Err(ServerError::RangeNotSatisfiable(length))
+        }
+    }
+    let ranges = coalesce(ranges);
+    if ranges.is_empty() || ranges.len() > MAX_RANGES {
+        return Ok(None);
+    }
+    Ok(Some(ranges))
+}
+
+/// Sorts `ranges` and merges the ones that overlap or are right next to each other.
+fn coalesce(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (first, last) in ranges {
+        match merged.last_mut() {
+            Some((_, previous_last)) if first <= previous_last.saturating_add(1) => *previous_last = last.max(*previous_last),
+            _ => merged.push((first, last))
+        }
+    }
+    merged
+}
+
+/// A `206 Partial Content` with the bytes from `first` to `last` of `file`, whose size is `length`.
+pub fn build_range_response(mut file: File, length: u64, (first, last): (u64, u64)) -> io::Result<Response> {
+    file.seek(SeekFrom::Start(first))?;
+    Ok(Response::new(206)
+        .with_header("Content-Range", &format!("bytes {}-{}/{}", first, last, length))
+        .with_streamed_body(file, last - first + 1))
+}
+
+/// A `206 Partial Content` with each of `ranges` of `file`, whose size is `length`, as a part of a
+/// `multipart/byteranges` body, each with its own `Content-Range`. The parts are read from the
+/// file as they're sent, like a single range is.
+pub fn build_multipart_range_response(file: File, length: u64, ranges: &[(u64, u64)]) -> Response {
+    let boundary = format!("{:x}", SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos());
+    let mut pieces = VecDeque::with_capacity(ranges.len() * 2 + 1);
+    for &(first, last) in ranges {
+        let part_head = format!("\r\n--{}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n", boundary, first, last, length);
+        pieces.push_back(Piece::Text(Cursor::new(part_head.into_bytes())));
+        pieces.push_back(Piece::File { offset: first, remaining: last - first + 1 });
+    }
+    pieces.push_back(Piece::Text(Cursor::new(format!("\r\n--{}--\r\n", boundary).into_bytes())));
+    let body_length = pieces.iter().map(|piece| match piece {
+        Piece::Text(text) => text.get_ref().len() as u64,
+        Piece::File { remaining, .. } => *remaining
+    }).sum();
+    Response::new(206)
+        .with_header("Content-Type", &format!("multipart/byteranges; boundary={}", boundary))
+        .with_streamed_body(MultipartBody { file, pieces }, body_length)
+}
+
+/// The body of a multipart range response, read a piece at a time.
+struct MultipartBody {
+    file: File,
+    pieces: VecDeque<Piece>
+}
+
+/// The boundary and headers before a part, or the part's bytes of the file.
+enum Piece {
+    Text(Cursor<Vec<u8>>),
+    File { offset: u64, remaining: u64 }
+}
+
+impl Read for MultipartBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(piece) = self.pieces.front_mut() {
+            let read = match piece {
+                Piece::Text(text) => text.read(buf)?,
+                Piece::File { remaining: 0, .. } => 0,
+                Piece::File { offset, remaining } => {
+                    self.file.seek(SeekFrom::Start(*offset))?;
+                    let wanted = (*remaining).min(buf.len() as u64) as usize;
+                    let read = self.file.read(&mut buf[..wanted])?;
+                    if read == 0 {
+                        // the file got shorter; the response is cut short rather than padded
+                        return Ok(0);
+                    }
+                    *offset += read as u64;
+                    *remaining -= read as u64;
+                    read
+                }
+            };
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            self.pieces.pop_front();
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use crate::server::range::{build_multipart_range_response, parse_ranges};
+
+    #[test]
+    fn range_headers() {
+        assert_eq!(parse_ranges("bytes=0-499", 1000).unwrap(), Some(vec![(0, 499)]));
+        assert_eq!(parse_ranges("bytes=0-499, 600-999", 1000).unwrap(), Some(vec![(0, 499), (600, 999)]));
+        assert_eq!(parse_ranges("bytes=900-", 1000).unwrap(), Some(vec![(900, 999)]));
+        assert_eq!(parse_ranges("bytes=-100", 1000).unwrap(), Some(vec![(900, 999)]));
+        assert_eq!(parse_ranges("bytes=-5000", 1000).unwrap(), Some(vec![(0, 999)]));
+        assert_eq!(parse_ranges("bytes=500-5000", 1000).unwrap(), Some(vec![(500, 999)]));
+        assert_eq!(parse_ranges("items=0-1", 1000).unwrap(), None);
+        assert_eq!(parse_ranges("bytes=5-1", 1000).unwrap(), None);
+        assert_eq!(parse_ranges("bytes=a-b", 1000).unwrap(), None);
+        assert!(parse_ranges("bytes=1000-", 1000).is_err());
+        assert!(parse_ranges("bytes=0-1,2000-3000", 1000).is_err());
+        assert!(parse_ranges("bytes=-0", 1000).is_err());
+    }
+
+    #[test]
+    fn coalesced_ranges() {
+        assert_eq!(parse_ranges("bytes=600-999, 0-499", 1000).unwrap(), Some(vec![(0, 499), (600, 999)]));
+        assert_eq!(parse_ranges("bytes=0-499, 200-599", 1000).unwrap(), Some(vec![(0, 599)]));
+        assert_eq!(parse_ranges("bytes=0-499, 500-599", 1000).unwrap(), Some(vec![(0, 599)]));
+        assert_eq!(parse_ranges("bytes=0-0, 0-0, 0-0, 0-0", 1000).unwrap(), Some(vec![(0, 0)]));
+        assert_eq!(parse_ranges("bytes=100-200, -5000", 1000).unwrap(), Some(vec![(0, 999)]));
+        let many = (0..16).map(|i| format!("{}-{}", i * 10, i * 10)).collect::<Vec<_>>().join(",");
+        assert_eq!(parse_ranges(&format!("bytes={}", many), 1000).unwrap().unwrap().len(), 16);
+        let too_many = (0..17).map(|i| format!("{}-{}", i * 10, i * 10)).collect::<Vec<_>>().join(",");
+        assert_eq!(parse_ranges(&format!("bytes={}", too_many), 1000).unwrap(), None);
+    }
+
+    #[test]
+    fn multipart_ranges() {
+        let file = std::env::temp_dir().join(format!("simple-rust-webserver-range-{}", std::process::id()));
+        std::fs::write(&file, "0123456789abcdefghij").unwrap();
+        let mut response = build_multipart_range_response(File::open(&file).unwrap(), 20, &[(0, 4), (10, 12)]);
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(response.status, 206);
+        assert!(response.body.is_empty());
+        let (_, content_type) = response.headers.iter().find(|(key, _)| key == "Content-Type").unwrap();
+        let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap().to_string();
+        let body = format!("\
+            \r\n--{b}\r\nContent-Range: bytes 0-4/20\r\n\r\n01234\
+            \r\n--{b}\r\nContent-Range: bytes 10-12/20\r\n\r\nabc\
+            \r\n--{b}--\r\n", b = boundary);
+        let mut written = vec![];
+        response.write_to(&mut written).unwrap();
+        let written = String::from_utf8(written).unwrap();
+        assert!(written.ends_with(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body)), "{}", written);
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use crate::server::resources;
+use crate::server::response::Response;
+
+/// Where a redirected path leads, and with which status.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Redirect {
+    /// an absolute url, or a path on this site
+    to: String,
+    status: u16
+}
+
+impl Redirect {
+    /// The response redirecting a request whose url had `query` (with its `?`, or empty) after the path.
+    /// The query is carried over to a path on this site, unless the destination has one of its own.
+    pub fn response(&self, query: &str) -> Response {
+        let location = if self.to.contains("://") || self.to.contains('?') {
+            self.to.clone()
+        } else {
+            format!("{}{}", self.to, query)
+        };
+        Response::new(self.status).with_header("Location", &location)
+    }
+}
+
+/// What redirects are keyed by for `path`: the path normalized, and without a trailing `/`, so that
+/// `/old`, `/old/`, `//old` and `/%6Fld` all find the same redirect.
+pub fn key(path: &str) -> String {
+    let path = resources::normalize_url_path(path);
+    match path.strip_suffix('/') {
+        Some(path) if !path.is_empty() => path.to_string(),
+        _ => path
+    }
+}
+
+/// Parses redirects from lines like `/old -> /new` or `/old -> https://example.com/new 308`,
+/// keyed by the [`key`] of the path they redirect from. The status is 301 unless given, and one of 301, 302, 307 and 308.
+/// Blank lines and lines starting with `#` are skipped.
+pub fn parse_redirects(text: &str) -> Result<HashMap<String, Redirect>, String> {
+    let mut redirects = HashMap::new();
+    let mut lines_of = HashMap::new();
+    for (i, line) in text.lines().enumerate().map(|(i, line)| (i + 1, line.trim())) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (from, rest) = line.split_once("->")
+            .ok_or_else(|| format!("line {}: expected `from -> to [status]`", i))?;
+        let mut rest = rest.split_whitespace();
+        let (from, to) = match (from.trim(), rest.next()) {
+            (from, Some(to)) if !from.is_empty() => (from.to_string(), to.to_string()),
+            _ => return Err(format!("line {}: expected `from -> to [status]`", i))
+        };
+        let status = match rest.next() {
+            None => 301,
+            Some(status) => match status.parse() {
+                Ok(status @ (301 | 302 | 307 | 308)) => status,
+                _ => return Err(format!("line {}: {} isn't a redirect status, use 301, 302, 307 or 308", i, status))
+            }
+        };
+        if rest.next().is_some() {
+            return Err(format!("line {}: expected `from -> to [status]`", i));
+        }
+        if let Some(first) = lines_of.insert(key(&from), i) {
+            return Err(format!("line {}: {} is already redirected on line {}", i, from, first));
+        }
+        redirects.insert(key(&from), Redirect { to, status });
+    }
+    Ok(redirects)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::server::redirects::{key, parse_redirects, Redirect};
+
+    #[test]
+    fn redirects_file() {
+        let redirects = parse_redirects("# moved\n/old -> /new\n\n /gone -> https://example.com/gone 308 \n").unwrap();
+        assert_eq!(redirects.len(), 2);
+        assert_eq!(redirects["/old"], Redirect { to: "/new".to_string(), status: 301 });
+        assert_eq!(redirects["/gone"], Redirect { to: "https://example.com/gone".to_string(), status: 308 });
+        assert_eq!(parse_redirects("/a -> /b\n/c -> /d\n/a -> /e").unwrap_err(), "line 3: /a is already redirected on line 1");
+        assert_eq!(parse_redirects("/a /b").unwrap_err(), "line 1: expected `from -> to [status]`");
+        assert!(parse_redirects("/a -> /b 200").is_err());
+        assert!(parse_redirects("/a -> /b 301 302").is_err());
+        assert!(parse_redirects(" -> /b").is_err());
+        assert_eq!(parse_redirects("/a -> /b\n/a/ -> /c").unwrap_err(), "line 2: /a/ is already redirected on line 1");
+        assert_eq!(parse_redirects("/a/./b/ -> /c").unwrap().keys().collect::<Vec<_>>(), ["/a/b"]);
+    }
+
+    #[test]
+    fn redirect_keys() {
+        for path in ["/old", "/old/", "//old", "/./old", "/%6Fld", "/x/../old"] {
+            assert_eq!(key(path), "/old", "{}", path);
+        }
+        assert_eq!(key("/"), "/");
+        assert_eq!(key(""), "/");
+        assert_eq!(key("/my page"), "/my%20page");
+    }
+}
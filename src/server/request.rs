@@ -0,0 +1,324 @@
+use std::cell::RefCell;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::time::Instant;
+use crate::server::encoding::parse_qualities;
+use crate::server::error::ServerError;
+use crate::server::multipart;
+use crate::server::multipart::Part;
+
+/// How many of the client's preferred languages are tried, at most.
+const MAX_LANGUAGES: usize = 8;
+
+/// An HTTP request: the request line, headers, and body.
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub url: String,
+    pub http_version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// the address of the listener that accepted the connection, if known
+    pub local_addr: Option<SocketAddr>,
+    /// the address of the client, if known
+    pub peer_addr: Option<SocketAddr>,
+    /// when the response has to be ready by, if the site has a request timeout
+    pub deadline: Option<Instant>,
+    /// names of the headers the response was chosen by, see [`Request::varying_header`]
+    varied_on: RefCell<Vec<String>>
+}
+
+/// Returns the length of the request head (request line, headers and the blank line that ends them),
+/// or `None` if the blank line hasn't been received yet.
+/// Bare `\n` line endings are recognized here even in strict mode, so that `Request::parse` can reject them.
+pub fn find_head_end(data: &[u8]) -> Option<usize> {
+    for (i, b) in data.iter().enumerate() {
+        if *b == b'\n' {
+            match data.get(i + 1) {
+                Some(b'\n') => return Some(i + 2),
+                Some(b'\r') if data.get(i + 2) == Some(&b'\n') => return Some(i + 3),
+                _ => {}
+            }
+        }
+    }
+    None
+}
+
+/// Splits a request head into lines. Only the line terminator is removed, so a lone `\r`
+/// inside a line is kept as is.
+fn split_lines(head: &str, strict: bool) -> Result<Vec<&str>, ServerError> {
+    let mut lines = vec![];
+    let mut rest = head;
+    while let Some(i) = rest.find('\n') {
+        let line = &rest[..i];
+        let line = match line.strip_suffix('\r') {
+            Some(line) => line,
+            None if strict => return Err(ServerError::BadRequest("Request line endings must be CRLF.".to_string())),
+            None => line
+        };
+        lines.push(line);
+        rest = &rest[i + 1..];
+    }
+    if !rest.is_empty() {
+        lines.push(rest);
+    }
+    Ok(lines)
+}
+
+/// Whether `method` is a valid token (RFC 7230, section 3.2.6), which is all a method has to be.
+fn is_token(method: &str) -> bool {
+    !method.is_empty() && method.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+impl Request {
+    /// Parses the head of a request, as delimited by [`find_head_end`].
+    pub fn parse(head: &str, strict: bool) -> Result<Request, ServerError> {
+        let mut lines = split_lines(head, strict)?.into_iter();
+        let request_line = lines.next()
+            .ok_or_else(|| ServerError::BadRequest("Malformatted request.".to_string()))?;
+        let args = request_line.split(' ').collect::<Vec<_>>();
+        if args.len() < 3 {
+            return Err(ServerError::BadRequest("Badly formatted HTTP request.".to_string()));
+        }
+        if !is_token(args[0]) {
+            return Err(ServerError::BadRequest("Invalid method.".to_string()));
+        }
+        let mut headers = vec![];
+        for line in lines {
+            if line.is_empty() {
+                break;
+            }
+            match line.split_once(':') {
+                Some((key, value)) => headers.push((
+                    key.trim().to_string(),
+                    value.trim_matches(|c| c == ' ' || c == '\t').to_string()
+                )),
+                None => return Err(ServerError::BadRequest("Malformed header line.".to_string()))
+            }
+        }
+        Ok(Request {
+            method: args[0].to_string(),
+            url: args[1].to_string(),
+            http_version: args[2].to_string(),
+            headers,
+            body: vec![],
+            local_addr: None,
+            peer_addr: None,
+            deadline: None,
+            varied_on: RefCell::new(vec![])
+        })
+    }
+
+    /// The length of the body according to `Content-Length`, 0 if there's no such header.
+    /// Bodies sent with a `Transfer-Encoding` are refused, since they can't be read here: reading
+    /// such a body as empty would take its chunks for the next request.
+    pub fn content_length(&self) -> Result<usize, ServerError> {
+        if let Some(encoding) = self.header("Transfer-Encoding") {
+            return Err(match self.header("Content-Length") {
+                // RFC 7230 section 3.3.3: a message with both may be an attempt at request smuggling
+                Some(_) => ServerError::BadRequest("Both Transfer-Encoding and Content-Length.".to_string()),
+                None => ServerError::NotImplemented(format!("Transfer-Encoding: {}", encoding))
+            });
+        }
+        match self.header("Content-Length") {
+            Some(length) => length.parse()
+                .map_err(|_| ServerError::BadRequest("Invalid Content-Length.".to_string())),
+            None => Ok(0)
+        }
+    }
+
+    /// Undoes the `Content-Encoding` of the body. Only gzip is understood; bodies in any other
+    /// encoding are rejected with a 415. A decoded body longer than `limit` is rejected with a 413,
+    /// without inflating more of it than that.
+    pub fn decode_body(&mut self, limit: Option<usize>) -> Result<(), ServerError> {
+        let encoding = match self.header("Content-Encoding") {
+            Some(encoding) => encoding.to_ascii_lowercase(),
+            None => return Ok(())
+        };
+        match encoding.as_str() {
+            "identity" => Ok(()),
+            "gzip" | "x-gzip" => {
+                let mut decoded = vec![];
+                let most = limit.map_or(u64::MAX, |limit| limit as u64 + 1);
+                flate2::read::GzDecoder::new(&self.body[..]).take(most).read_to_end(&mut decoded)
+                    .map_err(|_| ServerError::BadRequest("Body isn't valid gzip.".to_string()))?;
+                if let Some(limit) = limit.filter(|limit| decoded.len() > *limit) {
+                    return Err(ServerError::PayloadTooLarge(format!("gzip body inflates past {} bytes", limit)));
+                }
+                self.body = decoded;
+                Ok(())
+            }
+            _ => Err(ServerError::UnsupportedMediaType(format!("Content-Encoding: {}", encoding)))
+        }
+    }
+
+    /// The fields and files of a `multipart/form-data` body, like an upload form sends.
+    /// Bodies of any other type are rejected with a 415, and malformed ones with a 400.
+    pub fn multipart_parts(&self) -> Result<Vec<Part>, ServerError> {
+        let content_type = self.header("Content-Type").unwrap_or_default();
+        let boundary = multipart::boundary(content_type)
+            .ok_or_else(|| ServerError::UnsupportedMediaType(format!("{} isn't multipart/form-data", content_type)))??;
+        multipart::parse(&self.body, &boundary)
+    }
+
+    /// The language tags in the `Accept-Language` header, lowercased, most wanted first. Tags
+    /// the client refuses with `q=0`, `*`, and anything that isn't a language tag are left out,
+    /// and only the `MAX_LANGUAGES` most wanted are kept, since each may cost a file lookup.
+    /// The response then varies on the header.
+    pub fn preferred_languages(&self) -> Vec<String> {
+        let mut languages = parse_qualities(self.varying_header("Accept-Language").unwrap_or_default());
+        languages.retain(|(tag, quality)| *quality > 0.0
+            && tag.split('-').all(|subtag| !subtag.is_empty() && subtag.bytes().all(|b| b.is_ascii_alphanumeric())));
+        // stable, so equally wanted languages stay in the order they were listed
+        languages.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        languages.into_iter().take(MAX_LANGUAGES).map(|(tag, _)| tag).collect()
+    }
+
+    /// Header names are case-insensitive; this returns the first value for `name`.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Like [`Request::header`], but also records that the response depends on this header,
+    /// whether or not the client sent it, so that it ends up in the response's `Vary` header.
+    pub fn varying_header(&self, name: &str) -> Option<&str> {
+        let mut varied_on = self.varied_on.borrow_mut();
+        if !varied_on.iter().any(|h| h.eq_ignore_ascii_case(name)) {
+            varied_on.push(name.to_string());
+        }
+        self.header(name)
+    }
+
+    /// The value for the response's `Vary` header, if the response depended on any request headers.
+    pub fn vary(&self) -> Option<String> {
+        let varied_on = self.varied_on.borrow();
+        if varied_on.is_empty() {
+            None
+        } else {
+            Some(varied_on.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::server::error::ServerError;
+    use crate::server::request::{find_head_end, Request};
+
+    fn parse(data: &str, strict: bool) -> Result<Request, ServerError> {
+        let end = find_head_end(data.as_bytes())
+            .ok_or_else(|| ServerError::BadRequest("no end of head".to_string()))?;
+        Request::parse(&data[..end], strict)
+    }
+
+    #[test]
+    fn crlf_request() {
+        let request = parse("GET /a.html HTTP/1.1\r\nHost: example.com\r\n\r\nbody", true).unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.url, "/a.html");
+        assert_eq!(request.http_version, "HTTP/1.1");
+        assert_eq!(request.header("host"), Some("example.com"));
+    }
+
+    #[test]
+    fn all_lf_request() {
+        let data = "GET /a.html HTTP/1.1\nHost: example.com\nAccept: */*\n\nbody";
+        assert_eq!(find_head_end(data.as_bytes()), Some(data.len() - 4));
+        let request = parse(data, false).unwrap();
+        assert_eq!(request.url, "/a.html");
+        assert_eq!(request.header("Accept"), Some("*/*"));
+        assert!(parse(data, true).is_err());
+    }
+
+    #[test]
+    fn mixed_line_endings() {
+        let request = parse("GET / HTTP/1.1\r\nHost: example.com\nAccept: */*\r\n\n", false).unwrap();
+        assert_eq!(request.http_version, "HTTP/1.1");
+        assert_eq!(request.header("Host"), Some("example.com"));
+        assert_eq!(request.header("Accept"), Some("*/*"));
+        assert!(Request::parse("GET / HTTP/1.1\r\nHost: example.com\nAccept: */*\r\n\n", true).is_err());
+    }
+
+    #[test]
+    fn lone_cr_in_header_value() {
+        let request = parse("GET / HTTP/1.1\nX-Odd: a\rb\n\n", false).unwrap();
+        assert_eq!(request.header("X-Odd"), Some("a\rb"));
+    }
+
+    #[test]
+    fn vary_lists_consulted_headers_once() {
+        let request = parse("GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n", true).unwrap();
+        assert_eq!(request.vary(), None);
+        request.header("Host");
+        assert_eq!(request.vary(), None);
+        assert_eq!(request.varying_header("accept-encoding"), Some("gzip"));
+        assert_eq!(request.varying_header("Authorization"), None);
+        request.varying_header("Accept-Encoding");
+        assert_eq!(request.vary(), Some("accept-encoding, Authorization".to_string()));
+    }
+
+    #[test]
+    fn preferred_languages() {
+        let request = parse("GET / HTTP/1.1\r\nAccept-Language: en;q=0.8, fr-CA, de;q=0, *;q=0.1, ../x, fr;q=0.9\r\n\r\n", true).unwrap();
+        assert_eq!(request.preferred_languages(), vec!["fr-ca", "fr", "en"]);
+        assert_eq!(request.vary(), Some("Accept-Language".to_string()));
+        assert!(parse("GET / HTTP/1.1\r\n\r\n", true).unwrap().preferred_languages().is_empty());
+        let many = (0..100).map(|i| format!("l{};q=0.{:02}", i, i)).collect::<Vec<_>>().join(", ");
+        let request = parse(&format!("GET / HTTP/1.1\r\nAccept-Language: {}\r\n\r\n", many), true).unwrap();
+        assert_eq!(request.preferred_languages(), vec!["l99", "l98", "l97", "l96", "l95", "l94", "l93", "l92"]);
+    }
+
+    #[test]
+    fn method_must_be_a_token() {
+        assert_eq!(parse("FROBNICATE / HTTP/1.1\r\n\r\n", true).unwrap().method, "FROBNICATE");
+        assert!(parse("G(T / HTTP/1.1\r\n\r\n", true).is_err());
+        assert!(parse(" / HTTP/1.1\r\n\r\n", true).is_err());
+    }
+
+    #[test]
+    fn content_length() {
+        assert_eq!(parse("PUT / HTTP/1.1\r\nContent-Length: 12\r\n\r\n", true).unwrap().content_length().unwrap(), 12);
+        assert_eq!(parse("GET / HTTP/1.1\r\n\r\n", true).unwrap().content_length().unwrap(), 0);
+        assert!(parse("PUT / HTTP/1.1\r\nContent-Length: -1\r\n\r\n", true).unwrap().content_length().is_err());
+        let chunked = parse("PUT / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n", true).unwrap();
+        assert_eq!(chunked.content_length().unwrap_err().status(), 501);
+        let both = parse("PUT / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n", true).unwrap();
+        assert_eq!(both.content_length().unwrap_err().status(), 400);
+    }
+
+    #[test]
+    fn gzip_bodies() {
+        let json = br#"{"name": "index.html", "size": 14}"#;
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, json).unwrap();
+        let mut request = parse("POST / HTTP/1.1\r\nContent-Encoding: gzip\r\n\r\n", true).unwrap();
+        request.body = encoder.finish().unwrap();
+        let compressed = request.body.clone();
+        request.decode_body(Some(json.len())).unwrap();
+        assert_eq!(request.body, json);
+        // the limit is on what the body inflates to
+        request.body = compressed;
+        assert_eq!(request.decode_body(Some(json.len() - 1)).unwrap_err().status(), 413);
+
+        let mut request = parse("POST / HTTP/1.1\r\nContent-Encoding: gzip\r\n\r\n", true).unwrap();
+        request.body = json.to_vec();
+        assert_eq!(request.decode_body(None).unwrap_err().status(), 400);
+
+        let mut request = parse("POST / HTTP/1.1\r\nContent-Encoding: br\r\n\r\n", true).unwrap();
+        assert_eq!(request.decode_body(None).unwrap_err().status(), 415);
+
+        let mut request = parse("POST / HTTP/1.1\r\n\r\n", true).unwrap();
+        request.body = json.to_vec();
+        request.decode_body(Some(1)).unwrap();
+        assert_eq!(request.body, json);
+    }
+
+    #[test]
+    fn head_needs_blank_line() {
+        assert_eq!(find_head_end(b"GET / HTTP/1.1\nHost: example.com\n"), None);
+        assert_eq!(find_head_end(b"GET / HTTP/1.1\r\nHost: example.com\r\n"), None);
+        assert_eq!(find_head_end(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"), Some(37));
+    }
+}
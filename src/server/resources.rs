@@ -0,0 +1,397 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::server::error::ServerError;
+use crate::server::SendMethod;
+
+/// Extensions served as text (and so eligible for compression and templating) by the
+/// configurable layouts; everything else is sent as is.
+const TEXT_EXTENSIONS: [&str; 9] = ["html", "htm", "css", "js", "json", "txt", "svg", "xml", "md"];
+
+/// Where in the website directory the file for a url is looked up.
+#[derive(Clone, Debug, Default)]
+pub enum ResourceLayout {
+    /// The original site structure: `.js` files from `scripts/`, `.html`, `.css`, `.jpg`, `.ico` and `.png`
+    /// files from `layout/`, and `/` is the index file in `layout/`. Only the last segment of the url path counts,
+    /// and other extensions aren't served.
+    #[default]
+    Split,
+    /// Url paths map onto this directory as they are, so `/js/app.js` is `<root>/js/app.js`.
+    /// A path ending in `/` means the index file in that directory.
+    DocumentRoot(PathBuf),
+    /// Like `DocumentRoot`, but the directory depends on the file's extension (without the dot).
+    /// Every rule for the extension is tried in order, then `fallback`; the first directory that
+    /// has the file wins. Without any such directory, the request is a 404.
+    Rules {
+        rules: Vec<(String, PathBuf)>,
+        fallback: Option<PathBuf>
+    }
+}
+
+impl ResourceLayout {
+    /// The directories, relative to the website location, this layout serves files from.
+    pub fn directories(&self) -> Vec<&Path> {
+        match self {
+            ResourceLayout::Split => vec![Path::new("layout")],
+            ResourceLayout::DocumentRoot(root) => vec![root],
+            ResourceLayout::Rules { rules, fallback } => rules.iter()
+                .map(|(_, dir)| dir.as_path())
+                .chain(fallback.as_deref())
+                .collect()
+        }
+    }
+
+    /// Finds the file for `url` under `loc`, trying each of `index_files` in order for directories.
+    /// The file might not exist; reading it tells.
+    pub(super) fn resolve(&self, loc: &Path, url: &str, index_files: &[String]) -> Result<(SendMethod, PathBuf), ServerError> {
+        let (rules, fallback) = match self {
+            ResourceLayout::Split => return resolve_split(loc, url, index_files),
+            ResourceLayout::DocumentRoot(root) => (&[][..], Some(root)),
+            ResourceLayout::Rules { rules, fallback } => (&rules[..], fallback.as_ref())
+        };
+        let path = relative_path(url)?;
+        let names = if url.split('?').next().unwrap_or_default().ends_with('/') || path.as_os_str().is_empty() {
+            index_files.iter().map(|name| path.join(name)).collect()
+        } else {
+            vec![path]
+        };
+        let candidates: Vec<PathBuf> = names.iter()
+            .flat_map(|name| {
+                let extension = extension_of(name);
+                rules.iter()
+                    .filter(move |(ext, _)| ext.eq_ignore_ascii_case(&extension))
+                    .map(|(_, dir)| dir)
+                    .chain(fallback)
+                    .map(move |dir| loc.join(dir).join(name))
+            })
+            .collect();
+        match first_existing(&candidates) {
+            Some(found) => Ok((send_method_for(found), found.clone())),
+            None => Err(ServerError::NotFound(format!("no directory is configured for {}", url)))
+        }
+    }
+}
+
+/// What to do about a trailing `/` that doesn't match what's on disk. The root path is always left alone.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TrailingSlash {
+    /// serve urls as they come
+    #[default]
+    None,
+    /// `301` from `/docs` to `/docs/` when `docs` is a directory
+    RedirectToSlash,
+    /// `301` from `/page.html/` to `/page.html` when `page.html` is a file
+    RedirectToNoSlash
+}
+
+/// A url prefix served from its own directory, like nginx's `alias`.
+#[derive(Clone, Debug)]
+pub struct Mount {
+    /// without a trailing `/`, so `/static` for `/static/`
+    prefix: String,
+    /// canonical path of the directory
+    dir: PathBuf
+}
+
+impl Mount {
+    pub(super) fn new(prefix: &str, dir: PathBuf) -> Mount {
+        Mount {
+            prefix: format!("/{}", prefix.trim_matches('/')).trim_end_matches('/').to_string(),
+            dir
+        }
+    }
+
+    /// The rest of `path` if it's under this mount; `/static` and `/static/a.css` are, `/statics` isn't.
+    fn strip<'p>(&self, path: &'p str) -> Option<&'p str> {
+        path.strip_prefix(&self.prefix).filter(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+}
+
+/// Finds the file for `url` in the mount with the longest prefix of it, if there is one.
+pub(super) fn resolve_mounted(mounts: &[Mount], url: &str, index_files: &[String]) -> Result<Option<(SendMethod, PathBuf)>, ServerError> {
+    let path = url.split('?').next().unwrap_or_default();
+    let (mount, rest) = match mounts.iter()
+        .filter_map(|mount| mount.strip(path).map(|rest| (mount, rest)))
+        .max_by_key(|(mount, _)| mount.prefix.len()) {
+        Some(found) => found,
+        None => return Ok(None)
+    };
+    let relative = relative_path(rest)?;
+    let candidates: Vec<PathBuf> = if rest.is_empty() || rest.ends_with('/') {
+        index_files.iter().map(|name| mount.dir.join(&relative).join(name)).collect()
+    } else {
+        vec![mount.dir.join(&relative)]
+    };
+    let resolved = match first_existing(&candidates) {
+        Some(found) => found.clone(),
+        None => return Err(ServerError::NotFound(format!("no index file is configured for {}", url)))
+    };
+    // a symlink in the mounted directory mustn't lead out of it
+    check_no_symlink_escape(&resolved, &mount.dir)?;
+    Ok(Some((send_method_for(&resolved), resolved)))
+}
+
+/// Refuses `path`, which is `root` joined with a relative path, if a symlink among its components
+/// leads out of `root`, which has to be canonical. Symlinks that stay within `root` are fine,
+/// and paths that don't exist are left for reading them to fail.
+pub(super) fn check_no_symlink_escape(path: &Path, root: &Path) -> Result<(), ServerError> {
+    let has_symlink = path.ancestors()
+        .take_while(|ancestor| ancestor.starts_with(root) && *ancestor != root)
+        .any(|ancestor| fs::symlink_metadata(ancestor).is_ok_and(|metadata| metadata.file_type().is_symlink()));
+    if !has_symlink {
+        return Ok(());
+    }
+    match fs::canonicalize(path) {
+        Ok(canonical) if !canonical.starts_with(root) =>
+            Err(ServerError::Forbidden(format!("{} leads out of {}", path.display(), root.display()))),
+        _ => Ok(())
+    }
+}
+
+/// The first of `candidates` that is a file, or else the first one, so that reading it fails.
+fn first_existing(candidates: &[PathBuf]) -> Option<&PathBuf> {
+    candidates.iter().find(|candidate| candidate.is_file()).or_else(|| candidates.first())
+}
+
+/// Parses mount points from lines like `/static/ -> /srv/static`. Blank lines and lines starting with `#` are skipped.
+pub fn parse_mounts(text: &str) -> Result<Vec<(String, PathBuf)>, String> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| match line.split_once("->") {
+            Some((prefix, dir)) if !prefix.trim().is_empty() && !dir.trim().is_empty() =>
+                Ok((prefix.trim().to_string(), PathBuf::from(dir.trim()))),
+            _ => Err(format!("line {}: expected `prefix -> directory`", i + 1))
+        })
+        .collect()
+}
+
+fn extension_of(path: &Path) -> String {
+    path.extension().and_then(|ext| ext.to_str()).unwrap_or_default().to_ascii_lowercase()
+}
+
+pub fn send_method_for(path: &Path) -> SendMethod {
+    if TEXT_EXTENSIONS.contains(&extension_of(path).as_str()) {
+        SendMethod::PlainText
+    } else {
+        SendMethod::Binary
+    }
+}
+
+fn resolve_split(loc: &Path, url: &str, index_files: &[String]) -> Result<(SendMethod, PathBuf), ServerError> {
+    let path: Vec<&str> = url.split('/').filter(|s| !s.is_empty()).collect();
+    if !path.is_empty() {
+        let mut last_file = path.last().unwrap();
+        let args: Vec<_> = last_file.split('?').collect();
+        if args.len() > 1 {
+            last_file = args.first().unwrap();
+            let _args: Vec<_> = args.last().unwrap().split('&').collect();
+            // do something with args
+        }
+        let last_file = percent_decode(last_file);
+        if last_file.contains(['/', '\\', '\0']) || last_file == ".." {
+            return Err(ServerError::Forbidden(format!("{} has a backslash, slash or NUL in its file name", url)));
+        }
+        let last_file = last_file.as_str();
+        if last_file.ends_with(".js") {
+            Ok((SendMethod::PlainText, loc.join("scripts").join(last_file)))
+        } else if [".html", ".css"].iter().any(|s| last_file.ends_with(s)) {
+            Ok((SendMethod::PlainText, loc.join("layout").join(last_file)))
+        } else if [".jpg", ".ico", ".png"].iter().any(|s| last_file.ends_with(s)) {
+            Ok((SendMethod::Binary, loc.join("layout").join(last_file)))
+        } else {
+            Err(ServerError::NotFound(format!("Don't know how to look for resource at {}", url)))
+        }
+    } else {
+        let candidates: Vec<PathBuf> = index_files.iter().map(|name| loc.join("layout").join(name)).collect();
+        match first_existing(&candidates) {
+            Some(found) => Ok((SendMethod::PlainText, found.clone())),
+            None => Err(ServerError::NotFound(format!("no index file is configured for {}", url)))
+        }
+    }
+}
+
+/// Percent-decodes `path` and resolves it the way RFC 3986 section 5.2.4 removes dot segments:
+/// empty and `.` segments go, and `..` drops the segment before it, but never climbs above the root.
+/// A trailing `/` is kept, so `/docs/` still names a directory. `path` must not have a query.
+/// The segments are percent-encoded again where they have to be, so that a `%3F` doesn't turn into
+/// the start of a query and a `%0D%0A` can't end up in a header as a line break.
+pub fn normalize_url_path(path: &str) -> String {
+    let decoded = percent_decode(path);
+    let mut segments: Vec<&str> = vec![];
+    let mut trailing_slash = false;
+    for segment in decoded.split('/') {
+        trailing_slash = matches!(segment, "" | "." | "..");
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment)
+        }
+    }
+    let segments: Vec<String> = segments.into_iter().map(percent_encode_segment).collect();
+    if segments.is_empty() {
+        "/".to_string()
+    } else if trailing_slash {
+        format!("/{}/", segments.join("/"))
+    } else {
+        format!("/{}", segments.join("/"))
+    }
+}
+
+/// Percent-encodes every byte of `segment` that RFC 3986 doesn't allow in a path segment as it is.
+fn percent_encode_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~'
+            | b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+' | b',' | b';' | b'=' | b':' | b'@' =>
+                encoded.push(byte as char),
+            _ => encoded += &format!("%{:02X}", byte)
+        }
+    }
+    encoded
+}
+
+/// Decodes `%XX` escapes, leaving malformed ones as they are.
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// The path of `url` as a relative file path, refusing paths that would climb out of where they're joined onto.
+/// Each segment is percent-decoded on its own, so an encoded `/` can't add a directory.
+pub fn relative_path(url: &str) -> Result<PathBuf, ServerError> {
+    let path = url.split('?').next().unwrap_or_default();
+    let mut relative = PathBuf::new();
+    for segment in path.split('/') {
+        match percent_decode(segment).as_str() {
+            "" | "." => {}
+            ".." => return Err(ServerError::Forbidden(format!("{} leaves the website location", url))),
+            segment if segment.contains(['\\', '/', '\0']) =>
+                return Err(ServerError::Forbidden(format!("{} has a backslash, slash or NUL in a segment", url))),
+            segment => relative.push(segment)
+        }
+    }
+    Ok(relative)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+    use crate::server::resources::{normalize_url_path, parse_mounts, relative_path, ResourceLayout};
+    use crate::server::scratch::ScratchDir;
+    use crate::server::SendMethod;
+
+    fn resolve(layout: &ResourceLayout, loc: &Path, url: &str) -> Option<(bool, PathBuf)> {
+        layout.resolve(loc, url, &["index.html".to_string()]).ok()
+            .map(|(method, path)| (matches!(method, SendMethod::PlainText), path.strip_prefix(loc).unwrap().to_path_buf()))
+    }
+
+    #[test]
+    fn split_layout() {
+        let loc = Path::new("/site");
+        let layout = ResourceLayout::Split;
+        assert_eq!(resolve(&layout, loc, "/"), Some((true, "layout/index.html".into())));
+        assert_eq!(resolve(&layout, loc, "/deep/app.js?v=2"), Some((true, "scripts/app.js".into())));
+        assert_eq!(resolve(&layout, loc, "/logo.png"), Some((false, "layout/logo.png".into())));
+        assert_eq!(resolve(&layout, loc, "/font.woff"), None);
+    }
+
+    #[test]
+    fn document_root() {
+        let loc = Path::new("/site");
+        let layout = ResourceLayout::DocumentRoot("public".into());
+        assert_eq!(resolve(&layout, loc, "/"), Some((true, "public/index.html".into())));
+        assert_eq!(resolve(&layout, loc, "/docs/"), Some((true, "public/docs/index.html".into())));
+        assert_eq!(resolve(&layout, loc, "/js/app.js?v=2"), Some((true, "public/js/app.js".into())));
+        assert_eq!(resolve(&layout, loc, "/font.woff"), Some((false, "public/font.woff".into())));
+        assert!(layout.resolve(loc, "/../secret", &[]).is_err());
+        assert!(layout.resolve(loc, "/docs/", &[]).is_err());
+    }
+
+    #[test]
+    fn rules_fall_back_in_order() {
+        let scratch = ScratchDir::new("resources");
+        let loc = scratch.path();
+        for dir in ["scripts", "vendor", "public"] {
+            std::fs::create_dir_all(loc.join(dir)).unwrap();
+        }
+        std::fs::write(loc.join("vendor/lib.js"), "").unwrap();
+        std::fs::write(loc.join("scripts/lib.js"), "").unwrap();
+        std::fs::write(loc.join("vendor/only-vendor.js"), "").unwrap();
+        std::fs::write(loc.join("public/everywhere.js"), "").unwrap();
+        let layout = ResourceLayout::Rules {
+            rules: vec![("js".to_string(), "scripts".into()), ("JS".to_string(), "vendor".into())],
+            fallback: Some("public".into())
+        };
+        assert_eq!(resolve(&layout, loc, "/lib.js"), Some((true, "scripts/lib.js".into())));
+        assert_eq!(resolve(&layout, loc, "/only-vendor.js"), Some((true, "vendor/only-vendor.js".into())));
+        assert_eq!(resolve(&layout, loc, "/everywhere.js"), Some((true, "public/everywhere.js".into())));
+        // nowhere to be found, so the first candidate is what ends up a 404
+        assert_eq!(resolve(&layout, loc, "/missing.js"), Some((true, "scripts/missing.js".into())));
+        assert_eq!(resolve(&layout, loc, "/page.html"), Some((true, "public/page.html".into())));
+
+        let no_fallback = ResourceLayout::Rules { rules: vec![("js".to_string(), "scripts".into())], fallback: None };
+        assert_eq!(resolve(&no_fallback, loc, "/page.html"), None);
+    }
+
+    #[test]
+    fn url_normalization() {
+        assert_eq!(normalize_url_path("//a//b"), "/a/b");
+        assert_eq!(normalize_url_path("/a/./b"), "/a/b");
+        assert_eq!(normalize_url_path("/a/../b"), "/b");
+        assert_eq!(normalize_url_path("/a/../../b"), "/b");
+        assert_eq!(normalize_url_path("/layout/../scripts/app.js"), "/scripts/app.js");
+        assert_eq!(normalize_url_path("/docs/"), "/docs/");
+        assert_eq!(normalize_url_path("/docs/."), "/docs/");
+        assert_eq!(normalize_url_path("/a/b/.."), "/a/");
+        assert_eq!(normalize_url_path(""), "/");
+        assert_eq!(normalize_url_path("/.."), "/");
+        assert_eq!(normalize_url_path("/my%20page.html"), "/my%20page.html");
+        assert_eq!(normalize_url_path("/%7Eme/r%C3%A9sum%c3%a9.html"), "/~me/r%C3%A9sum%C3%A9.html");
+        assert_eq!(normalize_url_path("/%2e%2e/%2E%2E/secret"), "/secret");
+        assert_eq!(normalize_url_path("/a%2Fb"), "/a/b");
+        assert_eq!(normalize_url_path("/100%/%zz"), "/100%25/%25zz");
+        // what decodes to a query, fragment or line break stays encoded
+        assert_eq!(normalize_url_path("/what%3F%23x%0D%0A"), "/what%3F%23x%0D%0A");
+    }
+
+    #[test]
+    fn relative_paths() {
+        assert_eq!(relative_path("/a/my%20page.html?x=1").unwrap(), Path::new("a/my page.html"));
+        assert_eq!(relative_path("/what%3F.html").unwrap(), Path::new("what?.html"));
+        assert!(relative_path("/a/%2e%2e/%2e%2e/secret").is_err());
+        assert!(relative_path("/a%2F..%2F..%2Fsecret").is_err());
+        assert!(relative_path("/a%5C..").is_err());
+    }
+
+    #[test]
+    fn mounts_file() {
+        let mounts = parse_mounts("# static files\n/static/ -> /srv/static\n\n  /docs -> ../docs  \n").unwrap();
+        assert_eq!(mounts, vec![
+            ("/static/".to_string(), "/srv/static".into()),
+            ("/docs".to_string(), "../docs".into())
+        ]);
+        assert_eq!(parse_mounts("/static/ /srv/static").unwrap_err(), "line 1: expected `prefix -> directory`");
+        assert!(parse_mounts("/a -> /a\n -> /b").is_err());
+    }
+}
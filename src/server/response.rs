@@ -0,0 +1,409 @@
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::io::{BufWriter, Read, Write};
+use crate::server::cookie;
+use crate::server::cookie::CookieOptions;
+use crate::server::error::ServerError;
+
+/// How much of a streamed body is read before it's written out.
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// An HTTP response, written out with [`Response::write_to`] once it's complete.
+#[derive(Debug)]
+pub struct Response {
+    pub http_version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// sent instead of `body` if there is one
+    streamed: Option<StreamedBody>,
+    /// whether the body is written to the connection by someone else after the head, until it closes
+    open_ended: bool
+}
+
+/// A body copied from a reader, like an open file, while the response is written, so that
+/// it never has to fit in memory.
+struct StreamedBody {
+    reader: Box<dyn Read>,
+    length: u64
+}
+
+impl Debug for StreamedBody {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "StreamedBody({} bytes)", self.length)
+    }
+}
+
+/// When [`Response::send`] flushes what it has written to the connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FlushStrategy {
+    /// write through a buffer of `CHUNK_SIZE` bytes and flush once the response is complete, so that
+    /// a small response goes out together; a larger one goes out whenever the buffer fills
+    #[default]
+    OnCompletion,
+    /// flush the head, and then every chunk of the body as soon as it's written, so that a slow
+    /// streamed body reaches the client as it's read
+    PerChunk
+}
+
+/// The standard reason phrase for `status`, or an empty one (which is allowed) for statuses
+/// the server doesn't use.
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        417 => "Expectation Failed",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => ""
+    }
+}
+
+impl Response {
+    /// A response with the standard reason phrase for `status`.
+    pub fn new(status: u16) -> Response {
+        Response {
+            http_version: "HTTP/1.1".to_string(),
+            status,
+            reason: reason_phrase(status).to_string(),
+            headers: vec![],
+            body: vec![],
+            streamed: None,
+            open_ended: false
+        }
+    }
+
+    /// A `304 Not Modified` for a representation the client already has. It carries no body,
+    /// and therefore no `Content-Length` either.
+    pub fn not_modified(etag: &str) -> Response {
+        Response::new(304).with_header("ETag", etag)
+    }
+
+    /// A `204 No Content`, e.g. for a health check or a DELETE that has nothing to say.
+    /// Like a `304`, it's written without a body or a `Content-Length`.
+    pub fn no_content() -> Response {
+        Response::new(204)
+    }
+
+    pub fn with_header(mut self, key: &str, value: &str) -> Response {
+        self.headers.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Sets the cookie `name` to `value` with a `Set-Cookie` header. Each cookie gets a header of its own,
+    /// so this can be called once for every cookie. `value` is sent as is, so it has to be a valid
+    /// cookie value already: no whitespace, quotes, commas, semicolons or backslashes. Fails otherwise,
+    /// or if the name, path or domain could end the header or add attributes to it.
+    pub fn with_cookie(self, name: &str, value: &str, options: CookieOptions) -> Result<Response, ServerError> {
+        Ok(self.with_header("Set-Cookie", &cookie::set_cookie(name, value, &options)?))
+    }
+
+    pub fn with_body(mut self, body: Vec<u8>) -> Response {
+        self.body = body;
+        self
+    }
+
+    /// Sends the first `length` bytes of `reader` as the body, reading them only as they're written.
+    pub fn with_streamed_body(mut self, reader: impl Read + 'static, length: u64) -> Response {
+        self.streamed = Some(StreamedBody { reader: Box::new(reader), length });
+        self
+    }
+
+    /// A response whose body goes on until the connection is closed, like an event stream.
+    /// Only the head is written by [`Response::write_to`], without a `Content-Length`,
+    /// and whoever has the connection writes the body as it becomes available.
+    pub fn open_ended(mut self) -> Response {
+        self.open_ended = true;
+        self
+    }
+
+    /// Whether the status may have a body at all; 1xx, 204 and 304 responses never do.
+    fn status_allows_body(&self) -> bool {
+        !(100..200).contains(&self.status) && self.status != 204 && self.status != 304
+    }
+
+    /// The status line, headers, a `Content-Length` for the body, and the blank line that ends them.
+    /// Statuses that never have a body and open-ended responses get no `Content-Length`.
+    /// CR, LF and NUL are left out of header names and values, so that no value, wherever it came
+    /// from, can end its header early and add headers or a body of its own.
+    fn head(&self) -> String {
+        let strip = |text: &str| text.replace(['\r', '\n', '\0'], "");
+        let mut head = format!("{} {} {}\r\n", self.http_version, self.status, strip(&self.reason));
+        for (key, value) in &self.headers {
+            head += &format!("{}: {}\r\n", strip(key), strip(value));
+        }
+        if self.status_allows_body() && !self.open_ended {
+            let length = self.streamed.as_ref().map_or(self.body.len() as u64, |streamed| streamed.length);
+            head += &format!("Content-Length: {}\r\n", length);
+        }
+        head += "\r\n";
+        head
+    }
+
+    /// Writes the response without copying the body into a buffer first. A streamed body is
+    /// read as it's written; if it ends early, the response is cut short and this fails.
+    /// Nothing is flushed; see [`Response::send`] for that.
+    pub fn write_to(&mut self, writer: &mut impl Write) -> io::Result<()> {
+        self.write_chunks(writer, false)
+    }
+
+    /// Writes the response like [`Response::write_to`] and flushes it as `flush` says. Whichever
+    /// write or flush fails first is the error, and nothing more is written after it.
+    pub fn send(&mut self, writer: &mut impl Write, flush: FlushStrategy) -> io::Result<()> {
+        match flush {
+            FlushStrategy::OnCompletion => {
+                let mut buffered = BufWriter::with_capacity(CHUNK_SIZE, writer);
+                self.write_chunks(&mut buffered, false)?;
+                buffered.flush()
+            }
+            FlushStrategy::PerChunk => self.write_chunks(writer, true)
+        }
+    }
+
+    /// Writes the head and body, flushing after the head and each chunk of the body if `flush_chunks`.
+    /// A body set on a response whose status can't have one is left out, since the client would
+    /// take it for the start of the next response.
+    fn write_chunks(&mut self, writer: &mut impl Write, flush_chunks: bool) -> io::Result<()> {
+        let write_chunk = |writer: &mut _, chunk: &[u8]| {
+            Write::write_all(writer, chunk)?;
+            if flush_chunks {
+                Write::flush(writer)?;
+            }
+            Ok(())
+        };
+        write_chunk(writer, self.head().as_bytes())?;
+        if self.open_ended || !self.status_allows_body() {
+            return Ok(());
+        }
+        match &mut self.streamed {
+            Some(StreamedBody { reader, length }) => {
+                let mut chunk = vec![0; CHUNK_SIZE];
+                let mut copied = 0;
+                while copied < *length {
+                    let wanted = (*length - copied).min(CHUNK_SIZE as u64) as usize;
+                    let read = match reader.read(&mut chunk[..wanted]) {
+                        Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                           format!("body ended after {} of {} bytes", copied, length))),
+                        Ok(read) => read,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e)
+                    };
+                    write_chunk(writer, &chunk[..read])?;
+                    copied += read as u64;
+                }
+                Ok(())
+            }
+            None => write_chunk(writer, &self.body)
+        }
+    }
+}
+
+/// Counts the bytes written through it, headers and all, for logging how much a response took.
+pub struct CountingWriter<W: Write> {
+    inner: W,
+    written: u64
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W) -> CountingWriter<W> {
+        CountingWriter { inner, written: 0 }
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.written
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::io::{Read, Write};
+    use crate::server::cookie::{CookieOptions, SameSite};
+    use crate::server::response::{CHUNK_SIZE, CountingWriter, FlushStrategy, reason_phrase, Response};
+
+    #[test]
+    fn counts_whole_response() {
+        let mut response = Response::new(200)
+            .with_header("Content-Type", "text/plain")
+            .with_body(b"hello".to_vec());
+        let mut writer = CountingWriter::new(vec![]);
+        response.write_to(&mut writer).unwrap();
+        writer.flush().unwrap();
+        let expected = b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\nhello";
+        assert_eq!(writer.inner, expected);
+        assert_eq!(writer.bytes_written(), expected.len() as u64);
+    }
+
+    #[test]
+    fn cookies() {
+        let mut written = vec![];
+        Response::new(204)
+            .with_cookie("theme", "dark", CookieOptions::default()).unwrap()
+            .with_cookie("session", "abc123", CookieOptions { http_only: true, same_site: SameSite::Strict, ..CookieOptions::default() }).unwrap()
+            .write_to(&mut written).unwrap();
+        assert!(Response::new(204).with_cookie("session", "x; Domain=evil.com", CookieOptions::default()).is_err());
+        assert_eq!(String::from_utf8(written).unwrap(), "HTTP/1.1 204 No Content\r\n\
+            Set-Cookie: theme=dark; Path=/; SameSite=Lax\r\n\
+            Set-Cookie: session=abc123; Path=/; HttpOnly; SameSite=Strict\r\n\r\n");
+    }
+
+    #[test]
+    fn reason_phrases() {
+        assert_eq!(reason_phrase(200), "OK");
+        assert_eq!(reason_phrase(206), "Partial Content");
+        assert_eq!(reason_phrase(304), "Not Modified");
+        assert_eq!(reason_phrase(400), "Bad Request");
+        assert_eq!(reason_phrase(413), "Payload Too Large");
+        assert_eq!(reason_phrase(416), "Range Not Satisfiable");
+        assert_eq!(reason_phrase(412), "Precondition Failed");
+        assert_eq!(reason_phrase(429), "Too Many Requests");
+        assert_eq!(reason_phrase(503), "Service Unavailable");
+        assert_eq!(reason_phrase(504), "Gateway Timeout");
+        assert_eq!(reason_phrase(599), "");
+        assert_eq!(Response::new(404).reason, "Not Found");
+    }
+
+    #[test]
+    fn not_modified_has_no_body() {
+        let mut written = vec![];
+        Response::not_modified("\"abc\"").write_to(&mut written).unwrap();
+        assert_eq!(written, b"HTTP/1.1 304 Not Modified\r\nETag: \"abc\"\r\n\r\n");
+    }
+
+    #[test]
+    fn line_breaks_in_headers() {
+        let mut written = vec![];
+        Response::new(302).with_header("Location", "/a\r\nSet-Cookie: x=1").with_header("X-\nEvil", "a\0b")
+            .write_to(&mut written).unwrap();
+        assert_eq!(written, b"HTTP/1.1 302 Found\r\nLocation: /aSet-Cookie: x=1\r\nX-Evil: ab\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn bodyless_statuses() {
+        let mut written = vec![];
+        Response::no_content().with_header("X-Health", "ok").with_body(b"ignored".to_vec())
+            .write_to(&mut written).unwrap();
+        assert_eq!(written, b"HTTP/1.1 204 No Content\r\nX-Health: ok\r\n\r\n");
+
+        let mut written = vec![];
+        Response::not_modified("\"abc\"").with_streamed_body(&b"ignored"[..], 7)
+            .send(&mut written, FlushStrategy::PerChunk).unwrap();
+        assert_eq!(written, b"HTTP/1.1 304 Not Modified\r\nETag: \"abc\"\r\n\r\n");
+
+        // an empty 200 still says how long it is, so the client knows where it ends
+        let mut written = vec![];
+        Response::new(200).write_to(&mut written).unwrap();
+        assert_eq!(written, b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn open_ended_writes_only_the_head() {
+        let mut written = vec![];
+        Response::new(200).with_header("Content-Type", "text/event-stream").with_body(b"ignored".to_vec())
+            .open_ended()
+            .write_to(&mut written).unwrap();
+        assert_eq!(written, b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\r\n");
+    }
+
+    #[test]
+    fn streamed_bodies() {
+        let mut written = vec![];
+        Response::new(200).with_streamed_body(io::Cursor::new(b"hello, world".to_vec()), 5)
+            .write_to(&mut written).unwrap();
+        assert_eq!(written, b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello");
+
+        // only a buffer's worth of the body is ever held in memory
+        let length = 256 * 1024 * 1024;
+        let mut writer = CountingWriter::new(io::sink());
+        Response::new(200).with_streamed_body(io::repeat(b'x').take(length), length)
+            .write_to(&mut writer).unwrap();
+        assert!(writer.bytes_written() > length);
+
+        let mut written = vec![];
+        let result = Response::new(200).with_streamed_body(io::Cursor::new(b"short".to_vec()), 10)
+            .write_to(&mut written);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert!(written.ends_with(b"Content-Length: 10\r\n\r\nshort"));
+    }
+
+    /// Records how many bytes each write got, and where the flushes were.
+    #[derive(Default)]
+    struct Recorder {
+        calls: Vec<Option<usize>>,
+        fail_flush: bool
+    }
+
+    impl Write for Recorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.calls.push(Some(buf.len()));
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.calls.push(None);
+            if self.fail_flush {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "gone"));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flush_strategies() {
+        let head = "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n".len();
+        let mut recorder = Recorder::default();
+        Response::new(200).with_body(b"hello".to_vec()).send(&mut recorder, FlushStrategy::OnCompletion).unwrap();
+        assert_eq!(recorder.calls, [Some(head + 5), None]);
+
+        let length = 2 * CHUNK_SIZE + 10;
+        let mut recorder = Recorder::default();
+        Response::new(200).with_streamed_body(io::repeat(b'x'), length as u64)
+            .send(&mut recorder, FlushStrategy::PerChunk).unwrap();
+        let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", length).len();
+        assert_eq!(recorder.calls, [Some(head), None, Some(CHUNK_SIZE), None, Some(CHUNK_SIZE), None, Some(10), None]);
+
+        // a failed flush ends the response
+        for flush in [FlushStrategy::OnCompletion, FlushStrategy::PerChunk] {
+            let mut recorder = Recorder { fail_flush: true, ..Recorder::default() };
+            let result = Response::new(200).with_streamed_body(io::repeat(b'x'), length as u64).send(&mut recorder, flush);
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+            assert_eq!(recorder.calls.last(), Some(&None));
+        }
+    }
+}
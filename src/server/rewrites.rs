@@ -0,0 +1,121 @@
+use regex::Regex;
+use crate::server::error::ServerError;
+use crate::server::response::Response;
+
+/// How many times rules may rewrite a url before it's taken to be going round in circles.
+pub const MAX_REWRITE_PASSES: usize = 10;
+
+/// A pattern for url paths and what paths matching it turn into.
+#[derive(Clone, Debug)]
+pub struct RewriteRule {
+    pattern: Regex,
+    /// may refer to capture groups as `$1` or `${name}`, and may have a query of its own
+    replacement: String,
+    /// answer with a redirect with this status, instead of serving the rewritten url
+    redirect: Option<u16>
+}
+
+/// What the rules made of a url.
+pub enum Rewritten {
+    /// serve this url, which may be the one requested
+    Url(String),
+    Redirect(Response)
+}
+
+impl RewriteRule {
+    /// Fails if `pattern` isn't a valid regex.
+    pub fn new(pattern: &str, replacement: &str, redirect: Option<u16>) -> Result<RewriteRule, regex::Error> {
+        Ok(RewriteRule {
+            pattern: Regex::new(pattern)?,
+            replacement: replacement.to_string(),
+            redirect
+        })
+    }
+}
+
+/// Runs `path` through `rules`: the first rule matching it rewrites it, and the rewritten path goes through
+/// the rules again, until none match or a rule redirects. The request's `query` (with its `?`, or empty)
+/// is kept unless a replacement brings a query of its own.
+pub fn apply(rules: &[RewriteRule], path: &str, query: &str) -> Result<Rewritten, ServerError> {
+    let mut url = format!("{}{}", path, query);
+    for _ in 0..MAX_REWRITE_PASSES {
+        let (path, query) = url.split_at(url.find('?').unwrap_or(url.len()));
+        let rule = match rules.iter().find(|rule| rule.pattern.is_match(path)) {
+            Some(rule) => rule,
+            None => return Ok(Rewritten::Url(url))
+        };
+        let rewritten = rule.pattern.replace(path, rule.replacement.as_str());
+        let rewritten = if rewritten.contains('?') {
+            rewritten.into_owned()
+        } else {
+            format!("{}{}", rewritten, query)
+        };
+        if let Some(status) = rule.redirect {
+            return Ok(Rewritten::Redirect(Response::new(status).with_header("Location", &rewritten)));
+        }
+        url = rewritten;
+    }
+    Err(ServerError::Rewrite(format!("{}{} is still being rewritten after {} passes", path, query, MAX_REWRITE_PASSES)))
+}
+
+/// Parses rewrite rules from lines like `^/img/(\d+)/(.*)$ -> /images/$2?size=$1`, in order.
+/// A status after the replacement, like `^/old/(.*)$ -> /new/$1 301`, makes the rule redirect.
+/// Blank lines and lines starting with `#` are skipped.
+pub fn parse_rewrites(text: &str) -> Result<Vec<RewriteRule>, String> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| {
+            let (pattern, rest) = line.split_once("->")
+                .ok_or_else(|| format!("line {}: expected `pattern -> replacement [status]`", i))?;
+            let rest: Vec<&str> = rest.split_whitespace().collect();
+            let (replacement, redirect) = match rest[..] {
+                [replacement] => (replacement, None),
+                [replacement, status] => match status.parse() {
+                    Ok(status @ (301 | 302 | 307 | 308)) => (replacement, Some(status)),
+                    _ => return Err(format!("line {}: {} isn't a redirect status, use 301, 302, 307 or 308", i, status))
+                },
+                _ => return Err(format!("line {}: expected `pattern -> replacement [status]`", i))
+            };
+            RewriteRule::new(pattern.trim(), replacement, redirect).map_err(|e| format!("line {}: {}", i, e))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::server::rewrites::{apply, parse_rewrites, Rewritten};
+
+    fn rewrite(rules: &str, path: &str, query: &str) -> String {
+        match apply(&parse_rewrites(rules).unwrap(), path, query).unwrap() {
+            Rewritten::Url(url) => url,
+            Rewritten::Redirect(response) => format!("{} {}", response.status, response.headers[0].1)
+        }
+    }
+
+    #[test]
+    fn rewriting() {
+        let rules = "^/img/(\\d+)/(.*)$ -> /images/$2?size=$1\n^/old/(?P<rest>.*)$ -> /new/${rest} 308\n^/a$ -> /b\n^/b$ -> /c";
+        assert_eq!(rewrite(rules, "/img/200/cat.png", "?v=1"), "/images/cat.png?size=200");
+        assert_eq!(rewrite(rules, "/old/x/y.html", "?v=1"), "308 /new/x/y.html?v=1");
+        assert_eq!(rewrite(rules, "/a", "?v=1"), "/c?v=1");
+        assert_eq!(rewrite(rules, "/img/cat.png", ""), "/img/cat.png");
+    }
+
+    #[test]
+    fn rewrite_loops() {
+        let rules = parse_rewrites("^/a$ -> /b\n^/b$ -> /a").unwrap();
+        assert!(apply(&rules, "/a", "").is_err());
+        let rules = parse_rewrites("^/(.*)$ -> /x/$1").unwrap();
+        assert!(apply(&rules, "/a", "").is_err());
+    }
+
+    #[test]
+    fn bad_rules() {
+        assert!(parse_rewrites("^/(unclosed$ -> /x").unwrap_err().starts_with("line 1: "));
+        assert!(parse_rewrites("# fine\n^/a$ /b").unwrap_err().starts_with("line 2: "));
+        assert!(parse_rewrites("^/a$ -> /b 200").is_err());
+        assert!(parse_rewrites("^/a$ -> /b 301 x").is_err());
+    }
+}
@@ -0,0 +1,47 @@
+use std::fmt::{Display, Formatter};
+use std::ops::Deref;
+use std::path::Path;
+
+/// A fresh, empty directory under the system temp dir for a single test, removed along with
+/// everything in it when dropped, so keep it alive for as long as anything reads from it.
+pub(crate) struct ScratchDir(String);
+
+impl ScratchDir {
+    pub(crate) fn new(name: &str) -> ScratchDir {
+        let dir = std::env::temp_dir()
+            .join(format!("simple-rust-webserver-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        ScratchDir(dir.to_str().unwrap().to_string())
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        Path::new(&self.0)
+    }
+}
+
+impl Deref for ScratchDir {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for ScratchDir {
+    fn as_ref(&self) -> &Path {
+        self.path()
+    }
+}
+
+impl Display for ScratchDir {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,146 @@
+use std::net::TcpStream;
+use std::sync::{Arc, RwLock};
+use tracing::{error, info};
+use crate::server::request::Request;
+use crate::server::resources;
+use crate::server::{ServerError, Website, WebsiteError};
+
+/// Serves the connections a [`crate::server::Server`] accepts.
+pub trait Handler: Send + Sync + 'static {
+    /// Reads requests off `stream` and answers them until the connection is done.
+    fn handle_connection(&self, stream: TcpStream);
+}
+
+impl Handler for Website {
+    fn handle_connection(&self, stream: TcpStream) {
+        self.handle_connection_with(stream, &|_| self, &Website::handle_request)
+    }
+}
+
+/// Which requests a site in a [`SiteSelector`] answers.
+#[derive(Clone, Debug)]
+pub enum SiteRule {
+    /// requests whose `Host` header names this host, with any port ignored; an IPv6 address
+    /// may be given with or without its brackets
+    Host(String),
+    /// requests on connections accepted on this local port
+    Port(u16),
+    /// requests whose url path, once normalized, is this prefix or under it, so `/admin` matches
+    /// `/admin/users` and `//admin`, but not `/administrator`
+    PathPrefix(String)
+}
+
+impl SiteRule {
+    fn matches(&self, request: &Request) -> bool {
+        match self {
+            SiteRule::Host(host) => request.header("Host")
+                .is_some_and(|value| host_name(value).eq_ignore_ascii_case(host.trim_start_matches('[').trim_end_matches(']'))),
+            SiteRule::Port(port) => request.local_addr.is_some_and(|addr| addr.port() == *port),
+            SiteRule::PathPrefix(prefix) => {
+                let path = resources::normalize_url_path(request.url.split('?').next().unwrap_or_default());
+                let prefix = format!("/{}", prefix.trim_matches('/'));
+                path.strip_prefix(prefix.trim_end_matches('/')).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            }
+        }
+    }
+}
+
+/// The host a `Host` header value names, without its port, and without the brackets around an IPv6 address.
+fn host_name(value: &str) -> &str {
+    let value = value.trim();
+    match value.strip_prefix('[') {
+        Some(bracketed) => bracketed.split(']').next().unwrap_or_default(),
+        None => value.split(':').next().unwrap_or_default()
+    }
+}
+
+/// Serves several websites, picking one per request by the first rule that matches it.
+/// Connections are read with the default site's settings, like its keep-alive timeout,
+/// since the site isn't known until a request has been read.
+///
+/// The server reads no configuration file, so there are no `[[site]]` tables to list sites in;
+/// they're put together in code, with a rule for each.
+pub struct SiteSelector {
+    default: Arc<Website>,
+    sites: Vec<(SiteRule, Arc<Website>)>
+}
+
+impl SiteSelector {
+    /// `default` answers requests no rule matches.
+    pub fn new(default: Arc<Website>) -> SiteSelector {
+        SiteSelector {
+            default,
+            sites: vec![]
+        }
+    }
+
+    /// Rules are checked in the order they're added.
+    pub fn with_site(mut self, rule: SiteRule, site: Arc<Website>) -> SiteSelector {
+        self.sites.push((rule, site));
+        self
+    }
+
+    fn select(&self, request: &Request) -> &Website {
+        self.sites.iter()
+            .find(|(rule, _)| rule.matches(request))
+            .map_or(&self.default, |(_, site)| site)
+    }
+}
+
+impl Handler for SiteSelector {
+    fn handle_connection(&self, stream: TcpStream) {
+        self.default.handle_connection_with(stream, &|request| self.select(request), &Website::handle_request)
+    }
+}
+
+/// Builds the website a [`ReloadableSite`] serves.
+pub type SiteBuilder = Box<dyn Fn() -> Result<Website, WebsiteError> + Send + Sync>;
+
+/// Serves a website that can be rebuilt while the server keeps running, e.g. to pick up changed
+/// mounts or redirects files, or a site root that's a symlink to a new release.
+/// Every connection is served by the site that was current when it was accepted.
+pub struct ReloadableSite {
+    build: SiteBuilder,
+    current: RwLock<Arc<Website>>
+}
+
+impl ReloadableSite {
+    /// Builds the first site right away, so a broken configuration is noticed before serving.
+    pub fn new(build: SiteBuilder) -> Result<ReloadableSite, WebsiteError> {
+        let site = build()?;
+        Ok(ReloadableSite {
+            build,
+            current: RwLock::new(Arc::new(site))
+        })
+    }
+
+    /// Replaces the site with a freshly built one; if building it fails, the old one stays.
+    pub fn reload(&self) -> Result<(), WebsiteError> {
+        let site = Arc::new((self.build)()?);
+        *self.current.write().unwrap() = site;
+        Ok(())
+    }
+
+    /// Reloads the site whenever the process receives a SIGHUP.
+    #[cfg(unix)]
+    pub fn reload_on_sighup(self: &Arc<Self>) -> Result<(), ServerError> {
+        let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGHUP])?;
+        let site = Arc::clone(self);
+        std::thread::spawn(move || {
+            for _ in signals.forever() {
+                match site.reload() {
+                    Ok(()) => info!("received SIGHUP, reloaded the website"),
+                    Err(e) => error!("received SIGHUP, but kept the old website: {}", e)
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl Handler for ReloadableSite {
+    fn handle_connection(&self, stream: TcpStream) {
+        let site = Arc::clone(&self.current.read().unwrap());
+        site.handle_connection(stream)
+    }
+}
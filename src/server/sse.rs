@@ -0,0 +1,138 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use crate::server::request::Request;
+
+/// Takes over a connection once the head of its `text/event-stream` response is written, along with
+/// the request that opened it. Events are pushed with [`EventStream::send`] for as long as the
+/// handler, or whatever it hands the stream to, holds on to it; dropping the stream closes the connection.
+/// Keeping the stream and returning frees the worker thread for other connections.
+pub type EventStreamHandler = Box<dyn Fn(&Request, EventStream) + Send + Sync>;
+
+/// One server-sent event, formatted as the HTML Living Standard's event stream format describes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub data: String,
+    pub id: Option<String>,
+    /// how long clients wait before reconnecting after the connection is lost
+    pub retry: Option<Duration>
+}
+
+impl SseEvent {
+    /// An unnamed event, which clients dispatch as a `message`. `data` may span several lines.
+    pub fn new(data: &str) -> SseEvent {
+        SseEvent {
+            data: data.to_string(),
+            ..SseEvent::default()
+        }
+    }
+
+    pub fn with_event(mut self, event: &str) -> SseEvent {
+        self.event = Some(event.to_string());
+        self
+    }
+
+    pub fn with_id(mut self, id: &str) -> SseEvent {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    pub fn with_retry(mut self, retry: Duration) -> SseEvent {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+impl Display for SseEvent {
+    /// Fields can't contain line breaks, so they're dropped from everything but the data,
+    /// which gets a `data:` line per line and is put back together by the client.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let single_line = |value: &str| value.replace(['\r', '\n'], "");
+        if let Some(event) = &self.event {
+            writeln!(f, "event: {}", single_line(event))?;
+        }
+        if let Some(id) = &self.id {
+            writeln!(f, "id: {}", single_line(id))?;
+        }
+        if let Some(retry) = self.retry {
+            writeln!(f, "retry: {}", retry.as_millis())?;
+        }
+        for line in self.data.split("\r\n").flat_map(|line| line.split(['\r', '\n'])) {
+            writeln!(f, "data: {}", line)?;
+        }
+        writeln!(f)
+    }
+}
+
+/// Counts the event streams that are open, so their number can be capped.
+#[derive(Clone, Default)]
+pub(super) struct StreamCount(Arc<AtomicUsize>);
+
+impl StreamCount {
+    /// Counts one more open stream, unless `max` are open already.
+    pub(super) fn reserve(&self, max: usize) -> Option<StreamSlot> {
+        self.0.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| (open < max).then_some(open + 1))
+            .ok()
+            .map(|_| StreamSlot(Arc::clone(&self.0)))
+    }
+}
+
+/// An open event stream, counted until dropped.
+pub(super) struct StreamSlot(Arc<AtomicUsize>);
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The connection of an open `text/event-stream` response.
+pub struct EventStream {
+    stream: TcpStream,
+    _slot: StreamSlot
+}
+
+impl EventStream {
+    pub(super) fn new(stream: TcpStream, slot: StreamSlot) -> EventStream {
+        EventStream { stream, _slot: slot }
+    }
+
+    /// Writes `event` to the client. An error means the client has gone away, or stopped
+    /// reading for so long that the write timed out, and the stream should be dropped.
+    pub fn send(&mut self, event: &SseEvent) -> io::Result<()> {
+        self.stream.write_all(event.to_string().as_bytes())?;
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use crate::server::sse::{SseEvent, StreamCount};
+
+    #[test]
+    fn event_format() {
+        assert_eq!(SseEvent::new("hello").to_string(), "data: hello\n\n");
+        let event = SseEvent::new("first\nsecond\r\nthird")
+            .with_event("update")
+            .with_id("7")
+            .with_retry(Duration::from_secs(3));
+        assert_eq!(event.to_string(), "event: update\nid: 7\nretry: 3000\ndata: first\ndata: second\ndata: third\n\n");
+        assert_eq!(SseEvent::new("").with_event("a\nb").to_string(), "event: ab\ndata: \n\n");
+    }
+
+    #[test]
+    fn stream_count_is_capped() {
+        let count = StreamCount::default();
+        let first = count.reserve(2).unwrap();
+        let _second = count.reserve(2).unwrap();
+        assert!(count.reserve(2).is_none());
+        drop(first);
+        assert!(count.reserve(2).is_some());
+    }
+}
@@ -0,0 +1,105 @@
+use std::fs;
+use std::path::Path;
+use chrono::{Datelike, Local};
+use crate::server::error::ServerError;
+
+/// How deeply `{{include:...}}` may nest before rendering gives up.
+pub const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Replaces `{{year}}`, `{{date}}` and `{{include:file.html}}` tokens in `page`.
+/// Included files are looked up in `include_dir` and rendered as well; unknown tokens are left alone.
+/// `name` is the page's own file name, so that a page including itself is caught as a cycle.
+pub fn render(page: &str, name: &str, include_dir: &Path) -> Result<String, ServerError> {
+    render_nested(page, include_dir, &mut vec![name.to_string()])
+}
+
+/// `stack` holds the names of the pages currently being rendered, outermost first.
+fn render_nested(page: &str, include_dir: &Path, stack: &mut Vec<String>) -> Result<String, ServerError> {
+    let mut rendered = String::with_capacity(page.len());
+    let mut rest = page;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break
+        };
+        rendered.push_str(&rest[..start]);
+        match substitute(rest[start + 2..end].trim(), include_dir, stack)? {
+            Some(value) => rendered.push_str(&value),
+            None => rendered.push_str(&rest[start..end + 2])
+        }
+        rest = &rest[end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+fn substitute(token: &str, include_dir: &Path, stack: &mut Vec<String>) -> Result<Option<String>, ServerError> {
+    if let Some(name) = token.strip_prefix("include:") {
+        return include(name.trim(), include_dir, stack).map(Some);
+    }
+    let now = Local::now();
+    Ok(match token {
+        "year" => Some(now.year().to_string()),
+        "date" => Some(now.format("%Y-%m-%d").to_string()),
+        _ => None
+    })
+}
+
+fn include(name: &str, include_dir: &Path, stack: &mut Vec<String>) -> Result<String, ServerError> {
+    if name.is_empty() || name.starts_with('/') || name.split(['/', '\\']).any(|s| s == "..") {
+        return Err(ServerError::Template(format!("can't include {}", name)));
+    }
+    if stack.iter().any(|page| page == name) {
+        return Err(ServerError::Template(format!("include cycle: {} -> {}", stack.join(" -> "), name)));
+    }
+    if stack.len() > MAX_INCLUDE_DEPTH {
+        return Err(ServerError::Template(format!("includes nested too deeply: {}", stack.join(" -> "))));
+    }
+    let source = fs::read_to_string(include_dir.join(name))
+        .map_err(|e| ServerError::Template(format!("including {} failed: {}", name, e)))?;
+    stack.push(name.to_string());
+    let rendered = render_nested(&source, include_dir, stack);
+    stack.pop();
+    rendered
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Datelike, Local};
+    use crate::server::scratch::ScratchDir;
+    use crate::server::template::{MAX_INCLUDE_DEPTH, render};
+
+    #[test]
+    fn variables_and_includes() {
+        let scratch = ScratchDir::new("template-basic");
+        let dir = scratch.path();
+        std::fs::write(dir.join("header.html"), "<header>{{include:title.html}}</header>").unwrap();
+        std::fs::write(dir.join("title.html"), "site").unwrap();
+        let page = render("{{ include:header.html }}<p>(c) {{year}} {{unknown}} {{ half</p>", "index.html", dir).unwrap();
+        assert_eq!(page, format!("<header>site</header><p>(c) {} {{{{unknown}}}} {{{{ half</p>", Local::now().year()));
+    }
+
+    #[test]
+    fn include_cycles() {
+        let scratch = ScratchDir::new("template-cycles");
+        let dir = scratch.path();
+        std::fs::write(dir.join("a.html"), "{{include:b.html}}").unwrap();
+        std::fs::write(dir.join("b.html"), "{{include:a.html}}").unwrap();
+        assert!(render("{{include:a.html}}", "index.html", dir).is_err());
+        assert!(render("{{include:index.html}}", "index.html", dir).is_err());
+        assert!(render("{{include:missing.html}}", "index.html", dir).is_err());
+        assert!(render("{{include:../secret}}", "index.html", dir).is_err());
+    }
+
+    #[test]
+    fn include_depth_limit() {
+        let scratch = ScratchDir::new("template-depth");
+        let dir = scratch.path();
+        for i in 0..MAX_INCLUDE_DEPTH + 1 {
+            std::fs::write(dir.join(format!("{}.html", i)), format!("{{{{include:{}.html}}}}", i + 1)).unwrap();
+        }
+        std::fs::write(dir.join(format!("{}.html", MAX_INCLUDE_DEPTH + 1)), "bottom").unwrap();
+        assert!(render("{{include:0.html}}", "index.html", dir).is_err());
+        assert_eq!(render("{{include:2.html}}", "index.html", dir).unwrap(), "bottom");
+    }
+}
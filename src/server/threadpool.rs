@@ -1,19 +1,33 @@
+use std::panic::AssertUnwindSafe;
 use std::sync::{Arc, mpsc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{Receiver, Sender};
 use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info_span, warn};
+use crate::server::error::panic_message;
 
-type Job = Box<dyn FnOnce() + Send + 'static>;
+pub struct Job {
+    label: String,
+    work: Box<dyn FnOnce() + Send + 'static>
+}
 
 pub struct Worker {
     id: usize,
-    thread: std::thread::JoinHandle<()>
+    thread: thread::JoinHandle<()>,
+    /// label of the job this worker is running, if any
+    current_job: Arc<Mutex<Option<String>>>
 }
 
 pub struct ThreadPool {
     sender: Sender<Job>,
-    workers: Vec<Worker>
+    workers: Vec<Worker>,
+    jobs_submitted: AtomicUsize
 }
 
+/// How often `shutdown` checks whether the workers are done.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 impl ThreadPool {
     pub fn new(num_workers: usize) -> ThreadPool {
 
@@ -27,30 +41,131 @@ impl ThreadPool {
         }
         ThreadPool {
             sender,
-            workers
+            workers,
+            jobs_submitted: AtomicUsize::new(0)
         }
     }
 
     pub fn execute<F>(&self, f: F)
         where F: FnOnce() + Send + 'static {
-        self.sender.send(Box::new(f));
+        let n = self.jobs_submitted.fetch_add(1, Ordering::Relaxed);
+        self.execute_labeled(format!("job #{}", n), f)
+    }
+
+    /// Like `execute`, but `label` is what gets reported if the job is still running at shutdown.
+    pub fn execute_labeled<F>(&self, label: String, f: F)
+        where F: FnOnce() + Send + 'static {
+        if self.sender.send(Job { label, work: Box::new(f) }).is_err() {
+            error!("Couldn't hand a job to the thread pool; every worker has stopped.");
+        }
+    }
+
+    /// Stops handing out jobs and waits up to `grace` for the workers to finish what they're doing.
+    /// Workers still busy after that are detached, and the labels of their jobs are returned.
+    pub fn shutdown(self, grace: Duration) -> Vec<String> {
+        // closing the channel makes every idle worker exit its loop
+        drop(self.sender);
+        let deadline = Instant::now() + grace;
+        while self.workers.iter().any(|w| !w.thread.is_finished()) && Instant::now() < deadline {
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+        let mut unfinished = vec![];
+        for worker in self.workers {
+            if worker.thread.is_finished() {
+                let _ = worker.thread.join();
+            } else {
+                let job = worker.current_job.lock()
+                    .map(|job| job.clone().unwrap_or_else(|| "unknown job".to_string()))
+                    .unwrap_or_else(|_| "unknown job".to_string());
+                warn!("Worker {} didn't finish {} in time; detaching it.", worker.id, job);
+                unfinished.push(job);
+            }
+        }
+        unfinished
     }
 }
 
 impl Worker {
     pub fn new(id: usize, receiver: Arc<Mutex<Receiver<Job>>>) -> Worker {
+        let current_job = Arc::new(Mutex::new(None));
+        let worker_job = Arc::clone(&current_job);
         let join_handle = thread::spawn(move || loop {
-            if let Ok(job) = Worker::get_job(&receiver) {
-                println!("Worker {} processing a job!", id);
-                job();
-            } // skip over bad unwraps
+            match Worker::get_job(&receiver) {
+                Ok(job) => {
+                    let _span = info_span!("worker-job", worker_id = id, job = %job.label).entered();
+                    debug!("Worker {} processing {}!", id, job.label);
+                    if let Ok(mut current) = worker_job.lock() {
+                        *current = Some(job.label.clone());
+                    }
+                    // a panicking job is over, but the worker goes on to the next one
+                    if let Err(panic) = std::panic::catch_unwind(AssertUnwindSafe(job.work)) {
+                        error!("Worker {} panicked in {}: {}", id, job.label, panic_message(panic.as_ref()));
+                    }
+                    if let Ok(mut current) = worker_job.lock() {
+                        *current = None;
+                    }
+                }
+                Err(true) => break, // the pool is shutting down
+                Err(false) => {} // skip over bad unwraps
+            }
         });
         Worker {
             id,
-            thread: join_handle
+            thread: join_handle,
+            current_job
         }
     }
-    fn get_job(receiver: &Arc<Mutex<Receiver<Job>>>) -> Result<Job, ()> {
-        receiver.lock().map_err(|_| ())?.recv().map_err(|_| ())
+    /// `Err(true)` means the channel is closed and no more jobs will arrive
+    fn get_job(receiver: &Arc<Mutex<Receiver<Job>>>) -> Result<Job, bool> {
+        receiver.lock().map_err(|_| false)?.recv().map_err(|_| true)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use crate::server::threadpool::ThreadPool;
+
+    #[test]
+    fn shutdown_waits_for_short_jobs() {
+        let pool = ThreadPool::new(2);
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..4 {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(20));
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        assert!(pool.shutdown(Duration::from_secs(5)).is_empty());
+        assert_eq!(done.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn workers_survive_panics() {
+        let pool = ThreadPool::new(1);
+        let done = Arc::new(AtomicUsize::new(0));
+        pool.execute(|| panic!("bad job"));
+        let counted = Arc::clone(&done);
+        pool.execute(move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+        });
+        assert!(pool.shutdown(Duration::from_secs(5)).is_empty());
+        assert_eq!(done.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn shutdown_is_bounded_by_grace_period() {
+        let pool = ThreadPool::new(2);
+        pool.execute_labeled("sleepy job".to_string(), || thread::sleep(Duration::from_secs(30)));
+        // give a worker the chance to pick the job up
+        thread::sleep(Duration::from_millis(50));
+        let start = Instant::now();
+        let unfinished = pool.shutdown(Duration::from_millis(100));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(unfinished, vec!["sleepy job".to_string()]);
+    }
+}
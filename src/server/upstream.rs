@@ -0,0 +1,85 @@
+use std::io::Read;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+use crate::server::error::is_timeout;
+
+/// Fetches resources from upstream servers for the cache, which can then be tested with a fake one.
+pub trait UpstreamClient: Send + Sync {
+    /// GETs `url` with `headers` added to the request. Error statuses are responses like any other;
+    /// only failing to get a response at all is an error.
+    fn get(&self, url: &str, headers: &[(String, String)]) -> Result<UpstreamResponse, UpstreamError>;
+}
+
+/// What an upstream answered, with the body decompressed if it was sent gzipped.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpstreamResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>
+}
+
+/// Why an upstream didn't answer.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UpstreamError {
+    /// it couldn't be connected to, or the connection broke, so trying again may work
+    Unreachable(String),
+    /// it didn't answer in time
+    TimedOut(String),
+    /// its answer made no sense, or the request couldn't be made in the first place
+    Failed(String)
+}
+
+impl Display for UpstreamError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpstreamError::Unreachable(reason) => write!(f, "unreachable: {}", reason),
+            UpstreamError::TimedOut(reason) => write!(f, "timed out: {}", reason),
+            UpstreamError::Failed(reason) => write!(f, "failed: {}", reason)
+        }
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+/// The [`UpstreamClient`] the cache uses unless it's given another one.
+pub struct UreqClient {
+    agent: ureq::Agent
+}
+
+impl UreqClient {
+    /// A client that gives up on connecting after `connect_timeout`, and on a whole request after `timeout`.
+    pub fn new(connect_timeout: Duration, timeout: Duration) -> UreqClient {
+        UreqClient {
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(connect_timeout)
+                .timeout(timeout)
+                .build()
+        }
+    }
+}
+
+impl UpstreamClient for UreqClient {
+    fn get(&self, url: &str, headers: &[(String, String)]) -> Result<UpstreamResponse, UpstreamError> {
+        let request = headers.iter().fold(self.agent.get(url), |request, (name, value)| request.set(name, value));
+        let response = match request.call() {
+            // ureq decompresses gzipped bodies as they're read
+            Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+            Err(e) if is_timeout(std::error::Error::source(&e)) => return Err(UpstreamError::TimedOut(e.to_string())),
+            Err(e) if matches!(e.kind(), ureq::ErrorKind::ConnectionFailed | ureq::ErrorKind::Dns | ureq::ErrorKind::Io) =>
+                return Err(UpstreamError::Unreachable(e.to_string())),
+            Err(e) => return Err(UpstreamError::Failed(e.to_string()))
+        };
+        let status = response.status();
+        let headers = response.headers_names().into_iter()
+            .flat_map(|name| response.all(&name).into_iter()
+                .map(|value| (name.clone(), value.to_string()))
+                .collect::<Vec<_>>())
+            .collect();
+        let mut body = vec![];
+        response.into_reader().read_to_end(&mut body).map_err(|e| match is_timeout(Some(&e)) {
+            true => UpstreamError::TimedOut(format!("reading the body: {}", e)),
+            false => UpstreamError::Unreachable(format!("reading the body: {}", e))
+        })?;
+        Ok(UpstreamResponse { status, headers, body })
+    }
+}
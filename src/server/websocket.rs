@@ -0,0 +1,53 @@
+use std::net::TcpStream;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use crate::server::error::ServerError;
+use crate::server::request::Request;
+use crate::server::response::Response;
+
+/// Appended to the client's key before hashing it, as RFC 6455 section 1.3 says.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Takes over a connection once its WebSocket handshake is done, along with the request that opened it.
+/// Frames are read and written straight from the stream.
+pub type WebSocketHandler = Box<dyn Fn(TcpStream, Request) + Send + Sync>;
+
+/// Whether `request` asks to switch the connection to the WebSocket protocol.
+pub fn is_upgrade(request: &Request) -> bool {
+    let has_token = |name: &str, token: &str| request.header(name)
+        .is_some_and(|value| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)));
+    request.method == "GET" && has_token("Upgrade", "websocket") && has_token("Connection", "upgrade")
+}
+
+/// The `Sec-WebSocket-Accept` value that answers a `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = sha1_smol::Sha1::new();
+    hasher.update(key.trim().as_bytes());
+    hasher.update(GUID.as_bytes());
+    STANDARD.encode(hasher.digest().bytes())
+}
+
+/// The `101 Switching Protocols` that completes the handshake an upgrade request starts.
+pub fn handshake(request: &Request) -> Result<Response, ServerError> {
+    let key = request.header("Sec-WebSocket-Key")
+        .filter(|key| STANDARD.decode(key.trim()).is_ok_and(|nonce| nonce.len() == 16))
+        .ok_or_else(|| ServerError::BadRequest("Missing or invalid Sec-WebSocket-Key.".to_string()))?;
+    if request.header("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        return Err(ServerError::BadRequest("Unsupported Sec-WebSocket-Version.".to_string()));
+    }
+    Ok(Response::new(101)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", &accept_key(key)))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::server::websocket::accept_key;
+
+    #[test]
+    fn accept_key_from_rfc() {
+        // the example in RFC 6455 section 1.3
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+}